@@ -0,0 +1,161 @@
+//! Overhead of registering and calling host functions, run with
+//! `cargo bench --bench host_calls`. `cargo bench --bench host_calls --
+//! --test` runs every benchmark once, to check that they still work.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rustc_nightly_reduction::{
+    host_import, register_host_modules, ApiError, HostLinker, HostModule, InstantiationError,
+    MLApiHost, ModuleContext, ModuleRegistry, Overrides, Shim, WasmMemoryHandle,
+};
+
+/// Host module with imports that do nothing, so calling them measures the
+/// trampoline alone.
+struct NoopHost;
+
+impl HostModule for NoopHost {
+    fn name() -> &'static str {
+        "noop"
+    }
+
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for NoopHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = rustc_nightly_reduction::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", "noop")
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, NoopHost, "noop__call", () => |_host| Ok::<(), ApiError>(()))?;
+        host_import!(linker, NoopHost, "noop__call_args", (
+            a: u32,
+            b: u32,
+            c: u64,
+            d: f32,
+        ) => |_host| {
+            let _ = (a, b, c, d);
+            Ok::<(), ApiError>(())
+        })
+    }
+}
+
+/// Calls `noop__call` `CALLS` times from `run` and `noop__call_args` from
+/// `run_args`, `empty` calls nothing.
+const GUEST: &str = r#"(module
+  (import "env" "noop__call" (func $noop (result i32)))
+  (import "env" "noop__call_args" (func $noop_args (param i32 i32 i64 f32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "empty") (result i32) (i32.const 0))
+  (func (export "run") (param $n i32) (result i32)
+    (local $failed i32)
+    (block $done (loop $next
+      (br_if $done (i32.eqz (local.get $n)))
+      (local.set $failed (i32.or (local.get $failed) (call $noop)))
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br $next)))
+    (local.get $failed))
+  (func (export "run_args") (param $n i32) (result i32)
+    (local $failed i32)
+    (block $done (loop $next
+      (br_if $done (i32.eqz (local.get $n)))
+      (local.set $failed (i32.or (local.get $failed)
+        (call $noop_args (local.get $n) (i32.const 2) (i64.const 3) (f32.const 4))))
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br $next)))
+    (local.get $failed)))"#;
+
+const CALLS: u32 = 1_000;
+
+fn import_registration(c: &mut Criterion) {
+    let engine = wasmtime::Engine::default();
+    c.bench_function("imports/ml_api", |b| {
+        b.iter_batched(
+            || HostLinker::new(&engine),
+            |mut linker| {
+                <MLApiHost as Shim<'_>>::imports(&mut linker).unwrap();
+                // Dropped outside the measurement
+                linker
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn host_call(c: &mut Criterion) {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(
+        &mut linker,
+        &ModuleRegistry::empty().with_module::<NoopHost>(),
+        &Overrides::none(),
+    )
+    .unwrap();
+    let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+    let context = ModuleContext::builder()
+        .with_module(NoopHost)
+        .build()
+        .unwrap();
+    let mut store = context.into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let empty = instance
+        .get_typed_func::<(), i32>(&mut store, "empty")
+        .unwrap();
+    let run = instance
+        .get_typed_func::<u32, i32>(&mut store, "run")
+        .unwrap();
+    let run_args = instance
+        .get_typed_func::<u32, i32>(&mut store, "run_args")
+        .unwrap();
+
+    let mut group = c.benchmark_group("host_call");
+    group.bench_function("guest_call_only", |b| {
+        b.iter(|| assert_eq!(empty.call(&mut store, ()).unwrap(), 0))
+    });
+    group.throughput(Throughput::Elements(u64::from(CALLS)));
+    group.bench_function("noop_import", |b| {
+        b.iter(|| assert_eq!(run.call(&mut store, CALLS).unwrap(), 0))
+    });
+    group.bench_function("noop_import_4_args", |b| {
+        b.iter(|| assert_eq!(run_args.call(&mut store, CALLS).unwrap(), 0))
+    });
+    group.finish();
+}
+
+fn marshalling(c: &mut Criterion) {
+    const SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+    let mut buffer = vec![b'a'; 2 * SIZES[2] + 8];
+    // `read_pod_slice` rejects misaligned slices
+    let offset = buffer.as_ptr().align_offset(8) as u64;
+    let memory = WasmMemoryHandle::new(&mut buffer);
+
+    let mut group = c.benchmark_group("read_str");
+    for &size in &SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| memory.read_str(offset, size as u64).unwrap().len())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("read_pod_slice");
+    for &size in &SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let count = (size / std::mem::size_of::<u32>()) as u64;
+            b.iter(|| memory.read_pod_slice::<u32>(offset, count).unwrap().len())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, import_registration, host_call, marshalling);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+//! Pooled checkouts against cold instantiation of the same guest, run with
+//! `cargo bench --bench instance_pool`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustc_nightly_reduction::{
+    register_host_modules, HostLinker, InstancePool, MLApiHost, ModuleContext, ModuleRegistry,
+    Overrides, PoolReset, ShutdownPolicy,
+};
+
+const GUEST: &str = r#"(module
+  (import "env" "ml__remaining_fuel" (func (result i64)))
+  (memory (export "memory") 16)
+  (func (export "run") (result i32) (i32.const 1)))"#;
+
+const ITERATIONS: u32 = 2_000;
+
+fn context() -> Result<ModuleContext, rustc_nightly_reduction::ModuleContextError> {
+    ModuleContext::builder()
+        .with_module(MLApiHost::default())
+        .with_job_threads(1)
+        .build()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!("{:<28} {:>10.2?} per session", name, elapsed / ITERATIONS);
+}
+
+fn main() {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let linker = Arc::new(linker);
+    let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut store = context().unwrap().into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let result: i32 = ModuleContext::call_export(&mut store, &instance, "run", ()).unwrap();
+        assert_eq!(result, 1);
+    }
+    report("cold instantiation", start.elapsed());
+
+    for (name, reset) in [
+        (
+            "pooled, context reset",
+            PoolReset::Context(ShutdownPolicy::CancelAll(Duration::ZERO)),
+        ),
+        ("pooled, re-instantiated", PoolReset::Reinstantiate),
+    ] {
+        let pool = InstancePool::builder()
+            .with_size(4)
+            .with_reset(reset)
+            .build(Arc::clone(&linker), module.clone(), context)
+            .unwrap();
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let mut instance = pool.checkout().unwrap();
+            let result: i32 = instance.call_export("run", ()).unwrap();
+            assert_eq!(result, 1);
+        }
+        report(name, start.elapsed());
+    }
+}
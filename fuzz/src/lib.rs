@@ -0,0 +1,306 @@
+//! Fuzz targets for the guest memory helpers, shared by the cargo-fuzz
+//! targets in `fuzz_targets/` and the smoke run in `tests/fuzz_smoke.rs`.
+//!
+//! Guest memory sits between canary bytes the helpers must never touch.
+//! Every call is checked against bounds and alignment worked out here, so a
+//! helper returning `Ok` for an invalid access, or an error for a valid one,
+//! fails just like a panic does.
+
+use std::fmt::Debug;
+
+use rustc_nightly_reduction::{
+    ApiError, FutureHandle, GuestPtr, GuestStr, PlainOldData, ProtocolConfig, WasmMemoryHandle,
+};
+
+const CANARY: u8 = 0xa5;
+const CANARY_LEN: usize = 64;
+
+/// Reads the fuzzer's bytes as the choices of a run, with `0`s once they
+/// are used up.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let (taken, rest) = self.0.split_at(n.min(self.0.len()));
+        self.0 = rest;
+        taken
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1).first().copied().unwrap_or(0)
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from(self.u8()) | u16::from(self.u8()) << 8
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut value = [0; 8];
+        let bytes = self.bytes(8);
+        value[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(value)
+    }
+
+    /// An address or length, mostly around the memory so accesses are in
+    /// bounds often enough to be interesting, sometimes anything at all.
+    fn offset(&mut self, memory_len: usize) -> u64 {
+        match self.u8() % 8 {
+            0 => self.u64(),
+            1 => u64::MAX - u64::from(self.u8()),
+            _ => u64::from(self.u16()) % (memory_len as u64 + 16),
+        }
+    }
+}
+
+/// Guest memory of `len` bytes between two runs of canaries.
+struct Guarded {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Guarded {
+    fn new(contents: &[u8], len: usize) -> Self {
+        let mut buf = vec![CANARY; CANARY_LEN + len + CANARY_LEN];
+        let memory = &mut buf[CANARY_LEN..CANARY_LEN + len];
+        for (byte, value) in memory.iter_mut().zip(contents.iter().cycle()) {
+            *byte = *value;
+        }
+        Self { buf, len }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[CANARY_LEN..CANARY_LEN + self.len]
+    }
+
+    fn handle(&mut self) -> WasmMemoryHandle<'_> {
+        WasmMemoryHandle::new(&mut self.buf[CANARY_LEN..CANARY_LEN + self.len])
+    }
+
+    fn check_canaries(&self) {
+        let (head, rest) = self.buf.split_at(CANARY_LEN);
+        let tail = &rest[self.len..];
+        assert!(
+            head.iter().chain(tail).all(|&byte| byte == CANARY),
+            "a helper wrote outside of guest memory"
+        );
+    }
+
+    /// The byte range of `len` bytes at `ptr`, if it is in bounds.
+    fn range(&self, ptr: u64, len: u64) -> Option<std::ops::Range<usize>> {
+        let end = ptr.checked_add(len)?;
+        (end <= self.len as u64).then_some(ptr as usize..end as usize)
+    }
+
+    /// Whether guest address `ptr` is aligned to `align` in host memory,
+    /// which is what borrowing a slice of it depends on.
+    fn host_aligned(&self, ptr: u64, align: usize) -> bool {
+        (self.bytes().as_ptr() as usize)
+            .wrapping_add(ptr as usize)
+            .is_multiple_of(align)
+    }
+}
+
+/// Asserts that `result` is `Ok` exactly if the access was `valid`.
+#[track_caller]
+fn check<T: Debug>(what: &str, valid: bool, result: &Result<T, ApiError>) {
+    match result {
+        Ok(value) => assert!(
+            valid,
+            "{} succeeded with {:?} for an invalid access",
+            what, value
+        ),
+        Err(err) => assert!(
+            !valid,
+            "{} failed for a valid access: {}",
+            what,
+            err.display()
+        ),
+    }
+}
+
+/// Asserts that nothing outside of `range` changed since `before`.
+#[track_caller]
+fn check_untouched(before: &[u8], after: &[u8], range: Option<std::ops::Range<usize>>) {
+    let range = range.unwrap_or(0..0);
+    assert_eq!(
+        before[..range.start],
+        after[..range.start],
+        "write before its range"
+    );
+    assert_eq!(
+        before[range.end..],
+        after[range.end..],
+        "write after its range"
+    );
+}
+
+/// Runs the `PlainOldData` helpers for `T` on one access.
+fn pod_access<T: PlainOldData + PartialEq + Debug>(input: &mut Input<'_>, guarded: &mut Guarded) {
+    let size = std::mem::size_of::<T>() as u64;
+    let align = std::mem::align_of::<T>() as u64;
+    let ptr = input.offset(guarded.len);
+    let count = input.offset(guarded.len);
+    // Values to write, decoded by the helpers from a buffer known to fit
+    let mut source = input.bytes(size as usize * 8).to_vec();
+    source.resize(size as usize * 8, 0);
+    let source = WasmMemoryHandle::new(&mut source);
+    let value = source.read_pod::<T>(0).expect("the source holds a value");
+    let values = source
+        .read_pod_vec::<T>(0, u64::from(input.u8() % 8))
+        .expect("the source holds 8 values");
+
+    let before = guarded.bytes().to_vec();
+    let single = guarded
+        .range(ptr, size)
+        .filter(|_| ptr.is_multiple_of(align));
+    let bytes = count
+        .checked_mul(size)
+        .and_then(|len| guarded.range(ptr, len));
+    let written = guarded.range(ptr, values.len() as u64 * size);
+    match input.u8() % 6 {
+        0 => check(
+            "read_pod",
+            single.is_some(),
+            &guarded.handle().read_pod::<T>(ptr),
+        ),
+        1 => {
+            let result = guarded.handle().write_pod(ptr, &value);
+            check("write_pod", single.is_some(), &result);
+            check_untouched(&before, guarded.bytes(), single);
+        }
+        2 => {
+            let valid = bytes.is_some() && guarded.host_aligned(ptr, align as usize);
+            let memory = guarded.handle();
+            let result = memory.read_pod_slice::<T>(ptr, count).map(<[T]>::len);
+            check("read_pod_slice", valid, &result);
+        }
+        3 => {
+            let result = guarded.handle().read_pod_vec::<T>(ptr, count);
+            check(
+                "read_pod_vec",
+                bytes.is_some(),
+                &result.map(|values| values.len()),
+            );
+        }
+        4 => {
+            let result = guarded.handle().write_pod_slice(ptr, &values);
+            check("write_pod_slice", written.is_some(), &result);
+            check_untouched(&before, guarded.bytes(), written.clone());
+            if written.is_some() {
+                let read = guarded.handle().read_pod_vec::<T>(ptr, values.len() as u64);
+                assert_eq!(read.unwrap(), values, "read back what was written");
+            }
+        }
+        _ => {
+            let n = input.offset(guarded.len);
+            let expected = n.checked_mul(size).and_then(|bytes| ptr.checked_add(bytes));
+            let result = GuestPtr::<T>::new(ptr).offset(n).map(GuestPtr::raw);
+            check("GuestPtr::offset", expected.is_some(), &result);
+            if let (Some(expected), Ok(raw)) = (expected, result) {
+                assert_eq!(raw, expected);
+            }
+        }
+    }
+}
+
+/// Feeds guest memory of a fuzzed size and contents to a sequence of fuzzed
+/// reads and writes.
+pub fn memory_access(data: &[u8]) {
+    let mut input = Input(data);
+    let len = usize::from(input.u16() % 512);
+    let contents_len = usize::from(input.u8());
+    let contents = input.bytes(contents_len);
+    let mut guarded = Guarded::new(if contents.is_empty() { &[0] } else { contents }, len);
+    while !input.is_empty() {
+        let ptr = input.offset(len);
+        let count = input.offset(len);
+        let range = guarded.range(ptr, count);
+        let expected = range.clone().map(|range| guarded.bytes()[range].to_vec());
+        match input.u8() % 11 {
+            0 => {
+                let memory = guarded.handle();
+                let result = memory.read_str(ptr, count);
+                let utf8 = expected.as_deref().map(std::str::from_utf8);
+                check("read_str", matches!(utf8, Some(Ok(_))), &result);
+                if let (Some(Ok(expected)), Ok(read)) = (utf8, result) {
+                    assert_eq!(read, expected);
+                }
+            }
+            1 => {
+                let memory = guarded.handle();
+                let result = memory.read_str_lossy(ptr, count);
+                check("read_str_lossy", range.is_some(), &result);
+            }
+            2 => {
+                let before = guarded.bytes().to_vec();
+                let fill = input.u8();
+                let mut memory = guarded.handle();
+                let result = memory.bytes_mut(ptr, count).map(|bytes| {
+                    bytes.fill(fill);
+                    bytes.len()
+                });
+                check("bytes_mut", range.is_some(), &result);
+                if let Ok(len) = result {
+                    assert_eq!(len as u64, count);
+                }
+                check_untouched(&before, guarded.bytes(), range);
+            }
+            3 => pod_access::<u8>(&mut input, &mut guarded),
+            4 => pod_access::<u16>(&mut input, &mut guarded),
+            5 => pod_access::<u32>(&mut input, &mut guarded),
+            6 => pod_access::<u64>(&mut input, &mut guarded),
+            7 => pod_access::<[u16; 3]>(&mut input, &mut guarded),
+            8 => pod_access::<[u32; 5]>(&mut input, &mut guarded),
+            9 => pod_access::<FutureHandle>(&mut input, &mut guarded),
+            _ => {
+                // Null is only allowed for empty strings
+                let memory = guarded.handle();
+                let result = GuestStr::new(ptr, count).read(&memory);
+                let utf8 = expected.as_deref().map(std::str::from_utf8);
+                let valid = matches!(utf8, Some(Ok(_))) && (ptr != 0 || count == 0);
+                check("GuestStr::read", valid, &result);
+            }
+        }
+        guarded.check_canaries();
+    }
+}
+
+/// Reads a [`ProtocolConfig`] from fuzzed guest memory at a fuzzed address.
+pub fn protocol_config(data: &[u8]) {
+    let mut input = Input(data);
+    let ptr = input.offset(data.len());
+    let version = input.u8();
+    let contents = input.bytes(data.len());
+    let mut guarded = Guarded::new(
+        if contents.is_empty() { &[0] } else { contents },
+        contents.len(),
+    );
+    // Mostly a known version, random ones are almost always rejected first
+    if version < 192 {
+        let _ = guarded.handle().write_pod(
+            ptr,
+            &u32::from(version % (ProtocolConfig::VERSION as u8 + 1)),
+        );
+    }
+    let result = ProtocolConfig::read_from(&guarded.handle(), GuestPtr::new(ptr));
+    if ptr == 0 || guarded.range(ptr, 4).is_none() {
+        check("ProtocolConfig::read_from", false, &result);
+    }
+    if let Ok(config) = result {
+        assert_eq!(
+            config.version,
+            ProtocolConfig::VERSION,
+            "read configs are upgraded"
+        );
+        assert!(
+            config.validate().is_ok(),
+            "read configs are valid: {:?}",
+            config
+        );
+    }
+    guarded.check_canaries();
+}
@@ -0,0 +1,15 @@
+#!/usr/bin/env bash
+# Lints and tests the crate with and without the default `host-logging`
+# feature, which must only decide what is logged, never what guests see.
+#
+#     scripts/check_host_logging.sh
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+for features in "" "--no-default-features"; do
+    echo "checking with features: ${features:-default}"
+    # shellcheck disable=SC2086
+    cargo clippy --all-targets $features -- -D warnings
+    # shellcheck disable=SC2086
+    cargo test $features
+done
@@ -0,0 +1,111 @@
+#!/usr/bin/env bash
+# Times an incremental release build of a generated crate registering
+# `IMPORTS` `host_import!`s of a few signatures, once with the shared
+# untyped trampoline and once with the `typed-trampolines` feature.
+#
+#     scripts/trampoline_compile_times.sh [IMPORTS]
+set -euo pipefail
+
+imports=${1:-80}
+root=$(cd "$(dirname "$0")/.." && pwd)
+crate="$root/target/trampoline-compile-times"
+
+# The wasmtime requirement of the crate, e.g. `"41.0"`
+wasmtime=$(sed -nE 's/^wasmtime = ("[^"]*").*/\1/p' "$root/Cargo.toml")
+if [ -z "$wasmtime" ]; then
+    echo "no wasmtime dependency in $root/Cargo.toml" >&2
+    exit 1
+fi
+
+mkdir -p "$crate/src"
+# Same dependency versions as the crate, if it has a lockfile
+if [ -f "$root/Cargo.lock" ]; then
+    cp "$root/Cargo.lock" "$crate/Cargo.lock"
+fi
+cat > "$crate/Cargo.toml" <<TOML
+[package]
+name = "trampoline-compile-times"
+version = "0.0.0"
+edition = "2018"
+
+# Not part of the crate's workspace
+[workspace]
+
+[dependencies]
+rustc-nightly-reduction = { path = "$root", default-features = false }
+wasmtime = $wasmtime
+
+[features]
+typed-trampolines = ["rustc-nightly-reduction/typed-trampolines"]
+TOML
+
+{
+    cat <<'RUST'
+use rustc_nightly_reduction::{
+    host_import, ApiError, HostLinker, HostModule, InstantiationError, ModuleContext, Shim,
+    WasmMemoryHandle,
+};
+
+pub struct Generated;
+
+impl HostModule for Generated {
+    fn name() -> &'static str {
+        "generated"
+    }
+
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for Generated {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = rustc_nightly_reduction::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", "generated")
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+RUST
+    for ((i = 0; i < imports; i++)); do
+        case $((i % 4)) in
+            0) params="a: u32" ;;
+            1) params="a: u32, b: u32" ;;
+            2) params="a: u64, b: u32, c: f64" ;;
+            3) params="name: str" ;;
+        esac
+        echo "        host_import!(linker, Generated, \"generated__import_$i\", ($params) => |_host| {"
+        echo "            let _ = ($(echo "$params" | sed -E 's/: [a-z0-9]+//g'),);"
+        echo "            Ok::<(), ApiError>(())"
+        echo "        })?;"
+    done
+    cat <<'RUST'
+        Ok(())
+    }
+}
+RUST
+} > "$crate/src/lib.rs"
+
+build() {
+    local label=$1
+    shift
+    cargo build --release --quiet --manifest-path "$crate/Cargo.toml" "$@"
+    touch "$crate/src/lib.rs"
+    local start end
+    start=$(date +%s%N)
+    cargo build --release --quiet --manifest-path "$crate/Cargo.toml" "$@"
+    end=$(date +%s%N)
+    local rlib
+    rlib=$(ls -t "$crate"/target/release/deps/libtrampoline_compile_times-*.rlib | head -n 1)
+    printf '%-8s %6.2fs %10d bytes\n' "$label" "$(awk "BEGIN { print ($end - $start) / 1e9 }")" \
+        "$(stat -c %s "$rlib")"
+}
+
+echo "$imports imports"
+build untyped
+build typed --features typed-trampolines
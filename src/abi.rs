@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Version of the conventions imports marshal their arguments with. Guests
+/// work with hosts of the same major version, minor versions only add
+/// imports.
+///
+/// Passed across the boundary as `major << 16 | minor`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AbiVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl AbiVersion {
+    /// The version of this host, returned by the `host__abi_version()`
+    /// import.
+    pub const HOST: Self = Self::new(1, 0);
+
+    /// Assumed for guests that don't export [`GUEST_ABI_EXPORT`], which
+    /// predate the handshake.
+    pub const UNVERSIONED: Self = Self::new(1, 0);
+
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    pub const fn from_raw(raw: u32) -> Self {
+        Self::new((raw >> 16) as u16, raw as u16)
+    }
+
+    pub const fn raw(self) -> u32 {
+        (self.major as u32) << 16 | self.minor as u32
+    }
+
+    /// Whether a guest of version `self` works with a host of version
+    /// `host`.
+    pub fn is_compatible_with(self, host: Self) -> bool {
+        self.major == host.major
+    }
+}
+
+impl fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The export of a guest declaring the [`AbiVersion`] it was built for,
+/// an `i32` global or a function taking nothing and returning one.
+pub const GUEST_ABI_EXPORT: &str = "__host_abi_version";
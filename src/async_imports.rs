@@ -0,0 +1,53 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::task::Poll;
+
+/// Future returned by the async shim and backend methods, which are called
+/// from wasmtime host functions and so have to be `Send`.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Polls `future` inside `catch_unwind`, the async counterpart of the panic
+/// handling of [`host_import!`].
+#[doc(hidden)]
+pub async fn catch_unwind_async<F: Future>(future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut future = Box::pin(future);
+    std::future::poll_fn(move |cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outputs_are_passed_through_across_awaits() {
+        let output = catch_unwind_async(async {
+            tokio::task::yield_now().await;
+            7
+        })
+        .await;
+        assert_eq!(output.ok(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn panics_after_an_await_are_caught() {
+        let failing = async {
+            tokio::task::yield_now().await;
+            panic!("backend {} went away", 3);
+        };
+        let payload = catch_unwind_async(failing).await.unwrap_err();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+        assert_eq!(message, Some("backend 3 went away"));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt::{Display, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write as _};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{CallSite, ErrorCode, ImportParam, ParamRole};
+
+/// Receives an [`AuditEntry`] for every host call of the instances it is
+/// installed in with
+/// [`ModuleContextBuilder::with_audit_sink`](crate::ModuleContextBuilder::with_audit_sink).
+///
+/// Every import declared with [`host_import!`](crate::host_import) is
+/// recorded, including calls that were denied or rate limited. The
+/// bookkeeping imports of the linker, such as `get_last_error` and
+/// `host__has_capability`, and the `time` module's clocks aren't.
+///
+/// Entries are recorded on the calling thread once the call returned, so
+/// sinks should hand them off rather than block.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: AuditEntry);
+}
+
+/// A host call made by a guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    pub module: &'static str,
+    pub function: &'static str,
+    /// The scalar arguments and the lengths of string and slice arguments,
+    /// as `name=value` pairs separated by spaces. Pointers and guest memory
+    /// contents are left out.
+    pub args: String,
+    /// Code returned to the guest, or the code of the error the call
+    /// trapped with.
+    pub code: ErrorCode,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(
+        site: CallSite,
+        params: &[ImportParam],
+        values: &[&dyn Display],
+        code: ErrorCode,
+    ) -> Self {
+        let mut args = String::new();
+        for (param, value) in params.iter().zip(values) {
+            if let ParamRole::Value | ParamRole::Len = param.role {
+                if !args.is_empty() {
+                    args.push(' ');
+                }
+                let _ = write!(args, "{}={}", param.name, value);
+            }
+        }
+        Self {
+            timestamp: SystemTime::now(),
+            module: site.module(),
+            function: site.function(),
+            args,
+            code,
+        }
+    }
+}
+
+/// Keeps entries in memory, for tests.
+#[derive(Debug, Default)]
+pub struct VecAuditSink(Mutex<Vec<AuditEntry>>);
+
+impl VecAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Takes the entries recorded so far.
+    pub fn take(&self) -> Vec<AuditEntry> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl AuditSink for VecAuditSink {
+    fn record(&self, entry: AuditEntry) {
+        self.0.lock().unwrap().push(entry);
+    }
+}
+
+/// Appends entries to a file as JSON lines like
+/// `{"timestamp_us":1639000000000000,"module":"ml_api","function":"ml__start_training","args":"epochs=3","code":0}`.
+///
+/// Every line is flushed as it is written, failing writes are logged.
+#[derive(Debug)]
+pub struct JsonLinesAuditSink(Mutex<LineWriter<File>>);
+
+impl JsonLinesAuditSink {
+    /// Appends to `path`, which is created if it doesn't exist yet.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Mutex::new(LineWriter::new(file))))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JsonEntry<'a> {
+    timestamp_us: u64,
+    module: &'a str,
+    function: &'a str,
+    args: &'a str,
+    code: u32,
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, entry: AuditEntry) {
+        let json = JsonEntry {
+            timestamp_us: entry
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            module: entry.module,
+            function: entry.function,
+            args: &entry.args,
+            code: entry.code as u32,
+        };
+        let mut line = serde_json::to_vec(&json).expect("audit entries serialize");
+        line.push(b'\n');
+        if let Err(err) = self.0.lock().unwrap().write_all(&line) {
+            log::warn!("failed to write audit entry: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::test_support::{self, call, Guest};
+    use crate::{MLApiHost, ModuleContext};
+
+    /// Starts a training with `$epochs` and reads the last error.
+    const GUEST: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (import "env" "ml__get_last_error"
+            (func $get_last_error (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "mnist")
+          (func (export "start") (param $epochs i32) (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (local.get $epochs)
+              (i32.const 64) (i32.const 5)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (i32.const 32)))
+          (func (export "last_error") (result i32)
+            (call $get_last_error (i32.const 128) (i32.const 256))))
+    "#;
+
+    fn guest(sink: Arc<dyn AuditSink>) -> Guest {
+        test_support::guest::<MLApiHost>(
+            GUEST,
+            ModuleContext::builder()
+                .with_module(MLApiHost::default())
+                .with_job_threads(1)
+                .with_audit_sink(sink),
+        )
+    }
+
+    #[test]
+    fn start_training_is_recorded_once() {
+        let sink = Arc::new(VecAuditSink::new());
+        let (mut store, instance) = guest(sink.clone());
+        let before = SystemTime::now();
+        let code = call(&mut store, &instance, "start", 3);
+        assert_eq!(code, ErrorCode::Success as u32);
+
+        let entries = sink.take();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        let entry = &entries[0];
+        assert_eq!(entry.module, "ml_api");
+        assert_eq!(entry.function, crate::ml_imports::START_TRAINING);
+        assert_eq!(entry.code, ErrorCode::Success);
+        assert!(entry.timestamp >= before);
+        assert!(
+            entry
+                .args
+                .starts_with("model_name_len=5 epochs=3 dataset_uri_len=5"),
+            "{}",
+            entry.args
+        );
+        assert!(entry.args.contains("seed=42"), "{}", entry.args);
+        assert!(sink.entries().is_empty());
+    }
+
+    #[test]
+    fn failed_calls_record_their_code_and_bookkeeping_isnt_recorded() {
+        let sink = Arc::new(VecAuditSink::new());
+        let (mut store, instance) = guest(sink.clone());
+        let code = call(&mut store, &instance, "start", 0);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        call(&mut store, &instance, "last_error", ());
+
+        let codes: Vec<_> = sink
+            .entries()
+            .iter()
+            .map(|entry| (entry.function, entry.code))
+            .collect();
+        assert_eq!(
+            codes,
+            [(
+                crate::ml_imports::START_TRAINING,
+                ErrorCode::InvalidArgument
+            )]
+        );
+    }
+
+    #[test]
+    fn summaries_leave_out_pointers() {
+        let site = CallSite::register("audit_test", "audit_test__write");
+        let params = [
+            ImportParam::new("buf_ptr", ParamRole::SlicePtr { elem: "u8" }),
+            ImportParam::new("buf_len", ParamRole::Len),
+            ImportParam::new("level", ParamRole::Value),
+            ImportParam::new("out", ParamRole::Out { pointee: "u32" }),
+        ];
+        let entry = AuditEntry::new(site, &params, &[&1024, &16, &3, &2048], ErrorCode::Success);
+        assert_eq!(entry.args, "buf_len=16 level=3");
+        assert_eq!(
+            (entry.module, entry.function),
+            ("audit_test", "audit_test__write")
+        );
+    }
+
+    #[test]
+    fn json_lines_sinks_append_one_line_per_call() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = Arc::new(JsonLinesAuditSink::create(&path).unwrap());
+        let (mut store, instance) = guest(sink);
+        for epochs in [3, 0] {
+            call(&mut store, &instance, "start", epochs);
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["function"], crate::ml_imports::START_TRAINING);
+        assert_eq!(lines[0]["module"], "ml_api");
+        assert_eq!(lines[0]["code"], 0);
+        assert_eq!(lines[1]["code"], ErrorCode::InvalidArgument as u32);
+        assert!(lines[1]["args"].as_str().unwrap().contains("epochs=0"));
+        assert!(lines[0]["timestamp_us"].as_u64().unwrap() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModuleCacheError {
+    #[error("Failed to create the module cache directory `{}`", .path.display())]
+    Directory {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to compile the guest module")]
+    Compile(#[source] anyhow::Error),
+}
+
+/// Compiled guest modules serialized to a directory, so that a host start
+/// only compiles guests it hasn't seen before.
+///
+/// Artifacts are keyed by the wasm bytes and the engine's configuration, and
+/// stored with a checksum. A missing, unreadable or corrupted artifact, or
+/// one wasmtime refuses to load into the engine, is a miss: the module is
+/// compiled and the artifact replaced. Failing to store an artifact is only
+/// logged.
+pub struct ModuleCache {
+    engine: wasmtime::Engine,
+    dir: PathBuf,
+    /// Digest of what the engine's configuration compiles to, see
+    /// [`engine_fingerprint`].
+    engine_fingerprint: [u8; 32],
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ModuleCache {
+    /// Caches modules of `engine` in `dir`, which is created if it doesn't
+    /// exist yet.
+    ///
+    /// # Safety
+    ///
+    /// Artifacts are native code that is loaded with
+    /// `wasmtime::Module::deserialize` without being validated. The checksum
+    /// only catches corruption, so `dir` has to be writable by trusted
+    /// processes only, such as other starts of this host.
+    pub unsafe fn new(
+        engine: &wasmtime::Engine,
+        dir: impl Into<PathBuf>,
+    ) -> Result<Self, ModuleCacheError> {
+        let dir = dir.into();
+        if let Err(source) = std::fs::create_dir_all(&dir) {
+            return Err(ModuleCacheError::Directory { path: dir, source });
+        }
+        Ok(Self {
+            engine: engine.clone(),
+            engine_fingerprint: engine_fingerprint(engine)?,
+            dir,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of modules loaded from an artifact.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of modules that had to be compiled.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Where the artifact of `wasm` is stored.
+    pub fn artifact_path(&self, wasm: &[u8]) -> PathBuf {
+        let key = Sha256::new()
+            .chain(self.engine_fingerprint)
+            .chain(wasm)
+            .finalize();
+        self.dir.join(format!("{}.cwasm", hex(&key)))
+    }
+
+    /// The module of `wasm`, which may also be in the text format, from its
+    /// artifact if there is a usable one.
+    pub fn load(&self, wasm: &[u8]) -> Result<wasmtime::Module, ModuleCacheError> {
+        let path = self.artifact_path(wasm);
+        match self.deserialize(&path) {
+            Ok(module) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(module);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!(
+                "recompiling unusable module artifact `{}`: {}",
+                path.display(),
+                err
+            ),
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let module =
+            wasmtime::Module::new(&self.engine, wasm).map_err(ModuleCacheError::Compile)?;
+        if let Err(err) = self.store(&path, &module) {
+            log::warn!(
+                "failed to store module artifact `{}`: {}",
+                path.display(),
+                err
+            );
+        }
+        Ok(module)
+    }
+
+    fn deserialize(&self, path: &Path) -> io::Result<wasmtime::Module> {
+        let file = std::fs::read(path)?;
+        if file.len() < 32 {
+            return Err(invalid_data("artifact is truncated"));
+        }
+        let (checksum, artifact) = file.split_at(32);
+        if *Sha256::digest(artifact) != *checksum {
+            return Err(invalid_data("artifact checksum mismatch"));
+        }
+        // Safe as of the contract of `new`, the checksum ensures the
+        // artifact is the one a trusted process stored
+        unsafe { wasmtime::Module::deserialize(&self.engine, artifact) }
+            .map_err(|err| invalid_data(format!("{:#}", err)))
+    }
+
+    fn store(&self, path: &Path, module: &wasmtime::Module) -> anyhow::Result<()> {
+        let artifact = module.serialize()?;
+        let mut file = Sha256::digest(&artifact).to_vec();
+        file.extend_from_slice(&artifact);
+        // Written next to the destination and renamed, so concurrent loads
+        // never see a partial artifact
+        let staging = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&staging, &file)?;
+        std::fs::rename(&staging, path)?;
+        Ok(())
+    }
+}
+
+/// Digest of an empty module compiled with `engine`. Serialized modules
+/// record wasmtime's version and the compilation settings of the engine, so
+/// engines only agree on it if they can load each other's artifacts.
+fn engine_fingerprint(engine: &wasmtime::Engine) -> Result<[u8; 32], ModuleCacheError> {
+    let artifact = wasmtime::Module::new(engine, "(module)")
+        .and_then(|module| module.serialize())
+        .map_err(ModuleCacheError::Compile)?;
+    Ok(Sha256::digest(&artifact).into())
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST: &str = r#"(module (func (export "answer") (result i32) (i32.const 42)))"#;
+
+    /// An empty cache directory of its own for the test `name`.
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("module-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn cache(engine: &wasmtime::Engine, dir: &Path) -> ModuleCache {
+        // SAFETY: the directory is only written by the test
+        unsafe { ModuleCache::new(engine, dir) }.unwrap()
+    }
+
+    /// Checks that `module` is the one of [`GUEST`].
+    fn answer(engine: &wasmtime::Engine, module: &wasmtime::Module) -> i32 {
+        let mut store = wasmtime::Store::new(engine, ());
+        let instance = wasmtime::Instance::new(&mut store, module, &[]).unwrap();
+        let answer = instance
+            .get_typed_func::<(), i32>(&mut store, "answer")
+            .unwrap();
+        answer.call(&mut store, ()).unwrap()
+    }
+
+    #[test]
+    fn stored_modules_are_hits() {
+        let dir = dir("hit");
+        let engine = wasmtime::Engine::default();
+        let cache = cache(&engine, &dir);
+        assert_eq!(cache.dir(), dir);
+        assert!(!cache.artifact_path(GUEST.as_bytes()).exists());
+
+        let compiled = cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+        assert!(cache.artifact_path(GUEST.as_bytes()).exists());
+        assert_eq!(answer(&engine, &compiled), 42);
+
+        let loaded = cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        assert_eq!(answer(&engine, &loaded), 42);
+
+        // Another start of the host finds the artifact as well
+        let restarted = self::cache(&engine, &dir);
+        restarted.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((restarted.hits(), restarted.misses()), (1, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_modules_are_misses() {
+        let dir = dir("miss");
+        let engine = wasmtime::Engine::default();
+        let cache = cache(&engine, &dir);
+        cache.load(GUEST.as_bytes()).unwrap();
+        let other = "(module)";
+        assert_ne!(
+            cache.artifact_path(other.as_bytes()),
+            cache.artifact_path(GUEST.as_bytes())
+        );
+        cache.load(other.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_artifacts_are_recompiled() {
+        let dir = dir("corrupted");
+        let engine = wasmtime::Engine::default();
+        let cache = cache(&engine, &dir);
+        cache.load(GUEST.as_bytes()).unwrap();
+        let path = cache.artifact_path(GUEST.as_bytes());
+
+        let mut artifact = std::fs::read(&path).unwrap();
+        let last = artifact.len() - 1;
+        artifact[last] ^= 0xff;
+        std::fs::write(&path, &artifact).unwrap();
+        let module = cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert_eq!(answer(&engine, &module), 42);
+
+        std::fs::write(&path, b"short").unwrap();
+        cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 3));
+
+        // Recompiling replaced the artifact
+        cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn engines_of_other_configs_dont_share_artifacts() {
+        let dir = dir("engines");
+        let engine = wasmtime::Engine::default();
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let fueled = wasmtime::Engine::new(&config).unwrap();
+        let cache = self::cache(&engine, &dir);
+        let other = self::cache(&fueled, &dir);
+        let path = cache.artifact_path(GUEST.as_bytes());
+        assert_ne!(path, other.artifact_path(GUEST.as_bytes()));
+
+        cache.load(GUEST.as_bytes()).unwrap();
+        other.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((other.hits(), other.misses()), (0, 1));
+
+        // An artifact of the other engine under this engine's key, with a
+        // valid checksum, is refused by wasmtime and recompiled
+        std::fs::copy(other.artifact_path(GUEST.as_bytes()), &path).unwrap();
+        let module = cache.load(GUEST.as_bytes()).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert_eq!(answer(&engine, &module), 42);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_modules_fail_to_compile() {
+        let dir = dir("invalid");
+        let cache = cache(&wasmtime::Engine::default(), &dir);
+        assert!(matches!(
+            cache.load(b"(module (func (result i32)))"),
+            Err(ModuleCacheError::Compile(_))
+        ));
+        assert!(!cache
+            .artifact_path(b"(module (func (result i32)))")
+            .exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
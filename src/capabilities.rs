@@ -0,0 +1,190 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use crate::HostModule;
+
+import_names!(pub mod host_imports = "host" {
+    HAS_CAPABILITY = "has_capability",
+    ABI_VERSION = "abi_version",
+});
+
+/// The host modules a guest instance may call, by [`HostModule::name`].
+///
+/// Every import checks it before running: calls to a module that isn't
+/// granted fail with [`ErrorCode::PermissionDenied`](crate::ErrorCode) without
+/// reaching the module. Imports are registered regardless, so one compiled
+/// guest links against every permission set. Guests can check what they
+/// were granted with the `host__has_capability(name_ptr, name_len) -> u32`
+/// import, which returns `1` for granted modules.
+///
+/// By default every module is granted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities(Grant);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Grant {
+    AllExcept(BTreeSet<Cow<'static, str>>),
+    Only(BTreeSet<Cow<'static, str>>),
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Self(Grant::AllExcept(BTreeSet::new()))
+    }
+
+    pub fn none() -> Self {
+        Self(Grant::Only(BTreeSet::new()))
+    }
+
+    /// Grants the host module named `module`.
+    pub fn with(mut self, module: impl Into<Cow<'static, str>>) -> Self {
+        match &mut self.0 {
+            Grant::AllExcept(denied) => {
+                denied.remove(&module.into());
+            }
+            Grant::Only(granted) => {
+                granted.insert(module.into());
+            }
+        }
+        self
+    }
+
+    /// Revokes the host module named `module`.
+    pub fn without(mut self, module: impl Into<Cow<'static, str>>) -> Self {
+        match &mut self.0 {
+            Grant::AllExcept(denied) => {
+                denied.insert(module.into());
+            }
+            Grant::Only(granted) => {
+                granted.remove(&module.into());
+            }
+        }
+        self
+    }
+
+    pub fn with_module<M: HostModule>(self) -> Self {
+        self.with(M::name())
+    }
+
+    pub fn without_module<M: HostModule>(self) -> Self {
+        self.without(M::name())
+    }
+
+    pub fn allows(&self, module: &str) -> bool {
+        match &self.0 {
+            Grant::AllExcept(denied) => !denied.contains(module),
+            Grant::Only(granted) => granted.contains(module),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, call, Guest};
+    use crate::{ErrorCode, MLApiHost, ModuleContext};
+
+    #[test]
+    fn grants_start_from_all_or_none() {
+        let all = Capabilities::default();
+        assert_eq!(all, Capabilities::all());
+        assert!(all.allows("ml_api") && all.allows("storage"));
+        let some = Capabilities::all().without("storage");
+        assert!(some.allows("ml_api") && !some.allows("storage"));
+        assert!(some.with("storage").allows("storage"));
+
+        let none = Capabilities::none();
+        assert!(!none.allows("ml_api"));
+        let some = Capabilities::none().with_module::<MLApiHost>();
+        assert!(some.allows("ml_api") && !some.allows("storage"));
+        assert!(!some.without_module::<MLApiHost>().allows("ml_api"));
+    }
+
+    /// Starts a training and asks for the capability named at `64`.
+    const GUEST: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (import "env" "host__has_capability"
+            (func $has_capability (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "ml_api")
+          (data (i32.const 80) "storage")
+          (func (export "start") (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (i32.const 3)
+              (i32.const 64) (i32.const 5)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (i32.const 32)))
+          (func (export "may_train") (result i32)
+            (call $has_capability (i32.const 64) (i32.const 6)))
+          (func (export "may_store") (result i32)
+            (call $has_capability (i32.const 80) (i32.const 7))))
+    "#;
+
+    fn guest(capabilities: Capabilities) -> Guest {
+        test_support::guest::<MLApiHost>(
+            GUEST,
+            ModuleContext::builder()
+                .with_module(MLApiHost::default())
+                .with_job_threads(1)
+                .with_capabilities(capabilities),
+        )
+    }
+
+    #[test]
+    fn granted_modules_are_called() {
+        let (mut store, instance) = guest(Capabilities::none().with_module::<MLApiHost>());
+        assert_eq!(
+            call(&mut store, &instance, "start", ()),
+            ErrorCode::Success as u32
+        );
+        let futures = store.data().module::<MLApiHost>().unwrap().futures().len();
+        assert_eq!(futures, 1);
+    }
+
+    #[test]
+    fn denied_modules_fail_without_running() {
+        let (mut store, instance) = guest(Capabilities::all().without_module::<MLApiHost>());
+        assert_eq!(
+            call(&mut store, &instance, "start", ()),
+            ErrorCode::PermissionDenied as u32
+        );
+        let err = store.data().last_error().unwrap();
+        assert_eq!(err.code(), ErrorCode::PermissionDenied);
+        assert!(err
+            .guest_message()
+            .ends_with("instance isn't granted host module `ml_api`"));
+        let futures = store.data().module::<MLApiHost>().unwrap().futures().len();
+        assert_eq!(futures, 0);
+    }
+
+    #[test]
+    fn guests_query_their_capabilities() {
+        let (mut store, instance) = guest(Capabilities::all().without("storage"));
+        assert_eq!(call(&mut store, &instance, "may_train", ()), 1);
+        assert_eq!(call(&mut store, &instance, "may_store", ()), 0);
+        assert_eq!(
+            store.data().capabilities(),
+            &Capabilities::all().without("storage")
+        );
+
+        // The host module itself is always callable
+        let (mut store, instance) = guest(Capabilities::none());
+        assert_eq!(call(&mut store, &instance, "may_train", ()), 0);
+    }
+}
@@ -0,0 +1,1398 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use wasmtime::AsContextMut;
+
+use crate::engine::DeprecatedCall;
+use crate::memory::MemoryExports;
+use crate::rate_limit::RateLimiter;
+use crate::{
+    stats, ApiError, CallSite, CallStat, Capabilities, ClockSource, ErrorCode, FutureHandle,
+    HostModule, HostTrap, JobExecutor, LiveFuture, ModuleError, ProtocolConfig, RateLimit,
+    RetryPolicy, StoreLimiter, SystemClock, WorkerShutdown,
+};
+#[cfg(feature = "audit")]
+use crate::{AuditEntry, AuditSink, ImportParam};
+
+/// How [`ModuleContext::shutdown`] treats outstanding work, such as pending
+/// trainings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Wait up to the timeout for the work to finish, then cancel it.
+    WaitAll(Duration),
+    /// Cancel the work right away, where it can still report its outcome
+    /// within the timeout.
+    CancelAll(Duration),
+}
+
+/// [`HostModule`] functions called for a module's type erased state.
+#[derive(Clone, Copy)]
+struct ModuleHooks {
+    shutdown: fn(&mut (dyn Any + Send), ShutdownPolicy) -> Vec<FutureHandle>,
+    live_futures: fn(&(dyn Any + Send)) -> Vec<LiveFuture>,
+    reset: fn(&mut (dyn Any + Send)),
+}
+
+impl ModuleHooks {
+    fn of<M: HostModule + Any + Send>() -> Self {
+        Self {
+            shutdown: |state, policy| {
+                let state = state
+                    .downcast_mut::<M>()
+                    .expect("module state keyed by its type");
+                M::shutdown(state, policy)
+            },
+            live_futures: |state| {
+                let state = state
+                    .downcast_ref::<M>()
+                    .expect("module state keyed by its type");
+                M::live_futures(state)
+            },
+            reset: |state| {
+                let state = state
+                    .downcast_mut::<M>()
+                    .expect("module state keyed by its type");
+                M::reset(state)
+            },
+        }
+    }
+}
+
+/// Host modules in use by a host call, by module name, with the call using
+/// them.
+#[derive(Clone, Debug, Default)]
+struct Leases(Arc<Mutex<HashMap<&'static str, CallSite>>>);
+
+impl Leases {
+    fn lock(&self) -> MutexGuard<'_, HashMap<&'static str, CallSite>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Marks a host module as in use by a host call until it is dropped, see
+/// [`ModuleContext::lease_module`]. Doesn't borrow the context, so the call
+/// can still call back into the guest while holding it.
+#[must_use = "the module is only in use while the lease is held"]
+#[derive(Debug)]
+pub struct ModuleLease {
+    leases: Leases,
+    module: &'static str,
+}
+
+impl Drop for ModuleLease {
+    fn drop(&mut self) {
+        self.leases.lock().remove(self.module);
+    }
+}
+
+/// Stops a runaway guest from another thread, see
+/// [`ModuleContext::interrupt_handle`].
+///
+/// Epochs are counted per engine, interrupting bumps the epoch of the
+/// store's engine, which only the interrupted store traps on.
+#[derive(Clone, Debug)]
+pub struct InterruptHandle {
+    engine: wasmtime::Engine,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    fn new(engine: &wasmtime::Engine) -> Self {
+        Self {
+            engine: engine.clone(),
+            interrupted: Arc::default(),
+        }
+    }
+
+    /// Makes the guest trap at its next epoch check, or on its next call if
+    /// it isn't running.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.engine.increment_epoch();
+    }
+
+    /// Whether the guest was interrupted since the last check, clearing the
+    /// interrupt so that later calls run again.
+    fn take(&self) -> bool {
+        self.interrupted.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Host side state of a single guest instance, stored as the data of its
+/// `wasmtime::Store`.
+///
+/// The context is `Send`, so stores can be moved between the threads of a
+/// pool, e.g. across the await points of a multi-threaded async runtime.
+/// It is deliberately not `Sync`: host module states, extensions, clocks
+/// and backends only have to be `Send`, as wasmtime hands the store's data
+/// to one host call at a time. State shared with other threads, such as
+/// [`FutureCompleter`](crate::FutureCompleter)s and the [`JobExecutor`],
+/// sits behind an `Arc` and a lock.
+#[derive(Default)]
+pub struct ModuleContext {
+    /// State of every host module added to this instance, keyed by the type
+    /// returned from [`HostModule::get`](crate::HostModule::get).
+    modules: HashMap<TypeId, Box<dyn Any + Send>>,
+    /// Hooks of the modules added through the builder.
+    hooks: Vec<(TypeId, ModuleHooks)>,
+    /// Arbitrary embedder data, see [`ModuleContext::insert`].
+    extensions: HashMap<TypeId, Box<dyn Any + Send>>,
+    pub(crate) call_counters: stats::CallCounters,
+    /// Deprecated aliases the guest was warned about.
+    #[cfg(feature = "host-logging")]
+    deprecation_warnings: stats::CallSiteSet,
+    protocol_defaults: ProtocolConfig,
+    retry_policy: RetryPolicy,
+    diagnostics: bool,
+    seed: Option<u64>,
+    reseed: bool,
+    /// Error of the last failed host call, cleared by every successful one.
+    pub(crate) last_error: Option<ApiError>,
+    /// Fatal error of the host call that trapped the guest, taken by
+    /// [`call`](Self::call) to report it as [`ModuleError::HostError`].
+    pub(crate) fatal_error: Option<ApiError>,
+    /// Host function that panicked, after which host state may be
+    /// inconsistent and no further calls are served.
+    poisoned: Option<&'static str>,
+    /// Host function called last, see [`current_call`](Self::current_call).
+    current_call: Option<CallSite>,
+    leases: Leases,
+    jobs: JobExecutor,
+    interruptible: bool,
+    interrupt: Option<InterruptHandle>,
+    fuel: Option<u64>,
+    limits: StoreLimiter,
+    pub(crate) memory_exports: MemoryExports,
+    /// Memory exports of the guest module, if it was instantiated through
+    /// [`HostLinker::instantiate`](crate::HostLinker::instantiate).
+    pub(crate) guest_memories: Option<Vec<Arc<str>>>,
+    capabilities: Capabilities,
+    rate_limits: RateLimiter,
+    call_clock: CallClock,
+    #[cfg(feature = "audit")]
+    audit_sink: Option<Arc<dyn AuditSink>>,
+}
+
+// Every field has to stay `Send`, see the docs of `ModuleContext`
+const _: () = {
+    fn assert_send<T: Send>() {}
+    let _ = assert_send::<ModuleContext>;
+    let _ = assert_send::<ModuleContextBuilder>;
+};
+
+/// Clock host calls are timed with, `None` if they aren't.
+struct CallClock(Option<Box<dyn ClockSource>>);
+
+impl Default for CallClock {
+    fn default() -> Self {
+        Self(Some(Box::new(SystemClock::default())))
+    }
+}
+
+impl ModuleContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> ModuleContextBuilder {
+        ModuleContextBuilder::default()
+    }
+
+    /// Protocol configuration used by shims when the guest doesn't provide one.
+    pub fn protocol_defaults(&self) -> &ProtocolConfig {
+        &self.protocol_defaults
+    }
+
+    /// How host modules retry transient failures of their backends, see
+    /// [`RetryPolicy`].
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Whether the guest may call diagnostic host functions such as
+    /// `list_active_futures`, off by default.
+    pub fn diagnostics_enabled(&self) -> bool {
+        self.diagnostics
+    }
+
+    /// Seed of the instance's random streams, such as the one of
+    /// [`RandomApiHost`](crate::RandomApiHost). Without one they are seeded
+    /// from host entropy.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Whether the guest may restart its random stream from a seed of its
+    /// choosing, off by default.
+    pub fn reseed_allowed(&self) -> bool {
+        self.reseed
+    }
+
+    /// Name of the memory export imports access guest memory through, by
+    /// default [`GUEST_MEMORY_EXPORT`](crate::GUEST_MEMORY_EXPORT).
+    pub fn memory_export(&self) -> &str {
+        &self.memory_exports.0[0]
+    }
+
+    /// Memory exports imports can select with
+    /// [`MemorySelector::Index`](crate::MemorySelector::Index), starting
+    /// with the default one.
+    pub fn memory_exports(&self) -> impl Iterator<Item = &str> + '_ {
+        self.memory_exports.0.iter().map(|name| &**name)
+    }
+
+    /// Host modules the guest may call, by default all of them.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Fails a call of `site` with [`ErrorCode::PermissionDenied`] if its
+    /// module isn't granted to the instance, or with
+    /// [`ErrorCode::RateLimited`] if it exceeds its rate limit, returning the
+    /// code for the guest. Denials are expected for guests probing for
+    /// optional modules and rejections for guests polling in a loop, so both
+    /// are recorded like failed calls but not logged as warnings.
+    #[doc(hidden)]
+    pub fn admit_call(&mut self, site: CallSite) -> Option<u32> {
+        if !self.capabilities.allows(site.module()) {
+            #[cfg(all(feature = "host-logging", not(feature = "tracing")))]
+            log::debug!(
+                target: site.module(),
+                "host call denied: module={} function={}",
+                site.module(),
+                site.function()
+            );
+            #[cfg(all(feature = "host-logging", feature = "tracing"))]
+            tracing::debug!(
+                error_code = ErrorCode::PermissionDenied as u32,
+                "host call denied"
+            );
+            self.call_counters.record(site, true);
+            self.last_error = Some(ApiError::new(
+                ErrorCode::PermissionDenied,
+                format!("instance isn't granted host module `{}`", site.module()),
+            ));
+            return Some(ErrorCode::PermissionDenied as u32);
+        }
+        if !self.rate_limits.admit(site) {
+            #[cfg(all(feature = "host-logging", not(feature = "tracing")))]
+            log::trace!(
+                target: site.module(),
+                "host call rate limited: module={} function={}",
+                site.module(),
+                site.function()
+            );
+            #[cfg(all(feature = "host-logging", feature = "tracing"))]
+            tracing::trace!(
+                error_code = ErrorCode::RateLimited as u32,
+                "host call rate limited"
+            );
+            self.call_counters.record(site, true);
+            self.last_error = Some(ApiError::new(
+                ErrorCode::RateLimited,
+                "import called more often than its rate limit allows",
+            ));
+            return Some(ErrorCode::RateLimited as u32);
+        }
+        None
+    }
+
+    /// Start time of a host call, if calls are timed.
+    #[doc(hidden)]
+    #[inline]
+    pub fn call_started(&self) -> Option<Duration> {
+        self.call_clock.0.as_ref().map(|clock| clock.monotonic())
+    }
+
+    /// Records the duration of a call of `site` that started at `started`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn call_finished(&mut self, site: CallSite, started: Option<Duration>) {
+        if let (Some(clock), Some(started)) = (&self.call_clock.0, started) {
+            let duration = clock.monotonic().saturating_sub(started);
+            self.call_counters.record_duration(site, duration);
+        }
+    }
+
+    /// Records a call of `site` with the sink of the instance, if it has one.
+    /// `values` are the wasm level arguments in the order of `params`.
+    #[cfg(feature = "audit")]
+    #[doc(hidden)]
+    #[inline]
+    pub fn audit(
+        &self,
+        site: CallSite,
+        params: &[ImportParam],
+        values: &[&dyn std::fmt::Display],
+        result: &crate::HostResult,
+    ) {
+        if let Some(sink) = &self.audit_sink {
+            self.record_audit(&**sink, site, params, values, result);
+        }
+    }
+
+    #[cfg(feature = "audit")]
+    #[cold]
+    #[inline(never)]
+    fn record_audit(
+        &self,
+        sink: &dyn AuditSink,
+        site: CallSite,
+        params: &[ImportParam],
+        values: &[&dyn std::fmt::Display],
+        result: &crate::HostResult,
+    ) {
+        let code = match result {
+            Ok(raw) => ErrorCode::from_raw(*raw).unwrap_or(ErrorCode::Internal),
+            // A fatal error, or a panic if there is none
+            Err(_) => self
+                .fatal_error
+                .as_ref()
+                .map_or(ErrorCode::Internal, ApiError::code),
+        };
+        sink.record(AuditEntry::new(site, params, values, code));
+    }
+
+    /// Error returned by the last host call of this instance, if it failed.
+    /// Guests read it with the `get_last_error` import.
+    pub fn last_error(&self) -> Option<&ApiError> {
+        self.last_error.as_ref()
+    }
+
+    /// Moves the context into a new store for `engine`, limited by the
+    /// context's [`limits`](Self::limits). Contexts built
+    /// [`with_interrupts`](ModuleContextBuilder::with_interrupts) get an
+    /// [`interrupt_handle`](Self::interrupt_handle) for the store.
+    pub fn into_store(self, engine: &wasmtime::Engine) -> wasmtime::Store<ModuleContext> {
+        let interruptible = self.interruptible;
+        let mut store = wasmtime::Store::new(engine, self);
+        store.limiter(|context| &mut context.limits);
+        if interruptible {
+            store.data_mut().interrupt = Some(InterruptHandle::new(engine));
+        }
+        // Checks for an interrupt on every epoch of the engine. Stores
+        // without a deadline trap right away on engines with epoch
+        // interruption, which another context sharing the engine may have
+        // turned on, so contexts without interrupts keep going too
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(|store| {
+            let interrupted = store
+                .data()
+                .interrupt
+                .as_ref()
+                .is_some_and(InterruptHandle::take);
+            Ok(if interrupted {
+                wasmtime::UpdateDeadline::Interrupt
+            } else {
+                wasmtime::UpdateDeadline::Continue(1)
+            })
+        });
+        store
+    }
+
+    /// Resource caps of the instance, only applied to stores created with
+    /// [`into_store`](Self::into_store).
+    pub fn limits(&self) -> &StoreLimiter {
+        &self.limits
+    }
+
+    /// Whether the guest tried to grow a memory past the memory cap. The
+    /// guest itself only sees `memory.grow` fail.
+    pub fn hit_memory_limit(&self) -> bool {
+        self.limits.hit_memory_limit()
+    }
+
+    /// Handle for stopping a runaway guest from another thread, if the
+    /// context was built [`with_interrupts`](ModuleContextBuilder::with_interrupts)
+    /// and moved into a store with [`into_store`](Self::into_store).
+    ///
+    /// Interrupting doesn't abort host calls that are in progress, including
+    /// async ones waiting on a future. The guest traps with
+    /// `wasmtime::Trap::Interrupt` once it runs again, at the next function
+    /// entry or loop header.
+    pub fn interrupt_handle(&self) -> Option<InterruptHandle> {
+        self.interrupt.clone()
+    }
+
+    /// Fuel budget of every guest call made through [`call`](Self::call).
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Fuel the guest has left, `None` if the store doesn't consume fuel.
+    pub fn remaining_fuel(mut store: impl AsContextMut<Data = ModuleContext>) -> Option<u64> {
+        // Fails if the engine doesn't consume fuel
+        store.as_context_mut().get_fuel().ok()
+    }
+
+    /// Calls a guest export, with exactly the [`fuel`](Self::fuel) budget
+    /// if the context has one. Running out of it is reported as
+    /// [`ModuleError::OutOfFuel`], fatal host call errors as
+    /// [`ModuleError::HostError`].
+    pub fn call<Params, Results>(
+        store: impl AsContextMut<Data = ModuleContext>,
+        func: &wasmtime::TypedFunc<Params, Results>,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        Self::call_as(store, None, func, params)
+    }
+
+    /// Calls the guest export `function` like [`call`](Self::call), failing
+    /// with [`ModuleError::MissingExport`] if the instance has none of these
+    /// parameter and result types.
+    pub fn call_export<Params, Results>(
+        mut store: impl AsContextMut<Data = ModuleContext>,
+        instance: &wasmtime::Instance,
+        function: &str,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        let func = instance
+            .get_typed_func::<Params, Results>(&mut store, function)
+            .map_err(|source| ModuleError::MissingExport {
+                function: function.to_owned(),
+                source,
+            })?;
+        Self::call_as(store, Some(function), &func, params)
+    }
+
+    fn call_as<Params, Results>(
+        mut store: impl AsContextMut<Data = ModuleContext>,
+        function: Option<&str>,
+        func: &wasmtime::TypedFunc<Params, Results>,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        let mut store = store.as_context_mut();
+        let budget = store.data().fuel;
+        if let Some(budget) = budget {
+            store.set_fuel(budget).map_err(|_| fuel_disabled())?;
+        }
+        store.data_mut().fatal_error = None;
+        func.call(&mut store, params).map_err(|trap| {
+            if let Some(err) = store.data_mut().fatal_error.take() {
+                return ModuleError::HostError(err);
+            }
+            match budget {
+                Some(budget)
+                    if trap.downcast_ref::<wasmtime::Trap>()
+                        == Some(&wasmtime::Trap::OutOfFuel) =>
+                {
+                    ModuleError::OutOfFuel { budget }
+                }
+                _ => match function {
+                    Some(function) => ModuleError::from_trap(function, trap),
+                    None => ModuleError::from_guest_trap(trap),
+                },
+            }
+        })
+    }
+
+    /// Executor for the background work of this instance's host modules,
+    /// shut down when the context is dropped.
+    pub fn jobs(&self) -> &JobExecutor {
+        &self.jobs
+    }
+
+    /// Resolves or cancels the outstanding work of every host module added
+    /// through the [`builder`](Self::builder), returning the trainings that
+    /// were cancelled because they were still pending. Outcomes reported
+    /// for them afterwards are dropped. Ends the session, see
+    /// [`finish_session`](Self::finish_session).
+    ///
+    /// Dropping the context shuts it down with
+    /// [`ShutdownPolicy::CancelAll`] and a short timeout.
+    pub fn shutdown(&mut self, policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        let mut cancelled = Vec::new();
+        for (type_id, hooks) in &self.hooks {
+            if let Some(state) = self.modules.get_mut(type_id) {
+                cancelled.extend((hooks.shutdown)(state.as_mut(), policy));
+            }
+        }
+        self.finish_session();
+        cancelled
+    }
+
+    /// Signals that the guest is done with its session, warning about every
+    /// future it hasn't freed with the default `host-logging` feature. Leaks
+    /// are counted in the
+    /// [`call_stats`](Self::call_stats) of the function that created the
+    /// future, and the leaked futures are returned.
+    ///
+    /// The futures stay in their tables, so a leak is reported again by the
+    /// next session that still finds it.
+    pub fn finish_session(&mut self) -> Vec<LiveFuture> {
+        let mut leaked = Vec::new();
+        for (type_id, hooks) in &self.hooks {
+            if let Some(state) = self.modules.get(type_id) {
+                leaked.extend((hooks.live_futures)(state.as_ref()));
+            }
+        }
+        for future in &leaked {
+            #[cfg(feature = "host-logging")]
+            match future.origin {
+                Some(site) => log::warn!(
+                    target: site.module(),
+                    "leaked future handle: handle={} module={} function={} age={:?}",
+                    future.handle.raw(),
+                    site.module(),
+                    site.function(),
+                    future.created.elapsed()
+                ),
+                None => log::warn!(
+                    "leaked future handle: handle={} age={:?}",
+                    future.handle.raw(),
+                    future.created.elapsed()
+                ),
+            }
+            if let Some(site) = future.origin {
+                self.call_counters.record_leak(site);
+            }
+        }
+        leaked
+    }
+
+    /// Returns the context to the state of a fresh instance for the next
+    /// guest session, as [`InstancePool`](crate::InstancePool) does between
+    /// checkouts: outstanding work is shut down with `policy`, the state of
+    /// every host module is [`reset`](HostModule::reset) and the call stats
+    /// and last error are cleared. Returns the trainings that were cancelled,
+    /// like [`shutdown`](Self::shutdown).
+    ///
+    /// Guest memory and a [poisoned](Self::is_poisoned) state are kept, only
+    /// a new instance gets rid of them.
+    pub fn reset(&mut self, policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        let cancelled = self.shutdown(policy);
+        self.reset_modules();
+        self.call_counters = stats::CallCounters::default();
+        #[cfg(feature = "host-logging")]
+        self.deprecation_warnings.clear();
+        self.rate_limits.refill();
+        self.last_error = None;
+        self.fatal_error = None;
+        self.current_call = None;
+        cancelled
+    }
+
+    /// [`HostModule::reset`] for the state of every module added through the
+    /// builder.
+    pub(crate) fn reset_modules(&mut self) {
+        for (type_id, hooks) in &self.hooks {
+            if let Some(state) = self.modules.get_mut(type_id) {
+                (hooks.reset)(state.as_mut());
+            }
+        }
+    }
+
+    /// Host function the guest called last, which is the one in progress
+    /// while a host call runs.
+    pub fn current_call(&self) -> Option<CallSite> {
+        self.current_call
+    }
+
+    #[doc(hidden)]
+    pub fn enter_call(&mut self, site: CallSite) {
+        self.current_call = Some(site);
+    }
+
+    /// Marks the host module of `site` as in use by the call until the lease
+    /// is dropped, which [`host_import!`] functions do around
+    /// [`HostModule::get`]. Host functions calling back into the guest hold
+    /// the lease across the callback, so the guest can't re-enter the module
+    /// through another of its imports while the outer call has its state
+    /// half updated.
+    ///
+    /// Fails with [`ErrorCode::Busy`] if an outer call already holds the
+    /// module. Leases are released when dropped, including by unwinding and
+    /// by returning a trap.
+    pub fn lease_module(&self, site: CallSite) -> Result<ModuleLease, ApiError> {
+        let mut leases = self.leases.lock();
+        if let Some(outer) = leases.get(site.module()) {
+            return Err(ApiError::new(
+                ErrorCode::Busy,
+                format!(
+                    "host module `{}` is in use by `{}`, which called back into the guest",
+                    site.module(),
+                    outer.function()
+                ),
+            ));
+        }
+        leases.insert(site.module(), site);
+        Ok(ModuleLease {
+            leases: self.leases.clone(),
+            module: site.module(),
+        })
+    }
+
+    /// Whether a host call of this instance panicked. Every host call of a
+    /// poisoned instance fails with [`ErrorCode::Internal`].
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
+
+    #[doc(hidden)]
+    pub fn check_poisoned(&self) -> Result<(), ApiError> {
+        match self.poisoned {
+            Some(function) => Err(ApiError::internal(format!(
+                "instance is poisoned, host function `{}` panicked",
+                function
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Poisons the instance after the host function at `site` panicked,
+    /// returning the trap to abort the guest with.
+    #[doc(hidden)]
+    pub fn poison(&mut self, site: CallSite, payload: Box<dyn Any + Send>) -> HostTrap {
+        let panic = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("non-string panic payload");
+        let message = format!(
+            "host call panicked: module={} function={}: {}",
+            site.module(),
+            site.function(),
+            panic
+        );
+        #[cfg(feature = "host-logging")]
+        log::error!(target: site.module(), "{}", message);
+        self.call_counters.record(site, true);
+        self.last_error = Some(ApiError::internal(message.clone()));
+        self.fatal_error = self.last_error.clone();
+        self.poisoned = Some(site.function());
+        HostTrap::new(message)
+    }
+
+    /// Adds the state of a host module, returning the previous state if the
+    /// module was already added.
+    pub fn insert_module<T: Any + Send>(&mut self, state: T) -> Option<T> {
+        self.modules
+            .insert(TypeId::of::<T>(), Box::new(state))
+            .map(|prev| {
+                *prev
+                    .downcast::<T>()
+                    .expect("module state keyed by its type")
+            })
+    }
+
+    pub fn has_module<T: Any + Send>(&self) -> bool {
+        self.modules.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn module<T: Any + Send>(&self) -> Result<&T, ApiError> {
+        self.modules
+            .get(&TypeId::of::<T>())
+            .and_then(|state| state.downcast_ref::<T>())
+            .ok_or_else(not_registered::<T>)
+    }
+
+    pub fn module_mut<T: Any + Send>(&mut self) -> Result<&mut T, ApiError> {
+        self.modules
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|state| state.downcast_mut::<T>())
+            .ok_or_else(not_registered::<T>)
+    }
+
+    /// Stores embedder data that shims can look up by type, returning the
+    /// previous value of the same type.
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.extensions
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .map(|prev| *prev.downcast::<T>().expect("extension keyed by its type"))
+    }
+
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.extensions
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.extensions
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut::<T>())
+    }
+
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.extensions
+            .remove(&TypeId::of::<T>())
+            .map(|value| *value.downcast::<T>().expect("extension keyed by its type"))
+    }
+
+    /// Number of calls and failures of every host function this instance
+    /// has called so far.
+    pub fn call_stats(&self) -> Vec<CallStat> {
+        self.call_counters.snapshot()
+    }
+
+    /// Counts a call through a deprecated alias, warning about the alias the
+    /// first time the instance calls it with the default `host-logging`
+    /// feature.
+    pub(crate) fn record_deprecated_call(&mut self, call: &DeprecatedCall) {
+        self.call_counters.record_deprecated(call.site);
+        #[cfg(feature = "host-logging")]
+        if self.deprecation_warnings.insert(call.alias) {
+            log::warn!(
+                target: call.site.module(),
+                "guest called `{}`, which is deprecated since {}, use `{}` instead",
+                call.alias.function(),
+                call.deprecation.since,
+                call.deprecation.replacement
+            );
+        }
+    }
+}
+
+impl Drop for ModuleContext {
+    fn drop(&mut self) {
+        // Shutting down warns about every handle the guest leaked
+        self.shutdown(ShutdownPolicy::CancelAll(Duration::from_millis(100)));
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModuleContextError {
+    #[error("Host module `{module}` was added more than once")]
+    DuplicateModule { module: &'static str },
+}
+
+/// Assembles a [`ModuleContext`] with the host modules enabled for an
+/// instance before it is handed to `wasmtime::Store::new`.
+#[derive(Default)]
+pub struct ModuleContextBuilder {
+    modules: Vec<(TypeId, &'static str, Box<dyn Any + Send>, ModuleHooks)>,
+    protocol_defaults: ProtocolConfig,
+    retry_policy: RetryPolicy,
+    diagnostics: bool,
+    seed: Option<u64>,
+    reseed: bool,
+    job_threads: Option<usize>,
+    job_shutdown: WorkerShutdown,
+    fuel: Option<u64>,
+    interruptible: bool,
+    limits: StoreLimiter,
+    memory_exports: MemoryExports,
+    capabilities: Capabilities,
+    rate_limits: RateLimiter,
+    call_clock: CallClock,
+    #[cfg(feature = "audit")]
+    audit_sink: Option<Arc<dyn AuditSink>>,
+}
+
+impl ModuleContextBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_module<M: HostModule + Any + Send>(mut self, state: M) -> Self {
+        self.modules.push((
+            TypeId::of::<M>(),
+            M::name(),
+            Box::new(state),
+            ModuleHooks::of::<M>(),
+        ));
+        self
+    }
+
+    pub fn with_protocol_defaults(mut self, protocol: ProtocolConfig) -> Self {
+        self.protocol_defaults = protocol;
+        self
+    }
+
+    /// Retries trainings the backend failed to start with a transient
+    /// error, and other backend calls of host modules that opt in. Sync
+    /// imports hand the guest a pending future while the start is retried
+    /// on the executor, see [`RetryPolicy`].
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn with_diagnostics(mut self, enabled: bool) -> Self {
+        self.diagnostics = enabled;
+        self
+    }
+
+    /// Seeds the instance's random streams, making them replayable.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Lets the guest reseed its random stream with `random__reseed`.
+    pub fn with_reseed(mut self, allowed: bool) -> Self {
+        self.reseed = allowed;
+        self
+    }
+
+    /// Number of worker threads of the instance's [`JobExecutor`], by default
+    /// [`JobExecutor::default_threads`].
+    pub fn with_job_threads(mut self, threads: usize) -> Self {
+        self.job_threads = Some(threads);
+        self
+    }
+
+    pub fn with_job_shutdown(mut self, policy: WorkerShutdown) -> Self {
+        self.job_shutdown = policy;
+        self
+    }
+
+    /// Gives every guest call made through [`ModuleContext::call`] a budget
+    /// of `fuel` units, making runaway guests fail deterministically. The
+    /// engine has to consume fuel, see
+    /// [`configure_engine`](Self::configure_engine).
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Lets the embedder stop the guest from another thread through
+    /// [`ModuleContext::interrupt_handle`]. The engine has to use epoch
+    /// interruption, see [`configure_engine`](Self::configure_engine).
+    pub fn with_interrupts(mut self) -> Self {
+        self.interruptible = true;
+        self
+    }
+
+    /// Caps each linear memory of the instance at `bytes`, rounded down to
+    /// whole 64 KiB wasm pages.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.limits.set_memory_size(bytes);
+        self
+    }
+
+    /// Caps each table of the instance at `elements`.
+    pub fn with_table_limit(mut self, elements: u32) -> Self {
+        self.limits.set_table_elements(elements);
+        self
+    }
+
+    /// Caps the number of instances in the store, by default
+    /// `wasmtime::DEFAULT_INSTANCE_LIMIT`.
+    pub fn with_instance_limit(mut self, instances: usize) -> Self {
+        self.limits.set_instances(instances);
+        self
+    }
+
+    /// Name of the guest's memory export, for toolchains that don't call it
+    /// `memory`.
+    pub fn with_memory_export(mut self, name: impl Into<String>) -> Self {
+        self.memory_exports.0[0] = Arc::from(name.into());
+        self
+    }
+
+    /// Memory exports of guests with several memories, in the order imports
+    /// select them by index. The first one is the default, an empty list
+    /// keeps the current ones. Listing several makes
+    /// [`configure_engine`](Self::configure_engine) enable multi-memory.
+    pub fn with_memory_exports<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let names: Vec<Arc<str>> = names
+            .into_iter()
+            .map(|name| Arc::from(name.into()))
+            .collect();
+        if !names.is_empty() {
+            self.memory_exports.0 = names;
+        }
+        self
+    }
+
+    /// Host modules the guest may call, see [`Capabilities`].
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Limits how often the guest may call `import`, a full import name
+    /// such as [`logging_imports::WRITE`](crate::logging_imports::WRITE).
+    /// Calls over the limit fail with [`ErrorCode::RateLimited`] without
+    /// running the import. Imports are unlimited by default.
+    pub fn with_rate_limit(mut self, import: &'static str, limit: RateLimit) -> Self {
+        self.rate_limits.limit_import(import, limit);
+        self
+    }
+
+    /// Limits the calls of every import of `M` that has no limit of its own,
+    /// which share a single bucket.
+    pub fn with_module_rate_limit<M: HostModule>(mut self, limit: RateLimit) -> Self {
+        self.rate_limits.limit_module(M::name(), limit);
+        self
+    }
+
+    /// Whether host calls are timed for their
+    /// [`CallStat::durations`], on by default. Timing reads the clock twice
+    /// per call.
+    pub fn with_call_timing(mut self, enabled: bool) -> Self {
+        self.call_clock = match enabled {
+            true => CallClock::default(),
+            false => CallClock(None),
+        };
+        self
+    }
+
+    /// Times host calls with the monotonic time of `clock` rather than the
+    /// [`SystemClock`].
+    pub fn with_call_clock(mut self, clock: impl ClockSource + 'static) -> Self {
+        self.call_clock = CallClock(Some(Box::new(clock)));
+        self
+    }
+
+    /// Records every host call of the instance with `sink`, see
+    /// [`AuditSink`].
+    #[cfg(feature = "audit")]
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Enables the engine features the context's options rely on.
+    pub fn configure_engine<'c>(
+        &self,
+        config: &'c mut wasmtime::Config,
+    ) -> &'c mut wasmtime::Config {
+        if self.fuel.is_some() {
+            config.consume_fuel(true);
+        }
+        if self.interruptible {
+            config.epoch_interruption(true);
+        }
+        if self.memory_exports.0.len() > 1 {
+            config.wasm_multi_memory(true);
+        }
+        config
+    }
+
+    pub fn build(self) -> Result<ModuleContext, ModuleContextError> {
+        // `ModuleContext` implements `Drop`, so it can't be built with
+        // struct update syntax
+        let mut context = ModuleContext::default();
+        context.protocol_defaults = self.protocol_defaults;
+        context.retry_policy = self.retry_policy;
+        context.diagnostics = self.diagnostics;
+        context.seed = self.seed;
+        context.reseed = self.reseed;
+        context.jobs = JobExecutor::new(
+            self.job_threads
+                .unwrap_or_else(JobExecutor::default_threads),
+            self.job_shutdown,
+        );
+        context.fuel = self.fuel;
+        context.interruptible = self.interruptible;
+        context.limits = self.limits;
+        context.memory_exports = self.memory_exports;
+        context.capabilities = self.capabilities;
+        context.rate_limits = self.rate_limits;
+        context.call_clock = self.call_clock;
+        #[cfg(feature = "audit")]
+        {
+            context.audit_sink = self.audit_sink;
+        }
+        for (type_id, module, state, hook) in self.modules {
+            if context.modules.insert(type_id, state).is_some() {
+                return Err(ModuleContextError::DuplicateModule { module });
+            }
+            context.hooks.push((type_id, hook));
+        }
+        Ok(context)
+    }
+}
+
+fn not_registered<T>() -> ApiError {
+    ApiError::new(
+        ErrorCode::ModuleNotRegistered,
+        format!(
+            "host module `{}` is not registered",
+            std::any::type_name::<T>()
+        ),
+    )
+}
+
+fn fuel_disabled() -> ModuleError {
+    ModuleError::HostError(ApiError::new(
+        ErrorCode::InvalidArgument,
+        "fuel budget set, but the engine doesn't consume fuel",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host module counting how often it was looked up.
+    #[derive(Debug, Default, PartialEq)]
+    struct Counter(u32);
+
+    impl HostModule for Counter {
+        fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+            host_context.module_mut::<Self>()
+        }
+
+        fn name() -> &'static str {
+            "counter"
+        }
+    }
+
+    fn context(builder: ModuleContextBuilder) -> ModuleContext {
+        builder.with_job_threads(1).build().unwrap()
+    }
+
+    #[test]
+    fn module_state_persists_between_lookups() {
+        let mut context = context(ModuleContext::builder().with_module(Counter(0)));
+        assert!(context.has_module::<Counter>());
+        Counter::get(&mut context).unwrap().0 += 1;
+        Counter::get(&mut context).unwrap().0 += 1;
+        assert_eq!(context.module::<Counter>().unwrap(), &Counter(2));
+    }
+
+    #[test]
+    fn unregistered_modules_are_reported() {
+        let mut context = context(ModuleContext::builder());
+        assert!(!context.has_module::<Counter>());
+        let err = Counter::get(&mut context).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ModuleNotRegistered);
+        assert!(
+            err.guest_message().contains("Counter"),
+            "{}",
+            err.guest_message()
+        );
+        assert_eq!(context.insert_module(Counter(1)), None);
+        assert_eq!(context.insert_module(Counter(2)), Some(Counter(1)));
+        assert_eq!(Counter::get(&mut context).unwrap(), &mut Counter(2));
+    }
+
+    #[test]
+    fn extensions_are_stored_by_type() {
+        #[derive(Debug, PartialEq)]
+        struct TenantId(&'static str);
+
+        let mut context = context(ModuleContext::builder().with_module(Counter(7)));
+        assert_eq!(context.insert(TenantId("embark")), None);
+        assert_eq!(context.insert(42_u32), None);
+        assert_eq!(context.get::<TenantId>(), Some(&TenantId("embark")));
+        assert_eq!(context.get::<u32>(), Some(&42));
+        assert_eq!(context.get::<u64>(), None);
+
+        assert_eq!(context.insert(TenantId("other")), Some(TenantId("embark")));
+        *context.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(context.remove::<u32>(), Some(43));
+        assert_eq!(context.remove::<u32>(), None);
+        assert_eq!(context.get::<TenantId>(), Some(&TenantId("other")));
+
+        // Extensions and module state don't share their storage
+        assert_eq!(context.get::<Counter>(), None);
+        context.insert(Counter(1));
+        assert_eq!(Counter::get(&mut context).unwrap(), &mut Counter(7));
+    }
+
+    #[test]
+    fn builder_assembles_the_context() {
+        let protocol = ProtocolConfig {
+            batch_size: 64,
+            ..ProtocolConfig::default()
+        };
+        let context = context(
+            ModuleContext::builder()
+                .with_module(Counter(3))
+                .with_protocol_defaults(protocol)
+                .with_diagnostics(true),
+        );
+        assert_eq!(context.module::<Counter>().unwrap(), &Counter(3));
+        assert_eq!(context.protocol_defaults(), &protocol);
+        assert!(context.diagnostics_enabled());
+        assert!(context.last_error().is_none());
+    }
+
+    #[test]
+    fn builder_rejects_modules_added_twice() {
+        let err = ModuleContext::builder()
+            .with_module(Counter(1))
+            .with_module(Counter(2))
+            .with_job_threads(1)
+            .build()
+            .err()
+            .expect("the module was added twice");
+        assert!(matches!(
+            err,
+            ModuleContextError::DuplicateModule { module: "counter" }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Host module `counter` was added more than once"
+        );
+    }
+
+    #[test]
+    fn interrupt_handles_need_interrupts() {
+        let store = context(ModuleContext::builder()).into_store(&wasmtime::Engine::default());
+        assert!(store.data().interrupt_handle().is_none());
+        let builder = ModuleContext::builder().with_interrupts();
+        let engine =
+            wasmtime::Engine::new(builder.configure_engine(&mut wasmtime::Config::new())).unwrap();
+        let store = context(builder).into_store(&engine);
+        assert!(store.data().interrupt_handle().is_some());
+    }
+
+    #[test]
+    fn contexts_without_interrupts_run_on_epoch_engines() {
+        let wat = r#"
+            (module
+              (func (export "count") (param i32) (result i32)
+                (loop $again
+                  (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+                  (br_if $again (local.get 0)))
+                (i32.const 7)))
+        "#;
+        // Shared with an interruptible context, which turned epochs on
+        let interruptible = ModuleContext::builder().with_interrupts();
+        let engine =
+            wasmtime::Engine::new(interruptible.configure_engine(&mut wasmtime::Config::new()))
+                .unwrap();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = context(ModuleContext::builder()).into_store(&engine);
+        assert!(store.data().interrupt_handle().is_none());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let count = instance
+            .get_typed_func::<i32, i32>(&mut store, "count")
+            .unwrap();
+        assert_eq!(count.call(&mut store, 1000).unwrap(), 7);
+        // Epochs ticked by other contexts' interrupts don't stop it either
+        engine.increment_epoch();
+        engine.increment_epoch();
+        assert_eq!(count.call(&mut store, 1000).unwrap(), 7);
+    }
+
+    #[test]
+    fn interrupts_let_host_calls_finish_and_stop_the_guest() {
+        let wat = r#"
+            (module
+              (import "env" "slow" (func $slow))
+              (func (export "spin")
+                (call $slow)
+                (loop $forever (br $forever))))
+        "#;
+        let builder = ModuleContext::builder().with_interrupts();
+        let engine =
+            wasmtime::Engine::new(builder.configure_engine(&mut wasmtime::Config::new())).unwrap();
+        let mut linker = wasmtime::Linker::new(&engine);
+        let (started, running) = std::sync::mpsc::channel::<()>();
+        let started = std::sync::Mutex::new(started);
+        let finished = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let slow_finished = finished.clone();
+        linker
+            .func_wrap("env", "slow", move || {
+                started.lock().unwrap().send(()).unwrap();
+                // Interrupted in the meantime
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                slow_finished.store(true, std::sync::atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = context(builder).into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+
+        let interrupt = store.data().interrupt_handle().unwrap();
+        let interrupter = std::thread::spawn(move || {
+            running.recv().unwrap();
+            interrupt.interrupt();
+        });
+        let started = std::time::Instant::now();
+        match ModuleContext::call_export::<(), ()>(&mut store, &instance, "spin", ()) {
+            Err(ModuleError::Interrupted { function }) => assert_eq!(function, "spin"),
+            res => panic!(
+                "the guest wasn't interrupted: {:?}",
+                res.map_err(|e| e.to_string())
+            ),
+        }
+        assert!(finished.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        interrupter.join().unwrap();
+    }
+
+    /// Counts down from its argument, spending fuel on every iteration.
+    const COUNTDOWN: &str = r#"
+        (module
+          (func (export "countdown") (param $n i32)
+            (loop $next
+              (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+              (br_if $next (i32.gt_s (local.get $n) (i32.const 0))))))
+    "#;
+
+    fn countdown(
+        builder: ModuleContextBuilder,
+    ) -> (wasmtime::Store<ModuleContext>, wasmtime::Instance) {
+        let mut config = wasmtime::Config::new();
+        builder.configure_engine(&mut config);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        let module = wasmtime::Module::new(&engine, COUNTDOWN).unwrap();
+        let mut store = context(builder).into_store(&engine);
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        (store, instance)
+    }
+
+    #[test]
+    fn fuel_budgets_stop_runaway_guests() {
+        let (mut store, instance) = countdown(ModuleContext::builder().with_fuel(10_000));
+        assert_eq!(store.data().fuel(), Some(10_000));
+        ModuleContext::call_export::<_, ()>(&mut store, &instance, "countdown", 10).unwrap();
+        match ModuleContext::call_export::<_, ()>(&mut store, &instance, "countdown", 1_000_000) {
+            Err(ModuleError::OutOfFuel { budget }) => assert_eq!(budget, 10_000),
+            res => panic!(
+                "the budget wasn't enforced: {:?}",
+                res.map_err(|e| e.to_string())
+            ),
+        }
+        // Every call gets the whole budget, also after running out
+        ModuleContext::call_export::<_, ()>(&mut store, &instance, "countdown", 10).unwrap();
+    }
+
+    #[test]
+    fn fuel_use_is_deterministic() {
+        let spent = |n: i32| {
+            let (mut store, instance) = countdown(ModuleContext::builder().with_fuel(1_000_000));
+            ModuleContext::call_export::<_, ()>(&mut store, &instance, "countdown", n).unwrap();
+            1_000_000 - ModuleContext::remaining_fuel(&mut store).unwrap()
+        };
+        let hundred = spent(100);
+        assert_eq!(spent(100), hundred);
+        assert!(spent(200) > hundred);
+    }
+
+    #[test]
+    fn fuel_budgets_need_an_engine_consuming_fuel() {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, COUNTDOWN).unwrap();
+        let mut store = context(ModuleContext::builder().with_fuel(100)).into_store(&engine);
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        assert_eq!(ModuleContext::remaining_fuel(&mut store), None);
+        match ModuleContext::call_export::<_, ()>(&mut store, &instance, "countdown", 10) {
+            Err(ModuleError::HostError(err)) => assert_eq!(err.code(), ErrorCode::InvalidArgument),
+            res => panic!("{:?}", res.map_err(|e| e.to_string())),
+        }
+    }
+
+    /// A guest trapping on `unreachable` and calling `env::fail`, which fails
+    /// fatally.
+    const FAILING: &str = r#"
+        (module
+          (import "env" "fail" (func $fail))
+          (func $crash (export "crash") (unreachable))
+          (func (export "fail") (call $fail)))
+    "#;
+
+    fn failing() -> (wasmtime::Store<ModuleContext>, wasmtime::Instance) {
+        let engine = wasmtime::Engine::default();
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "fail",
+                |mut caller: wasmtime::Caller<'_, ModuleContext>| -> anyhow::Result<()> {
+                    let err = ApiError::fatal(ErrorCode::Internal, "model store is gone");
+                    caller.data_mut().fatal_error = Some(err.clone());
+                    Err(anyhow::Error::msg(err.display().to_string()))
+                },
+            )
+            .unwrap();
+        let module = wasmtime::Module::new(&engine, FAILING).unwrap();
+        let mut store = context(ModuleContext::builder()).into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        (store, instance)
+    }
+
+    #[test]
+    fn guest_traps_name_the_export() {
+        let (mut store, instance) = failing();
+        let err =
+            ModuleContext::call_export::<(), ()>(&mut store, &instance, "crash", ()).unwrap_err();
+        match &err {
+            ModuleError::Trap { function, trap, .. } => {
+                assert_eq!(function, "crash");
+                assert_eq!(
+                    trap.downcast_ref::<wasmtime::Trap>(),
+                    Some(&wasmtime::Trap::UnreachableCodeReached)
+                );
+            }
+            err => panic!("{:?}", err),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Guest trapped in `crash`: wasm trap: wasm `unreachable` instruction executed"
+        );
+
+        // Without the export's name it is taken from the backtrace
+        let crash = instance
+            .get_typed_func::<(), ()>(&mut store, "crash")
+            .unwrap();
+        match ModuleContext::call(&mut store, &crash, ()) {
+            Err(ModuleError::Trap { function, .. }) => assert_eq!(function, "crash"),
+            res => panic!("{:?}", res.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn fatal_host_errors_are_host_errors() {
+        let (mut store, instance) = failing();
+        let err =
+            ModuleContext::call_export::<(), ()>(&mut store, &instance, "fail", ()).unwrap_err();
+        match &err {
+            ModuleError::HostError(host) => {
+                assert_eq!(host.code(), ErrorCode::Internal);
+                assert_eq!(
+                    err.to_string(),
+                    format!("Host call failed fatally: {}", host.display())
+                );
+            }
+            err => panic!("{:?}", err),
+        }
+        // The error doesn't stick to later calls
+        assert!(matches!(
+            ModuleContext::call_export::<(), ()>(&mut store, &instance, "crash", ()),
+            Err(ModuleError::Trap { .. })
+        ));
+    }
+
+    #[test]
+    fn exports_of_other_types_are_missing() {
+        let (mut store, instance) = failing();
+        for (function, res) in [
+            (
+                "crash",
+                ModuleContext::call_export::<u32, ()>(&mut store, &instance, "crash", 1),
+            ),
+            (
+                "nope",
+                ModuleContext::call_export::<(), ()>(&mut store, &instance, "nope", ()),
+            ),
+        ] {
+            match res {
+                Err(err @ ModuleError::MissingExport { .. }) => assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "Guest doesn't export a function `{}` of the expected type",
+                        function
+                    )
+                ),
+                res => panic!("{:?}", res.map_err(|e| e.to_string())),
+            }
+        }
+    }
+}
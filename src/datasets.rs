@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::handles::SlotTable;
+use crate::{
+    error_code_ranges, ApiError, ErrorCode, HostLinker, HostModule, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod dataset_imports = "dataset" {
+    OPEN = "open",
+    READ_CHUNK = "read_chunk",
+    CLOSE = "close",
+});
+
+/// An open dataset, streamed to the guest front to back.
+pub type DatasetReader = Box<dyn Read + Send>;
+
+/// Where [`DatasetApiHost`] opens datasets from.
+pub trait DatasetSource: Send {
+    /// Opens the dataset at `uri`, failing with [`ErrorCode::NotFound`] if
+    /// there is none.
+    fn open(&mut self, uri: &str) -> Result<DatasetReader, ApiError>;
+}
+
+/// Serves datasets from memory, for tests and small datasets.
+#[derive(Debug, Default)]
+pub struct MemoryDatasets {
+    datasets: HashMap<String, Arc<[u8]>>,
+}
+
+impl MemoryDatasets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_dataset(mut self, uri: impl Into<String>, data: impl Into<Arc<[u8]>>) -> Self {
+        self.insert(uri, data);
+        self
+    }
+
+    /// Adds a dataset, replacing any previous one at `uri`. Readers that are
+    /// already open keep reading the previous data.
+    pub fn insert(&mut self, uri: impl Into<String>, data: impl Into<Arc<[u8]>>) {
+        self.datasets.insert(uri.into(), data.into());
+    }
+}
+
+impl DatasetSource for MemoryDatasets {
+    fn open(&mut self, uri: &str) -> Result<DatasetReader, ApiError> {
+        let data = self
+            .datasets
+            .get(uri)
+            .ok_or_else(|| ApiError::not_found(format!("no dataset at `{}`", uri)))?;
+        Ok(Box::new(std::io::Cursor::new(Arc::clone(data))))
+    }
+}
+
+/// Serves the files below a directory, with URIs being paths relative to it.
+///
+/// URIs that could reach outside of the directory, such as absolute paths or
+/// ones containing `..`, are rejected with [`ErrorCode::InvalidArgument`].
+#[cfg(feature = "datasets-fs")]
+#[derive(Debug)]
+pub struct FileDatasets {
+    root: std::path::PathBuf,
+}
+
+#[cfg(feature = "datasets-fs")]
+impl FileDatasets {
+    pub fn new(root: impl Into<std::path::PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[cfg(feature = "datasets-fs")]
+impl DatasetSource for FileDatasets {
+    fn open(&mut self, uri: &str) -> Result<DatasetReader, ApiError> {
+        let relative = std::path::Path::new(uri);
+        if !relative
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!("dataset `{}` is not a relative path", uri),
+            ));
+        }
+        Ok(Box::new(std::fs::File::open(self.root.join(relative))?))
+    }
+}
+
+slot_handle! {
+    /// Handle of a dataset opened by the guest, never `0`. Like a
+    /// [`FutureHandle`](crate::FutureHandle) it includes the generation of
+    /// its slot, so closed handles fail with [`ErrorCode::StaleHandle`].
+    pub struct DatasetHandle = "dataset";
+}
+
+/// Host module streaming datasets to the guest in chunks sized by the
+/// guest, so a dataset never has to fit into guest memory at once.
+pub struct DatasetApiHost {
+    source: Box<dyn DatasetSource>,
+    datasets: SlotTable<DatasetHandle, DatasetReader>,
+}
+
+impl Default for DatasetApiHost {
+    /// Serves an empty [`MemoryDatasets`].
+    fn default() -> Self {
+        Self::new(MemoryDatasets::new())
+    }
+}
+
+impl DatasetApiHost {
+    pub const DEFAULT_CAPACITY: usize = 64;
+
+    pub fn new(source: impl DatasetSource + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            datasets: SlotTable::with_capacity(Self::DEFAULT_CAPACITY),
+        }
+    }
+
+    /// Caps the number of datasets the guest can have open at once, further
+    /// ones fail with [`ErrorCode::Busy`].
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.datasets.set_capacity(capacity);
+        self
+    }
+
+    /// Number of datasets the guest has open.
+    pub fn open_datasets(&self) -> usize {
+        self.datasets.len()
+    }
+
+    pub fn open_dataset(&mut self, uri: &str) -> Result<DatasetHandle, ApiError> {
+        if uri.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "dataset uri must not be empty",
+            ));
+        }
+        self.datasets.check_capacity()?;
+        let reader = self.source.open(uri)?;
+        self.datasets.insert(reader)
+    }
+
+    /// Reads the next chunk of the dataset into `buf`, returning how many
+    /// bytes were read. `0` means the dataset has been read to the end.
+    pub fn read_chunk(&mut self, handle: DatasetHandle, buf: &mut [u8]) -> Result<usize, ApiError> {
+        let reader = self.datasets.get_mut(handle)?;
+        loop {
+            match reader.read(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                read => return Ok(read?),
+            }
+        }
+    }
+
+    /// Closes the dataset, the handle is stale afterwards.
+    pub fn close_dataset(&mut self, handle: DatasetHandle) -> Result<(), ApiError> {
+        self.datasets.remove(handle).map(drop)
+    }
+}
+
+impl HostModule for DatasetApiHost {
+    fn name() -> &'static str {
+        "dataset_api"
+    }
+    fn log_target() -> &'static str {
+        "host::dataset_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::DATASETS)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+
+    /// Closes the datasets the guest left open.
+    fn reset(state: &mut Self) {
+        state.datasets.clear();
+    }
+}
+
+impl<'t> Shim<'t> for DatasetApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", dataset_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, DatasetApiHost, dataset_imports::OPEN, (
+            uri: str,
+            handle_out: *mut DatasetHandle,
+        ) => |host| host.open_dataset(uri))?;
+        // Reads straight into the guest buffer, at most `buf.len()` bytes
+        host_import!(linker, DatasetApiHost, dataset_imports::READ_CHUNK, (
+            handle: u64,
+            buf: GuestSlice<u8>,
+            read_out: GuestPtr<u32>,
+        ) => |host, memory| {
+            // Reads at most `u32::MAX` bytes, the most `read_out` can report
+            let buf = buf.bytes_mut(memory)?;
+            let len = buf.len().min(u32::MAX as usize);
+            let read = host.read_chunk(DatasetHandle(handle), &mut buf[..len])?;
+            read_out.write(memory, &(read as u32))
+        })?;
+        host_import!(linker, DatasetApiHost, dataset_imports::CLOSE, (handle: u64)
+            => |host| host.close_dataset(DatasetHandle(handle)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{call, guest, guest_bytes, Guest};
+
+    /// Streams the dataset at the `len` bytes of uri at 32 in chunks of
+    /// `chunk` bytes to 1024 on, writing its handle to 8 and the total bytes
+    /// read to 24. Returns the first failed code.
+    const STREAM: &str = r#"
+        (module
+          (import "env" "dataset__open" (func $open (param i32 i32 i32) (result i32)))
+          (import "env" "dataset__read_chunk"
+            (func $read_chunk (param i64 i32 i32 i32) (result i32)))
+          (import "env" "dataset__close" (func $close (param i64) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 32) "train")
+          (func (export "stream") (param $len i32) (param $chunk i32) (result i32)
+            (local $code i32)
+            (local.set $code (call $open (i32.const 32) (local.get $len) (i32.const 8)))
+            (if (local.get $code) (then (return (local.get $code))))
+            (loop $next
+              (local.set $code
+                (call $read_chunk (i64.load (i32.const 8))
+                  (i32.add (i32.const 1024) (i32.load (i32.const 24)))
+                  (local.get $chunk)
+                  (i32.const 16)))
+              (if (local.get $code) (then (return (local.get $code))))
+              (i32.store (i32.const 24)
+                (i32.add (i32.load (i32.const 24)) (i32.load (i32.const 16))))
+              (br_if $next (i32.load (i32.const 16))))
+            (call $close (i64.load (i32.const 8))))
+          (func (export "close") (result i32)
+            (call $close (i64.load (i32.const 8))))
+          (func (export "read") (param $len i32) (result i32)
+            (call $read_chunk (i64.load (i32.const 8))
+              (i32.const 1024) (local.get $len) (i32.const 16))))
+    "#;
+
+    fn dataset() -> Vec<u8> {
+        (0..3000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn stream_guest(host: DatasetApiHost) -> Guest {
+        guest::<DatasetApiHost>(STREAM, ModuleContext::builder().with_module(host))
+    }
+
+    #[test]
+    fn guests_reassemble_datasets_from_chunks() {
+        let data = dataset();
+        let host = DatasetApiHost::new(MemoryDatasets::new().with_dataset("train", data.clone()));
+        let (mut store, instance) = stream_guest(host);
+
+        assert_eq!(
+            call(&mut store, &instance, "stream", (5, 256)),
+            ErrorCode::Success as u32
+        );
+        let total = guest_bytes(&mut store, &instance, 24, 4);
+        assert_eq!(total, 3000_u32.to_le_bytes());
+        assert_eq!(guest_bytes(&mut store, &instance, 1024, 3000), data);
+        // Nothing is written past the dataset
+        assert_eq!(guest_bytes(&mut store, &instance, 4024, 256), [0; 256]);
+        let host = store.data_mut().module_mut::<DatasetApiHost>().unwrap();
+        assert_eq!(host.open_datasets(), 0);
+    }
+
+    #[test]
+    fn chunks_never_exceed_the_guest_buffer() {
+        let mut host = DatasetApiHost::new(MemoryDatasets::new().with_dataset("train", dataset()));
+        let handle = host.open_dataset("train").unwrap();
+        let mut buf = [0xff; 8];
+        assert_eq!(host.read_chunk(handle, &mut buf[..3]).unwrap(), 3);
+        assert_eq!(buf, [0, 7, 14, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(host.read_chunk(handle, &mut []).unwrap(), 0);
+        assert_eq!(host.read_chunk(handle, &mut buf[..1]).unwrap(), 1);
+        assert_eq!(buf[0], 21);
+    }
+
+    #[test]
+    fn closed_datasets_are_stale_and_unknown_ones_not_found() {
+        let data = dataset();
+        let host = DatasetApiHost::new(MemoryDatasets::new().with_dataset("train", data));
+        let (mut store, instance) = stream_guest(host);
+        assert_eq!(
+            call(&mut store, &instance, "stream", (5, 1024)),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "close", ()),
+            ErrorCode::StaleHandle as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "read", 4),
+            ErrorCode::StaleHandle as u32
+        );
+        // `trai` isn't a dataset
+        assert_eq!(
+            call(&mut store, &instance, "stream", (4, 1024)),
+            ErrorCode::NotFound as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "stream", (0, 1024)),
+            ErrorCode::InvalidArgument as u32
+        );
+
+        let mut host = DatasetApiHost::default();
+        let err = host.close_dataset(DatasetHandle(u64::MAX)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn open_datasets_are_capped_and_closed_on_reset() {
+        let source = MemoryDatasets::new().with_dataset("train", dataset());
+        let mut host = DatasetApiHost::new(source).with_capacity(2);
+        let first = host.open_dataset("train").unwrap();
+        host.open_dataset("train").unwrap();
+        let err = host.open_dataset("train").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+        // Every reader streams from the start
+        let mut buf = [0; 2];
+        host.read_chunk(first, &mut buf).unwrap();
+        host.close_dataset(first).unwrap();
+        let reopened = host.open_dataset("train").unwrap();
+        host.read_chunk(reopened, &mut buf).unwrap();
+        assert_eq!(buf, [0, 7]);
+
+        DatasetApiHost::reset(&mut host);
+        assert_eq!(host.open_datasets(), 0);
+        let err = host.read_chunk(reopened, &mut buf).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+    }
+
+    #[cfg(feature = "datasets-fs")]
+    #[test]
+    fn file_datasets_stay_below_their_root() {
+        let root = std::env::temp_dir().join(format!("datasets-fs-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("mnist")).unwrap();
+        std::fs::write(root.join("mnist/train"), b"digits").unwrap();
+        let mut datasets = FileDatasets::new(&root);
+
+        let mut data = Vec::new();
+        datasets
+            .open("mnist/train")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"digits");
+        assert_eq!(
+            datasets.open("mnist/test").err().unwrap().code(),
+            ErrorCode::NotFound
+        );
+        for uri in ["../mnist/train", "/etc/passwd", "mnist/../mnist/train"] {
+            let err = datasets.open(uri).err().unwrap();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{}", uri);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
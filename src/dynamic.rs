@@ -0,0 +1,238 @@
+use std::fmt;
+
+use wasmtime::{Val, ValType};
+
+use crate::engine::DeprecatedCall;
+use crate::{
+    ApiError, CallSite, ErrorCode, HostCaller, HostResult, HostTrap, ImportParam, ImportValue,
+    ModuleContext, WasmMemoryHandle,
+};
+
+type DynFunc = Box<
+    dyn Fn(
+            &mut ModuleContext,
+            &mut WasmMemoryHandle<'_>,
+            &[Val],
+            &mut [Val],
+        ) -> Result<(), ApiError>
+        + Send
+        + Sync,
+>;
+
+/// An import described at runtime instead of by the types of a
+/// [`host_import!`](crate::host_import) body, registered with
+/// [`HostLinker::define_dynamic`](crate::HostLinker::define_dynamic).
+///
+/// Calls go through the same checks, stats and error handling as those of
+/// `host_import!` imports, only the arguments are decoded by `func` from the
+/// wasm values, e.g. with [`DynArgs`]. None of it is generic over the
+/// signature, so a module registering all of its imports this way
+/// compiles to a fraction of the code, at the cost of slower calls. Meant
+/// for rarely called modules, see `#[wasm_shim(dynamic)]`.
+///
+/// Like every import the function returns an `i32` error code to the guest,
+/// `results` are the wasm results after it. `func` gets the instance's host
+/// context, the guest's memory, the arguments and the results to fill in,
+/// which are zeroed if it fails.
+pub struct DynHostFunction {
+    pub name: &'static str,
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+    func: DynFunc,
+    doc: Vec<ImportParam>,
+}
+
+impl DynHostFunction {
+    pub fn new(
+        name: &'static str,
+        params: Vec<ValType>,
+        results: Vec<ValType>,
+        func: impl Fn(
+                &mut ModuleContext,
+                &mut WasmMemoryHandle<'_>,
+                &[Val],
+                &mut [Val],
+            ) -> Result<(), ApiError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            name,
+            params,
+            results,
+            func: Box::new(func),
+            doc: Vec::new(),
+        }
+    }
+
+    /// Names the wasm level parameters for the
+    /// [`manifest`](crate::HostLinker::manifest) and audit records, like
+    /// `host_import!` does for its imports.
+    pub fn with_doc(mut self, params: &[ImportParam]) -> Self {
+        self.doc = params.to_vec();
+        self
+    }
+
+    pub(crate) fn func_type(&self, engine: &wasmtime::Engine) -> wasmtime::FuncType {
+        let results = std::iter::once(ValType::I32).chain(self.results.iter().cloned());
+        wasmtime::FuncType::new(engine, self.params.iter().cloned(), results)
+    }
+
+    pub(crate) fn doc(&self) -> &[ImportParam] {
+        &self.doc
+    }
+}
+
+impl fmt::Debug for DynHostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynHostFunction")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("results", &self.results)
+            .finish()
+    }
+}
+
+/// Decodes the arguments of a [`DynHostFunction`] call in order.
+pub struct DynArgs<'a> {
+    args: std::slice::Iter<'a, Val>,
+}
+
+impl<'a> DynArgs<'a> {
+    pub fn new(args: &'a [Val]) -> Self {
+        Self { args: args.iter() }
+    }
+
+    /// The next argument, a `T`.
+    pub fn value<T: ImportValue>(&mut self) -> Result<T, ApiError> {
+        let arg = self.next()?;
+        T::from_val(arg).ok_or_else(|| mismatch(arg, std::any::type_name::<T>()))
+    }
+
+    /// The next argument, a guest pointer or length, `i32` for 32-bit
+    /// guests and `i64` for memory64 guests.
+    pub fn addr(&mut self) -> Result<u64, ApiError> {
+        match *self.next()? {
+            Val::I32(raw) => Ok(u64::from(raw as u32)),
+            Val::I64(raw) => Ok(raw as u64),
+            ref arg => Err(mismatch(arg, "guest address")),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a Val, ApiError> {
+        self.args
+            .next()
+            .ok_or_else(|| ApiError::fatal(ErrorCode::Internal, "missing import argument"))
+    }
+}
+
+/// wasmtime checks the arguments against the declared parameters, so this
+/// is a function decoding other types than it declared.
+fn mismatch(arg: &Val, expected: &str) -> ApiError {
+    ApiError::fatal(
+        ErrorCode::Internal,
+        format!("import argument {:?} isn't a {}", arg, expected),
+    )
+}
+
+/// [`HostModule::log_call`](crate::HostModule::log_call) of a module.
+type LogCall = fn(&mut ModuleContext, CallSite, Result<(), ApiError>) -> HostResult;
+
+/// A [`DynHostFunction`] registered for a host module, with the hooks of
+/// the module that `host_import!` calls.
+pub(crate) struct DynImport {
+    pub(crate) site: CallSite,
+    pub(crate) check_call: fn(&ModuleContext, CallSite) -> Result<(), ApiError>,
+    pub(crate) log_call: LogCall,
+    pub(crate) func: DynHostFunction,
+}
+
+impl DynImport {
+    pub(crate) fn call(
+        &self,
+        caller: &mut dyn HostCaller,
+        deprecated: Option<&DeprecatedCall>,
+        args: &[Val],
+        results: &mut [Val],
+    ) -> Result<(), HostTrap> {
+        if let Some(deprecated) = deprecated {
+            caller.context_mut().record_deprecated_call(deprecated);
+        }
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "host_call",
+            host_module = self.site.module(),
+            function = self.site.function()
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let result = self.call_checked(caller, args, results);
+        #[cfg(feature = "audit")]
+        {
+            let values: Vec<_> = args.iter().map(DisplayVal).collect();
+            let values: Vec<_> = values.iter().map(|v| v as &dyn fmt::Display).collect();
+            caller
+                .context()
+                .audit(self.site, &self.func.doc, &values, &result);
+        }
+        let code = result?;
+        results[0] = Val::I32(code as i32);
+        if code != ErrorCode::Success as u32 {
+            for (result, ty) in results[1..].iter_mut().zip(&self.func.results) {
+                // Non-nullable references have no zero, the host function
+                // set them
+                if let Some(zero) = Val::default_for_ty(ty) {
+                    *result = zero;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The body of `host_import!`'s function around `func`.
+    fn call_checked(
+        &self,
+        caller: &mut dyn HostCaller,
+        args: &[Val],
+        results: &mut [Val],
+    ) -> HostResult {
+        let site = self.site;
+        if let Some(code) = caller.context_mut().admit_call(site) {
+            return Ok(code);
+        }
+        let started = caller.context().call_started();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            caller.context().check_poisoned()?;
+            (self.check_call)(caller.context(), site)?;
+            let _lease = caller.context().lease_module(site)?;
+            caller.context_mut().enter_call(site);
+            let (mut memory, host_context) = caller.memory()?;
+            (self.func.func)(host_context, &mut memory, args, &mut results[1..])
+        }));
+        caller.context_mut().call_finished(site, started);
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => return Err(caller.context_mut().poison(site, payload)),
+        };
+        (self.log_call)(caller.context_mut(), site, result)
+    }
+}
+
+/// An argument as audited by `host_import!`, which sees unsigned pointers
+/// and lengths.
+#[cfg(feature = "audit")]
+struct DisplayVal<'a>(&'a Val);
+
+#[cfg(feature = "audit")]
+impl fmt::Display for DisplayVal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self.0 {
+            Val::I32(raw) => write!(f, "{}", raw as u32),
+            Val::I64(raw) => write!(f, "{}", raw as u64),
+            Val::F32(bits) => write!(f, "{}", f32::from_bits(bits)),
+            Val::F64(bits) => write!(f, "{}", f64::from_bits(bits)),
+            ref other => write!(f, "{:?}", other),
+        }
+    }
+}
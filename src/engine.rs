@@ -0,0 +1,449 @@
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "native-runtime")]
+use crate::NativeValue;
+use crate::{
+    guest_memory, ApiError, CallSite, ImportParam, ImportReturn, InstantiationError, ModuleContext,
+    WasmLinker, WasmMemoryHandle,
+};
+
+/// A runtime running guests, which host modules register their imports
+/// with. [`HostLinker`](crate::HostLinker) runs them on wasmtime, with the
+/// `native-runtime` feature [`NativeLinker`](crate::NativeLinker) calls them
+/// without any wasm, to test shims.
+///
+/// The sync imports of [`host_import!`](crate::host_import) are registered
+/// through this trait, async imports still need a `HostLinker`.
+pub trait Runtime {
+    /// The runtime's trap, raised for a [`HostTrap`] returned by a host
+    /// function.
+    type Trap: From<HostTrap>;
+
+    /// Whether host modules should register async imports.
+    fn is_async(&self) -> bool;
+
+    /// Whether imports take `u64` pointers and lengths.
+    fn is_memory64(&self) -> bool;
+
+    /// Registers `func` as `namespace::name` on behalf of the host module
+    /// `module`, with the wasm signature of its parameters `P` and a `u32`
+    /// result.
+    fn define_import<P: ImportParams, F: HostFunc<P>>(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        func: F,
+    ) -> Result<(), InstantiationError>;
+
+    /// Makes the already registered import `namespace::{alias.import}`
+    /// available as `namespace::{alias.alias}` as well. Calls through
+    /// [deprecated](ImportAlias::deprecated) aliases are counted and warned
+    /// about, which needs the import to be registered through
+    /// [`define_import`](Self::define_import).
+    fn alias(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        alias: ImportAlias,
+    ) -> Result<(), InstantiationError>;
+
+    /// Names the parameters of a registered import, for runtimes that
+    /// describe their imports. Ignored by default.
+    fn describe_import(
+        &mut self,
+        _namespace: &'static str,
+        _name: &'static str,
+        _params: &[ImportParam],
+        _returns: ImportReturn,
+    ) {
+    }
+}
+
+/// An additional name of an import, so that renamed or versioned imports
+/// keep working for older guests, see [`Shim::aliases`](crate::Shim::aliases).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImportAlias {
+    pub alias: &'static str,
+    /// Full name of the aliased import.
+    pub import: &'static str,
+    pub deprecation: Option<Deprecation>,
+}
+
+impl ImportAlias {
+    pub const fn new(alias: &'static str, import: &'static str) -> Self {
+        Self {
+            alias,
+            import,
+            deprecation: None,
+        }
+    }
+
+    /// Marks the alias as deprecated since host version `since`. The first
+    /// call of an instance through it logs a warning pointing to
+    /// `replacement` with the default `host-logging` feature, every call
+    /// counts towards the
+    /// [`deprecated_calls`](crate::CallStat::deprecated_calls) of the import.
+    pub const fn deprecated(self, since: &'static str, replacement: &'static str) -> Self {
+        Self {
+            deprecation: Some(Deprecation { since, replacement }),
+            ..self
+        }
+    }
+}
+
+/// Why guests shouldn't call an [`ImportAlias`] anymore.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Deprecation {
+    /// Host version the alias was deprecated in.
+    pub since: &'static str,
+    /// Full name of the import guests should call instead.
+    pub replacement: &'static str,
+}
+
+/// A call through a deprecated alias, recorded on the instance before the
+/// aliased host function runs.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct DeprecatedCall {
+    /// The aliased import, whose call stats count the call.
+    pub(crate) site: CallSite,
+    /// The alias, warned about once per instance.
+    #[cfg(feature = "host-logging")]
+    pub(crate) alias: CallSite,
+    #[cfg(feature = "host-logging")]
+    pub(crate) deprecation: Deprecation,
+}
+
+impl DeprecatedCall {
+    pub(crate) fn new(module: &'static str, alias: &ImportAlias) -> Option<Self> {
+        // Only calls of deprecated aliases are recorded
+        alias.deprecation?;
+        Some(Self {
+            site: CallSite::register(module, alias.import),
+            #[cfg(feature = "host-logging")]
+            alias: CallSite::register(module, alias.alias),
+            #[cfg(feature = "host-logging")]
+            deprecation: alias.deprecation?,
+        })
+    }
+}
+
+/// A host function registered under its import name or an alias, shared by
+/// both so that the alias reuses the import's trampoline.
+pub(crate) struct AliasedFunc<F> {
+    func: Arc<F>,
+    deprecated: Option<DeprecatedCall>,
+}
+
+impl<F> AliasedFunc<F> {
+    pub(crate) fn new(func: Arc<F>, deprecated: Option<DeprecatedCall>) -> Self {
+        Self { func, deprecated }
+    }
+}
+
+impl<P, F: HostFunc<P>> HostFunc<P> for AliasedFunc<F> {
+    fn call(&self, caller: &mut dyn HostCaller, params: P) -> HostResult {
+        if let Some(deprecated) = &self.deprecated {
+            caller.context_mut().record_deprecated_call(deprecated);
+        }
+        self.func.call(caller, params)
+    }
+}
+
+/// The instance a host function was called from.
+pub trait HostCaller {
+    fn context(&self) -> &ModuleContext;
+
+    fn context_mut(&mut self) -> &mut ModuleContext;
+
+    /// Splits the borrow of the caller into the guest's memory and the host
+    /// context, see [`guest_memory`].
+    fn memory(&mut self) -> Result<(WasmMemoryHandle<'_>, &mut ModuleContext), ApiError>;
+}
+
+impl HostCaller for wasmtime::Caller<'_, ModuleContext> {
+    fn context(&self) -> &ModuleContext {
+        self.data()
+    }
+
+    fn context_mut(&mut self) -> &mut ModuleContext {
+        self.data_mut()
+    }
+
+    fn memory(&mut self) -> Result<(WasmMemoryHandle<'_>, &mut ModuleContext), ApiError> {
+        guest_memory(self)
+    }
+}
+
+/// A host call aborting the guest, with the message of the trap the runtime
+/// raises for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostTrap(String);
+
+impl HostTrap {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for HostTrap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HostTrap {}
+
+/// The result of a host call, the code returned to the guest or the trap
+/// aborting it. Host functions return this rather than the trap type of a
+/// runtime, so that shims don't depend on the wasmtime version.
+pub type HostResult<T = u32> = Result<T, HostTrap>;
+
+/// A host function registered with [`Runtime::define_import`], called with
+/// the instance and its wasm level arguments `P`.
+pub trait HostFunc<P>: Send + Sync + 'static {
+    fn call(&self, caller: &mut dyn HostCaller, params: P) -> HostResult;
+}
+
+/// A wasm level parameter of an import.
+pub trait ImportValue: wasmtime::WasmTy + Copy + 'static {
+    #[doc(hidden)]
+    const VAL_TYPE: wasmtime::ValType;
+
+    /// The value as passed to an untyped trampoline, `None` for another
+    /// type.
+    #[doc(hidden)]
+    fn from_val(value: &wasmtime::Val) -> Option<Self>;
+
+    #[cfg(feature = "native-runtime")]
+    fn from_native(value: NativeValue) -> Option<Self>;
+}
+
+macro_rules! import_values {
+    ($($ty:ty => $variant:ident, $from_raw:expr;)*) => {$(
+        impl ImportValue for $ty {
+            const VAL_TYPE: wasmtime::ValType = wasmtime::ValType::$variant;
+
+            fn from_val(value: &wasmtime::Val) -> Option<Self> {
+                match *value {
+                    // Unsigned values are passed as their bit pattern, floats
+                    // as their bits
+                    wasmtime::Val::$variant(raw) => Some($from_raw(raw)),
+                    _ => None,
+                }
+            }
+
+            #[cfg(feature = "native-runtime")]
+            #[allow(clippy::unnecessary_cast)]
+            fn from_native(value: NativeValue) -> Option<Self> {
+                match value {
+                    NativeValue::$variant(value) => Some(value as $ty),
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+
+import_values! {
+    u32 => I32, |raw: i32| raw as u32;
+    i32 => I32, std::convert::identity;
+    u64 => I64, |raw: i64| raw as u64;
+    i64 => I64, std::convert::identity;
+    f32 => F32, f32::from_bits;
+    f64 => F64, f64::from_bits;
+}
+
+/// The wasm level parameters of an import, a tuple of [`ImportValue`]s, with
+/// what each runtime needs to register a [`HostFunc`] taking them.
+pub trait ImportParams: Sized + 'static {
+    /// Defines `func` with the matching wasm signature, a thin trampoline
+    /// per import around the function. Only used with the
+    /// `typed-trampolines` feature.
+    #[doc(hidden)]
+    fn define_typed<F: HostFunc<Self>>(
+        linker: &mut WasmLinker,
+        namespace: &str,
+        name: &str,
+        func: F,
+    ) -> anyhow::Result<()>;
+
+    /// The wasm signature of imports taking these parameters and returning
+    /// a `u32`.
+    #[doc(hidden)]
+    fn func_type(engine: &wasmtime::Engine) -> wasmtime::FuncType;
+
+    /// The parameters as passed to an untyped trampoline, `None` if they
+    /// don't match the signature.
+    #[doc(hidden)]
+    fn from_vals(params: &[wasmtime::Val]) -> Option<Self>;
+
+    /// The parameters as passed to a `NativeLinker` import, `None` if they
+    /// don't match the signature.
+    #[cfg(feature = "native-runtime")]
+    #[doc(hidden)]
+    fn from_native(args: &[NativeValue]) -> Option<Self>;
+}
+
+macro_rules! import_params {
+    ($($ty:ident $arg:ident),*) => {
+        impl<Func, $($ty),*> HostFunc<($($ty,)*)> for Func
+        where
+            Func: Fn(&mut dyn HostCaller, $($ty),*) -> HostResult + Send + Sync + 'static,
+            $($ty: ImportValue,)*
+        {
+            fn call(
+                &self,
+                caller: &mut dyn HostCaller,
+                ($($arg,)*): ($($ty,)*),
+            ) -> HostResult {
+                self(caller, $($arg),*)
+            }
+        }
+
+        impl<$($ty: ImportValue),*> ImportParams for ($($ty,)*) {
+            fn define_typed<F: HostFunc<Self>>(
+                linker: &mut WasmLinker,
+                namespace: &str,
+                name: &str,
+                func: F,
+            ) -> anyhow::Result<()> {
+                linker.func_wrap(
+                    namespace,
+                    name,
+                    move |mut caller: wasmtime::Caller<'_, ModuleContext>, $($arg: $ty),*| {
+                        Ok(func.call(&mut caller, ($($arg,)*))?)
+                    },
+                )?;
+                Ok(())
+            }
+
+            fn func_type(engine: &wasmtime::Engine) -> wasmtime::FuncType {
+                let params: &[wasmtime::ValType] = &[$($ty::VAL_TYPE),*];
+                wasmtime::FuncType::new(engine, params.iter().cloned(), [wasmtime::ValType::I32])
+            }
+
+            fn from_vals(params: &[wasmtime::Val]) -> Option<Self> {
+                match params {
+                    [$($arg),*] => Some(($($ty::from_val($arg)?,)*)),
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+
+            #[cfg(feature = "native-runtime")]
+            fn from_native(args: &[NativeValue]) -> Option<Self> {
+                match args {
+                    [$($arg),*] => Some(($($ty::from_native(*$arg)?,)*)),
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+import_params!();
+import_params!(A1 a1);
+import_params!(A1 a1, A2 a2);
+import_params!(A1 a1, A2 a2, A3 a3);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10);
+import_params!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11);
+import_params!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12
+);
+import_params!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12,
+    A13 a13
+);
+import_params!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12,
+    A13 a13, A14 a14
+);
+import_params!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12,
+    A13 a13, A14 a14, A15 a15
+);
+import_params!(
+    A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6, A7 a7, A8 a8, A9 a9, A10 a10, A11 a11, A12 a12,
+    A13 a13, A14 a14, A15 a15, A16 a16
+);
+
+/// A host function taking its parameters as wasm values, so that imports of
+/// every signature share one trampoline.
+#[cfg(not(feature = "typed-trampolines"))]
+type UntypedFunc = Box<dyn Fn(&mut dyn HostCaller, &[wasmtime::Val]) -> HostResult + Send + Sync>;
+
+/// Defines `func` as `namespace::name` on `linker`.
+///
+/// By default every import goes through the one untyped trampoline of
+/// `define_untyped`, only the decoding of the parameters,
+/// [`ImportParams::from_vals`], is monomorphized once per signature. The
+/// `typed-trampolines` feature registers each import with
+/// `Linker::func_wrap` instead, whose calls skip the untyped values but
+/// which instantiates wasmtime's `IntoFunc` machinery once per import.
+#[cfg(feature = "typed-trampolines")]
+pub(crate) fn define_wasmtime<P: ImportParams, F: HostFunc<P>>(
+    linker: &mut WasmLinker,
+    namespace: &'static str,
+    name: &'static str,
+    func: F,
+) -> anyhow::Result<()> {
+    P::define_typed(linker, namespace, name, func)
+}
+
+#[cfg(not(feature = "typed-trampolines"))]
+pub(crate) fn define_wasmtime<P: ImportParams, F: HostFunc<P>>(
+    linker: &mut WasmLinker,
+    namespace: &'static str,
+    name: &'static str,
+    func: F,
+) -> anyhow::Result<()> {
+    let func: UntypedFunc = Box::new(move |caller, params| {
+        // wasmtime checks the arguments against the signature
+        let params = P::from_vals(params).ok_or_else(|| {
+            HostTrap::new(format!(
+                "arguments {:?} don't match the signature of `{}::{}`",
+                params, namespace, name
+            ))
+        })?;
+        func.call(caller, params)
+    });
+    let ty = P::func_type(linker.engine());
+    define_untyped(linker, namespace, name, ty, func)
+}
+
+#[cfg(not(feature = "typed-trampolines"))]
+fn define_untyped(
+    linker: &mut WasmLinker,
+    namespace: &str,
+    name: &str,
+    ty: wasmtime::FuncType,
+    func: UntypedFunc,
+) -> anyhow::Result<()> {
+    linker.func_new(namespace, name, ty, move |mut caller, params, results| {
+        let code = func(&mut caller, params)?;
+        results[0] = wasmtime::Val::I32(code as i32);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Host modules whose imports aren't tied to wasmtime, so they can be
+/// registered with any [`Runtime`]. Their [`Shim::imports`](crate::Shim::imports)
+/// adds the async variants for async `HostLinker`s on top.
+pub trait PortableImports {
+    /// Registers the sync imports of the module.
+    fn portable_imports<R: Runtime>(linker: &mut R) -> Result<(), InstantiationError>;
+}
@@ -0,0 +1,504 @@
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{ApiError, BoxFuture};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// What happens to the workers of a [`JobExecutor`] when it is dropped.
+/// Queued jobs that haven't started are discarded either way.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WorkerShutdown {
+    /// Wait for running jobs to finish.
+    #[default]
+    Join,
+    /// Let running jobs finish in the background.
+    Detach,
+}
+
+/// Thread pool running the background work of an instance, such as
+/// trainings, owned by its [`ModuleContext`](crate::ModuleContext).
+///
+/// Workers are only started once the first job is submitted, so instances
+/// that never run background work don't cost any threads.
+pub struct JobExecutor {
+    queue: JobQueue,
+    policy: WorkerShutdown,
+}
+
+/// Handle for submitting jobs to a [`JobExecutor`], for host modules to
+/// keep.
+#[derive(Clone)]
+pub struct JobQueue(Arc<Shared>);
+
+struct Shared {
+    threads: usize,
+    state: Mutex<State>,
+    ready: Condvar,
+    /// Notifies the timer thread of new timers and of the shutdown.
+    timers_changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    workers: Vec<JoinHandle<()>>,
+    /// Pending [`JobQueue::sleep`]s and [`JobQueue::submit_after`]s, fired
+    /// by `timer_thread`.
+    timers: Vec<(Instant, Wakeup)>,
+    timer_thread: Option<JoinHandle<()>>,
+    shutdown: bool,
+}
+
+/// What happens once a timer's deadline has passed.
+enum Wakeup {
+    /// A sleep resolves.
+    Sleep(Arc<Mutex<Timer>>),
+    /// A job is queued.
+    Submit(Job),
+}
+
+#[derive(Default)]
+struct Timer {
+    elapsed: bool,
+    waker: Option<Waker>,
+}
+
+impl Timer {
+    fn lock(timer: &Mutex<Timer>) -> MutexGuard<'_, Timer> {
+        timer.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Marks the timer as elapsed, returning the waker of the sleep waiting
+    /// for it.
+    fn fire(timer: &Mutex<Timer>) -> Option<Waker> {
+        let mut timer = Self::lock(timer);
+        timer.elapsed = true;
+        timer.waker.take()
+    }
+}
+
+impl Default for JobExecutor {
+    fn default() -> Self {
+        Self::new(Self::default_threads(), WorkerShutdown::default())
+    }
+}
+
+impl JobExecutor {
+    /// `threads` is clamped to at least one worker.
+    pub fn new(threads: usize, policy: WorkerShutdown) -> Self {
+        Self {
+            queue: JobQueue(Arc::new(Shared {
+                threads: threads.max(1),
+                state: Mutex::new(State::default()),
+                ready: Condvar::new(),
+                timers_changed: Condvar::new(),
+            })),
+            policy,
+        }
+    }
+
+    /// One worker per available core.
+    pub fn default_threads() -> usize {
+        std::thread::available_parallelism().map_or(1, |threads| threads.get())
+    }
+
+    pub fn threads(&self) -> usize {
+        self.queue.0.threads
+    }
+
+    pub fn policy(&self) -> WorkerShutdown {
+        self.policy
+    }
+
+    pub fn queue(&self) -> JobQueue {
+        self.queue.clone()
+    }
+}
+
+impl Drop for JobExecutor {
+    fn drop(&mut self) {
+        let (workers, timer_thread) = {
+            let mut state = self.queue.0.lock();
+            state.shutdown = true;
+            state.jobs.clear();
+            (
+                std::mem::take(&mut state.workers),
+                state.timer_thread.take(),
+            )
+        };
+        self.queue.0.ready.notify_all();
+        self.queue.0.timers_changed.notify_all();
+        if self.policy == WorkerShutdown::Join {
+            // Only waits for the pending sleeps to be woken up
+            if let Some(timer_thread) = timer_thread {
+                let _ = timer_thread.join();
+            }
+            for worker in workers {
+                // Workers catch job panics, so this only fails if the worker
+                // loop itself panicked
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl JobQueue {
+    /// Queues `job` to run on a worker. Fails once the executor has been
+    /// dropped.
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) -> Result<(), ApiError> {
+        let mut state = self.0.lock();
+        if state.shutdown {
+            return Err(ApiError::internal("job executor is shut down"));
+        }
+        self.0.push(&mut state, Box::new(job))?;
+        drop(state);
+        self.0.ready.notify_one();
+        Ok(())
+    }
+
+    /// Queues `job` once `delay` has passed, e.g. to retry work after a
+    /// backoff. Like a [`sleep`](Self::sleep) the wait is kept by the timer
+    /// thread, and the job is discarded if the executor is shut down before
+    /// it is queued. Fails once the executor has been dropped.
+    pub fn submit_after(
+        &self,
+        delay: Duration,
+        job: impl FnOnce() + Send + 'static,
+    ) -> Result<(), ApiError> {
+        self.schedule(Instant::now() + delay, Wakeup::Submit(Box::new(job)))
+    }
+
+    /// Resolves once `duration` has passed, for async host functions that
+    /// have to wait without blocking the thread running the guest. The wait
+    /// is kept by the executor's timer thread, so it doesn't take up a
+    /// worker, and is cut short if the executor is shut down.
+    pub fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let timer = Arc::new(Mutex::new(Timer::default()));
+        let skipped = self
+            .schedule(Instant::now() + duration, Wakeup::Sleep(timer.clone()))
+            .is_err();
+        Box::pin(std::future::poll_fn(move |cx| {
+            let mut timer = Timer::lock(&timer);
+            if skipped || timer.elapsed {
+                return Poll::Ready(());
+            }
+            timer.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }))
+    }
+
+    /// Fires `timer` at `deadline`, starting the timer thread on the first
+    /// call. Fails once the executor has been dropped.
+    fn schedule(&self, deadline: Instant, timer: Wakeup) -> Result<(), ApiError> {
+        let mut state = self.0.lock();
+        if state.shutdown {
+            return Err(ApiError::internal("job executor is shut down"));
+        }
+        if state.timer_thread.is_none() {
+            let shared = self.0.clone();
+            let timer_thread = std::thread::Builder::new()
+                .name("job-timer".to_owned())
+                .spawn(move || shared.fire_timers())?;
+            state.timer_thread = Some(timer_thread);
+        }
+        state.timers.push((deadline, timer));
+        drop(state);
+        self.0.timers_changed.notify_one();
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Queues `job`, starting the workers on the first one.
+    fn push(self: &Arc<Self>, state: &mut State, job: Job) -> std::io::Result<()> {
+        if state.workers.is_empty() {
+            for index in 0..self.threads {
+                let shared = self.clone();
+                let worker = std::thread::Builder::new()
+                    .name(format!("job-worker-{}", index))
+                    .spawn(move || shared.work())?;
+                state.workers.push(worker);
+            }
+        }
+        state.jobs.push_back(job);
+        Ok(())
+    }
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut state = self.lock();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some(job) = state.jobs.pop_front() {
+                        break job;
+                    }
+                    state = self
+                        .ready
+                        .wait(state)
+                        .unwrap_or_else(|err| err.into_inner());
+                }
+            };
+            // Jobs report their own panics where they can, this only keeps
+            // the worker alive
+            if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                log::error!("background job panicked");
+            }
+        }
+    }
+    /// Wakes up sleeps and queues jobs whose deadline has passed until the
+    /// executor is shut down, which wakes up all sleeps and discards the
+    /// jobs.
+    fn fire_timers(self: Arc<Self>) {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let shutdown = state.shutdown;
+            let (due, pending) = std::mem::take(&mut state.timers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|&(deadline, _)| shutdown || deadline <= now);
+            state.timers = pending;
+            if !due.is_empty() {
+                let mut sleeps = Vec::new();
+                for (_, wakeup) in due {
+                    match wakeup {
+                        Wakeup::Sleep(timer) => sleeps.push(timer),
+                        Wakeup::Submit(job) if !shutdown => {
+                            if let Err(err) = self.push(&mut state, job) {
+                                log::error!("failed to start a job worker: {}", err);
+                            }
+                            self.ready.notify_one();
+                        }
+                        Wakeup::Submit(_) => {}
+                    }
+                }
+                // Waking up a sleep may poll it, which must not find the
+                // state locked
+                drop(state);
+                for timer in sleeps {
+                    if let Some(waker) = Timer::fire(&timer) {
+                        waker.wake();
+                    }
+                }
+                state = self.lock();
+                continue;
+            }
+            if shutdown {
+                return;
+            }
+            state = match state.timers.iter().map(|&(deadline, _)| deadline).min() {
+                Some(deadline) => {
+                    self.timers_changed
+                        .wait_timeout(state, deadline.saturating_duration_since(now))
+                        .unwrap_or_else(|err| err.into_inner())
+                        .0
+                }
+                None => self
+                    .timers_changed
+                    .wait(state)
+                    .unwrap_or_else(|err| err.into_inner()),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::task::{Context, Wake};
+
+    use super::*;
+
+    struct Unpark(std::thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on(future: BoxFuture<'static, ()>) {
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = future;
+        while future.as_mut().poll(&mut cx).is_pending() {
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn sleeps_dont_take_up_workers() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let started = Instant::now();
+        let sleep = queue.sleep(Duration::from_millis(200));
+        let (sender, receiver) = mpsc::channel();
+        queue.submit(move || sender.send(()).unwrap()).unwrap();
+        receiver
+            .recv_timeout(Duration::from_millis(100))
+            .expect("the only worker is busy sleeping");
+        block_on(sleep);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn sleeps_resolve_in_deadline_order() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let started = Instant::now();
+        let long = queue.sleep(Duration::from_millis(150));
+        let short = queue.sleep(Duration::from_millis(30));
+        block_on(short);
+        assert!(started.elapsed() < Duration::from_millis(150));
+        block_on(long);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn shutdown_cuts_sleeps_short() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let started = Instant::now();
+        let sleep = queue.sleep(Duration::from_secs(60));
+        drop(executor);
+        block_on(sleep);
+        block_on(queue.sleep(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn delayed_jobs_are_queued_by_the_timer_thread() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let started = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        let delayed = sender.clone();
+        queue
+            .submit_after(Duration::from_millis(100), move || {
+                delayed.send("delayed").unwrap()
+            })
+            .unwrap();
+        queue.submit(move || sender.send("now").unwrap()).unwrap();
+        let recv = || receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(recv(), "now");
+        assert_eq!(recv(), "delayed");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn shutdown_discards_delayed_jobs() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let (sender, receiver) = mpsc::channel();
+        let delayed = sender.clone();
+        queue
+            .submit_after(Duration::from_secs(60), move || delayed.send(()).unwrap())
+            .unwrap();
+        drop(executor);
+        assert!(receiver.try_recv().is_err(), "a delayed job still ran");
+        let err = queue
+            .submit_after(Duration::ZERO, move || sender.send(()).unwrap())
+            .unwrap_err();
+        assert_eq!(err.code(), crate::ErrorCode::Internal);
+    }
+
+    #[test]
+    fn jobs_run_concurrently() {
+        let executor = JobExecutor::new(3, WorkerShutdown::Join);
+        let queue = executor.queue();
+        // Only passed once all three jobs run at the same time
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+        let (sender, receiver) = mpsc::channel();
+        for job in 0..3 {
+            let (barrier, sender) = (barrier.clone(), sender.clone());
+            queue
+                .submit(move || {
+                    barrier.wait();
+                    sender.send(job).unwrap();
+                })
+                .unwrap();
+        }
+        let mut done: Vec<_> = (0..3)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        done.sort_unstable();
+        assert_eq!(done, [0, 1, 2]);
+    }
+
+    #[test]
+    fn panicking_jobs_keep_their_worker() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        queue.submit(|| panic!("job failed")).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        queue.submit(move || sender.send(()).unwrap()).unwrap();
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the worker died with the panicking job");
+    }
+
+    /// Submits a job to a single worker that runs until `release` is sent
+    /// to, returning whether it has finished.
+    fn blocked_job(queue: &JobQueue) -> (mpsc::Sender<()>, Arc<Mutex<bool>>) {
+        let (release, released) = mpsc::channel();
+        let (started, running) = mpsc::channel();
+        let finished = Arc::new(Mutex::new(false));
+        let done = finished.clone();
+        queue
+            .submit(move || {
+                started.send(()).unwrap();
+                let _ = released.recv_timeout(Duration::from_millis(100));
+                *done.lock().unwrap() = true;
+            })
+            .unwrap();
+        running.recv_timeout(Duration::from_secs(10)).unwrap();
+        (release, finished)
+    }
+
+    #[test]
+    fn joining_waits_for_running_jobs() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Join);
+        let queue = executor.queue();
+        let (_release, finished) = blocked_job(&queue);
+        drop(executor);
+        assert!(*finished.lock().unwrap());
+    }
+
+    #[test]
+    fn detaching_leaves_running_jobs_behind() {
+        let executor = JobExecutor::new(1, WorkerShutdown::Detach);
+        assert_eq!(executor.policy(), WorkerShutdown::Detach);
+        let queue = executor.queue();
+        let (release, finished) = blocked_job(&queue);
+        drop(executor);
+        assert!(!*finished.lock().unwrap());
+        let _ = release.send(());
+    }
+
+    #[test]
+    fn shut_down_executors_discard_jobs() {
+        let executor = JobExecutor::new(0, WorkerShutdown::Join);
+        assert_eq!(executor.threads(), 1);
+        let queue = executor.queue();
+        let (_release, _) = blocked_job(&queue);
+        let (sender, receiver) = mpsc::channel();
+        let queued = sender.clone();
+        queue.submit(move || queued.send(()).unwrap()).unwrap();
+        // Joins the blocked job, the queued one is discarded
+        drop(executor);
+        assert!(receiver.try_recv().is_err(), "a queued job still ran");
+        let err = queue.submit(move || sender.send(()).unwrap()).unwrap_err();
+        assert_eq!(err.code(), crate::ErrorCode::Internal);
+    }
+}
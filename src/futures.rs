@@ -0,0 +1,588 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::handles::SlotTable;
+use crate::{ApiError, CallSite, ErrorCode, FutureHandle, PlainOldData};
+
+/// State of a training started by the guest.
+#[derive(Debug)]
+pub enum FutureState {
+    Pending,
+    /// The training finished, with a backend defined result payload.
+    Completed(Vec<u8>),
+    Failed(ApiError),
+    Cancelled,
+}
+
+impl FutureState {
+    pub fn status(&self) -> FutureStatus {
+        match self {
+            Self::Pending => FutureStatus::new(FutureStatus::PENDING, 0),
+            Self::Completed(result) => FutureStatus::new(FutureStatus::COMPLETED, result.len()),
+            Self::Failed(_) => FutureStatus::new(FutureStatus::FAILED, 0),
+            Self::Cancelled => FutureStatus::new(FutureStatus::CANCELLED, 0),
+        }
+    }
+}
+
+/// What `poll_future` reports to the guest about a future.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct FutureStatus {
+    /// One of the `FutureStatus::*` states.
+    pub state: u32,
+    /// Size of the result payload of a completed future in bytes.
+    pub result_len: u32,
+}
+
+const _: () = {
+    use std::mem::{align_of, offset_of, size_of};
+    assert!(size_of::<FutureStatus>() == 8);
+    assert!(align_of::<FutureStatus>() == 4);
+    assert!(offset_of!(FutureStatus, state) == 0);
+    assert!(offset_of!(FutureStatus, result_len) == 4);
+};
+
+// SAFETY: `repr(C)` with two `u32`s, so there is no padding
+unsafe impl PlainOldData for FutureStatus {}
+
+impl FutureStatus {
+    pub const PENDING: u32 = 0;
+    pub const COMPLETED: u32 = 1;
+    pub const FAILED: u32 = 2;
+    pub const CANCELLED: u32 = 3;
+
+    fn new(state: u32, result_len: usize) -> Self {
+        Self {
+            state,
+            // Payloads are produced by the host for a 32-bit guest, anything
+            // larger couldn't be retrieved anyway
+            result_len: u32::try_from(result_len).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+/// Outstanding futures of an instance, keyed by the handles the guest holds.
+///
+/// Futures are stored in slots that are reused once freed. Every reuse bumps
+/// the slot's generation, which is part of the handle, so a stale copy of a
+/// freed handle fails with [`ErrorCode::StaleHandle`] instead of addressing
+/// the slot's new future.
+///
+/// The number of futures is capped so a guest that never releases its
+/// handles can't grow host memory without bound.
+#[derive(Debug)]
+pub struct FutureTable {
+    futures: SlotTable<FutureHandle, Entry>,
+    /// Deadlines of pending futures that have one, keyed by slot.
+    deadlines: BTreeMap<u32, Instant>,
+    completed: FutureCompleter,
+    /// Allocation sequence number of the next future, slots are reused out
+    /// of order.
+    next_sequence: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: FutureState,
+    created: Instant,
+    origin: Option<CallSite>,
+    sequence: u64,
+}
+
+/// A future that is still in its [`FutureTable`], see
+/// [`FutureTable::live`].
+#[derive(Clone, Copy, Debug)]
+pub struct LiveFuture {
+    pub handle: FutureHandle,
+    /// Host function that created the future, if it was recorded.
+    pub origin: Option<CallSite>,
+    pub created: Instant,
+}
+
+/// Reports the outcome of futures running on other threads back to their
+/// [`FutureTable`], which applies it on its next [`update`](FutureTable::update).
+#[derive(Clone, Debug, Default)]
+pub struct FutureCompleter(Arc<Completions>);
+
+#[derive(Debug, Default)]
+struct Completions {
+    completed: Mutex<Vec<(FutureHandle, FutureState, Instant)>>,
+    reported: Condvar,
+}
+
+impl FutureCompleter {
+    pub fn complete(&self, handle: FutureHandle, state: FutureState) {
+        self.complete_at(handle, state, Instant::now());
+    }
+
+    /// Reports an outcome the future reached `at`, which decides whether it
+    /// was by the future's deadline.
+    pub fn complete_at(&self, handle: FutureHandle, state: FutureState, at: Instant) {
+        self.0.lock().push((handle, state, at));
+        self.0.reported.notify_all();
+    }
+}
+
+impl Completions {
+    fn lock(&self) -> MutexGuard<'_, Vec<(FutureHandle, FutureState, Instant)>> {
+        self.completed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Default for FutureTable {
+    fn default() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl FutureTable {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            futures: SlotTable::with_capacity(capacity),
+            deadlines: BTreeMap::new(),
+            completed: FutureCompleter::default(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Starts new slots at `generation` instead of `1`, to exercise
+    /// generation wrapping without freeing a slot billions of times.
+    #[doc(hidden)]
+    pub fn with_first_generation(mut self, generation: u32) -> Self {
+        self.futures = self.futures.with_first_generation(generation);
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.futures.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.futures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fails with [`ErrorCode::Busy`] if no more futures can be added.
+    pub fn check_capacity(&self) -> Result<(), ApiError> {
+        self.futures.check_capacity()
+    }
+
+    /// Adds a future, failing with [`ErrorCode::Busy`] if the table is full.
+    pub fn insert(&mut self, state: FutureState) -> Result<FutureHandle, ApiError> {
+        self.insert_from(None, state)
+    }
+
+    /// Adds a future created by the host function at `origin`, which is
+    /// reported if the future is [leaked](Self::live).
+    pub fn insert_from(
+        &mut self,
+        origin: Option<CallSite>,
+        state: FutureState,
+    ) -> Result<FutureHandle, ApiError> {
+        let handle = self.futures.insert(Entry {
+            state,
+            created: Instant::now(),
+            origin,
+            sequence: self.next_sequence,
+        })?;
+        self.next_sequence += 1;
+        Ok(handle)
+    }
+
+    pub fn get(&self, handle: FutureHandle) -> Result<&FutureState, ApiError> {
+        self.futures.get(handle).map(|entry| &entry.state)
+    }
+
+    pub fn get_mut(&mut self, handle: FutureHandle) -> Result<&mut FutureState, ApiError> {
+        self.futures.get_mut(handle).map(|entry| &mut entry.state)
+    }
+
+    /// Removes a future. Its slot is reused with the next generation, so the
+    /// handle is stale from now on.
+    pub fn remove(&mut self, handle: FutureHandle) -> Result<FutureState, ApiError> {
+        let entry = self.futures.remove(handle)?;
+        self.deadlines.remove(&handle.slot());
+        Ok(entry.state)
+    }
+
+    /// Removes every future, returning their handles. Like with
+    /// [`remove`](Self::remove), the handles are stale from now on.
+    pub fn clear(&mut self) -> Vec<FutureHandle> {
+        self.deadlines.clear();
+        self.futures.clear()
+    }
+
+    /// Fails the future with [`ErrorCode::TimedOut`] if it is still pending
+    /// after `deadline`, see [`update`](Self::update).
+    pub fn set_deadline(
+        &mut self,
+        handle: FutureHandle,
+        deadline: Instant,
+    ) -> Result<(), ApiError> {
+        self.futures.get(handle)?;
+        self.deadlines.insert(handle.slot(), deadline);
+        Ok(())
+    }
+
+    pub fn completer(&self) -> FutureCompleter {
+        self.completed.clone()
+    }
+
+    /// Applies the outcomes reported through [`completer`](Self::completer)s
+    /// and fails pending futures whose deadline has passed, calling
+    /// `timed_out` for each so their work can be cancelled. Only pending
+    /// futures are updated, so a future cancelled or freed in the meantime
+    /// stays that way.
+    ///
+    /// A future completed by its deadline is completed, even if the
+    /// completion is only applied after it. Completions of freed futures are
+    /// dropped, even if the slot has been reused since.
+    pub fn update(&mut self, timed_out: impl FnMut(FutureHandle)) {
+        self.update_at(Instant::now(), timed_out)
+    }
+
+    /// [`update`](Self::update) as of `now`, failing pending futures whose
+    /// deadline is before it.
+    pub fn update_at(&mut self, now: Instant, mut timed_out: impl FnMut(FutureHandle)) {
+        let completed = std::mem::take(&mut *self.completed.0.lock());
+        for (handle, state, at) in completed {
+            let pending = matches!(self.get(handle), Ok(FutureState::Pending));
+            if pending {
+                let deadline = self.deadlines.remove(&handle.slot());
+                let entry = self.get_mut(handle).expect("future was just looked up");
+                match deadline {
+                    Some(deadline) if at > deadline => {
+                        *entry = FutureState::Failed(timeout(deadline));
+                        timed_out(handle);
+                    }
+                    _ => *entry = state,
+                }
+            }
+        }
+
+        let futures = &mut self.futures;
+        self.deadlines.retain(|&index, deadline| {
+            let (handle, state) = match futures.slot_mut(index) {
+                Some((
+                    handle,
+                    Entry {
+                        state: state @ FutureState::Pending,
+                        ..
+                    },
+                )) => (handle, state),
+                // Settled without going through a completer
+                _ => return false,
+            };
+            if now <= *deadline {
+                return true;
+            }
+            *state = FutureState::Failed(timeout(*deadline));
+            timed_out(handle);
+            false
+        });
+    }
+
+    /// Blocks until a completion is reported through a
+    /// [`completer`](Self::completer) or `deadline` passes, returning whether
+    /// there is a completion to [`update`](Self::update) with.
+    pub fn wait(&self, deadline: Instant) -> bool {
+        let mut completed = self.completed.0.lock();
+        while completed.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return false;
+            }
+            completed = self
+                .completed
+                .0
+                .reported
+                .wait_timeout(completed, timeout)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+        true
+    }
+
+    /// Handles of the futures that are still pending, in allocation order.
+    pub fn pending(&self) -> impl Iterator<Item = FutureHandle> + '_ {
+        self.entries()
+            .filter(|(_, entry)| matches!(entry.state, FutureState::Pending))
+            .map(|(handle, _)| handle)
+    }
+
+    /// Every future in the table, in allocation order. Futures still in the table
+    /// when the guest is done with it have been leaked.
+    pub fn live(&self) -> impl Iterator<Item = LiveFuture> + '_ {
+        self.entries().map(|(handle, entry)| LiveFuture {
+            handle,
+            origin: entry.origin,
+            created: entry.created,
+        })
+    }
+
+    /// Handles of every future in the table, in allocation order.
+    pub fn handles(&self) -> impl Iterator<Item = FutureHandle> + '_ {
+        self.entries().map(|(handle, _)| handle)
+    }
+
+    /// The futures in allocation order, which freed slots being reused
+    /// last freed first doesn't keep.
+    fn entries(&self) -> impl Iterator<Item = (FutureHandle, &Entry)> + '_ {
+        let mut entries: Vec<_> = self.futures.iter().collect();
+        entries.sort_unstable_by_key(|(_, entry)| entry.sequence);
+        entries.into_iter()
+    }
+}
+
+fn timeout(deadline: Instant) -> ApiError {
+    ApiError::new(
+        ErrorCode::TimedOut,
+        format!(
+            "training timed out {:?} ago",
+            Instant::now().saturating_duration_since(deadline)
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handles::SlotHandle;
+
+    #[test]
+    fn handles_are_never_zero_and_look_up_their_future() {
+        let mut futures = FutureTable::new();
+        let pending = futures.insert(FutureState::Pending).unwrap();
+        let done = futures.insert(FutureState::Completed(vec![1, 2])).unwrap();
+        assert_ne!(pending.raw(), 0);
+        assert_ne!(done.raw(), 0);
+        assert_ne!(pending, done);
+        assert!(matches!(futures.get(pending), Ok(FutureState::Pending)));
+        assert!(
+            matches!(futures.get(done), Ok(FutureState::Completed(result)) if result == &[1, 2])
+        );
+        assert_eq!(futures.len(), 2);
+        assert_eq!(futures.handles().collect::<Vec<_>>(), [pending, done]);
+    }
+
+    #[test]
+    fn unknown_handles_are_not_found() {
+        let mut futures = FutureTable::new();
+        futures.insert(FutureState::Pending).unwrap();
+        // Generations start at 1, so `0` never addresses a future
+        assert!(futures.get(FutureHandle(0)).is_err());
+        for handle in [FutureHandle::new(7, 1), FutureHandle(u64::MAX)] {
+            let err = futures.get(handle).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::NotFound, "{:?}", handle);
+            let err = futures.remove(handle).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::NotFound, "{:?}", handle);
+        }
+    }
+
+    #[test]
+    fn full_tables_are_busy() {
+        let mut futures = FutureTable::with_capacity(2);
+        let first = futures.insert(FutureState::Pending).unwrap();
+        futures.insert(FutureState::Cancelled).unwrap();
+        let err = futures.insert(FutureState::Pending).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+        assert_eq!(
+            err.guest_message(),
+            "Busy(9): too many open futures (2), close some before opening new ones"
+        );
+        assert_eq!(
+            futures.check_capacity().unwrap_err().code(),
+            ErrorCode::Busy
+        );
+        assert_eq!(futures.len(), 2);
+        futures.remove(first).unwrap();
+        futures.check_capacity().unwrap();
+        futures.insert(FutureState::Pending).unwrap();
+        assert_eq!(FutureTable::new().capacity(), FutureTable::DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn removed_handles_are_not_handed_out_again() {
+        let mut futures = FutureTable::with_capacity(1);
+        let mut seen = std::collections::HashSet::new();
+        let mut last = futures.insert(FutureState::Pending).unwrap();
+        for _ in 0..100 {
+            assert!(seen.insert(last), "{:?} handed out twice", last);
+            assert!(matches!(futures.remove(last), Ok(FutureState::Pending)));
+            let err = futures.get(last).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::StaleHandle);
+            last = futures.insert(FutureState::Pending).unwrap();
+            assert_eq!(last.slot(), 0);
+        }
+    }
+
+    fn ms(ms: u64) -> std::time::Duration {
+        std::time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn jobs_running_past_their_deadline_time_out() {
+        let mut futures = FutureTable::new();
+        let slow = futures.insert(FutureState::Pending).unwrap();
+        let fast = futures.insert(FutureState::Pending).unwrap();
+        let start = Instant::now();
+        let deadline = start + ms(50);
+        futures.set_deadline(slow, deadline).unwrap();
+        futures.set_deadline(fast, deadline).unwrap();
+        let completer = futures.completer();
+        completer.complete_at(fast, FutureState::Completed(vec![2]), start + ms(5));
+
+        let mut timed_out = Vec::new();
+        futures.update_at(start + ms(10), |handle| timed_out.push(handle));
+        assert!(timed_out.is_empty());
+        assert!(matches!(futures.get(slow), Ok(FutureState::Pending)));
+        futures.update_at(deadline, |handle| timed_out.push(handle));
+        assert!(timed_out.is_empty(), "timed out at the deadline");
+        futures.update_at(start + ms(60), |handle| timed_out.push(handle));
+        assert_eq!(timed_out, [slow]);
+        match futures.get(slow).unwrap() {
+            FutureState::Failed(err) => assert_eq!(err.code(), ErrorCode::TimedOut),
+            state => panic!("{:?}", state),
+        }
+        assert!(matches!(futures.get(fast), Ok(FutureState::Completed(result)) if result == &[2]));
+
+        // The late completion doesn't replace the timeout
+        completer.complete_at(slow, FutureState::Completed(vec![1]), start + ms(150));
+        futures.update_at(start + ms(160), |handle| {
+            panic!("{:?} timed out twice", handle)
+        });
+        assert_eq!(
+            futures.get(slow).unwrap().status().state,
+            FutureStatus::FAILED
+        );
+    }
+
+    #[test]
+    fn completions_by_the_deadline_win_even_if_applied_later() {
+        let mut futures = FutureTable::new();
+        let on_time = futures.insert(FutureState::Pending).unwrap();
+        let late = futures.insert(FutureState::Pending).unwrap();
+        let deadline = Instant::now() + ms(20);
+        futures.set_deadline(on_time, deadline).unwrap();
+        futures.set_deadline(late, deadline).unwrap();
+        let completer = futures.completer();
+        completer.complete_at(on_time, FutureState::Completed(vec![3]), deadline);
+        let just_after = deadline + std::time::Duration::from_nanos(1);
+        completer.complete_at(late, FutureState::Completed(vec![4]), just_after);
+
+        let mut timed_out = Vec::new();
+        futures.update_at(deadline + ms(20), |handle| timed_out.push(handle));
+        assert_eq!(timed_out, [late]);
+        assert!(
+            matches!(futures.get(on_time), Ok(FutureState::Completed(result)) if result == &[3])
+        );
+        assert!(matches!(futures.get(late), Ok(FutureState::Failed(_))));
+    }
+
+    #[test]
+    fn settled_futures_keep_their_state_past_the_deadline() {
+        let mut futures = FutureTable::new();
+        let cancelled = futures.insert(FutureState::Pending).unwrap();
+        let freed = futures.insert(FutureState::Pending).unwrap();
+        let deadline = Instant::now();
+        futures.set_deadline(cancelled, deadline).unwrap();
+        futures.set_deadline(freed, deadline).unwrap();
+        *futures.get_mut(cancelled).unwrap() = FutureState::Cancelled;
+        futures.remove(freed).unwrap();
+        let reused = futures.insert(FutureState::Pending).unwrap();
+        assert_eq!(reused.slot(), freed.slot());
+        futures.update_at(deadline + ms(5), |handle| panic!("{:?} timed out", handle));
+        assert!(matches!(futures.get(cancelled), Ok(FutureState::Cancelled)));
+        assert!(matches!(futures.get(reused), Ok(FutureState::Pending)));
+        let err = futures.set_deadline(freed, deadline).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+    }
+
+    #[test]
+    fn stale_handles_leave_the_reused_slot_alone() {
+        let mut futures = FutureTable::with_capacity(1);
+        let freed = futures.insert(FutureState::Pending).unwrap();
+        futures.remove(freed).unwrap();
+        let reused = futures.insert(FutureState::Completed(vec![3])).unwrap();
+        assert_eq!(reused.slot(), freed.slot());
+        assert_eq!(reused.generation(), freed.generation() + 1);
+
+        assert_eq!(
+            futures.get(freed).unwrap_err().code(),
+            ErrorCode::StaleHandle
+        );
+        assert_eq!(
+            futures.get_mut(freed).unwrap_err().code(),
+            ErrorCode::StaleHandle
+        );
+        assert_eq!(
+            futures.remove(freed).unwrap_err().code(),
+            ErrorCode::StaleHandle
+        );
+        let err = futures.set_deadline(freed, Instant::now()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        // A late completion of the freed future doesn't settle the new one
+        futures.completer().complete(freed, FutureState::Cancelled);
+        futures.update(|_| {});
+        assert!(
+            matches!(futures.get(reused), Ok(FutureState::Completed(result)) if result == &[3])
+        );
+        assert_eq!(futures.handles().collect::<Vec<_>>(), [reused]);
+    }
+
+    #[test]
+    fn futures_are_listed_in_allocation_order() {
+        let mut futures = FutureTable::new();
+        let first = futures.insert(FutureState::Pending).unwrap();
+        let freed = futures.insert(FutureState::Pending).unwrap();
+        let last = futures.insert(FutureState::Completed(Vec::new())).unwrap();
+        futures.remove(freed).unwrap();
+        let reused = futures.insert(FutureState::Pending).unwrap();
+        assert_eq!(reused.slot(), freed.slot());
+
+        let handles: Vec<_> = futures.handles().collect();
+        assert_eq!(handles, [first, last, reused]);
+        let live: Vec<_> = futures.live().map(|future| future.handle).collect();
+        assert_eq!(live, handles);
+        assert_eq!(futures.pending().collect::<Vec<_>>(), [first, reused]);
+    }
+
+    #[test]
+    fn generations_wrap_around_zero() {
+        let mut futures = FutureTable::new().with_first_generation(u32::MAX);
+        let last = futures.insert(FutureState::Pending).unwrap();
+        assert_eq!(last.generation(), u32::MAX);
+        futures.remove(last).unwrap();
+
+        let wrapped = futures.insert(FutureState::Pending).unwrap();
+        assert_eq!((wrapped.slot(), wrapped.generation()), (last.slot(), 1));
+        assert_ne!(wrapped.raw(), 0);
+        assert_eq!(
+            futures.get(last).unwrap_err().code(),
+            ErrorCode::StaleHandle
+        );
+        assert!(matches!(futures.get(wrapped), Ok(FutureState::Pending)));
+    }
+
+    #[test]
+    fn handles_debug_as_slot_and_generation() {
+        let handle = FutureHandle::new(3, 7);
+        assert_eq!(handle.raw(), 7 << 32 | 3);
+        assert_eq!(
+            format!("{:?}", handle),
+            "FutureHandle { slot: 3, generation: 7 }"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use crate::{ApiError, ErrorCode, PlainOldData, WasmMemoryHandle};
+
+fn null_pointer<T>() -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidArgument,
+        format!("null pointer to `{}`", std::any::type_name::<T>()),
+    )
+}
+
+/// A guest pointer to a `T`. Stored as a `u64`, so it can hold the
+/// addresses of 32-bit guests, passed to host functions as `u32`s, and of
+/// memory64 guests alike.
+///
+/// A null pointer can be represented, but is rejected with
+/// [`ErrorCode::InvalidArgument`] when read or written through.
+pub struct GuestPtr<T> {
+    raw: u64,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> GuestPtr<T> {
+    pub fn new(raw: u64) -> Self {
+        Self {
+            raw,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn null() -> Self {
+        Self::new(0)
+    }
+
+    pub fn raw(self) -> u64 {
+        self.raw
+    }
+
+    pub fn is_null(self) -> bool {
+        self.raw == 0
+    }
+
+    /// Returns `self` if it isn't null.
+    pub fn non_null(self) -> Result<Self, ApiError> {
+        if self.is_null() {
+            Err(null_pointer::<T>())
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// The pointer `n` elements after this one.
+    pub fn offset(self, n: u64) -> Result<Self, ApiError> {
+        n.checked_mul(std::mem::size_of::<T>() as u64)
+            .and_then(|bytes| self.raw.checked_add(bytes))
+            .map(Self::new)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::OutOfBounds,
+                    format!(
+                        "offsetting {:#x} by {} `{}`s overflows",
+                        self.raw,
+                        n,
+                        std::any::type_name::<T>()
+                    ),
+                )
+            })
+    }
+}
+
+impl<T: PlainOldData> GuestPtr<T> {
+    pub fn read(self, memory: &WasmMemoryHandle<'_>) -> Result<T, ApiError> {
+        memory.read_pod(self.non_null()?.raw)
+    }
+
+    pub fn write(self, memory: &mut WasmMemoryHandle<'_>, value: &T) -> Result<(), ApiError> {
+        memory.write_pod(self.non_null()?.raw, value)
+    }
+}
+
+impl<T> Clone for GuestPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GuestPtr<T> {}
+
+impl<T> PartialEq for GuestPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<T> Eq for GuestPtr<T> {}
+
+impl<T> std::fmt::Debug for GuestPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GuestPtr<{}>({:#x})",
+            std::any::type_name::<T>(),
+            self.raw
+        )
+    }
+}
+
+/// Values that host functions take by reference from guest memory, the
+/// `config: &T` parameters of [`host_import!`]. Every [`PlainOldData`] type is
+/// read as is, types with their own guest encoding, such as versioned
+/// layouts, implement this directly.
+pub trait FromGuest: Sized {
+    fn from_guest(memory: &WasmMemoryHandle<'_>, ptr: GuestPtr<Self>) -> Result<Self, ApiError>;
+}
+
+impl<T: PlainOldData> FromGuest for T {
+    fn from_guest(memory: &WasmMemoryHandle<'_>, ptr: GuestPtr<Self>) -> Result<Self, ApiError> {
+        ptr.read(memory)
+    }
+}
+
+/// A guest `(ptr, count)` array of `T`s. A null pointer is only allowed for
+/// empty slices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestSlice<T> {
+    ptr: GuestPtr<T>,
+    count: u64,
+}
+
+impl<T> GuestSlice<T> {
+    pub fn new(ptr: u64, count: u64) -> Self {
+        Self {
+            ptr: GuestPtr::new(ptr),
+            count,
+        }
+    }
+
+    pub fn ptr(self) -> GuestPtr<T> {
+        self.ptr
+    }
+
+    pub fn len(self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.count == 0
+    }
+
+    fn checked_ptr(self) -> Result<u64, ApiError> {
+        if self.count == 0 {
+            Ok(self.ptr.raw)
+        } else {
+            Ok(self.ptr.non_null()?.raw)
+        }
+    }
+}
+
+impl<T: PlainOldData> GuestSlice<T> {
+    /// Borrows the slice, which has to be aligned for `T`.
+    pub fn read<'m>(self, memory: &'m WasmMemoryHandle<'_>) -> Result<&'m [T], ApiError> {
+        memory.read_pod_slice(self.checked_ptr()?, self.count)
+    }
+
+    /// Copies the slice out of the guest, regardless of its alignment.
+    pub fn to_vec(self, memory: &WasmMemoryHandle<'_>) -> Result<Vec<T>, ApiError> {
+        memory.read_pod_vec(self.checked_ptr()?, self.count)
+    }
+
+    /// Copies `values` into the slice, which has to be exactly as long.
+    pub fn write(self, memory: &mut WasmMemoryHandle<'_>, values: &[T]) -> Result<(), ApiError> {
+        if values.len() as u64 != self.count {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "can't write {} elements to a guest slice of {}",
+                    values.len(),
+                    self.count
+                ),
+            ));
+        }
+        memory.write_pod_slice(self.checked_ptr()?, values)
+    }
+}
+
+impl GuestSlice<u8> {
+    /// Borrows the bytes mutably, see [`WasmMemoryHandle::bytes_mut`].
+    pub fn bytes_mut<'m>(
+        self,
+        memory: &'m mut WasmMemoryHandle<'_>,
+    ) -> Result<&'m mut [u8], ApiError> {
+        memory.bytes_mut(self.checked_ptr()?, self.count)
+    }
+}
+
+/// A guest `(ptr, len)` UTF-8 string. A null pointer is only allowed for
+/// empty strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestStr(GuestSlice<u8>);
+
+impl GuestStr {
+    pub fn new(ptr: u64, len: u64) -> Self {
+        Self(GuestSlice::new(ptr, len))
+    }
+
+    pub fn len(self) -> u64 {
+        self.0.len()
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn read<'m>(self, memory: &'m WasmMemoryHandle<'_>) -> Result<&'m str, ApiError> {
+        memory.read_str(self.0.checked_ptr()?, self.0.count)
+    }
+
+    /// See [`WasmMemoryHandle::read_str_lossy`].
+    pub fn read_lossy<'m>(
+        self,
+        memory: &'m WasmMemoryHandle<'_>,
+    ) -> Result<Cow<'m, str>, ApiError> {
+        memory.read_str_lossy(self.0.checked_ptr()?, self.0.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_pointers_are_rejected_when_used() {
+        let mut bytes = [0; 16];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let null = GuestPtr::<u32>::null();
+        assert!(null.is_null());
+        let err = null.read(&memory).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert!(err.guest_message().ends_with("null pointer to `u32`"));
+        let err = null.write(&mut memory, &7).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert_eq!(bytes, [0; 16]);
+    }
+
+    #[test]
+    fn pointers_read_and_write_their_pointee() {
+        let mut bytes = [0; 16];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let ptr = GuestPtr::<u32>::new(4);
+        ptr.write(&mut memory, &7).unwrap();
+        ptr.offset(2).unwrap().write(&mut memory, &9).unwrap();
+        assert_eq!(ptr.read(&memory).unwrap(), 7);
+        assert_eq!(GuestPtr::<u32>::new(12).read(&memory).unwrap(), 9);
+        assert_eq!(&bytes[4..16], &[7, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn offsets_that_overflow_are_out_of_bounds() {
+        assert_eq!(GuestPtr::<u64>::new(8).offset(0).unwrap().raw(), 8);
+        let err = GuestPtr::<u64>::new(8).offset(u64::MAX / 8).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+        let err = GuestPtr::<u64>::new(0).offset(u64::MAX / 4).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn empty_slices_and_strings_may_be_null() {
+        let mut bytes = *b"hello";
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        assert_eq!(GuestStr::new(0, 0).read(&memory).unwrap(), "");
+        assert!(GuestSlice::<u8>::new(0, 0)
+            .to_vec(&memory)
+            .unwrap()
+            .is_empty());
+        assert!(GuestSlice::<u8>::new(0, 0)
+            .bytes_mut(&mut memory)
+            .unwrap()
+            .is_empty());
+        let err = GuestStr::new(0, 1).read(&memory).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        let err = GuestSlice::<u8>::new(0, 1).to_vec(&memory).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert_eq!(GuestStr::new(1, 4).read(&memory).unwrap(), "ello");
+    }
+
+    #[test]
+    fn slices_are_written_whole() {
+        let mut bytes = [0; 8];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let slice = GuestSlice::<u8>::new(2, 3);
+        let err = slice.write(&mut memory, &[1, 2]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        slice.write(&mut memory, &[1, 2, 3]).unwrap();
+        assert_eq!(slice.to_vec(&memory).unwrap(), [1, 2, 3]);
+        assert_eq!(bytes, [0, 0, 1, 2, 3, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use crate::{ErrorCode, ImportManifest, ImportReturn, ManifestImport, ParamRole};
+
+impl ImportManifest {
+    /// Generates `no_std` guest Rust for the imports, to be written to a file
+    /// from a build script and `include!`d into a module of the guest.
+    ///
+    /// The bindings have a module per import prefix, such as `ml`, holding
+    /// the raw `extern` functions in `raw` and a safe wrapper for every
+    /// described import:
+    ///
+    /// - `str` parameters are taken as `&str`, slices as `&mut [T]` and
+    ///   other pointers as references, passed to the import as addresses,
+    /// - out parameters are returned in the `Ok` value,
+    /// - error codes are turned into a `Result<_, ErrorCode>`, with an
+    ///   `ErrorCode` mirroring this crate's.
+    ///
+    /// Pointee types other than integers and floats, such as `FutureHandle`
+    /// or `ProtocolConfig`, are referred to by name and have to be defined
+    /// with the host's layout in the module the bindings are included in.
+    pub fn to_guest_rust(&self) -> String {
+        let mut out = String::new();
+        self.write_guest_rust(&mut out)
+            .expect("writing to a `String` can't fail");
+        out
+    }
+
+    fn write_guest_rust(&self, out: &mut String) -> fmt::Result {
+        let addr = match self.pointer_type {
+            "i64" => "u64",
+            _ => "u32",
+        };
+        writeln!(
+            out,
+            "// Generated by `ImportManifest::to_guest_rust`, do not edit.\n"
+        )?;
+        write_error_code(out)?;
+
+        let mut prefixes = BTreeMap::<_, Vec<_>>::new();
+        for (namespace, imports) in &self.namespaces {
+            for import in imports {
+                let (prefix, function) = match import.name.split_once("__") {
+                    Some((prefix, function)) => (ident(prefix), ident(function)),
+                    None => ("imports".to_owned(), ident(import.name)),
+                };
+                prefixes
+                    .entry(prefix)
+                    .or_default()
+                    .push((*namespace, function, import));
+            }
+        }
+        for (prefix, imports) in prefixes {
+            writeln!(out, "\npub mod {} {{", prefix)?;
+            writeln!(out, "    #[allow(unused_imports)]\n    use super::*;\n")?;
+            writeln!(out, "    pub mod raw {{")?;
+            for (namespace, function, import) in &imports {
+                write_raw(out, namespace, function, import)?;
+            }
+            writeln!(out, "    }}")?;
+            for (namespace, function, import) in &imports {
+                if let Some(wrapper) = Wrapper::new(addr, import) {
+                    wrapper.write(out, namespace, function, import)?;
+                }
+            }
+            writeln!(out, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_error_code(out: &mut String) -> fmt::Result {
+    let codes = ErrorCode::ALL
+        .iter()
+        .filter(|code| **code != ErrorCode::Success);
+    writeln!(
+        out,
+        "/// Error returned by an import, with the host's `ErrorCode` values."
+    )?;
+    writeln!(out, "#[derive(Clone, Copy, Debug, PartialEq, Eq)]")?;
+    writeln!(out, "pub enum ErrorCode {{")?;
+    for code in codes.clone() {
+        writeln!(out, "    /// {}", code.description())?;
+        writeln!(out, "    {:?},", code)?;
+    }
+    writeln!(
+        out,
+        "    /// A code of a newer host.\n    Unknown(u32),\n}}\n"
+    )?;
+    writeln!(out, "impl ErrorCode {{")?;
+    writeln!(
+        out,
+        "    pub fn code(self) -> u32 {{\n        match self {{"
+    )?;
+    for code in codes.clone() {
+        writeln!(out, "            Self::{:?} => {},", code, *code as u32)?;
+    }
+    writeln!(
+        out,
+        "            Self::Unknown(code) => code,\n        }}\n    }}\n"
+    )?;
+    writeln!(out, "    /// `Ok` for `0`, the success code.")?;
+    writeln!(
+        out,
+        "    pub fn check(code: u32) -> Result<(), Self> {{\n        match code {{"
+    )?;
+    writeln!(out, "            0 => Ok(()),")?;
+    for code in codes {
+        writeln!(
+            out,
+            "            {} => Err(Self::{:?}),",
+            *code as u32, code
+        )?;
+    }
+    writeln!(
+        out,
+        "            code => Err(Self::Unknown(code)),\n        }}\n    }}\n}}"
+    )
+}
+
+fn write_raw(
+    out: &mut String,
+    namespace: &str,
+    function: &str,
+    import: &ManifestImport,
+) -> fmt::Result {
+    let params = import
+        .params
+        .iter()
+        .map(|param| format!("{}: {}", ident(&param.name), rust_type(param.ty)))
+        .collect::<Vec<_>>();
+    writeln!(
+        out,
+        "        #[link(wasm_import_module = {:?})]\n        unsafe extern \"C\" {{",
+        namespace
+    )?;
+    writeln!(out, "            #[link_name = {:?}]", import.name)?;
+    writeln!(
+        out,
+        "            pub fn {}({}){};\n        }}",
+        function,
+        params.join(", "),
+        results(import.results.iter().map(|ty| rust_type(ty)))
+    )
+}
+
+/// How a safe wrapper passes the wasm parameters of an import.
+enum Arg {
+    Value(String, &'static str),
+    Str(String),
+    Slice(String, &'static str),
+    In(String, &'static str, bool),
+    Ptr(String, &'static str),
+    Out(String, &'static str),
+}
+
+struct Wrapper {
+    addr: &'static str,
+    args: Vec<Arg>,
+    returns: ImportReturn,
+}
+
+impl Wrapper {
+    /// `None` for imports without a description, which only get the raw
+    /// function.
+    fn new(addr: &'static str, import: &ManifestImport) -> Option<Self> {
+        let returns = import.returns?;
+        if returns == ImportReturn::ErrorCode && import.results != ["i32"] {
+            return None;
+        }
+        let mut args = Vec::new();
+        let mut params = import.params.iter();
+        while let Some(param) = params.next() {
+            let name = param.name.strip_suffix("_ptr").unwrap_or(&param.name);
+            let arg = match param.role? {
+                ParamRole::Value | ParamRole::Len => {
+                    Arg::Value(ident(&param.name), rust_type(param.ty))
+                }
+                ParamRole::StrPtr | ParamRole::SlicePtr { .. } => {
+                    // Followed by the length
+                    params
+                        .next()
+                        .filter(|len| len.role == Some(ParamRole::Len))?;
+                    match param.role? {
+                        ParamRole::SlicePtr { elem } => Arg::Slice(ident(name), elem),
+                        _ => Arg::Str(ident(name)),
+                    }
+                }
+                ParamRole::In { pointee, nullable } => Arg::In(ident(name), pointee, nullable),
+                ParamRole::Ptr { pointee } => Arg::Ptr(ident(name), pointee),
+                // Value returning imports have no `Ok` to return them in
+                ParamRole::Out { pointee } if returns == ImportReturn::Value => {
+                    Arg::Ptr(ident(name), pointee)
+                }
+                ParamRole::Out { pointee } => Arg::Out(ident(name), pointee),
+            };
+            args.push(arg);
+        }
+        Some(Self {
+            addr,
+            args,
+            returns,
+        })
+    }
+
+    fn write(
+        &self,
+        out: &mut String,
+        namespace: &str,
+        function: &str,
+        import: &ManifestImport,
+    ) -> fmt::Result {
+        let addr = self.addr;
+        let mut params = Vec::new();
+        let mut call = Vec::new();
+        let mut outs = Vec::new();
+        for arg in &self.args {
+            match arg {
+                Arg::Value(name, ty) => {
+                    params.push(format!("{}: {}", name, ty));
+                    call.push(name.clone());
+                }
+                Arg::Str(name) => {
+                    params.push(format!("{}: &str", name));
+                    call.push(format!("{}.as_ptr() as usize as {}", name, addr));
+                    call.push(format!("{}.len() as {}", name, addr));
+                }
+                Arg::Slice(name, elem) => {
+                    params.push(format!("{}: &mut [{}]", name, elem));
+                    call.push(format!("{}.as_mut_ptr() as usize as {}", name, addr));
+                    call.push(format!("{}.len() as {}", name, addr));
+                }
+                Arg::In(name, pointee, false) => {
+                    params.push(format!("{}: &{}", name, pointee));
+                    call.push(format!(
+                        "{} as *const {} as usize as {}",
+                        name, pointee, addr
+                    ));
+                }
+                Arg::In(name, pointee, true) => {
+                    params.push(format!("{}: Option<&{}>", name, pointee));
+                    call.push(format!(
+                        "{}.map_or(0, |{}| {} as *const {} as usize as {})",
+                        name, name, name, pointee, addr
+                    ));
+                }
+                Arg::Ptr(name, pointee) => {
+                    params.push(format!("{}: &mut {}", name, pointee));
+                    call.push(format!("{} as *mut {} as usize as {}", name, pointee, addr));
+                }
+                Arg::Out(name, pointee) => {
+                    outs.push((name, pointee));
+                    call.push(format!("{}.as_mut_ptr() as usize as {}", name, addr));
+                }
+            }
+        }
+
+        let value = match self.returns {
+            ImportReturn::ErrorCode => {
+                let ok = outs.iter().map(|(_, pointee)| **pointee);
+                format!(" -> Result<{}, ErrorCode>", tuple(ok))
+            }
+            ImportReturn::Value => results(import.results.iter().map(|ty| rust_type(ty))),
+        };
+        writeln!(out, "\n    /// `{}::{}`", namespace, import.name)?;
+        writeln!(
+            out,
+            "    pub fn {}({}){} {{",
+            function,
+            params.join(", "),
+            value
+        )?;
+        for (name, pointee) in &outs {
+            writeln!(
+                out,
+                "        let mut {} = core::mem::MaybeUninit::<{}>::uninit();",
+                name, pointee
+            )?;
+        }
+        let call = format!("unsafe {{ raw::{}({}) }}", function, call.join(", "));
+        if self.returns == ImportReturn::Value {
+            return writeln!(out, "        {}\n    }}", call);
+        }
+        writeln!(out, "        ErrorCode::check({})?;", call)?;
+        // The host wrote every out parameter as the call succeeded
+        let ok = outs
+            .iter()
+            .map(|(name, _)| format!("unsafe {{ {}.assume_init() }}", name));
+        writeln!(out, "        Ok({})\n    }}", tuple(ok))
+    }
+}
+
+/// `()`, `T` or `(T, U, ..)`.
+fn tuple<T: AsRef<str>>(items: impl Iterator<Item = T>) -> String {
+    let items = items
+        .map(|item| item.as_ref().to_owned())
+        .collect::<Vec<_>>();
+    match items.len() {
+        1 => items[0].clone(),
+        _ => format!("({})", items.join(", ")),
+    }
+}
+
+/// Return type of a function returning `results`.
+fn results<'r>(results: impl Iterator<Item = &'r str>) -> String {
+    let results = results.collect::<Vec<_>>();
+    match results.len() {
+        0 => String::new(),
+        _ => format!(" -> {}", tuple(results.into_iter())),
+    }
+}
+
+/// Integers are passed as unsigned, like all of this crate's imports.
+fn rust_type(wasm: &str) -> &'static str {
+    match wasm {
+        "i32" => "u32",
+        "i64" => "u64",
+        "f32" => "f32",
+        "f64" => "f64",
+        _ => "u128",
+    }
+}
+
+/// `name` as a Rust identifier.
+fn ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern",
+        "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "static", "struct", "trait", "true", "type", "unsafe", "use",
+        "where", "while", "abstract", "become", "box", "do", "final", "gen", "macro", "override",
+        "priv", "try", "typeof", "unsized", "virtual", "yield",
+    ];
+    let mut ident = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if KEYWORDS.contains(&ident.as_str()) {
+        ident.insert_str(0, "r#");
+    }
+    ident
+}
@@ -0,0 +1,283 @@
+use std::convert::TryFrom;
+
+use crate::{ApiError, ErrorCode};
+
+/// A handle declared with `slot_handle!`.
+pub(crate) trait SlotHandle: Copy + std::fmt::Debug {
+    /// What the handle refers to, for error messages.
+    const KIND: &'static str;
+
+    fn new(slot: u32, generation: u32) -> Self;
+
+    fn raw(self) -> u64;
+
+    fn slot(self) -> u32 {
+        self.raw() as u32
+    }
+
+    fn generation(self) -> u32 {
+        (self.raw() >> 32) as u32
+    }
+}
+
+/// Host resources the guest refers to by handle, such as the futures of a
+/// [`FutureTable`](crate::FutureTable), in generation tagged slots, so a
+/// handle that was freed fails with [`ErrorCode::StaleHandle`] even once its
+/// slot is reused.
+#[derive(Debug)]
+pub(crate) struct SlotTable<H, T> {
+    slots: Vec<Slot<T>>,
+    /// Indices of the empty slots, reused last freed first.
+    free: Vec<u32>,
+    len: usize,
+    capacity: usize,
+    first_generation: u32,
+    handle: std::marker::PhantomData<H>,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    /// Generation of the slot's current value, or of the next one if the
+    /// slot is empty.
+    generation: u32,
+    value: Option<T>,
+}
+
+impl<H: SlotHandle, T> SlotTable<H, T> {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            capacity,
+            first_generation: 1,
+            handle: std::marker::PhantomData,
+        }
+    }
+
+    /// Starts new slots at `generation` instead of `1`.
+    pub(crate) fn with_first_generation(mut self, generation: u32) -> Self {
+        self.first_generation = generation.max(1);
+        self
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// Fails with [`ErrorCode::Busy`] if no more values can be added.
+    pub(crate) fn check_capacity(&self) -> Result<(), ApiError> {
+        if self.len >= self.capacity {
+            return Err(ApiError::new(
+                ErrorCode::Busy,
+                format!(
+                    "too many open {}s ({}), close some before opening new ones",
+                    H::KIND,
+                    self.capacity
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn insert(&mut self, value: T) -> Result<H, ApiError> {
+        self.check_capacity()?;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                let index = u32::try_from(self.slots.len()).map_err(|_| {
+                    ApiError::internal(format!("{} table is out of slots", H::KIND))
+                })?;
+                self.slots.push(Slot {
+                    generation: self.first_generation,
+                    value: None,
+                });
+                index
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.value = Some(value);
+        self.len += 1;
+        Ok(H::new(index, slot.generation))
+    }
+
+    pub(crate) fn get(&self, handle: H) -> Result<&T, ApiError> {
+        let slot = self.slot(handle)?;
+        slot.value.as_ref().ok_or_else(|| unknown(handle))
+    }
+
+    pub(crate) fn get_mut(&mut self, handle: H) -> Result<&mut T, ApiError> {
+        self.slot(handle)?;
+        self.slots[handle.slot() as usize]
+            .value
+            .as_mut()
+            .ok_or_else(|| unknown(handle))
+    }
+
+    /// The slot of `handle`, if the handle is of its current generation.
+    fn slot(&self, handle: H) -> Result<&Slot<T>, ApiError> {
+        let slot = self
+            .slots
+            .get(handle.slot() as usize)
+            .ok_or_else(|| unknown(handle))?;
+        if slot.generation != handle.generation() {
+            return Err(ApiError::new(
+                ErrorCode::StaleHandle,
+                format!(
+                    "stale {} handle {:?}, the slot is at generation {}",
+                    H::KIND,
+                    handle,
+                    slot.generation
+                ),
+            ));
+        }
+        Ok(slot)
+    }
+
+    /// The value in slot `index` and its handle, if the slot isn't empty.
+    pub(crate) fn slot_mut(&mut self, index: u32) -> Option<(H, &mut T)> {
+        let slot = self.slots.get_mut(index as usize)?;
+        let value = slot.value.as_mut()?;
+        Some((H::new(index, slot.generation), value))
+    }
+
+    /// Every value and its handle, in slot order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (H, &T)> + '_ {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let value = slot.value.as_ref()?;
+            // Slots are only added while their index fits a `u32`
+            Some((H::new(index as u32, slot.generation), value))
+        })
+    }
+
+    /// Removes a value, its handle is stale from now on.
+    pub(crate) fn remove(&mut self, handle: H) -> Result<T, ApiError> {
+        self.get_mut(handle)?;
+        let slot = &mut self.slots[handle.slot() as usize];
+        let value = slot.value.take().expect("value was just looked up");
+        // Generation 0 is skipped, so handles are never `0`
+        slot.generation = match slot.generation.wrapping_add(1) {
+            0 => 1,
+            generation => generation,
+        };
+        self.free.push(handle.slot());
+        self.len -= 1;
+        Ok(value)
+    }
+
+    /// Removes every value, returning their handles in slot order.
+    pub(crate) fn clear(&mut self) -> Vec<H> {
+        let handles: Vec<H> = self.iter().map(|(handle, _)| handle).collect();
+        for &handle in &handles {
+            self.remove(handle).expect("handle of a live slot");
+        }
+        handles
+    }
+}
+
+pub(crate) fn unknown<H: SlotHandle>(handle: H) -> ApiError {
+    ApiError::not_found(format!("unknown {} handle {}", H::KIND, handle.raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    slot_handle! {
+        pub struct ItemHandle = "item";
+    }
+
+    #[test]
+    fn freed_slots_are_reused_last_first() {
+        let mut table = SlotTable::<ItemHandle, &str>::with_capacity(8);
+        let handles: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|item| table.insert(*item).unwrap())
+            .collect();
+        table.remove(handles[0]).unwrap();
+        table.remove(handles[2]).unwrap();
+
+        let reused = table.insert("d").unwrap();
+        assert_eq!((reused.slot(), reused.generation()), (2, 2));
+        let reused = table.insert("e").unwrap();
+        assert_eq!((reused.slot(), reused.generation()), (0, 2));
+        let added = table.insert("f").unwrap();
+        assert_eq!((added.slot(), added.generation()), (3, 1));
+        let items: Vec<_> = table.iter().map(|(_, item)| *item).collect();
+        assert_eq!(items, ["e", "b", "d", "f"]);
+        assert_eq!(table.len(), 4);
+    }
+
+    #[test]
+    fn handles_are_checked_against_their_slot() {
+        let mut table = SlotTable::<ItemHandle, u8>::with_capacity(8);
+        let freed = table.insert(1).unwrap();
+        table.remove(freed).unwrap();
+        let err = table.get(freed).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        assert!(
+            err.guest_message().starts_with(
+                "StaleHandle(10): stale item handle ItemHandle { slot: 0, generation: 1 }, \
+                 the slot is at generation 2"
+            ),
+            "{}",
+            err.guest_message()
+        );
+        // A freed slot's next generation addresses nothing until it's reused
+        let next = ItemHandle::new(0, 2);
+        assert_eq!(table.get(next).unwrap_err().code(), ErrorCode::NotFound);
+        let unknown = ItemHandle::new(5, 1);
+        assert_eq!(unknown.raw(), 1 << 32 | 5);
+        let err = table.remove(unknown).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err
+            .guest_message()
+            .ends_with(&format!("unknown item handle {}", unknown.raw())));
+    }
+
+    #[test]
+    fn capacity_counts_live_values() {
+        let mut table = SlotTable::<ItemHandle, u8>::with_capacity(1);
+        let first = table.insert(1).unwrap();
+        let err = table.insert(2).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+        assert!(err
+            .guest_message()
+            .ends_with("too many open items (1), close some before opening new ones"));
+        table.remove(first).unwrap();
+        table.insert(2).unwrap();
+        table.set_capacity(2);
+        table.insert(3).unwrap();
+        assert_eq!((table.len(), table.capacity()), (2, 2));
+    }
+
+    #[test]
+    fn clearing_makes_every_handle_stale() {
+        let mut table = SlotTable::<ItemHandle, u8>::with_capacity(8);
+        let kept = table.insert(1).unwrap();
+        let removed = table.insert(2).unwrap();
+        table.remove(removed).unwrap();
+        let last = table.insert(3).unwrap();
+
+        assert_eq!(table.clear(), [kept, last]);
+        assert_eq!(table.len(), 0);
+        for handle in [kept, last] {
+            assert_eq!(
+                table.get(handle).unwrap_err().code(),
+                ErrorCode::StaleHandle
+            );
+        }
+        assert!(table.slot_mut(0).is_none());
+        let reused = table.insert(4).unwrap();
+        let (handle, value) = table.slot_mut(reused.slot()).unwrap();
+        assert_eq!((handle, *value), (reused, 4));
+    }
+}
@@ -1,49 +1,448 @@
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Instant;
 
-struct ModuleContext;
+#[macro_use]
+mod macros;
 
-#[derive(Clone, Copy)]
-struct ProtocolConfig;
-#[derive(Clone, Copy)]
-struct FutureHandle(u64);
+mod abi;
+mod async_imports;
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "module-cache")]
+mod cache;
+mod capabilities;
+mod context;
+mod datasets;
+mod dynamic;
+mod engine;
+mod executor;
+mod futures;
+mod guest;
+mod guest_bindings;
+mod handles;
+mod limits;
+mod linker;
+mod logging;
+mod manifest;
+mod memory;
+mod metrics;
+#[cfg(feature = "test-util")]
+mod mock;
+mod models;
+#[cfg(feature = "native-runtime")]
+mod native;
+mod pool;
+mod protocol;
+mod random;
+mod rate_limit;
+mod retry;
+mod runtime;
+mod sessions;
+mod stats;
+mod storage;
+#[cfg(test)]
+pub(crate) mod test_support;
+mod time;
+mod training;
+mod validation;
 
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
-#[non_exhaustive]
-#[must_use]
-#[repr(u32)]
-enum ErrorCode {
-    Success = 0,
-    NotFound = 6,
+pub use abi::{AbiVersion, GUEST_ABI_EXPORT};
+#[doc(hidden)]
+pub use async_imports::catch_unwind_async;
+pub use async_imports::BoxFuture;
+#[cfg(feature = "audit")]
+pub use audit::{AuditEntry, AuditSink, JsonLinesAuditSink, VecAuditSink};
+#[cfg(feature = "module-cache")]
+pub use cache::{ModuleCache, ModuleCacheError};
+pub use capabilities::{host_imports, Capabilities};
+pub use context::{
+    InterruptHandle, ModuleContext, ModuleContextBuilder, ModuleContextError, ModuleLease,
+    ShutdownPolicy,
+};
+#[cfg(feature = "datasets-fs")]
+pub use datasets::FileDatasets;
+pub use datasets::{
+    dataset_imports, DatasetApiHost, DatasetHandle, DatasetReader, DatasetSource, MemoryDatasets,
+};
+pub use dynamic::{DynArgs, DynHostFunction};
+pub use engine::{
+    Deprecation, HostCaller, HostFunc, HostResult, HostTrap, ImportAlias, ImportParams,
+    ImportValue, PortableImports, Runtime,
+};
+pub use executor::{JobExecutor, JobQueue, WorkerShutdown};
+pub use futures::{FutureCompleter, FutureState, FutureStatus, FutureTable, LiveFuture};
+pub use guest::{FromGuest, GuestPtr, GuestSlice, GuestStr};
+pub use limits::StoreLimiter;
+pub use linker::{
+    register_host_modules, HostLinker, ModuleRegistry, Overrides, RegisteredImport,
+    RegisteredImports, GET_LAST_ERROR, GET_LAST_ERROR_ALLOC, REMAINING_FUEL,
+};
+pub use logging::{logging_imports, LoggingApiHost};
+pub use manifest::{
+    ImportManifest, ImportParam, ImportReturn, ManifestImport, ManifestParam, ParamRole,
+};
+pub use memory::{
+    copy_to_guest_alloc, copy_to_guest_alloc_async, guest_memory, guest_memory_in, MemorySelector,
+    PlainOldData, WasmMemoryHandle, GUEST_ALLOC_EXPORT, GUEST_MEMORY_EXPORT,
+};
+pub use metrics::{metrics_imports, Metric, MetricValue, MetricsApiHost, MetricsRegistry};
+#[cfg(feature = "test-util")]
+pub use mock::{MockMLApi, RecordedTraining};
+pub use models::{model_imports, DownloadHandle, ModelApiHost, UploadHandle};
+#[cfg(feature = "native-runtime")]
+pub use native::{NativeInstance, NativeLinker, NativeValue};
+pub use pool::{
+    InstancePool, InstancePoolBuilder, PoolError, PoolExhaustion, PoolReset, PooledInstance,
+};
+pub use protocol::{ProtocolConfig, ProtocolConfigV1, ProtocolConfigV2};
+pub use random::{random_imports, RandomApiHost};
+pub use rate_limit::RateLimit;
+pub use retry::RetryPolicy;
+pub use runtime::{HostRuntime, ReloadPolicy};
+pub use sessions::SessionHandle;
+pub use stats::{CallDurations, CallSite, CallStat, ImportName};
+#[cfg(feature = "storage-fs")]
+pub use storage::FileStorage;
+pub use storage::{storage_imports, MemoryStorage, StorageApiHost, StorageBackend};
+pub use time::{time_imports, ClockSource, SystemClock, TimeApiHost};
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing as __tracing;
+pub use training::{
+    EchoBackend, TrainingJob, TrainingMetrics, TrainingProgress, TrainingRequest, TrainingStart,
+};
+pub use validation::{
+    diagnose_module, validate_module, FuncSignature, ImportDiagnostic, ImportStatus,
+};
+#[cfg(feature = "derive")]
+pub use wasm_shim_derive::{wasm_shim, PlainOldData};
+#[doc(hidden)]
+pub use wasmtime as __wasmtime;
+
+slot_handle! {
+    /// Handle of a training started by the guest. Handles are never `0`, so
+    /// the guest can use that as "no handle".
+    ///
+    /// To the guest a handle is an opaque `u64`. The host packs the
+    /// [`FutureTable`] slot into the low 32 bits and the slot's generation,
+    /// which is never `0`, into the high 32 bits.
+    pub struct FutureHandle = "future";
+}
+
+impl FutureHandle {
+    /// A handle with the given [`raw`](Self::raw) value, such as one for
+    /// [`MockMLApi`] to return.
+    #[cfg(feature = "test-util")]
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+}
+
+error_codes! {
+    /// Error codes returned to the guest from host calls.
+    ///
+    /// The discriminants are part of the guest ABI and must never change once
+    /// they have shipped; new codes get new values. Codes any host call may
+    /// return are in [`error_code_ranges::COMMON`], codes only one host module
+    /// returns in the range it reserves with [`HostModule::error_code_range`].
+    #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+    #[non_exhaustive]
+    #[must_use]
+    #[repr(u32)]
+    pub enum ErrorCode {
+        /// The call succeeded.
+        Success = 0,
+        /// An argument was out of range or otherwise malformed.
+        InvalidArgument = 1,
+        /// A guest string was not valid UTF-8.
+        InvalidUtf8 = 2,
+        /// A guest pointer or length reached outside of guest memory.
+        OutOfBounds = 3,
+        /// The guest is not allowed to perform the call.
+        PermissionDenied = 4,
+        /// The operation did not complete in time.
+        TimedOut = 5,
+        /// The requested item doesn't exist.
+        NotFound = 6,
+        /// Something went wrong on the host side.
+        Internal = 7,
+        /// The host module backing the call wasn't added to the `ModuleContext`.
+        ModuleNotRegistered = 8,
+        /// A host side limit was reached, the call may succeed once the guest has
+        /// released resources.
+        Busy = 9,
+        /// The handle was freed, its slot may since have been reused.
+        StaleHandle = 10,
+        /// The guest called the import more often than its rate limit allows,
+        /// see [`RateLimit`].
+        RateLimited = 11,
+        /// The guest trapped while the host called into it, other than in the
+        /// ways covered by the codes below.
+        GuestTrapped = 12,
+        /// The guest reached an `unreachable` instruction while the host called
+        /// into it.
+        GuestUnreachable = 13,
+        /// The guest overflowed its stack while the host called into it.
+        StackOverflow = 14,
+        /// The guest was interrupted while the host called into it, usually
+        /// because its call was cancelled.
+        Interrupted = 15,
+    }
+}
+
+impl ErrorCode {
+    /// Human readable name of the code.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::InvalidArgument => "invalid argument",
+            Self::InvalidUtf8 => "invalid UTF-8",
+            Self::OutOfBounds => "out of bounds",
+            Self::PermissionDenied => "permission denied",
+            Self::TimedOut => "timed out",
+            Self::NotFound => "not found",
+            Self::Internal => "internal error",
+            Self::ModuleNotRegistered => "host module not registered",
+            Self::Busy => "busy",
+            Self::StaleHandle => "stale handle",
+            Self::RateLimited => "rate limited",
+            Self::GuestTrapped => "guest trapped",
+            Self::GuestUnreachable => "guest reached unreachable code",
+            Self::StackOverflow => "guest stack overflow",
+            Self::Interrupted => "guest interrupted",
+        }
+    }
+
+    /// Graceful errors are part of the normal control flow of some APIs
+    /// (e.g. probing for something that may not exist) and are not logged.
+    /// Interrupts are how guests get cancelled, so they are graceful too.
+    pub fn is_graceful(self) -> bool {
+        matches!(self, Self::NotFound | Self::RateLimited | Self::Interrupted)
+    }
+
+    /// Whether any host module may return the code, rather than only the one
+    /// that reserved it.
+    pub fn is_common(self) -> bool {
+        error_code_ranges::COMMON.contains(&(self as u32))
+    }
+}
+
+/// Ranges of raw [`ErrorCode`] values, so codes added for one host module
+/// never collide with those of another. Modules reserve theirs with
+/// [`HostModule::error_code_range`], which [`register_host_modules`] checks.
+pub mod error_code_ranges {
+    use std::ops::Range;
+
+    /// Codes any host call may return.
+    pub const COMMON: Range<u32> = 0..1000;
+    pub const ML: Range<u32> = 1000..2000;
+    pub const STORAGE: Range<u32> = 2000..3000;
+    pub const LOGGING: Range<u32> = 3000..4000;
+    pub const TIME: Range<u32> = 4000..5000;
+    pub const RANDOM: Range<u32> = 5000..6000;
+    pub const METRICS: Range<u32> = 6000..7000;
+    pub const DATASETS: Range<u32> = 7000..8000;
+    pub const MODELS: Range<u32> = 8000..9000;
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("unknown error code {0}")]
+pub struct UnknownErrorCode(pub u32);
+
+impl TryFrom<u32> for ErrorCode {
+    type Error = UnknownErrorCode;
+
+    fn try_from(raw: u32) -> Result<Self, Self::Error> {
+        Self::from_raw(raw).ok_or(UnknownErrorCode(raw))
+    }
+}
+
+/// Why a string isn't an [`ErrorCode`]. Codes added by newer hosts parse as
+/// [`Unknown`](Self::Unknown) or [`UnknownName`](Self::UnknownName), so tools
+/// can still tell them apart from malformed input.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorCodeError {
+    /// A number, or `Name(number)`, this host has no code for.
+    #[error(transparent)]
+    Unknown(#[from] UnknownErrorCode),
+    /// A name this host has no code for.
+    #[error("unknown error code name `{0}`")]
+    UnknownName(String),
+    /// `Name(number)` with the number of another code.
+    #[error("error code {} is {}, not {found}", code.name(), *code as u32)]
+    Mismatch { code: ErrorCode, found: u32 },
+    #[error("`{0}` is not an error code")]
+    Invalid(String),
+}
+
+/// `NotFound(6)`, the form host call errors are logged in.
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.name(), *self as u32)
+    }
 }
 
-trait HostModule<T = Self> {
+/// Parses `NotFound(6)`, `NotFound` or `6`.
+impl FromStr for ErrorCode {
+    type Err = ParseErrorCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseErrorCodeError::Invalid(s.to_owned());
+        if let Ok(raw) = s.parse::<u32>() {
+            return Ok(Self::try_from(raw)?);
+        }
+        let (name, raw) = match s.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            Some((name, raw)) => (name, Some(raw.parse::<u32>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+        let code = Self::ALL.iter().copied().find(|code| code.name() == name);
+        match (code, raw) {
+            (Some(code), None) => Ok(code),
+            (Some(code), Some(raw)) if code as u32 == raw => Ok(code),
+            (Some(code), Some(found)) => Err(ParseErrorCodeError::Mismatch { code, found }),
+            (None, Some(raw)) => Err(UnknownErrorCode(raw).into()),
+            (None, None) => Err(ParseErrorCodeError::UnknownName(name.to_owned())),
+        }
+    }
+}
+
+pub trait HostModule<T = Self> {
+    /// The module's state. [`host_import!`] functions only call this while
+    /// holding the module's [`ModuleLease`], so a guest calling back into the
+    /// module from a callback of one of its calls gets [`ErrorCode::Busy`].
     fn get(host_context: &mut ModuleContext) -> Result<&mut T, ApiError>;
 
     fn name() -> &'static str;
 
-    fn log_call(function: &'static str, res: Result<(), ApiError>) -> Result<u32, wasmtime::Trap> {
-        match res {
-            Ok(_) => Ok(ErrorCode::Success as u32),
-            Err(err) => {
-                // skip NotFound code as that is used for graceful errors by some APIs
-                // maybe should be moved to other mechanism?
-                if err.code() != ErrorCode::NotFound {
-                    let err_msg = format!(
-                        "{} \"{}\" failed: {}",
-                        Self::name(),
-                        function,
-                        err.display()
-                    );
-                    log::warn!("{}", err_msg);
-                }
-                Ok(err.code() as u32)
+    /// `log` target used for this module's host call diagnostics, so
+    /// embedders can set per-module log levels.
+    fn log_target() -> &'static str {
+        Self::name()
+    }
+
+    /// Raw [`ErrorCode`] values reserved for this module's own codes, see
+    /// [`error_code_ranges`]. `None` for modules only returning common codes.
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        None
+    }
+
+    /// The codes this module returns that aren't common, each inside its
+    /// [`error_code_range`](Self::error_code_range).
+    fn error_codes() -> &'static [ErrorCode] {
+        &[]
+    }
+
+    /// Called before every host call of this module, before any argument is
+    /// decoded. Rejecting the call returns the error to the guest without
+    /// running the function.
+    fn check_call(_host_context: &ModuleContext, _site: CallSite) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    /// Called for the module's state by [`ModuleContext::shutdown`], to
+    /// resolve or cancel outstanding work. Returns the futures that had to be
+    /// cancelled because they were still unresolved.
+    fn shutdown(_state: &mut T, _policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        Vec::new()
+    }
+
+    /// Futures of the module's state the guest hasn't freed yet, reported as
+    /// leaked by [`ModuleContext::finish_session`].
+    fn live_futures(_state: &T) -> Vec<LiveFuture> {
+        Vec::new()
+    }
+
+    /// Called for the module's state by [`ModuleContext::reset`] after the
+    /// shutdown, to forget what the guest session left behind, such as open
+    /// handles, so the next session of a pooled instance starts clean.
+    /// Configuration such as backends is kept.
+    fn reset(_state: &mut T) {}
+
+    /// Turns the result of a host call into the code returned to the guest,
+    /// recording it in the call stats and as the instance's last error.
+    /// [`Severity::Fatal`] errors trap the guest instead, with a [`HostTrap`]
+    /// the [`Runtime`] raises.
+    ///
+    /// Failures are logged to [`log_target`](Self::log_target), or with the
+    /// `tracing` feature recorded as events with an `error_code` field in
+    /// the span of the import. Without the default `host-logging` feature
+    /// nothing is logged, only the call stats are recorded.
+    fn log_call(
+        host_context: &mut ModuleContext,
+        site: CallSite,
+        res: Result<(), ApiError>,
+    ) -> HostResult {
+        let function = site.function();
+        host_context.call_counters.record(site, res.is_err());
+        let err = match res {
+            Ok(()) => {
+                host_context.last_error = None;
+                #[cfg(all(feature = "host-call-trace", not(feature = "tracing")))]
+                log::trace!(
+                    target: Self::log_target(),
+                    "host call: module={} function={}",
+                    Self::name(),
+                    function
+                );
+                return Ok(ErrorCode::Success as u32);
             }
+            Err(err) => err,
+        };
+        let code = err.code();
+        if err.severity() == Severity::Fatal {
+            let message = format!(
+                "fatal host call error: module={} function={} code={} error={}",
+                Self::name(),
+                function,
+                code,
+                err.display()
+            );
+            #[cfg(all(feature = "host-logging", not(feature = "tracing")))]
+            log::error!(target: Self::log_target(), "{}", message);
+            #[cfg(all(feature = "host-logging", feature = "tracing"))]
+            tracing::error!(error_code = code as u32, "{}", err.display());
+            host_context.fatal_error = Some(err.clone());
+            host_context.last_error = Some(err);
+            return Err(HostTrap::new(message));
+        }
+        debug_assert_ne!(
+            code,
+            ErrorCode::Success,
+            "`{}` reported an error with the Success code",
+            function
+        );
+        #[cfg(all(feature = "host-logging", not(feature = "tracing")))]
+        if !code.is_graceful() {
+            log::warn!(
+                target: Self::log_target(),
+                "host call failed: module={} function={} code={} error={}",
+                Self::name(),
+                function,
+                code,
+                err.display()
+            );
+        }
+        #[cfg(all(feature = "host-logging", feature = "tracing"))]
+        if code.is_graceful() {
+            tracing::debug!(error_code = code as u32, "{}", err.display());
+        } else {
+            tracing::warn!(error_code = code as u32, "{}", err.display());
         }
+        host_context.last_error = Some(err);
+        Ok(code as u32)
     }
 }
 
 #[derive(Debug, Clone)]
-enum ApiErrorMessage {
+pub enum ApiErrorMessage {
     None,
     Static(&'static str),
     Dynamic(String),
@@ -61,143 +460,2519 @@ impl From<&'static str> for ApiErrorMessage {
 
 impl From<String> for ApiErrorMessage {
     fn from(s: String) -> Self {
-        Self::Dynamic(s)
+        if s.is_empty() {
+            Self::None
+        } else {
+            Self::Dynamic(s)
+        }
+    }
+}
+
+impl From<Cow<'static, str>> for ApiErrorMessage {
+    fn from(s: Cow<'static, str>) -> Self {
+        match s {
+            Cow::Borrowed(s) => s.into(),
+            Cow::Owned(s) => s.into(),
+        }
     }
 }
 
-#[derive(Clone)]
-enum ApiError {}
+impl ApiErrorMessage {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::None => "",
+            Self::Static(msg) => msg,
+            Self::Dynamic(msg) => msg,
+        }
+    }
+
+    pub fn into_cow(self) -> Cow<'static, str> {
+        match self {
+            Self::None => Cow::Borrowed(""),
+            Self::Static(msg) => Cow::Borrowed(msg),
+            Self::Dynamic(msg) => Cow::Owned(msg),
+        }
+    }
+}
+
+/// How a failed host call affects the guest.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Severity {
+    /// The error code is returned to the guest, which can handle it.
+    Recoverable,
+    /// The host can't serve the guest reliably any more, e.g. because its
+    /// memory export is gone or host state is corrupted. The guest is trapped.
+    Fatal,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    code: ErrorCode,
+    severity: Severity,
+    message: ApiErrorMessage,
+    /// Context added on top of `message`, innermost first.
+    context: Vec<ApiErrorMessage>,
+    /// Where a non-graceful error was created, see [`ApiError::backtrace`].
+    #[cfg(any(debug_assertions, feature = "debug-errors"))]
+    backtrace: Option<std::sync::Arc<Backtrace>>,
+}
 
 impl ApiError {
-    fn display(&self) -> DisplayableApiError<'_> {
-        DisplayableApiError(self)
+    pub fn new(code: ErrorCode, msg: impl Into<ApiErrorMessage>) -> Self {
+        Self {
+            code,
+            severity: Severity::Recoverable,
+            message: msg.into(),
+            context: Vec::new(),
+            #[cfg(any(debug_assertions, feature = "debug-errors"))]
+            backtrace: if code.is_graceful() {
+                None
+            } else {
+                capture_backtrace()
+            },
+        }
+    }
+
+    /// An error that traps the guest instead of being returned to it.
+    pub fn fatal(code: ErrorCode, msg: impl Into<ApiErrorMessage>) -> Self {
+        Self {
+            severity: Severity::Fatal,
+            ..Self::new(code, msg)
+        }
+    }
+
+    /// Wraps the error with an outer message, displayed as `outer: inner`.
+    pub fn context(mut self, msg: impl Into<ApiErrorMessage>) -> Self {
+        self.context.push(msg.into());
+        self
+    }
+
+    pub fn not_found(msg: impl Into<ApiErrorMessage>) -> Self {
+        Self::new(ErrorCode::NotFound, msg)
     }
-    fn code(&self) -> ErrorCode {
-        todo!()
+
+    pub fn internal(msg: impl Into<ApiErrorMessage>) -> Self {
+        Self::new(ErrorCode::Internal, msg)
+    }
+
+    /// Error of a call the host made into the guest, e.g. to its allocator,
+    /// with the reason of the trap as the message. Errors that aren't guest
+    /// traps, such as traps raised by host functions themselves or
+    /// mismatched arguments, are `Internal`.
+    pub fn from_guest_call(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<wasmtime::Trap>() {
+            Some(&trap) => Self::from(trap),
+            None => Self::internal(trap_reason(&err)),
+        }
+    }
+
+    pub fn display(&self) -> DisplayableApiError<'_> {
+        DisplayableApiError {
+            err: self,
+            backtrace: true,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Where the error was created. Captured for non-graceful codes with the
+    /// `debug-errors` feature, or in debug builds if `RUST_BACKTRACE` is set.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        #[cfg(any(debug_assertions, feature = "debug-errors"))]
+        return self.backtrace.as_deref();
+        #[cfg(not(any(debug_assertions, feature = "debug-errors")))]
+        None
+    }
+
+    /// The error as reported to the guest, which never includes host
+    /// backtraces.
+    pub(crate) fn guest_message(&self) -> String {
+        DisplayableApiError {
+            err: self,
+            backtrace: false,
+        }
+        .to_string()
+    }
+}
+
+#[cfg(any(debug_assertions, feature = "debug-errors"))]
+fn capture_backtrace() -> Option<std::sync::Arc<Backtrace>> {
+    #[cfg(feature = "debug-errors")]
+    let backtrace = Backtrace::force_capture();
+    // Only captures if `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` is set
+    #[cfg(not(feature = "debug-errors"))]
+    let backtrace = Backtrace::capture();
+    match backtrace.status() {
+        std::backtrace::BacktraceStatus::Captured => Some(std::sync::Arc::new(backtrace)),
+        _ => None,
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        // `{:#}` includes the whole cause chain
+        Self::internal(format!("{:#}", err))
+    }
+}
+
+impl From<wasmtime::Trap> for ApiError {
+    /// Error of a call the host made into the guest that trapped with
+    /// `trap`, with the reason of the trap as the message.
+    fn from(trap: wasmtime::Trap) -> Self {
+        use wasmtime::Trap;
+        let code = match trap {
+            Trap::UnreachableCodeReached => ErrorCode::GuestUnreachable,
+            Trap::MemoryOutOfBounds => ErrorCode::OutOfBounds,
+            Trap::StackOverflow => ErrorCode::StackOverflow,
+            Trap::Interrupt => ErrorCode::Interrupted,
+            // Fuel is budgeted per call by `ModuleContext::call`, which
+            // reports running out as `ModuleError::OutOfFuel`
+            Trap::OutOfFuel => ErrorCode::GuestTrapped,
+            _ => ErrorCode::GuestTrapped,
+        };
+        Self::new(code, ApiErrorMessage::Dynamic(trap.to_string()))
     }
 }
 
-struct DisplayableApiError<'a>(&'a ApiError);
+impl From<std::io::Error> for ApiError {
+    fn from(err: std::io::Error) -> Self {
+        let code = match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            _ => ErrorCode::Internal,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+/// The code and messages of an [`ApiError`], followed by its
+/// [`backtrace`](ApiError::backtrace) if it has one.
+pub struct DisplayableApiError<'a> {
+    err: &'a ApiError,
+    backtrace: bool,
+}
 
 impl<'a> Display for DisplayableApiError<'a> {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = self.err.code;
+        write!(f, "{}", code)?;
+        for msg in self.err.context.iter().rev().chain(Some(&self.err.message)) {
+            if !matches!(msg, ApiErrorMessage::None) {
+                write!(f, ": {}", msg.as_str())?;
+            }
+        }
+        match self.err.backtrace() {
+            Some(backtrace) if self.backtrace => write!(f, "\nbacktrace:\n{}", backtrace),
+            _ => Ok(()),
+        }
     }
 }
 
+/// Failure of creating or calling a guest. The `Display` texts are stable,
+/// embedders may alert on them.
 #[derive(thiserror::Error, Debug)]
-enum ModuleError {
+pub enum ModuleError {
     #[error("Failed to create a module instance")]
     Instantiation(#[from] InstantiationError),
+    /// The guest used up the fuel budget of the call, see
+    /// [`ModuleContextBuilder::with_fuel`].
+    #[error("Guest ran out of fuel after {budget} units")]
+    OutOfFuel { budget: u64 },
+    /// The guest was stopped through
+    /// [`ModuleContext::interrupt_handle`] while running `function`.
+    #[error("Guest was interrupted in `{function}`")]
+    Interrupted { function: String },
+    /// A host call of the guest failed with a fatal error or panicked, which
+    /// trapped the guest.
+    #[error("Host call failed fatally: {}", .0.display())]
+    HostError(ApiError),
+    /// The guest trapped while running the export `function`, e.g. on an
+    /// `unreachable` instruction.
+    #[error("Guest trapped in `{function}`: {message}")]
+    Trap {
+        function: String,
+        /// The reason of the trap, without the backtrace of `trap`.
+        message: String,
+        /// The trap as returned by wasmtime, a [`wasmtime::Trap`] code for
+        /// traps of the guest itself.
+        #[source]
+        trap: WasmTrap,
+    },
+    /// The guest has no export `function` of the type it was called with.
+    #[error("Guest doesn't export a function `{function}` of the expected type")]
+    MissingExport {
+        function: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to compile the guest module")]
+    Compile(#[source] anyhow::Error),
+}
+
+impl ModuleError {
+    /// Classifies a trap of the guest export `function`. Without the
+    /// [`ModuleContext`] fuel exhaustion and host errors can't be told apart
+    /// from other traps, [`ModuleContext::call`] does.
+    pub fn from_trap(function: impl Into<String>, trap: WasmTrap) -> Self {
+        let function = function.into();
+        if trap.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::Interrupt) {
+            return Self::Interrupted { function };
+        }
+        let message = trap_reason(&trap);
+        Self::Trap {
+            function,
+            message,
+            trap,
+        }
+    }
+
+    /// [`ModuleError::from_trap`] with the function the guest was entered
+    /// through, from the wasm backtrace of the trap.
+    pub fn from_guest_trap(trap: WasmTrap) -> Self {
+        let frame = trap
+            .downcast_ref::<wasmtime::WasmBacktrace>()
+            .and_then(|backtrace| backtrace.frames().last());
+        let function = match frame {
+            Some(frame) => match frame.func_name() {
+                Some(name) => name.to_owned(),
+                None => format!("<wasm function {}>", frame.func_index()),
+            },
+            None => "<unknown>".to_owned(),
+        };
+        Self::from_trap(function, trap)
+    }
+}
+
+impl From<wasmtime::Trap> for ModuleError {
+    /// [`ModuleError::from_trap`] of a bare trap code, which doesn't tell
+    /// which export the guest was running.
+    fn from(trap: wasmtime::Trap) -> Self {
+        Self::from_trap("<unknown>", WasmTrap::from(trap))
+    }
+}
+
+/// The reason of a trap without the wasm backtrace wasmtime attaches as its
+/// outermost context: the [`wasmtime::Trap`] code of a guest trap, or the
+/// cause chain of the error a host function trapped with.
+fn trap_reason(trap: &WasmTrap) -> String {
+    if let Some(code) = trap.downcast_ref::<wasmtime::Trap>() {
+        return code.to_string();
+    }
+    let skip = usize::from(trap.downcast_ref::<wasmtime::WasmBacktrace>().is_some());
+    let causes: Vec<_> = trap.chain().skip(skip).map(ToString::to_string).collect();
+    causes.join(": ")
 }
 
 #[derive(thiserror::Error, Debug)]
-enum InstantiationError {
+pub enum InstantiationError {
     #[error("Failed Import")]
     Import(#[source] anyhow::Error),
+    /// `name` was registered more than once, `module` is the host module that
+    /// registered it first.
+    #[error("Import `{name}` is already registered by host module `{module}`")]
+    DuplicateImport { module: &'static str, name: String },
+    #[error("Failed to register the imports of host module `{module}`")]
+    HostModule {
+        module: &'static str,
+        #[source]
+        source: Box<InstantiationError>,
+    },
+    /// The guest module `guest` imports a function no host module provides.
+    #[error(
+        "Guest module `{guest}` imports `{namespace}::{name}`, which the host doesn't provide"
+    )]
+    MissingImport {
+        guest: String,
+        namespace: String,
+        name: String,
+    },
+    /// The guest module `guest` imports the host function `name` with a
+    /// signature other than the one it is registered with.
+    #[error(
+        "Guest module `{guest}` imports `{name}` as `{found}`, the host provides `{expected}`"
+    )]
+    SignatureMismatch {
+        guest: String,
+        name: String,
+        expected: FuncSignature,
+        found: FuncSignature,
+    },
+    /// The guest module `guest` imports host functions taking guest pointers,
+    /// but doesn't export the memory `name` they access.
+    #[error(
+        "Guest module `{guest}` doesn't export the memory `{name}` host imports access, its memories are {}",
+        memory::export_list(exports.iter())
+    )]
+    MissingMemoryExport {
+        guest: String,
+        name: String,
+        /// Memories the guest does export.
+        exports: Vec<String>,
+    },
+    /// The guest module `guest` has imports the host doesn't provide, see
+    /// [`HostLinker::validate_module`].
+    #[error(
+        "Guest module `{guest}` has {} unsatisfied imports:{}",
+        diagnostics.len(),
+        validation::list(diagnostics)
+    )]
+    InvalidImports {
+        guest: String,
+        diagnostics: Vec<ImportDiagnostic>,
+    },
+    /// The error codes reserved by host module `module` overlap the common
+    /// codes, or those of host module `other`.
+    #[error(
+        "Error codes {range:?} of host module `{module}` overlap {}",
+        other.map_or("the common codes".to_owned(), |other| format!("those of host module `{}`", other))
+    )]
+    ErrorCodeRangeOverlap {
+        module: &'static str,
+        other: Option<&'static str>,
+        range: std::ops::Range<u32>,
+    },
+    /// Host module `module` returns `code`, which is neither common nor in the
+    /// range it reserved.
+    #[error(
+        "Host module `{module}` returns error code {code} outside of its reserved range {range:?}"
+    )]
+    ErrorCodeOutOfRange {
+        module: &'static str,
+        code: ErrorCode,
+        range: Option<std::ops::Range<u32>>,
+    },
+    /// The guest was built for a major ABI version other than the host's,
+    /// see [`HostRuntime::call_entry_point`].
+    #[error("Guest module was built for ABI version {guest}, which the host's {host} is incompatible with")]
+    AbiMismatch { host: AbiVersion, guest: AbiVersion },
 }
 
-type WasmLinker = wasmtime::Linker<ModuleContext>;
+pub type WasmLinker = wasmtime::Linker<ModuleContext>;
+
+/// The error wasmtime traps guests with and returns from guest calls, the
+/// [`Shim::WasmTrap`] of modules registered with a [`HostLinker`]. Traps of
+/// the guest itself carry a [`wasmtime::Trap`] code, see
+/// [`ApiError::from_guest_call`].
+pub type WasmTrap = anyhow::Error;
+
+import_names!(pub mod ml_imports = "ml" {
+    START_TRAINING = "start_training",
+    START_TRAINING_V1 = "start_training_v1",
+    START_TRAINING_JSON = "start_training_json",
+    POLL_FUTURE = "poll_future",
+    CANCEL_TRAINING = "cancel_training",
+    FREE_FUTURE = "free_future",
+    GET_FUTURE_RESULT = "get_future_result",
+    LIST_ACTIVE_FUTURES = "list_active_futures",
+    RUN_INFERENCE = "run_inference",
+    GET_TRAINING_METRICS = "get_training_metrics",
+    CREATE_SESSION = "create_session",
+    DESTROY_SESSION = "destroy_session",
+    START_TRAINING_IN_SESSION = "start_training_in_session",
+    POLL_FUTURE_IN_SESSION = "poll_future_in_session",
+});
+
+/// Runs the trainings started through [`MLApiHost`].
+pub trait TrainingBackend: Send {
+    /// Starts the training the guest will refer to as `handle`. Backends that
+    /// train synchronously can return [`FutureState::Completed`] right away,
+    /// others a [`TrainingStart::Job`] to run in the background.
+    fn start(
+        &mut self,
+        handle: FutureHandle,
+        req: TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
+    ) -> Result<TrainingStart, ApiError>;
+
+    /// Async variant of [`start`](Self::start) used with async linkers, for
+    /// backends that have to wait for a job to be enqueued. Defaults to
+    /// `start`.
+    fn start_async<'a>(
+        &'a mut self,
+        handle: FutureHandle,
+        req: TrainingRequest<'a>,
+        protocol: &'a ProtocolConfig,
+    ) -> BoxFuture<'a, Result<TrainingStart, ApiError>> {
+        Box::pin(std::future::ready(self.start(handle, req, protocol)))
+    }
+
+    /// Stops a pending training. The future is reported as cancelled to the
+    /// guest either way.
+    fn cancel(&mut self, _handle: FutureHandle) {}
+
+    /// Runs `model` on `input`, writing the result to the start of `output`.
+    /// Returns the number of elements in the result, which is larger than
+    /// `output` if it didn't fit, in which case nothing is written. Defaults
+    /// to not supporting inference.
+    fn infer(
+        &mut self,
+        model: &str,
+        _input: &[f32],
+        _output: &mut [f32],
+    ) -> Result<usize, ApiError> {
+        Err(ApiError::not_found(format!(
+            "model `{}` isn't available for inference",
+            model
+        )))
+    }
+}
+
+/// Host module running trainings for the guest on a [`TrainingBackend`].
+///
+/// Besides the default session, guests can create further sessions with
+/// `create_session`, each with its own default [`ProtocolConfig`] and its own
+/// trainings, which are only visible to `poll_future_in_session` of the same
+/// session and are cancelled and freed along with it by `destroy_session`.
+/// Trainings started with the plain `start_training` import are part of the
+/// default session, which imports taking a session refer to as `0`. The
+/// plain `poll_future`, `cancel_training`, `free_future` and
+/// `list_active_futures` imports only see the default session's trainings.
+pub struct MLApiHost {
+    futures: FutureTable,
+    backend: retry::SharedBackend,
+    /// Queue of the [`ModuleContext`]'s executor, attached on the first call.
+    jobs: Option<JobQueue>,
+    /// Host call in progress, recorded as the origin of new futures.
+    call_site: Option<CallSite>,
+    /// The [`ModuleContext`]'s protocol defaults, attached on the first call.
+    protocol_defaults: ProtocolConfig,
+    /// Progress of every training in `futures`.
+    progress: HashMap<FutureHandle, TrainingProgress>,
+    sessions: sessions::SessionTable,
+    /// Session of every training in `futures` that isn't part of the
+    /// default session.
+    training_sessions: HashMap<FutureHandle, SessionHandle>,
+    session_shutdown: ShutdownPolicy,
+    /// The [`ModuleContext`]'s retry policy of `backend.start`, attached on
+    /// the first call.
+    retry_policy: RetryPolicy,
+}
+
+impl Default for MLApiHost {
+    fn default() -> Self {
+        Self {
+            futures: FutureTable::default(),
+            backend: retry::SharedBackend::default(),
+            jobs: None,
+            call_site: None,
+            protocol_defaults: ProtocolConfig::default(),
+            progress: HashMap::new(),
+            sessions: sessions::SessionTable::with_capacity(Self::DEFAULT_SESSION_LIMIT),
+            training_sessions: HashMap::new(),
+            session_shutdown: sessions::DEFAULT_SESSION_SHUTDOWN,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+}
+
+impl MLApiHost {
+    /// Without a backend trainings are accepted but stay pending forever.
+    pub fn with_backend(mut self, backend: impl TrainingBackend + 'static) -> Self {
+        self.backend = retry::SharedBackend::new(Box::new(backend));
+        self
+    }
+
+    /// Caps the number of trainings the guest can have outstanding at once,
+    /// see [`FutureTable`].
+    pub fn with_future_capacity(mut self, capacity: usize) -> Self {
+        self.futures = FutureTable::with_capacity(capacity);
+        self
+    }
+
+    pub fn futures(&self) -> &FutureTable {
+        &self.futures
+    }
+
+    pub fn futures_mut(&mut self) -> &mut FutureTable {
+        &mut self.futures
+    }
+
+    /// Protocol config of trainings started without one, the
+    /// [`ModuleContext::protocol_defaults`] of the instance.
+    pub fn protocol_defaults(&self) -> &ProtocolConfig {
+        &self.protocol_defaults
+    }
+
+    /// Applies finished and timed out trainings, cancelling the latter.
+    fn update(&mut self) {
+        let backend = &self.backend;
+        self.futures.update(|handle| backend.cancel(handle));
+    }
+
+    /// Starts tracking a training in `session`, with the deadline of its
+    /// protocol.
+    fn begin_start(
+        &mut self,
+        session: Option<SessionHandle>,
+        req: &TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
+    ) -> Result<FutureHandle, ApiError> {
+        if let Some(session) = session {
+            self.sessions.get_mut(session)?;
+        }
+        let handle = self
+            .futures
+            .insert_from(self.call_site, FutureState::Pending)?;
+        if let Some(timeout) = protocol.timeout() {
+            self.futures
+                .set_deadline(handle, Instant::now() + timeout)?;
+        }
+        self.progress
+            .insert(handle, TrainingProgress::new(req.epochs));
+        if let Some(session) = session {
+            self.training_sessions.insert(handle, session);
+        }
+        Ok(handle)
+    }
+
+    /// Records the initial state of a started training or submits its job,
+    /// forgetting the future if it couldn't be started.
+    fn finish_start(
+        &mut self,
+        handle: FutureHandle,
+        started: Result<TrainingStart, ApiError>,
+    ) -> Result<(), ApiError> {
+        let result = match started {
+            Ok(TrainingStart::State(state)) => {
+                *self.futures.get_mut(handle)? = state;
+                Ok(())
+            }
+            Ok(TrainingStart::Job(job)) => self.submit(handle, job),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.forget(handle);
+        }
+        result
+    }
+
+    /// Waits for pending trainings until the timeout of `policy`, then cancels
+    /// the ones still pending. Completions reported afterwards are dropped.
+    fn shutdown(&mut self, policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        self.update();
+        let pending = self.futures.pending().collect();
+        self.shutdown_trainings(policy, pending)
+    }
+
+    /// [`shutdown`](Self::shutdown) for the `pending` trainings only,
+    /// returning the ones that were cancelled at the timeout.
+    fn shutdown_trainings(
+        &mut self,
+        policy: ShutdownPolicy,
+        pending: Vec<FutureHandle>,
+    ) -> Vec<FutureHandle> {
+        if pending.is_empty() {
+            return pending;
+        }
+        let timeout = match policy {
+            ShutdownPolicy::WaitAll(timeout) => timeout,
+            ShutdownPolicy::CancelAll(timeout) => {
+                for &handle in &pending {
+                    self.backend.cancel(handle);
+                }
+                timeout
+            }
+        };
+        let is_pending =
+            |futures: &FutureTable, handle| matches!(futures.get(handle), Ok(FutureState::Pending));
+        let deadline = Instant::now() + timeout;
+        while pending
+            .iter()
+            .any(|&handle| is_pending(&self.futures, handle))
+            && self.futures.wait(deadline)
+        {
+            self.update();
+        }
+        let unresolved: Vec<_> = pending
+            .into_iter()
+            .filter(|&handle| is_pending(&self.futures, handle))
+            .collect();
+        for &handle in &unresolved {
+            if let Ok(state) = self.futures.get_mut(handle) {
+                *state = FutureState::Cancelled;
+            }
+            if let ShutdownPolicy::WaitAll(_) = policy {
+                self.backend.cancel(handle);
+            }
+        }
+        unresolved
+    }
+
+    /// Starts a training in `session`, see [`Shim::start_training_shim`].
+    pub fn start_training_in(
+        &mut self,
+        session: Option<SessionHandle>,
+        req: TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
+    ) -> Result<FutureHandle, ApiError> {
+        let handle = self.begin_start(session, &req, protocol)?;
+        let started = match self
+            .backend
+            .with(|backend| backend.start(handle, req, protocol))
+        {
+            Some(started) => started,
+            None => return Ok(handle),
+        };
+        match (started, &self.jobs) {
+            // Retried on the executor, the future stays pending until then
+            (Err(err), Some(jobs)) if self.retry_policy.next_attempt(1, &err).is_some() => {
+                retry::StartRetry {
+                    handle,
+                    request: training::OwnedTrainingRequest::new(&req),
+                    protocol: *protocol,
+                    policy: self.retry_policy,
+                    backend: self.backend.clone(),
+                    jobs: jobs.clone(),
+                    completer: self.futures.completer(),
+                    progress: self.progress.get(&handle).cloned().unwrap_or_default(),
+                    attempts: 1,
+                }
+                .start(err);
+            }
+            (started, _) => self.finish_start(handle, started)?,
+        }
+        Ok(handle)
+    }
 
-pub struct MLApiHost;
+    /// Async variant of [`start_training_in`](Self::start_training_in).
+    pub fn start_training_in_async<'s>(
+        &'s mut self,
+        session: Option<SessionHandle>,
+        req: TrainingRequest<'s>,
+        protocol: &'s ProtocolConfig,
+    ) -> BoxFuture<'s, Result<FutureHandle, ApiError>> {
+        Box::pin(async move {
+            let handle = self.begin_start(session, &req, protocol)?;
+            let policy = self.retry_policy;
+            let jobs = self.jobs.clone();
+            let shared = self.backend.clone();
+            let mut lent = shared.lend();
+            let started = match lent.get() {
+                Some(backend) => {
+                    let mut attempts = 0;
+                    Some(loop {
+                        attempts += 1;
+                        match backend.start_async(handle, req, protocol).await {
+                            Err(err) => match (policy.next_attempt(attempts, &err), &jobs) {
+                                (Some(wait), Some(jobs)) => jobs.sleep(wait).await,
+                                // Retrying right away would only hammer the
+                                // backend
+                                _ => break Err(policy.give_up(err, attempts)),
+                            },
+                            started => break started,
+                        }
+                    })
+                }
+                None => None,
+            };
+            drop(lent);
+            if let Some(started) = started {
+                self.finish_start(handle, started)?;
+            }
+            Ok(handle)
+        })
+    }
+
+    /// Polls a training of `session`, see [`Shim::poll_future_shim`].
+    pub fn poll_future_in(
+        &mut self,
+        session: Option<SessionHandle>,
+        handle: FutureHandle,
+    ) -> Result<FutureStatus, ApiError> {
+        self.update();
+        self.check_session(session, handle)?;
+        Ok(self.futures.get(handle)?.status())
+    }
+
+    fn submit(&mut self, handle: FutureHandle, job: TrainingJob) -> Result<(), ApiError> {
+        let jobs = self
+            .jobs
+            .as_ref()
+            .ok_or_else(|| ApiError::internal("`MLApiHost` has no job executor attached"))?;
+        let completer = self.futures.completer();
+        let progress = self.progress.get(&handle).cloned().unwrap_or_default();
+        jobs.submit(move || {
+            completer.complete(handle, training::run_training(handle, job, &progress))
+        })
+    }
+}
 
 impl HostModule for MLApiHost {
     fn name() -> &'static str {
-        todo!()
+        "ml_api"
     }
-    fn get(_host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
-        todo!()
+    fn log_target() -> &'static str {
+        "host::ml_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::ML)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        let call_site = host_context.current_call();
+        if host_context.module_mut::<Self>()?.jobs.is_none() {
+            let jobs = host_context.jobs().queue();
+            let protocol_defaults = *host_context.protocol_defaults();
+            let retry_policy = *host_context.retry_policy();
+            let host = host_context.module_mut::<Self>()?;
+            host.jobs = Some(jobs);
+            host.protocol_defaults = protocol_defaults;
+            host.retry_policy = retry_policy;
+        }
+        let host = host_context.module_mut::<Self>()?;
+        host.call_site = call_site;
+        Ok(host)
     }
-}
 
-struct WasmMemoryHandle<'a>(&'a mut [u8]);
+    fn shutdown(state: &mut Self, policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        MLApiHost::shutdown(state, policy)
+    }
 
-trait PlainOldData: 'static + Copy + Sized + Send + Sync {}
+    fn live_futures(state: &Self) -> Vec<LiveFuture> {
+        state.futures.live().collect()
+    }
 
-impl<T: 'static + Copy + Sized + Send + Sync> PlainOldData for T {}
+    fn reset(state: &mut Self) {
+        state.futures.clear();
+        state.progress.clear();
+        state.sessions.clear();
+        state.training_sessions.clear();
+    }
 
-trait Shim<'t> {
-    type Err;
+    fn check_call(host_context: &ModuleContext, site: CallSite) -> Result<(), ApiError> {
+        if site.function() == ml_imports::LIST_ACTIVE_FUTURES && !host_context.diagnostics_enabled()
+        {
+            return Err(ApiError::new(
+                ErrorCode::PermissionDenied,
+                "diagnostics are disabled for this instance",
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub trait Shim<'t> {
+    /// Shims a module doesn't implement fail with an `Internal`
+    /// [`ApiError`].
+    type Err: From<ApiError>;
     type Memory;
     type Context;
     type ImportTable;
     type ImportError;
     type WasmTrap;
 
-    fn namespace() -> (&'static str, &'static str) {
-        ("hello", "world")
+    /// The wasm module namespace the imports are registered under, and the
+    /// prefix of every import name, imports are named `{prefix}__{function}`
+    /// (see [`import_names!`]).
+    fn namespace() -> (&'static str, &'static str);
+
+    /// Additional names for imports, so that renamed or versioned imports
+    /// keep working for older guests.
+    fn aliases() -> &'static [ImportAlias] {
+        &[]
     }
 
     fn start_training_shim(
         &mut self,
-        _param1: &str,
-        _param2: u32,
-        _param3: &str,
-        _param4: &str,
-        _param5: u32,
-        _param6: &str,
-        _param7: &str,
-        _param8: u64,
+        _req: TrainingRequest<'_>,
         _protocol: &ProtocolConfig,
     ) -> Result<FutureHandle, Self::Err> {
-        todo!()
+        Err(ApiError::internal("start_training isn't implemented by this host module").into())
+    }
+
+    /// Async variant of [`start_training_shim`](Self::start_training_shim),
+    /// called by the imports of an async [`HostLinker`]. Defaults to the sync
+    /// shim.
+    fn start_training_shim_async<'s>(
+        &'s mut self,
+        req: TrainingRequest<'s>,
+        protocol: &'s ProtocolConfig,
+    ) -> BoxFuture<'s, Result<FutureHandle, Self::Err>>
+    where
+        Self::Err: Send + 's,
+    {
+        Box::pin(std::future::ready(self.start_training_shim(req, protocol)))
+    }
+
+    /// Reports the state of a future without consuming it.
+    fn poll_future_shim(&mut self, _handle: FutureHandle) -> Result<FutureStatus, Self::Err> {
+        Err(ApiError::internal("poll_future isn't implemented by this host module").into())
+    }
+
+    /// Cancels a pending training. Trainings that already finished are left
+    /// as they are.
+    fn cancel_training_shim(&mut self, _handle: FutureHandle) -> Result<(), Self::Err> {
+        Err(ApiError::internal("cancel_training isn't implemented by this host module").into())
+    }
+
+    /// The result payload of a completed training, in a backend defined
+    /// encoding. Failed trainings return their error.
+    fn future_result_shim(&mut self, _handle: FutureHandle) -> Result<&[u8], Self::Err> {
+        Err(ApiError::internal("get_future_result isn't implemented by this host module").into())
+    }
+
+    /// Handles of every future the guest hasn't freed yet, in allocation
+    /// order. Only reachable with diagnostics enabled.
+    fn active_futures_shim(&mut self) -> Result<Vec<FutureHandle>, Self::Err> {
+        Err(ApiError::internal("list_active_futures isn't implemented by this host module").into())
+    }
+
+    /// Releases a future, cancelling it first if it is still pending. The
+    /// handle is invalid afterwards.
+    fn free_future_shim(&mut self, _handle: FutureHandle) -> Result<(), Self::Err> {
+        Err(ApiError::internal("free_future isn't implemented by this host module").into())
+    }
+
+    /// Progress of a training, the last recorded one if it has finished.
+    fn get_training_metrics_shim(
+        &mut self,
+        _handle: FutureHandle,
+    ) -> Result<TrainingMetrics, Self::Err> {
+        Err(ApiError::internal("get_training_metrics isn't implemented by this host module").into())
+    }
+
+    /// Runs `model` on `input` synchronously, see [`TrainingBackend::infer`].
+    /// Returns the number of elements of the result, which only has been
+    /// written to `output` if it fits.
+    fn run_inference_shim(
+        &mut self,
+        _model: &str,
+        _input: &[f32],
+        _output: &mut [f32],
+    ) -> Result<u32, Self::Err> {
+        Err(ApiError::internal("run_inference isn't implemented by this host module").into())
     }
 
     fn imports(it: Self::ImportTable) -> Result<(), Self::ImportError>;
 }
 
+/// Registers the `start_training` imports with `$linker`, as async imports
+/// with `[async]`, starting trainings with the `$start` and `$start_in`
+/// shims and `.await`ing them if `await` is passed. The async and sync
+/// imports share their parameters and marshalling through this, so that
+/// their ABIs can't drift apart.
+macro_rules! start_training_imports {
+    ($linker:ident, [$($async:tt)?] $start:ident, $start_in:ident $(, $await:tt)?) => {
+        // Guests passing a null protocol config train with the instance's
+        // defaults
+        host_import!($linker, MLApiHost, ml_imports::START_TRAINING, (
+            model_name: str,
+            epochs: u32,
+            dataset_uri: str,
+            checkpoint_path: str,
+            eval_interval: u32,
+            optimizer: str,
+            run_name: str,
+            seed: u64,
+            protocol: Option<&ProtocolConfig>,
+            output: *mut FutureHandle,
+        ) => $($async)? |host| {
+            let req = TrainingRequest::from_import_args(
+                model_name,
+                epochs,
+                dataset_uri,
+                checkpoint_path,
+                eval_interval,
+                optimizer,
+                run_name,
+                seed,
+            )?;
+            let protocol = protocol.unwrap_or(host.protocol_defaults);
+            host.$start(req, &protocol)$(.$await)?
+        })?;
+        // Same as `start_training`, in a session created with
+        // `create_session` or `0` for the default session. Guests passing a
+        // null protocol config train with the session's defaults
+        host_import!($linker, MLApiHost, ml_imports::START_TRAINING_IN_SESSION, (
+            session: u64,
+            model_name: str,
+            epochs: u32,
+            dataset_uri: str,
+            checkpoint_path: str,
+            eval_interval: u32,
+            optimizer: str,
+            run_name: str,
+            seed: u64,
+            protocol: Option<&ProtocolConfig>,
+            output: *mut FutureHandle,
+        ) => $($async)? |host| {
+            let req = TrainingRequest::from_import_args(
+                model_name,
+                epochs,
+                dataset_uri,
+                checkpoint_path,
+                eval_interval,
+                optimizer,
+                run_name,
+                seed,
+            )?;
+            let session = SessionHandle::from_guest(session);
+            let protocol = match protocol {
+                Some(protocol) => protocol,
+                None => host.session_protocol(session)?,
+            };
+            host.$start_in(session, req, &protocol)$(.$await)?
+        })?;
+        // Same as `start_training`, for guests passing the config as JSON
+        host_import!($linker, MLApiHost, ml_imports::START_TRAINING_JSON, (
+            model_name: str,
+            epochs: u32,
+            dataset_uri: str,
+            checkpoint_path: str,
+            eval_interval: u32,
+            optimizer: str,
+            run_name: str,
+            seed: u64,
+            protocol_json: str,
+            output: *mut FutureHandle,
+        ) => $($async)? |host| {
+            let req = TrainingRequest::from_import_args(
+                model_name,
+                epochs,
+                dataset_uri,
+                checkpoint_path,
+                eval_interval,
+                optimizer,
+                run_name,
+                seed,
+            )?;
+            let protocol = ProtocolConfig::from_json(protocol_json)?;
+            host.$start(req, &protocol)$(.$await)?
+        })?;
+    };
+}
+
 impl<'t> Shim<'t> for MLApiHost {
     type Err = ApiError;
     type Memory = WasmMemoryHandle<'t>;
     type Context = ModuleContext;
-    type ImportTable = *mut WasmLinker;
+    type ImportTable = &'t mut HostLinker;
     type ImportError = InstantiationError;
-    type WasmTrap = wasmtime::Trap;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", ml_imports::PREFIX)
+    }
+
+    fn aliases() -> &'static [ImportAlias] {
+        const ALIASES: &[ImportAlias] = &[ImportAlias::new(
+            ml_imports::START_TRAINING_V1,
+            ml_imports::START_TRAINING,
+        )];
+        ALIASES
+    }
 
     fn start_training_shim(
         &mut self,
-        _param1: &str,
-        _param2: u32,
-        _param3: &str,
-        _param4: &str,
-        _param5: u32,
-        _param6: &str,
-        _param7: &str,
-        _param8: u64,
-        _protocol: &ProtocolConfig,
+        req: TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
     ) -> Result<FutureHandle, Self::Err> {
-        todo!()
+        self.start_training_in(None, req, protocol)
+    }
+
+    fn start_training_shim_async<'s>(
+        &'s mut self,
+        req: TrainingRequest<'s>,
+        protocol: &'s ProtocolConfig,
+    ) -> BoxFuture<'s, Result<FutureHandle, Self::Err>>
+    where
+        Self::Err: Send + 's,
+    {
+        self.start_training_in_async(None, req, protocol)
+    }
+
+    fn cancel_training_shim(&mut self, handle: FutureHandle) -> Result<(), Self::Err> {
+        self.update();
+        self.check_session(None, handle)?;
+        let state = self.futures.get_mut(handle)?;
+        if let FutureState::Pending = state {
+            *state = FutureState::Cancelled;
+            self.backend.cancel(handle);
+        }
+        Ok(())
+    }
+
+    fn future_result_shim(&mut self, handle: FutureHandle) -> Result<&[u8], Self::Err> {
+        self.update();
+        self.check_session(None, handle)?;
+        match self.futures.get(handle)? {
+            FutureState::Completed(result) => Ok(result),
+            FutureState::Failed(err) => Err(err.clone()),
+            FutureState::Pending => Err(ApiError::not_found(format!(
+                "training {} hasn't completed yet",
+                handle.raw()
+            ))),
+            FutureState::Cancelled => Err(ApiError::not_found(format!(
+                "training {} was cancelled",
+                handle.raw()
+            ))),
+        }
+    }
+
+    fn active_futures_shim(&mut self) -> Result<Vec<FutureHandle>, Self::Err> {
+        self.update();
+        Ok(self
+            .futures
+            .handles()
+            .filter(|handle| !self.training_sessions.contains_key(handle))
+            .collect())
+    }
+
+    fn free_future_shim(&mut self, handle: FutureHandle) -> Result<(), Self::Err> {
+        self.update();
+        self.check_session(None, handle)?;
+        let state = self.futures.remove(handle)?;
+        self.forget(handle);
+        if let FutureState::Pending = state {
+            self.backend.cancel(handle);
+        }
+        Ok(())
+    }
+
+    fn poll_future_shim(&mut self, handle: FutureHandle) -> Result<FutureStatus, Self::Err> {
+        self.poll_future_in(None, handle)
+    }
+
+    fn get_training_metrics_shim(
+        &mut self,
+        handle: FutureHandle,
+    ) -> Result<TrainingMetrics, Self::Err> {
+        // Validates the handle, finished trainings keep their progress
+        self.update();
+        self.check_session(None, handle)?;
+        Ok(self
+            .progress
+            .get(&handle)
+            .map(TrainingProgress::metrics)
+            .unwrap_or_default())
+    }
+
+    fn run_inference_shim(
+        &mut self,
+        model: &str,
+        input: &[f32],
+        output: &mut [f32],
+    ) -> Result<u32, Self::Err> {
+        if model.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "model name must not be empty",
+            ));
+        }
+        let written = match self
+            .backend
+            .with(|backend| backend.infer(model, input, output))
+        {
+            Some(written) => written?,
+            None => {
+                return Err(ApiError::not_found(format!(
+                    "model `{}` isn't available for inference",
+                    model
+                )))
+            }
+        };
+        u32::try_from(written).map_err(|_| {
+            ApiError::new(
+                ErrorCode::Internal,
+                format!("inference result of {} elements is too large", written),
+            )
+        })
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        // Starting a training waits for the backend, which async linkers
+        // don't block the guest's thread on
+        if linker.is_async() {
+            start_training_imports!(
+                linker,
+                [async] start_training_shim_async,
+                start_training_in_async,
+                await
+            );
+        }
+        Self::portable_imports(linker)
+    }
+}
+
+impl PortableImports for MLApiHost {
+    fn portable_imports<R: Runtime>(linker: &mut R) -> Result<(), InstantiationError> {
+        if !linker.is_async() {
+            start_training_imports!(linker, [] start_training_shim, start_training_in);
+        }
+        host_import!(linker, MLApiHost, ml_imports::POLL_FUTURE, (
+            handle: u64,
+            status_out: *mut FutureStatus,
+        ) => |host| host.poll_future_shim(FutureHandle(handle)))?;
+        // Only sees the trainings of `session`, `0` for the default session
+        host_import!(linker, MLApiHost, ml_imports::POLL_FUTURE_IN_SESSION, (
+            session: u64,
+            handle: u64,
+            status_out: *mut FutureStatus,
+        ) => |host| {
+            host.poll_future_in(SessionHandle::from_guest(session), FutureHandle(handle))
+        })?;
+        // A null protocol config gives the session the instance's defaults
+        host_import!(linker, MLApiHost, ml_imports::CREATE_SESSION, (
+            protocol: Option<&ProtocolConfig>,
+            output: *mut SessionHandle,
+        ) => |host| host.create_session(protocol))?;
+        // The default session `0` can't be destroyed
+        host_import!(linker, MLApiHost, ml_imports::DESTROY_SESSION, (session: u64) => |host| {
+            match SessionHandle::from_guest(session) {
+                Some(session) => host.destroy_session(session).map(drop),
+                None => Err(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    "the default session can't be destroyed",
+                )),
+            }
+        })?;
+        host_import!(linker, MLApiHost, ml_imports::CANCEL_TRAINING, (handle: u64)
+            => |host| host.cancel_training_shim(FutureHandle(handle)))?;
+        host_import!(linker, MLApiHost, ml_imports::FREE_FUTURE, (handle: u64)
+            => |host| host.free_future_shim(FutureHandle(handle)))?;
+        // The guest sizes `buf` with the `result_len` reported by `poll_future`
+        host_import!(linker, MLApiHost, ml_imports::GET_FUTURE_RESULT, (
+            handle: u64,
+            buf: GuestSlice<u8>,
+        ) => |host, memory| {
+            let result = host.future_result_shim(FutureHandle(handle))?;
+            if result.len() as u64 > buf.len() {
+                Err(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "result of training {} needs {} bytes, the buffer only has {}",
+                        handle,
+                        result.len(),
+                        buf.len()
+                    ),
+                ))
+            } else {
+                GuestSlice::new(buf.ptr().raw(), result.len() as u64).write(memory, result)
+            }
+        })?;
+        host_import!(linker, MLApiHost, ml_imports::GET_TRAINING_METRICS, (
+            handle: u64,
+            metrics_out: *mut TrainingMetrics,
+        ) => |host| host.get_training_metrics_shim(FutureHandle(handle)))?;
+        // Always writes the size of the result, so a guest whose buffer was
+        // too small can retry with a larger one
+        host_import!(linker, MLApiHost, ml_imports::RUN_INFERENCE, (
+            model: GuestStr,
+            input: GuestSlice<f32>,
+            output: GuestSlice<f32>,
+            written_out: GuestPtr<u32>,
+        ) => |host, memory| {
+            let mut values = output.to_vec(memory)?;
+            let written =
+                host.run_inference_shim(model.read(memory)?, input.read(memory)?, &mut values)?;
+            written_out.write(memory, &written)?;
+            if u64::from(written) > output.len() {
+                Err(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "inference result needs {} elements, the buffer only has {}",
+                        written,
+                        output.len()
+                    ),
+                ))
+            } else {
+                GuestSlice::new(output.ptr().raw(), u64::from(written))
+                    .write(memory, &values[..written as usize])
+            }
+        })?;
+        // Writes as many handles as fit and the total count, so the guest can
+        // retry with a larger buffer
+        host_import!(linker, MLApiHost, ml_imports::LIST_ACTIVE_FUTURES, (
+            buf: GuestSlice<FutureHandle>,
+            count_out: GuestPtr<u32>,
+        ) => |host, memory| {
+            let handles = host.active_futures_shim()?;
+            let written = handles.len().min(usize::try_from(buf.len()).unwrap_or(usize::MAX));
+            GuestSlice::new(buf.ptr().raw(), written as u64).write(memory, &handles[..written])?;
+            count_out.write(memory, &u32::try_from(handles.len()).unwrap_or(u32::MAX))
+        })?;
+        let (namespace, _prefix) = Self::namespace();
+        for &alias in Self::aliases() {
+            linker.alias(Self::name(), namespace, alias)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handles::SlotHandle;
+    use crate::test_support::{self, guest_bytes, Guest};
+
+    #[test]
+    fn error_codes_round_trip() {
+        for &code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::from_raw(code as u32), Some(code));
+            assert_eq!(ErrorCode::try_from(code as u32), Ok(code));
+            assert_eq!(code.name().parse(), Ok(code));
+        }
+        assert!(ErrorCode::ALL
+            .windows(2)
+            .all(|codes| (codes[0] as u32) < (codes[1] as u32)));
+        let known = (0..10_000).filter_map(ErrorCode::from_raw).count();
+        assert_eq!(known, ErrorCode::ALL.len());
+        assert_eq!(
+            ErrorCode::try_from(u32::MAX),
+            Err(UnknownErrorCode(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn displays_code_without_message() {
+        let err = ApiError::new(ErrorCode::NotFound, ApiErrorMessage::None);
+        assert_eq!(err.display().to_string(), "NotFound(6)");
+        // Empty static messages are no message at all
+        let err = ApiError::not_found("");
+        assert!(matches!(err.message, ApiErrorMessage::None));
+        assert_eq!(err.display().to_string(), "NotFound(6)");
+    }
+
+    #[test]
+    fn displays_static_message() {
+        let err = ApiError::not_found("no model with that name");
+        assert!(matches!(err.message, ApiErrorMessage::Static(_)));
+        assert_eq!(
+            err.display().to_string(),
+            "NotFound(6): no model with that name"
+        );
+    }
+
+    #[test]
+    fn displays_dynamic_message() {
+        let err = ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!("{} epochs is too many", 100_000),
+        );
+        assert!(matches!(err.message, ApiErrorMessage::Dynamic(_)));
+        assert_eq!(
+            err.guest_message(),
+            "InvalidArgument(1): 100000 epochs is too many"
+        );
+    }
+
+    #[test]
+    fn displays_context_outermost_first() {
+        let err = ApiError::not_found("no model with that name")
+            .context(ApiErrorMessage::None)
+            .context(String::from("loading `mnist`"))
+            .context("starting training");
+        assert_eq!(
+            err.display().to_string(),
+            "NotFound(6): starting training: loading `mnist`: no model with that name"
+        );
+    }
+
+    #[test]
+    fn log_call_returns_the_error_code() {
+        let mut context = ModuleContext::builder()
+            .with_module(MLApiHost::default())
+            .with_job_threads(1)
+            .build()
+            .unwrap();
+        let site = CallSite::register(MLApiHost::name(), ml_imports::START_TRAINING);
+        for code in [
+            ErrorCode::InvalidArgument,
+            ErrorCode::NotFound,
+            ErrorCode::Internal,
+        ] {
+            let err = ApiError::new(code, "training failed");
+            assert_eq!(err.code(), code);
+            let returned = MLApiHost::log_call(&mut context, site, Err(err)).unwrap();
+            assert_eq!(returned, code as u32);
+            assert_eq!(context.last_error().map(ApiError::code), Some(code));
+        }
+        let returned = MLApiHost::log_call(&mut context, site, Ok(())).unwrap();
+        assert_eq!(returned, ErrorCode::Success as u32);
+        assert!(context.last_error().is_none());
+        assert_eq!(ApiError::not_found("").code(), ErrorCode::NotFound);
+        assert_eq!(ApiError::internal("").code(), ErrorCode::Internal);
+    }
+
+    #[test]
+    fn messages_convert_from_cows() {
+        let empty = ApiErrorMessage::from(Cow::Borrowed(""));
+        assert!(matches!(empty, ApiErrorMessage::None));
+        let borrowed = ApiErrorMessage::from(Cow::Borrowed("batch size"));
+        assert!(matches!(borrowed, ApiErrorMessage::Static("batch size")));
+        let owned = ApiErrorMessage::from(Cow::<str>::Owned(format!("{} epochs", 3)));
+        assert!(matches!(&owned, ApiErrorMessage::Dynamic(msg) if msg == "3 epochs"));
+        assert!(matches!(borrowed.into_cow(), Cow::Borrowed("batch size")));
+        assert!(matches!(owned.into_cow(), Cow::Owned(msg) if msg == "3 epochs"));
+        assert_eq!(empty.into_cow(), "");
+    }
+
+    #[test]
+    fn empty_context_is_skipped() {
+        let err = ApiError::internal("disk full")
+            .context("")
+            .context(Cow::Borrowed("writing checkpoint"))
+            .context(String::new());
+        assert_eq!(
+            err.guest_message(),
+            "Internal(7): writing checkpoint: disk full"
+        );
+        let err = ApiError::new(ErrorCode::InvalidArgument, "").context("`epochs`");
+        assert_eq!(err.guest_message(), "InvalidArgument(1): `epochs`");
+    }
+
+    #[test]
+    fn anyhow_chains_keep_every_cause() {
+        let err = anyhow::anyhow!("disk full")
+            .context("writing checkpoint")
+            .context("saving `mnist`");
+        let err = ApiError::from(err);
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(
+            err.guest_message(),
+            "Internal(7): saving `mnist`: writing checkpoint: disk full"
+        );
+    }
+
+    #[test]
+    fn backend_errors_wrapping_traps_stay_internal() {
+        let err = anyhow::Error::new(wasmtime::Trap::StackOverflow).context("evaluating `mnist`");
+        let err = ApiError::from(err);
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert!(
+            err.guest_message()
+                .starts_with("Internal(7): evaluating `mnist`: "),
+            "{}",
+            err.guest_message()
+        );
+    }
+
+    #[test]
+    fn traps_map_to_error_codes() {
+        let err = ApiError::from(wasmtime::Trap::StackOverflow);
+        assert_eq!(err.code(), ErrorCode::StackOverflow);
+        let err = ApiError::from(wasmtime::Trap::OutOfFuel);
+        assert_eq!(err.code(), ErrorCode::GuestTrapped);
+        let err = ApiError::from_guest_call(wasmtime::Trap::MemoryOutOfBounds.into());
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+
+        assert!(matches!(
+            ModuleError::from(wasmtime::Trap::Interrupt),
+            ModuleError::Interrupted { .. }
+        ));
+        assert!(matches!(
+            ModuleError::from(wasmtime::Trap::UnreachableCodeReached),
+            ModuleError::Trap { .. }
+        ));
+    }
+
+    #[test]
+    fn io_errors_map_not_found() {
+        use std::io::{Error, ErrorKind};
+
+        let err = ApiError::from(Error::new(ErrorKind::NotFound, "no such dataset"));
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.guest_message(), "NotFound(6): no such dataset");
+        let err = ApiError::from(Error::new(ErrorKind::PermissionDenied, "read only"));
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(err.guest_message(), "Internal(7): read only");
+    }
+
+    /// Log records of every test, with the thread that logged them for each
+    /// test to filter by what it logged.
+    type Record = (std::thread::ThreadId, log::Level, String);
+
+    struct RecordLog(std::sync::Mutex<Vec<Record>>);
+
+    impl log::Log for RecordLog {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            let mut records = self.0.lock().unwrap_or_else(|err| err.into_inner());
+            let thread = std::thread::current().id();
+            records.push((thread, record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGS: RecordLog = RecordLog(std::sync::Mutex::new(Vec::new()));
+
+    /// Records logged by the current thread at `level` that contain `needle`.
+    fn logged(level: log::Level, needle: &str) -> usize {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&LOGS).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        let thread = std::thread::current().id();
+        let records = LOGS.0.lock().unwrap_or_else(|err| err.into_inner());
+        records
+            .iter()
+            .filter(|(by, logged, record)| {
+                *by == thread && *logged == level && record.contains(needle)
+            })
+            .count()
+    }
+
+    /// Starts a training with `START_TRAINING` and fails to start another,
+    /// returning the trace lines of the calls. With `tracing` calls are
+    /// traced as spans instead, see the tests of `host_import!`.
+    #[cfg(not(all(feature = "host-call-trace", feature = "tracing")))]
+    fn traced_start_training() -> usize {
+        // Installs the logger before the calls
+        assert_eq!(logged(log::Level::Trace, "function=ml__start_training"), 0);
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default(),
+            ModuleContext::builder(),
+        );
+        let code = start_training(&mut store, &instance, 3, 32);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let code = start_training(&mut store, &instance, 0, 32);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        logged(log::Level::Trace, "function=ml__start_training")
+    }
+
+    #[test]
+    #[cfg(all(feature = "host-call-trace", not(feature = "tracing")))]
+    fn successful_calls_are_traced_once_each() {
+        assert_eq!(traced_start_training(), 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "host-call-trace"))]
+    fn calls_arent_traced_without_host_call_trace() {
+        assert_eq!(traced_start_training(), 0);
+    }
+
+    #[test]
+    fn imports_fail_on_a_linker_that_has_them() {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        MLApiHost::imports(&mut linker).unwrap();
+        let registered = linker.registered_imports().iter().count();
+        match MLApiHost::imports(&mut linker) {
+            Err(InstantiationError::DuplicateImport { module, name }) => {
+                assert_eq!(module, "ml_api");
+                assert!(name.starts_with("env::ml__"), "{}", name);
+            }
+            res => panic!("registered the imports twice: {:?}", res.map(drop)),
+        }
+        assert_eq!(linker.registered_imports().iter().count(), registered);
+    }
+
+    /// Starts a training of "mnist" for `epochs`, writing the handle to
+    /// `output`, which is preset to all ones.
+    const START_TRAINING: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 32) "\ff\ff\ff\ff\ff\ff\ff\ff")
+          (data (i32.const 64) "mnist")
+          (data (i32.const 80) "data/mnist")
+          (func (export "start") (param $epochs i32) (param $output i32) (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (local.get $epochs)
+              (i32.const 80) (i32.const 10)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (local.get $output))))
+    "#;
+
+    fn start_training(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+        epochs: u32,
+        output: u32,
+    ) -> u32 {
+        ModuleContext::call_export(store, instance, "start", (epochs, output)).unwrap()
+    }
+
+    fn output(store: &mut wasmtime::Store<ModuleContext>, instance: &wasmtime::Instance) -> u64 {
+        let memory = instance.get_memory(&mut *store, "memory").unwrap();
+        let mut bytes = [0; 8];
+        memory.read(&*store, 32, &mut bytes).unwrap();
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn start_training_writes_the_handle_to_the_output() {
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default(),
+            ModuleContext::builder(),
+        );
+
+        let code = start_training(&mut store, &instance, 0, 32);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert_eq!(output(&mut store, &instance), u64::MAX);
+        let code = start_training(&mut store, &instance, 3, 0);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert_eq!(output(&mut store, &instance), u64::MAX);
+        assert!(store
+            .data()
+            .module::<MLApiHost>()
+            .unwrap()
+            .futures()
+            .is_empty());
+
+        let code = start_training(&mut store, &instance, 3, 32);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let handle = output(&mut store, &instance);
+        assert_ne!(handle, 0);
+        let futures = store.data().module::<MLApiHost>().unwrap().futures();
+        assert_eq!(
+            futures.handles().map(FutureHandle::raw).collect::<Vec<_>>(),
+            [handle]
+        );
+    }
+
+    #[test]
+    fn polling_reports_the_state_without_consuming_it() {
+        let mut host = MLApiHost::default();
+        let handle = host.futures_mut().insert(FutureState::Pending).unwrap();
+        let pending = FutureStatus {
+            state: FutureStatus::PENDING,
+            result_len: 0,
+        };
+        assert_eq!(host.poll_future_shim(handle).unwrap(), pending);
+        assert_eq!(host.poll_future_shim(handle).unwrap(), pending);
+
+        host.futures()
+            .completer()
+            .complete(handle, FutureState::Completed(vec![0; 12]));
+        let completed = FutureStatus {
+            state: FutureStatus::COMPLETED,
+            result_len: 12,
+        };
+        for _ in 0..3 {
+            assert_eq!(host.poll_future_shim(handle).unwrap(), completed);
+        }
+        assert_eq!(host.future_result_shim(handle).unwrap(), &[0; 12]);
+        assert_eq!(host.poll_future_shim(handle).unwrap(), completed);
+
+        host.free_future_shim(handle).unwrap();
+        let err = host.poll_future_shim(handle).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        let err = host.poll_future_shim(FutureHandle::new(3, 1)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    /// Backend recording the trainings it was told to cancel.
+    #[derive(Default)]
+    struct Cancels(std::sync::Arc<std::sync::Mutex<Vec<FutureHandle>>>);
+
+    impl TrainingBackend for Cancels {
+        fn start(
+            &mut self,
+            _handle: FutureHandle,
+            _req: TrainingRequest<'_>,
+            _protocol: &ProtocolConfig,
+        ) -> Result<TrainingStart, ApiError> {
+            Ok(TrainingStart::State(FutureState::Pending))
+        }
+
+        fn cancel(&mut self, handle: FutureHandle) {
+            self.0.lock().unwrap().push(handle);
+        }
+    }
+
+    #[test]
+    fn cancelling_only_stops_pending_trainings() {
+        let backend = Cancels::default();
+        let cancelled = backend.0.clone();
+        let mut host = MLApiHost::default().with_backend(backend);
+        let pending = host.futures_mut().insert(FutureState::Pending).unwrap();
+        let done = host
+            .futures_mut()
+            .insert(FutureState::Completed(vec![7]))
+            .unwrap();
+
+        host.cancel_training_shim(pending).unwrap();
+        assert_eq!(
+            host.poll_future_shim(pending).unwrap().state,
+            FutureStatus::CANCELLED
+        );
+        // Cancelling twice doesn't signal the backend again
+        host.cancel_training_shim(pending).unwrap();
+        assert_eq!(*cancelled.lock().unwrap(), [pending]);
+
+        host.cancel_training_shim(done).unwrap();
+        assert_eq!(
+            host.poll_future_shim(done).unwrap().state,
+            FutureStatus::COMPLETED
+        );
+        assert_eq!(host.future_result_shim(done).unwrap(), &[7]);
+        assert_eq!(*cancelled.lock().unwrap(), [pending]);
+
+        let err = host
+            .cancel_training_shim(FutureHandle::new(9, 1))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn freeing_cancels_pending_trainings() {
+        let backend = Cancels::default();
+        let cancelled = backend.0.clone();
+        let mut host = MLApiHost::default().with_backend(backend);
+        let pending = host.futures_mut().insert(FutureState::Pending).unwrap();
+        let done = host.futures_mut().insert(FutureState::Cancelled).unwrap();
+        host.free_future_shim(pending).unwrap();
+        host.free_future_shim(done).unwrap();
+        assert_eq!(*cancelled.lock().unwrap(), [pending]);
+        assert!(host.futures().is_empty());
+        for handle in [pending, done] {
+            let err = host.free_future_shim(handle).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::StaleHandle);
+        }
+        let err = host.free_future_shim(FutureHandle::new(5, 1)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn freed_futures_make_room_for_new_ones() {
+        let mut host = MLApiHost::default()
+            .with_backend(Cancels::default())
+            .with_future_capacity(2);
+        let req = || {
+            TrainingRequest::from_import_args("mnist", 3, "data/mnist", "", 0, "", "", 42).unwrap()
+        };
+        let protocol = ProtocolConfig::default();
+        let mut handles = std::collections::HashSet::new();
+        for _ in 0..10_000 {
+            let handle = host.start_training_shim(req(), &protocol).unwrap();
+            assert!(handles.insert(handle), "{:?} handed out twice", handle);
+            host.free_future_shim(handle).unwrap();
+        }
+        host.start_training_shim(req(), &protocol).unwrap();
+        host.start_training_shim(req(), &protocol).unwrap();
+        let err = host.start_training_shim(req(), &protocol).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+    }
+
+    /// Instantiates `wat` with the imports of `host`, which is the state of
+    /// the instance.
+    fn ml_guest(wat: &str, host: MLApiHost, context: ModuleContextBuilder) -> Guest {
+        let context = context.with_module(host).with_job_threads(1);
+        test_support::guest::<MLApiHost>(wat, context)
+    }
+
+    #[test]
+    fn results_are_copied_into_the_guest_buffer() {
+        let wat = r#"
+            (module
+              (import "env" "ml__get_future_result"
+                (func $get_future_result (param i64 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "result") (param i64 i32 i32) (result i32)
+                (call $get_future_result (local.get 0) (local.get 1) (local.get 2))))
+        "#;
+        let mut host = MLApiHost::default();
+        let futures = host.futures_mut();
+        let done = futures
+            .insert(FutureState::Completed(b"loss=0.25".to_vec()))
+            .unwrap();
+        let pending = futures.insert(FutureState::Pending).unwrap();
+        let cancelled = futures.insert(FutureState::Cancelled).unwrap();
+        let failed = futures
+            .insert(FutureState::Failed(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "diverged",
+            )))
+            .unwrap();
+        let (mut store, instance) = ml_guest(wat, host, ModuleContext::builder());
+        let result = |store: &mut wasmtime::Store<ModuleContext>, handle: FutureHandle, len| {
+            ModuleContext::call_export::<_, u32>(
+                store,
+                &instance,
+                "result",
+                (handle.raw(), 64, len),
+            )
+            .unwrap()
+        };
+
+        let code = result(&mut store, done, 8);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert!(store
+            .data()
+            .last_error()
+            .unwrap()
+            .guest_message()
+            .ends_with("needs 9 bytes, the buffer only has 8"));
+        assert_eq!(guest_bytes(&mut store, &instance, 64, 16), [0; 16]);
+        assert_eq!(result(&mut store, done, 16), ErrorCode::Success as u32);
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 64, 16),
+            b"loss=0.25\0\0\0\0\0\0\0"
+        );
+
+        for handle in [pending, cancelled] {
+            assert_eq!(result(&mut store, handle, 16), ErrorCode::NotFound as u32);
+        }
+        let code = result(&mut store, failed, 16);
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert!(store
+            .data()
+            .last_error()
+            .unwrap()
+            .guest_message()
+            .ends_with("diverged"));
+    }
+
+    const LIST_ACTIVE_FUTURES: &str = r#"
+        (module
+          (import "env" "ml__list_active_futures"
+            (func $list_active_futures (param i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "list") (param $capacity i32) (result i32)
+            (call $list_active_futures (i32.const 64) (local.get $capacity) (i32.const 32))))
+    "#;
+
+    fn list_active_futures(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+        capacity: u32,
+    ) -> u32 {
+        ModuleContext::call_export(store, instance, "list", capacity).unwrap()
+    }
+
+    #[test]
+    fn active_futures_are_listed_in_allocation_order() {
+        let mut host = MLApiHost::default();
+        let futures = host.futures_mut();
+        let first = futures.insert(FutureState::Pending).unwrap();
+        let freed = futures.insert(FutureState::Pending).unwrap();
+        let last = futures.insert(FutureState::Completed(Vec::new())).unwrap();
+        futures.remove(freed).unwrap();
+        // Reuses the freed slot, but is still listed last
+        let new = futures.insert(FutureState::Pending).unwrap();
+        let context = ModuleContext::builder().with_diagnostics(true);
+        let (mut store, instance) = ml_guest(LIST_ACTIVE_FUTURES, host, context);
+
+        let code = list_active_futures(&mut store, &instance, 4);
+        assert_eq!(code, ErrorCode::Success as u32);
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 32, 4),
+            3_u32.to_le_bytes()
+        );
+        let listed = guest_bytes(&mut store, &instance, 64, 32);
+        let expected: Vec<u8> = [first.raw(), last.raw(), new.raw(), 0]
+            .iter()
+            .flat_map(|handle| handle.to_le_bytes())
+            .collect();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn active_futures_report_the_count_beyond_the_buffer() {
+        let mut host = MLApiHost::default();
+        let handles: Vec<_> = (0..3)
+            .map(|_| host.futures_mut().insert(FutureState::Pending).unwrap())
+            .collect();
+        let context = ModuleContext::builder().with_diagnostics(true);
+        let (mut store, instance) = ml_guest(LIST_ACTIVE_FUTURES, host, context);
+
+        let code = list_active_futures(&mut store, &instance, 1);
+        assert_eq!(code, ErrorCode::Success as u32);
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 32, 4),
+            3_u32.to_le_bytes()
+        );
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 64, 16),
+            [handles[0].raw().to_le_bytes(), [0; 8]].concat()
+        );
+    }
+
+    #[test]
+    fn active_futures_need_diagnostics() {
+        let mut host = MLApiHost::default();
+        host.futures_mut().insert(FutureState::Pending).unwrap();
+        let (mut store, instance) = ml_guest(LIST_ACTIVE_FUTURES, host, ModuleContext::builder());
+        let code = list_active_futures(&mut store, &instance, 4);
+        assert_eq!(code, ErrorCode::PermissionDenied as u32);
+        assert_eq!(guest_bytes(&mut store, &instance, 32, 40), [0; 40]);
+    }
+
+    #[test]
+    fn fatal_errors_trap_naming_the_call() {
+        let mut context = ModuleContext::builder()
+            .with_module(MLApiHost::default())
+            .with_job_threads(1)
+            .build()
+            .unwrap();
+        let site = CallSite::register(MLApiHost::name(), ml_imports::POLL_FUTURE);
+        let err = ApiError::fatal(ErrorCode::Internal, "host state is corrupted");
+        assert_eq!(err.severity(), Severity::Fatal);
+        let trap = MLApiHost::log_call(&mut context, site, Err(err)).unwrap_err();
+        // Debug builds add the backtrace of the error
+        assert!(
+            trap.message().starts_with(
+                "fatal host call error: module=ml_api function=ml__poll_future \
+                 code=Internal(7) error=Internal(7): host state is corrupted"
+            ),
+            "{}",
+            trap
+        );
+        assert_eq!(
+            context.last_error().map(ApiError::severity),
+            Some(Severity::Fatal)
+        );
+        let err = ApiError::internal("recoverable");
+        assert_eq!(err.severity(), Severity::Recoverable);
+        let code = MLApiHost::log_call(&mut context, site, Err(err)).unwrap();
+        assert_eq!(code, ErrorCode::Internal as u32);
     }
 
-    fn imports(it: Self::ImportTable) -> Result<(), Self::ImportError> {
-        let (namespace, prefix) = Self::namespace();
-        let wasmtime_linker = unsafe { &mut *it };
-        wasmtime_linker
+    #[test]
+    fn fatal_errors_abort_the_guest() {
+        let wat = r#"
+            (module
+              (import "env" "ml__corrupted" (func $corrupted (result i32)))
+              (memory (export "memory") 1)
+              (global $after (export "after") (mut i32) (i32.const 0))
+              (func (export "call") (result i32)
+                (drop (call $corrupted))
+                (global.set $after (i32.const 1))
+                (i32.const 0)))
+        "#;
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        linker
             .func_wrap(
-                namespace,
-                format!("{}__{}", prefix, "start_training").as_str(),
-                move |_caller: wasmtime::Caller<'_, ModuleContext>,
-                      _param1_1: u32,
-                      _param1_2: u32,
-                      _param2: u32,
-                      _param3_1: u32,
-                      _param3_2: u32,
-                      _param4_1: u32,
-                      _param4_2: u32,
-                      _param5: u32,
-                      _param6_1: u32,
-                      _param6_2: u32,
-                      _param7_1: u32,
-                      _param7_2: u32,
-                      _param8: u64,
-                      _protocol_ptr: u32,
-                      _output_ptr: u32|
-                      -> Result<u32, wasmtime::Trap> {
-                    let result = Ok(());
-                    Self::log_call("start_training", result)
+                MLApiHost::name(),
+                "env",
+                "ml__corrupted",
+                |mut caller: wasmtime::Caller<'_, ModuleContext>| {
+                    let site = CallSite::register(MLApiHost::name(), "ml__corrupted");
+                    let err = ApiError::fatal(ErrorCode::Internal, "host state is corrupted");
+                    MLApiHost::log_call(caller.data_mut(), site, Err(err)).map_err(Into::into)
                 },
             )
-            .map_err(|err| InstantiationError::Import(err))?;
-        return Ok(());
+            .unwrap();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = ModuleContext::builder()
+            .build()
+            .unwrap()
+            .into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        match ModuleContext::call_export::<(), u32>(&mut store, &instance, "call", ()) {
+            Err(ModuleError::HostError(err)) => {
+                assert_eq!(err.code(), ErrorCode::Internal);
+                assert_eq!(err.guest_message(), "Internal(7): host state is corrupted");
+            }
+            res => panic!(
+                "the guest kept running: {:?}",
+                res.map_err(|err| err.to_string())
+            ),
+        }
+        let after = instance.get_global(&mut store, "after").unwrap();
+        assert_eq!(after.get(&mut store).i32(), Some(0));
+    }
+
+    /// Backend that takes a while to enqueue trainings, as long as the
+    /// runtime's timer says.
+    struct Sleepy;
+
+    impl TrainingBackend for Sleepy {
+        fn start(
+            &mut self,
+            _handle: FutureHandle,
+            _req: TrainingRequest<'_>,
+            _protocol: &ProtocolConfig,
+        ) -> Result<TrainingStart, ApiError> {
+            unreachable!("only called through an async linker")
+        }
+
+        fn start_async<'a>(
+            &'a mut self,
+            _handle: FutureHandle,
+            _req: TrainingRequest<'a>,
+            _protocol: &'a ProtocolConfig,
+        ) -> BoxFuture<'a, Result<TrainingStart, ApiError>> {
+            Box::pin(async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                Ok(TrainingStart::State(FutureState::Completed(vec![1])))
+            })
+        }
+    }
+
+    #[test]
+    fn async_and_sync_imports_share_their_abi() {
+        let engine = wasmtime::Engine::new(wasmtime::Config::new().async_support(true)).unwrap();
+        let mut sync = HostLinker::new(&wasmtime::Engine::default());
+        MLApiHost::imports(&mut sync).unwrap();
+        let mut async_linker = HostLinker::new_async(&engine);
+        MLApiHost::imports(&mut async_linker).unwrap();
+        let (sync, async_linker) = (sync.manifest(), async_linker.manifest());
+        let start_training = sync.namespaces["env"]
+            .iter()
+            .find(|import| import.name == "ml__start_training_in_session")
+            .unwrap();
+        assert_eq!(start_training.params.len(), 16);
+        assert_eq!(sync, async_linker);
+    }
+
+    #[tokio::test]
+    async fn async_imports_let_the_runtime_make_progress() {
+        let engine = wasmtime::Engine::new(wasmtime::Config::new().async_support(true)).unwrap();
+        let mut linker = HostLinker::new_async(&engine);
+        assert!(linker.is_async());
+        MLApiHost::imports(&mut linker).unwrap();
+        let module = wasmtime::Module::new(&engine, START_TRAINING).unwrap();
+        let mut store = ModuleContext::builder()
+            .with_module(MLApiHost::default().with_backend(Sleepy))
+            .with_job_threads(1)
+            .build()
+            .unwrap()
+            .into_store(&engine);
+        let instance = linker
+            .linker()
+            .instantiate_async(&mut store, &module)
+            .await
+            .unwrap();
+        let start = instance
+            .get_typed_func::<(u32, u32), u32>(&mut store, "start")
+            .unwrap();
+
+        // The test runtime has a single thread, which only runs `ticks` while
+        // the host call awaits the backend
+        let ticks = std::sync::atomic::AtomicU32::new(0);
+        let ticker = async {
+            for _ in 0..5 {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        };
+        let call = async {
+            let code = start.call_async(&mut store, (3, 32)).await;
+            (code, ticks.load(std::sync::atomic::Ordering::SeqCst))
+        };
+        let ((code, ticked), ()) = tokio::join!(call, ticker);
+        assert_eq!(code.unwrap(), ErrorCode::Success as u32);
+        assert_eq!(ticked, 5);
+        let futures = store.data().module::<MLApiHost>().unwrap().futures();
+        let handle = futures.handles().next().unwrap();
+        assert!(
+            matches!(futures.get(handle), Ok(FutureState::Completed(result)) if result == &[1])
+        );
+    }
+
+    /// Backend running every training as a job, which panics for trainings
+    /// of 13 epochs and otherwise returns the number of epochs.
+    struct Jobs;
+
+    impl TrainingBackend for Jobs {
+        fn start(
+            &mut self,
+            _handle: FutureHandle,
+            req: TrainingRequest<'_>,
+            _protocol: &ProtocolConfig,
+        ) -> Result<TrainingStart, ApiError> {
+            let epochs = req.epochs;
+            Ok(TrainingStart::Job(Box::new(move |_| {
+                if epochs == 13 {
+                    panic!("unlucky");
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                Ok(vec![epochs as u8])
+            })))
+        }
+    }
+
+    #[test]
+    fn jobs_complete_their_futures_in_the_background() {
+        let context = ModuleContext::builder().with_job_threads(4);
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default().with_backend(Jobs),
+            context,
+        );
+        for epochs in [1, 2, 13, 3] {
+            let code = start_training(&mut store, &instance, epochs, 32);
+            assert_eq!(code, ErrorCode::Success as u32);
+        }
+
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        while host.futures().pending().next().is_some() && Instant::now() < deadline {
+            host.futures().wait(deadline);
+            host.update();
+        }
+        let handles: Vec<_> = host.futures().handles().collect();
+        let mut results = Vec::new();
+        for handle in handles {
+            match host.futures().get(handle).unwrap() {
+                FutureState::Completed(result) => results.push(result[0]),
+                FutureState::Failed(err) => {
+                    assert_eq!(err.code(), ErrorCode::Internal);
+                    assert!(
+                        err.guest_message().ends_with("panicked"),
+                        "{}",
+                        err.guest_message()
+                    );
+                    results.push(13);
+                }
+                state => panic!("{:?} is still {:?}", handle, state),
+            }
+        }
+        assert_eq!(results, [1, 2, 13, 3]);
+    }
+
+    /// Backend running each training as a job that finishes once it is
+    /// released, recording the trainings it was told to cancel.
+    struct Slow {
+        release: std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<()>>>,
+        cancelled: std::sync::Arc<std::sync::Mutex<Vec<FutureHandle>>>,
+    }
+
+    impl TrainingBackend for Slow {
+        fn start(
+            &mut self,
+            _handle: FutureHandle,
+            _req: TrainingRequest<'_>,
+            _protocol: &ProtocolConfig,
+        ) -> Result<TrainingStart, ApiError> {
+            let release = self.release.clone();
+            Ok(TrainingStart::Job(Box::new(move |_| {
+                let _ = release
+                    .lock()
+                    .unwrap()
+                    .recv_timeout(std::time::Duration::from_secs(10));
+                Ok(vec![1])
+            })))
+        }
+
+        fn cancel(&mut self, handle: FutureHandle) {
+            self.cancelled.lock().unwrap().push(handle);
+        }
+    }
+
+    /// Starts a slow training in an instance built from `context`, returning
+    /// the store, the training's handle, the sender releasing it and the
+    /// trainings cancelled so far.
+    #[allow(clippy::type_complexity)]
+    fn slow_training(
+        context: ModuleContextBuilder,
+    ) -> (
+        wasmtime::Store<ModuleContext>,
+        FutureHandle,
+        std::sync::mpsc::Sender<()>,
+        std::sync::Arc<std::sync::Mutex<Vec<FutureHandle>>>,
+    ) {
+        let (release, released) = std::sync::mpsc::channel();
+        let cancelled = std::sync::Arc::default();
+        let backend = Slow {
+            release: std::sync::Arc::new(std::sync::Mutex::new(released)),
+            cancelled: std::sync::Arc::clone(&cancelled),
+        };
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default().with_backend(backend),
+            context,
+        );
+        let code = start_training(&mut store, &instance, 1, 32);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let handle = FutureHandle(output(&mut store, &instance));
+        (store, handle, release, cancelled)
+    }
+
+    fn state(store: &mut wasmtime::Store<ModuleContext>, handle: FutureHandle) -> u32 {
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        host.poll_future_shim(handle).unwrap().state
+    }
+
+    #[test]
+    fn waiting_shutdowns_let_trainings_finish() {
+        let (mut store, handle, release, cancelled) = slow_training(ModuleContext::builder());
+        release.send(()).unwrap();
+        let unresolved = store
+            .data_mut()
+            .shutdown(ShutdownPolicy::WaitAll(std::time::Duration::from_secs(10)));
+        assert_eq!(unresolved, []);
+        assert_eq!(state(&mut store, handle), FutureStatus::COMPLETED);
+        assert_eq!(*cancelled.lock().unwrap(), []);
+    }
+
+    #[test]
+    fn waiting_shutdowns_cancel_trainings_after_the_timeout() {
+        let (mut store, handle, release, cancelled) = slow_training(ModuleContext::builder());
+        let unresolved =
+            store
+                .data_mut()
+                .shutdown(ShutdownPolicy::WaitAll(std::time::Duration::from_millis(
+                    20,
+                )));
+        assert_eq!(unresolved, [handle]);
+        assert_eq!(state(&mut store, handle), FutureStatus::CANCELLED);
+        assert_eq!(*cancelled.lock().unwrap(), [handle]);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn cancelling_shutdowns_drop_late_completions() {
+        let (mut store, handle, release, cancelled) = slow_training(ModuleContext::builder());
+        let unresolved = store
+            .data_mut()
+            .shutdown(ShutdownPolicy::CancelAll(std::time::Duration::ZERO));
+        assert_eq!(unresolved, [handle]);
+        assert_eq!(*cancelled.lock().unwrap(), [handle]);
+
+        release.send(()).unwrap();
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        assert!(host
+            .futures()
+            .wait(Instant::now() + std::time::Duration::from_secs(10)));
+        assert_eq!(state(&mut store, handle), FutureStatus::CANCELLED);
+    }
+
+    #[test]
+    fn dropping_the_context_cancels_pending_trainings() {
+        // Detached so dropping the context doesn't wait for the job
+        let context = ModuleContext::builder().with_job_shutdown(WorkerShutdown::Detach);
+        let (store, handle, release, cancelled) = slow_training(context);
+        drop(store);
+        assert_eq!(*cancelled.lock().unwrap(), [handle]);
+        let _ = release.send(());
+    }
+
+    #[test]
+    fn finishing_a_session_reports_the_futures_left_behind() {
+        // Installs the logger before the session finishes
+        logged(log::Level::Warn, "function=ml__leaky");
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default(),
+            ModuleContext::builder(),
+        );
+        let code = start_training(&mut store, &instance, 1, 32);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let started = FutureHandle(output(&mut store, &instance));
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        host.free_future_shim(started).unwrap();
+        // Handles repeat across instances, the function names the leaks of
+        // this test
+        let site = CallSite::register(MLApiHost::name(), "ml__leaky");
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                host.futures_mut()
+                    .insert_from(Some(site), FutureState::Pending)
+                    .unwrap()
+            })
+            .collect();
+        host.free_future_shim(handles[0]).unwrap();
+
+        let leaked = store.data_mut().finish_session();
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].handle, handles[1]);
+        assert_eq!(leaked[0].origin, Some(site));
+        let warnings = |count| {
+            if cfg!(feature = "host-logging") {
+                count
+            } else {
+                0
+            }
+        };
+        assert_eq!(logged(log::Level::Warn, "function=ml__leaky"), warnings(1));
+        let needle = format!(
+            "handle={} module=ml_api function=ml__leaky",
+            handles[1].raw()
+        );
+        assert_eq!(logged(log::Level::Warn, &needle), warnings(1));
+        // Leaks stay in the table until they're freed
+        assert_eq!(store.data_mut().finish_session().len(), 1);
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        host.free_future_shim(handles[1]).unwrap();
+        assert_eq!(store.data_mut().finish_session().len(), 0);
+        assert_eq!(logged(log::Level::Warn, "function=ml__leaky"), warnings(2));
+    }
+
+    #[test]
+    fn started_trainings_record_their_origin() {
+        let (mut store, instance) = ml_guest(
+            START_TRAINING,
+            MLApiHost::default(),
+            ModuleContext::builder(),
+        );
+        let code = start_training(&mut store, &instance, 1, 32);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let handle = FutureHandle(output(&mut store, &instance));
+
+        let leaked = store.data_mut().finish_session();
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].handle, handle);
+        let origin = leaked[0].origin.unwrap();
+        assert_eq!(origin.module(), MLApiHost::name());
+        assert_eq!(origin.function(), ml_imports::START_TRAINING);
+        let stats = store.data().call_stats();
+        let start = stats
+            .iter()
+            .find(|stat| stat.function == ml_imports::START_TRAINING)
+            .unwrap();
+        assert_eq!((start.calls, start.leaked), (1, 1));
+    }
+
+    /// Runs the model named at 16 with `len` bytes of name on the four
+    /// floats at 64, writing the result to 128 and its length to 32, which
+    /// is preset to all ones.
+    const RUN_INFERENCE: &str = r#"
+        (module
+          (import "env" "ml__run_inference"
+            (func $run_inference (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "echo")
+          (data (i32.const 32) "\ff\ff\ff\ff")
+          ;; 1.5, -0.0, a NaN with a payload and the smallest subnormal
+          (data (i32.const 64) "\00\00\c0\3f\00\00\00\80\01\00\c0\7f\01\00\00\00")
+          (func (export "infer") (param $len i32) (param $capacity i32) (result i32)
+            (call $run_inference
+              (i32.const 16) (local.get $len)
+              (i32.const 64) (i32.const 4)
+              (i32.const 128) (local.get $capacity)
+              (i32.const 32))))
+    "#;
+
+    fn infer(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+        model_len: u32,
+        capacity: u32,
+    ) -> u32 {
+        ModuleContext::call_export(&mut *store, instance, "infer", (model_len, capacity)).unwrap()
+    }
+
+    #[test]
+    fn inference_results_are_byte_exact() {
+        let (mut store, instance) = ml_guest(
+            RUN_INFERENCE,
+            MLApiHost::default().with_backend(EchoBackend),
+            ModuleContext::builder(),
+        );
+        assert_eq!(
+            infer(&mut store, &instance, 4, 6),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 32, 4),
+            4_u32.to_le_bytes()
+        );
+        let input = guest_bytes(&mut store, &instance, 64, 16);
+        assert_eq!(guest_bytes(&mut store, &instance, 128, 16), input);
+        // Only the result is written, not the whole buffer
+        assert_eq!(guest_bytes(&mut store, &instance, 144, 8), [0; 8]);
+    }
+
+    #[test]
+    fn small_inference_buffers_get_the_required_size() {
+        let (mut store, instance) = ml_guest(
+            RUN_INFERENCE,
+            MLApiHost::default().with_backend(EchoBackend),
+            ModuleContext::builder(),
+        );
+        assert_eq!(
+            infer(&mut store, &instance, 4, 3),
+            ErrorCode::InvalidArgument as u32
+        );
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 32, 4),
+            4_u32.to_le_bytes()
+        );
+        assert_eq!(guest_bytes(&mut store, &instance, 128, 16), [0; 16]);
+        let message = store.data().last_error().unwrap().guest_message();
+        assert!(
+            message.ends_with("inference result needs 4 elements, the buffer only has 3"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn inference_needs_a_model_and_a_backend() {
+        let (mut store, instance) = ml_guest(
+            RUN_INFERENCE,
+            MLApiHost::default().with_backend(EchoBackend),
+            ModuleContext::builder(),
+        );
+        assert_eq!(
+            infer(&mut store, &instance, 0, 4),
+            ErrorCode::InvalidArgument as u32
+        );
+        assert_eq!(guest_bytes(&mut store, &instance, 32, 4), [0xff; 4]);
+
+        let (mut store, instance) = ml_guest(
+            RUN_INFERENCE,
+            MLApiHost::default(),
+            ModuleContext::builder(),
+        );
+        assert_eq!(
+            infer(&mut store, &instance, 4, 4),
+            ErrorCode::NotFound as u32
+        );
+        let message = store.data().last_error().unwrap().guest_message();
+        assert_eq!(
+            message,
+            "NotFound(6): model `echo` isn't available for inference"
+        );
+    }
+
+    /// Starts a training of "mnist" for `epochs`, writing the handle to 32,
+    /// and writes the metrics of the training at 32 to 48.
+    const TRAINING_METRICS: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (import "env" "ml__get_training_metrics"
+            (func $get_training_metrics (param i64 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "mnist")
+          (data (i32.const 80) "data/mnist")
+          (func (export "start") (param $epochs i32) (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (local.get $epochs)
+              (i32.const 80) (i32.const 10)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (i32.const 32)))
+          (func (export "metrics") (result i32)
+            (call $get_training_metrics (i64.load (i32.const 32)) (i32.const 48))))
+    "#;
+
+    /// Backend whose jobs record an epoch every time they are told to,
+    /// confirming each one.
+    struct Ticking {
+        tick: std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<()>>>,
+        ticked: std::sync::mpsc::Sender<u32>,
+    }
+
+    impl TrainingBackend for Ticking {
+        fn start(
+            &mut self,
+            _handle: FutureHandle,
+            req: TrainingRequest<'_>,
+            _protocol: &ProtocolConfig,
+        ) -> Result<TrainingStart, ApiError> {
+            let (tick, ticked, epochs) = (self.tick.clone(), self.ticked.clone(), req.epochs);
+            Ok(TrainingStart::Job(Box::new(move |progress| {
+                for epoch in 1..=epochs {
+                    let _ = tick.lock().unwrap().recv();
+                    progress.report(epoch, 1.0 / epoch as f32, 100.0 * epoch as f32);
+                    let _ = ticked.send(epoch);
+                }
+                Ok(Vec::new())
+            })))
+        }
+    }
+
+    /// The metrics the guest read.
+    fn guest_metrics(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+    ) -> TrainingMetrics {
+        let code: u32 = ModuleContext::call_export(&mut *store, instance, "metrics", ()).unwrap();
+        assert_eq!(code, ErrorCode::Success as u32);
+        let bytes = guest_bytes(store, instance, 48, 16);
+        let field = |at: usize| <[u8; 4]>::try_from(&bytes[at..at + 4]).unwrap();
+        TrainingMetrics {
+            epoch: u32::from_le_bytes(field(0)),
+            total_epochs: u32::from_le_bytes(field(4)),
+            loss: f32::from_le_bytes(field(8)),
+            samples_per_sec: f32::from_le_bytes(field(12)),
+        }
+    }
+
+    #[test]
+    fn guests_follow_the_progress_of_their_trainings() {
+        let (tick, ticks) = std::sync::mpsc::channel();
+        let (ticked, confirmations) = std::sync::mpsc::channel();
+        let backend = Ticking {
+            tick: std::sync::Arc::new(std::sync::Mutex::new(ticks)),
+            ticked,
+        };
+        let (mut store, instance) = ml_guest(
+            TRAINING_METRICS,
+            MLApiHost::default().with_backend(backend),
+            ModuleContext::builder(),
+        );
+        let code: u32 = ModuleContext::call_export(&mut store, &instance, "start", 2).unwrap();
+        assert_eq!(code, ErrorCode::Success as u32);
+        assert_eq!(
+            guest_metrics(&mut store, &instance),
+            TrainingMetrics {
+                total_epochs: 2,
+                ..TrainingMetrics::default()
+            }
+        );
+
+        let at_epoch = |epoch: u32| TrainingMetrics {
+            epoch,
+            total_epochs: 2,
+            loss: 1.0 / epoch as f32,
+            samples_per_sec: 100.0 * epoch as f32,
+        };
+        tick.send(()).unwrap();
+        assert_eq!(confirmations.recv().unwrap(), 1);
+        assert_eq!(guest_metrics(&mut store, &instance), at_epoch(1));
+        tick.send(()).unwrap();
+        assert_eq!(confirmations.recv().unwrap(), 2);
+
+        // Finished trainings keep reporting their last epoch
+        let handle = FutureHandle(output(&mut store, &instance));
+        let host = store.data_mut().module_mut::<MLApiHost>().unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        while host.poll_future_shim(handle).unwrap().state == FutureStatus::PENDING {
+            assert!(host.futures().wait(deadline), "the training never finished");
+        }
+        assert_eq!(
+            host.poll_future_shim(handle).unwrap().state,
+            FutureStatus::COMPLETED
+        );
+        assert_eq!(guest_metrics(&mut store, &instance), at_epoch(2));
+    }
+
+    #[test]
+    fn cancelled_trainings_keep_their_metrics() {
+        let mut host = MLApiHost::default();
+        let handle = host.futures_mut().insert(FutureState::Pending).unwrap();
+        let progress = TrainingProgress::new(5);
+        progress.report(3, 0.5, 10.0);
+        host.progress.insert(handle, progress);
+
+        host.cancel_training_shim(handle).unwrap();
+        let metrics = host.get_training_metrics_shim(handle).unwrap();
+        assert_eq!(
+            (metrics.epoch, metrics.total_epochs, metrics.loss),
+            (3, 5, 0.5)
+        );
+
+        host.free_future_shim(handle).unwrap();
+        let err = host.get_training_metrics_shim(handle).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        let err = host
+            .get_training_metrics_shim(FutureHandle::new(9, 1))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
     }
 }
@@ -0,0 +1,184 @@
+/// Caps on the resources a guest instance may allocate, installed as the
+/// `wasmtime::ResourceLimiter` of stores created with
+/// [`ModuleContext::into_store`](crate::ModuleContext::into_store).
+///
+/// Growing past a cap fails the way wasm specifies it, `memory.grow` and
+/// `table.grow` return `-1` to the guest instead of trapping.
+#[derive(Clone, Debug)]
+pub struct StoreLimiter {
+    memory_size: Option<usize>,
+    table_elements: Option<u32>,
+    instances: usize,
+    hit_memory_limit: bool,
+}
+
+impl Default for StoreLimiter {
+    fn default() -> Self {
+        Self {
+            memory_size: None,
+            table_elements: None,
+            instances: wasmtime::DEFAULT_INSTANCE_LIMIT,
+            hit_memory_limit: false,
+        }
+    }
+}
+
+impl StoreLimiter {
+    /// Maximum size of each linear memory in bytes, unlimited by default.
+    pub fn memory_size(&self) -> Option<usize> {
+        self.memory_size
+    }
+
+    /// Maximum number of elements of each table, unlimited by default.
+    pub fn table_elements(&self) -> Option<u32> {
+        self.table_elements
+    }
+
+    /// Maximum number of instances in the store, including the instances of
+    /// the guest's own imports.
+    pub fn instances(&self) -> usize {
+        self.instances
+    }
+
+    /// Whether a memory failed to grow because of the memory cap.
+    pub fn hit_memory_limit(&self) -> bool {
+        self.hit_memory_limit
+    }
+
+    pub(crate) fn set_memory_size(&mut self, bytes: usize) {
+        self.memory_size = Some(bytes);
+    }
+
+    pub(crate) fn set_table_elements(&mut self, elements: u32) {
+        self.table_elements = Some(elements);
+    }
+
+    pub(crate) fn set_instances(&mut self, instances: usize) {
+        self.instances = instances;
+    }
+}
+
+impl wasmtime::ResourceLimiter for StoreLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.memory_size {
+            Some(limit) if desired > limit => {
+                self.hit_memory_limit = true;
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        Ok(self
+            .table_elements
+            .is_none_or(|limit| desired <= limit as usize))
+    }
+
+    fn instances(&self) -> usize {
+        self.instances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ModuleContext;
+
+    const GREEDY: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (table 1 funcref)
+          ;; Grows the memory a page at a time until it fails, returning its
+          ;; size in pages
+          (func (export "grow_memory") (result i32)
+            (block $full
+              (loop $grow
+                (br_if $full (i32.eq (memory.grow (i32.const 1)) (i32.const -1)))
+                (br $grow)))
+            (memory.size))
+          (func (export "grow_table") (param i32) (result i32)
+            (table.grow (ref.null func) (local.get 0))))
+    "#;
+
+    fn greedy(store: &mut wasmtime::Store<ModuleContext>) -> wasmtime::Instance {
+        let module = wasmtime::Module::new(store.engine(), GREEDY).unwrap();
+        wasmtime::Instance::new(&mut *store, &module, &[]).unwrap()
+    }
+
+    fn run<R: wasmtime::WasmResults>(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+        name: &str,
+        params: impl wasmtime::WasmParams,
+    ) -> R {
+        ModuleContext::call_export(&mut *store, instance, name, params).unwrap()
+    }
+
+    fn store(builder: crate::ModuleContextBuilder) -> wasmtime::Store<ModuleContext> {
+        builder
+            .build()
+            .unwrap()
+            .into_store(&wasmtime::Engine::default())
+    }
+
+    #[test]
+    fn memory_stops_growing_at_the_cap() {
+        let mut store = store(ModuleContext::builder().with_memory_limit(5 * 65_536));
+        let instance = greedy(&mut store);
+        assert!(!store.data().hit_memory_limit());
+
+        let pages: u32 = run(&mut store, &instance, "grow_memory", ());
+        assert_eq!(pages, 5);
+        assert!(store.data().hit_memory_limit());
+        // The guest keeps running after the failed grow
+        let pages: u32 = run(&mut store, &instance, "grow_memory", ());
+        assert_eq!(pages, 5);
+    }
+
+    #[test]
+    fn caps_round_down_to_whole_pages() {
+        let mut store = store(ModuleContext::builder().with_memory_limit(3 * 65_536 - 1));
+        let instance = greedy(&mut store);
+        let pages: u32 = run(&mut store, &instance, "grow_memory", ());
+        assert_eq!(pages, 2);
+    }
+
+    #[test]
+    fn tables_stop_growing_at_the_cap() {
+        let mut store = store(ModuleContext::builder().with_table_limit(4));
+        let instance = greedy(&mut store);
+        let previous: i32 = run(&mut store, &instance, "grow_table", 3);
+        assert_eq!(previous, 1);
+        let previous: i32 = run(&mut store, &instance, "grow_table", 1);
+        assert_eq!(previous, -1);
+        // Table limits don't count as memory limits
+        assert!(!store.data().hit_memory_limit());
+    }
+
+    #[test]
+    fn instances_are_capped_per_store() {
+        let mut store = store(ModuleContext::builder().with_instance_limit(1));
+        greedy(&mut store);
+        let module = wasmtime::Module::new(store.engine(), GREEDY).unwrap();
+        assert!(wasmtime::Instance::new(&mut store, &module, &[]).is_err());
+    }
+
+    #[test]
+    fn limits_are_unset_by_default() {
+        let limits = ModuleContext::builder().build().unwrap().limits().clone();
+        assert_eq!(limits.memory_size(), None);
+        assert_eq!(limits.table_elements(), None);
+        assert_eq!(limits.instances(), wasmtime::DEFAULT_INSTANCE_LIMIT);
+        assert!(!limits.hit_memory_limit());
+    }
+}
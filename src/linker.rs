@@ -0,0 +1,1663 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::dynamic::DynImport;
+use crate::engine::{define_wasmtime, AliasedFunc, DeprecatedCall};
+use crate::manifest::ImportDoc;
+use crate::validation::{diagnose, guest_name};
+use crate::{
+    copy_to_guest_alloc, copy_to_guest_alloc_async, error_code_ranges, guest_memory, host_imports,
+    ApiError, CallSite, DynHostFunction, ErrorCode, FuncSignature, HostFunc, HostModule,
+    ImportAlias, ImportName, ImportParam, ImportParams, ImportReturn, ImportStatus,
+    InstantiationError, ModuleContext, ParamRole, PlainOldData, Runtime, Severity, Shim,
+    WasmLinker,
+};
+
+/// Name of the import every host module gets for reading the message of the
+/// instance's last error, registered as `{prefix}__get_last_error`.
+pub const GET_LAST_ERROR: &str = "get_last_error";
+
+/// Name of the import every host module gets for reading the message of the
+/// instance's last error into a buffer from the guest's allocator, registered
+/// as `{prefix}__get_last_error_alloc`.
+pub const GET_LAST_ERROR_ALLOC: &str = "get_last_error_alloc";
+
+/// Name of the import every host module gets for reading the fuel the guest
+/// has left, registered as `{prefix}__remaining_fuel`.
+pub const REMAINING_FUEL: &str = "remaining_fuel";
+
+/// Defines the host function of an import under another name, for
+/// deprecated aliases.
+type Redefine =
+    Box<dyn Fn(&mut WasmLinker, &'static str, DeprecatedCall) -> anyhow::Result<()> + Send + Sync>;
+
+/// A [`WasmLinker`] that remembers which host module registered each import,
+/// so that collisions are reported instead of silently shadowing or failing
+/// with an opaque wasmtime error.
+pub struct HostLinker {
+    linker: WasmLinker,
+    /// `(namespace, name)` of every registered import and the host module
+    /// that registered it.
+    registered: HashMap<(&'static str, &'static str), &'static str>,
+    /// Parameter names and roles of the imports, for the
+    /// [`manifest`](Self::manifest).
+    docs: HashMap<(&'static str, &'static str), ImportDoc>,
+    /// Imports registered through [`define_import`](Self::define_import) or
+    /// [`define_dynamic`](Self::define_dynamic), which deprecated aliases
+    /// can be registered for.
+    redefine: HashMap<(&'static str, &'static str), Redefine>,
+    /// Imports the embedder may replace, see [`Overrides`].
+    overrides: Overrides,
+    /// Imports the embedder replaced and the host module that registered
+    /// them first.
+    overridden: HashMap<(&'static str, &'static str), &'static str>,
+    is_async: bool,
+    memory64: bool,
+    stub_unknown_imports: bool,
+}
+
+impl HostLinker {
+    pub fn new(engine: &wasmtime::Engine) -> Self {
+        Self {
+            linker: WasmLinker::new(engine),
+            registered: HashMap::new(),
+            docs: HashMap::new(),
+            redefine: HashMap::new(),
+            overrides: Overrides::none(),
+            overridden: HashMap::new(),
+            is_async: false,
+            memory64: false,
+            stub_unknown_imports: false,
+        }
+    }
+
+    /// A linker for an engine configured with `Config::async_support(true)`.
+    /// Host modules register async variants of the imports that may block,
+    /// so guests have to be called with `call_async`.
+    pub fn new_async(engine: &wasmtime::Engine) -> Self {
+        Self {
+            is_async: true,
+            ..Self::new(engine)
+        }
+    }
+
+    /// Whether host modules should register async imports.
+    pub fn is_async(&self) -> bool {
+        self.is_async
+    }
+
+    /// Makes host modules register imports taking `u64` pointers and
+    /// lengths, for guests using memory64. Has to be set before any module
+    /// is registered, a linker serves guests of one pointer width.
+    pub fn with_memory64(mut self, memory64: bool) -> Self {
+        self.memory64 = memory64;
+        self
+    }
+
+    /// Whether imports take `u64` pointers and lengths.
+    pub fn is_memory64(&self) -> bool {
+        self.memory64
+    }
+
+    /// Makes [`instantiate`](Self::instantiate) satisfy function imports of
+    /// the guest that no host module provides with stubs that trap when
+    /// called, for trying out guests built against imports this host doesn't
+    /// implement yet. Imports with a mismatched signature still fail.
+    pub fn with_unknown_import_stubs(mut self, stub: bool) -> Self {
+        self.stub_unknown_imports = stub;
+        self
+    }
+
+    pub fn stubs_unknown_imports(&self) -> bool {
+        self.stub_unknown_imports
+    }
+
+    pub fn linker(&self) -> &WasmLinker {
+        &self.linker
+    }
+
+    pub fn linker_mut(&mut self) -> &mut WasmLinker {
+        &mut self.linker
+    }
+
+    pub fn into_inner(self) -> WasmLinker {
+        self.linker
+    }
+
+    /// Registers `func` as `namespace::name` on behalf of the host module
+    /// `module`.
+    pub fn func_wrap<Params, Args>(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        func: impl wasmtime::IntoFunc<ModuleContext, Params, Args>,
+    ) -> Result<(), InstantiationError> {
+        self.define(module, namespace, name, |linker| {
+            linker.func_wrap(namespace, name, func)
+        })
+    }
+
+    /// Registers a [`HostFunc`] as `namespace::name` on behalf of the host
+    /// module `module`, see [`Runtime::define_import`].
+    pub fn define_import<P: ImportParams, F: HostFunc<P>>(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        func: F,
+    ) -> Result<(), InstantiationError> {
+        let func = Arc::new(func);
+        self.define(module, namespace, name, |linker| {
+            define_wasmtime::<P, _>(
+                linker,
+                namespace,
+                name,
+                AliasedFunc::new(func.clone(), None),
+            )?;
+            Ok(linker)
+        })?;
+        let redefine: Redefine = Box::new(move |linker, alias, deprecated| {
+            let func = AliasedFunc::new(func.clone(), Some(deprecated));
+            define_wasmtime::<P, _>(linker, namespace, alias, func)
+        });
+        self.redefine.insert((namespace, name), redefine);
+        Ok(())
+    }
+
+    /// Registers a [`DynHostFunction`] as `namespace::{func.name}` on behalf
+    /// of the host module `M`, through one untyped `Linker::func_new`
+    /// trampoline shared by every signature.
+    pub fn define_dynamic<M: HostModule>(
+        &mut self,
+        namespace: &'static str,
+        func: DynHostFunction,
+    ) -> Result<(), InstantiationError> {
+        let import = DynImport {
+            site: CallSite::register(M::name(), func.name),
+            check_call: M::check_call,
+            log_call: M::log_call,
+            func,
+        };
+        self.define_dyn_import(M::name(), namespace, Arc::new(import))
+    }
+
+    fn define_dyn_import(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        import: Arc<DynImport>,
+    ) -> Result<(), InstantiationError> {
+        let name = import.func.name;
+        let ty = import.func.func_type(self.linker.engine());
+        let func = import.clone();
+        self.define(module, namespace, name, |linker| {
+            linker.func_new(
+                namespace,
+                name,
+                ty.clone(),
+                move |mut caller, args, results| Ok(func.call(&mut caller, None, args, results)?),
+            )
+        })?;
+        self.describe_import(namespace, name, import.func.doc(), ImportReturn::ErrorCode);
+        let redefine: Redefine = Box::new(move |linker, alias, deprecated| {
+            let func = import.clone();
+            linker.func_new(
+                namespace,
+                alias,
+                ty.clone(),
+                move |mut caller, args, results| {
+                    Ok(func.call(&mut caller, Some(&deprecated), args, results)?)
+                },
+            )?;
+            Ok(())
+        });
+        self.redefine.insert((namespace, name), redefine);
+        Ok(())
+    }
+
+    /// Registers an async import as `namespace::name` on behalf of the host
+    /// module `module`, which `define` adds to the underlying linker, e.g.
+    /// with `Linker::func_wrap_async`.
+    pub fn func_wrap_async(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        define: impl FnOnce(&mut WasmLinker) -> anyhow::Result<&mut WasmLinker>,
+    ) -> Result<(), InstantiationError> {
+        self.define(module, namespace, name, define)
+    }
+
+    fn define(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        define: impl FnOnce(&mut WasmLinker) -> anyhow::Result<&mut WasmLinker>,
+    ) -> Result<(), InstantiationError> {
+        let key = (namespace, ImportName::new(name).as_str());
+        if let Some(&first) = self.registered.get(&key) {
+            // Declared overrides replace the host module's import once, the
+            // last definition wins
+            if !self.overrides.contains(namespace, name) || self.overridden.contains_key(&key) {
+                return Err(InstantiationError::DuplicateImport {
+                    module: first,
+                    name: format!("{}::{}", namespace, name),
+                });
+            }
+            self.linker.allow_shadowing(true);
+            let defined = define(&mut self.linker).map(|_| ());
+            self.linker.allow_shadowing(false);
+            defined.map_err(InstantiationError::Import)?;
+            log::debug!(
+                "import `{}::{}` of host module `{}` is overridden by `{}`",
+                namespace,
+                name,
+                first,
+                module
+            );
+            self.overridden.insert(key, first);
+            self.redefine.remove(&key);
+            self.registered.insert(key, module);
+            return Ok(());
+        }
+        define(&mut self.linker).map_err(InstantiationError::Import)?;
+        self.registered.insert(key, module);
+        Ok(())
+    }
+
+    /// Instantiates `module` in `store`, recording the module's memory exports
+    /// so a missing memory is reported along with the ones it has.
+    ///
+    /// Guests importing host functions that take guest pointers without
+    /// exporting the memory they access fail with
+    /// [`InstantiationError::MissingMemoryExport`]. Imports the host can't
+    /// satisfy fail with [`InstantiationError::MissingImport`] or
+    /// [`InstantiationError::SignatureMismatch`] for the first of them, see
+    /// [`validate_module`](Self::validate_module) for all of them at once.
+    pub fn instantiate(
+        &self,
+        mut store: impl wasmtime::AsContextMut<Data = ModuleContext>,
+        module: &wasmtime::Module,
+    ) -> Result<wasmtime::Instance, InstantiationError> {
+        self.prepare_instance(store.as_context_mut().data_mut(), module)?;
+        let instance = if self.stub_unknown_imports {
+            self.with_stubs(module)?.instantiate(store, module)
+        } else {
+            self.linker.instantiate(store, module)
+        };
+        instance.map_err(|err| self.instantiation_error(module, err))
+    }
+
+    /// Async variant of [`instantiate`](Self::instantiate), for linkers
+    /// created with [`new_async`](Self::new_async).
+    pub async fn instantiate_async(
+        &self,
+        mut store: impl wasmtime::AsContextMut<Data = ModuleContext>,
+        module: &wasmtime::Module,
+    ) -> Result<wasmtime::Instance, InstantiationError> {
+        self.prepare_instance(store.as_context_mut().data_mut(), module)?;
+        let instance = if self.stub_unknown_imports {
+            let linker = self.with_stubs(module)?;
+            linker.instantiate_async(store, module).await
+        } else {
+            self.linker.instantiate_async(store, module).await
+        };
+        instance.map_err(|err| self.instantiation_error(module, err))
+    }
+
+    fn prepare_instance(
+        &self,
+        host_context: &mut ModuleContext,
+        module: &wasmtime::Module,
+    ) -> Result<(), InstantiationError> {
+        record_memories(host_context, module);
+        let name = host_context.memory_export();
+        let exports = host_context.guest_memories.as_deref().unwrap_or_default();
+        if exports.iter().any(|export| **export == *name) {
+            return Ok(());
+        }
+        let needs_memory = module.imports().any(|import| {
+            let doc = self.import_doc(import.module(), import.name());
+            matches!(import.ty(), wasmtime::ExternType::Func(_))
+                && doc.is_some_and(ImportDoc::takes_pointers)
+        });
+        if !needs_memory {
+            return Ok(());
+        }
+        Err(InstantiationError::MissingMemoryExport {
+            guest: guest_name(module),
+            name: name.to_owned(),
+            exports: exports.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    /// Classifies why wasmtime failed to instantiate `module`, falling back
+    /// to [`InstantiationError::Import`].
+    fn instantiation_error(
+        &self,
+        module: &wasmtime::Module,
+        err: anyhow::Error,
+    ) -> InstantiationError {
+        let mut store = wasmtime::Store::new(self.linker.engine(), ModuleContext::default());
+        let guest = guest_name(module);
+        diagnose(module, |namespace, name| {
+            let func = self.linker.get(&mut store, namespace, name)?.into_func()?;
+            let host_module = self.registered.get(&(namespace, name)).copied();
+            Some((FuncSignature::from(&func.ty(&store)), host_module))
+        })
+        .iter()
+        // Missing functions were stubbed, whatever failed wasn't one of them
+        .filter(|diagnostic| {
+            !self.stub_unknown_imports || diagnostic.status != ImportStatus::Missing
+        })
+        .find_map(|diagnostic| diagnostic.to_error(&guest))
+        .unwrap_or(InstantiationError::Import(err))
+    }
+
+    /// A copy of the linker with a trapping stub for every function import
+    /// of `module` it doesn't define, so the stubs don't collide with
+    /// imports registered later.
+    fn with_stubs(&self, module: &wasmtime::Module) -> Result<WasmLinker, InstantiationError> {
+        let mut store = wasmtime::Store::new(self.linker.engine(), ModuleContext::default());
+        let mut linker = self.linker.clone();
+        for import in module.imports() {
+            let (namespace, name) = (import.module(), import.name());
+            let ty = match import.ty() {
+                wasmtime::ExternType::Func(ty) => ty,
+                _ => continue,
+            };
+            if self.linker.get(&mut store, namespace, name).is_some() {
+                continue;
+            }
+            log::warn!(
+                "guest import `{}::{}` is not implemented, calling it traps",
+                namespace,
+                name
+            );
+            let message = format!(
+                "host function '{}' is not implemented in this host build (imported from '{}')",
+                name, namespace
+            );
+            linker
+                .func_new(namespace, name, ty, move |_caller, _params, _results| {
+                    Err(anyhow::Error::msg(message.clone()))
+                })
+                .map_err(InstantiationError::Import)?;
+        }
+        Ok(linker)
+    }
+
+    /// Makes the already registered import `namespace::{alias.import}`
+    /// available as `namespace::{alias.alias}` as well, see
+    /// [`Runtime::alias`].
+    pub fn alias(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        alias: ImportAlias,
+    ) -> Result<(), InstantiationError> {
+        let name = alias.import;
+        let key = (namespace, ImportName::new(alias.alias).as_str());
+        if let Some(first) = self.registered.get(&key) {
+            return Err(InstantiationError::DuplicateImport {
+                module: first,
+                name: format!("{}::{}", namespace, alias.alias),
+            });
+        }
+        match DeprecatedCall::new(module, &alias) {
+            // Deprecated aliases get their own function to tell their calls
+            // apart
+            Some(deprecated) => {
+                let redefine = self.redefine.get(&(namespace, name)).ok_or_else(|| {
+                    InstantiationError::Import(anyhow::anyhow!(
+                        "can't deprecate `{}::{}`, its import `{}` isn't registered through `define_import`",
+                        namespace,
+                        alias.alias,
+                        name
+                    ))
+                })?;
+                redefine(&mut self.linker, alias.alias, deprecated)
+            }
+            None => self
+                .linker
+                .alias(namespace, name, namespace, alias.alias)
+                .map(|_| ()),
+        }
+        .map_err(InstantiationError::Import)?;
+        self.registered.insert(key, module);
+        if let Some(doc) = self.docs.get(&(namespace, name)).cloned() {
+            self.docs.insert(key, doc);
+        }
+        Ok(())
+    }
+
+    /// Names the wasm level parameters of the registered import
+    /// `namespace::name` and says what its result means, for the
+    /// [`manifest`](Self::manifest). [`host_import!`](crate::host_import)
+    /// describes the imports it registers.
+    pub fn describe_import(
+        &mut self,
+        namespace: &'static str,
+        name: &'static str,
+        params: &[ImportParam],
+        returns: ImportReturn,
+    ) {
+        let doc = ImportDoc {
+            params: params.to_vec(),
+            returns,
+        };
+        self.docs
+            .insert((namespace, ImportName::new(name).as_str()), doc);
+    }
+
+    /// Every import registered so far, with its wasm signature as read back
+    /// from the underlying linker. Items defined on the
+    /// [`linker_mut`](Self::linker_mut) directly aren't included.
+    pub fn registered_imports(&self) -> RegisteredImports {
+        let mut store = wasmtime::Store::new(self.linker.engine(), ModuleContext::default());
+        let mut imports = self
+            .registered
+            .iter()
+            .filter_map(|((namespace, name), module)| {
+                let func = match self.linker.get(&mut store, namespace, name)? {
+                    wasmtime::Extern::Func(func) => func,
+                    _ => return None,
+                };
+                let ty = func.ty(&store);
+                Some(RegisteredImport {
+                    namespace,
+                    name: ImportName::new(name),
+                    module,
+                    overrides: self.overridden.get(&(*namespace, *name)).copied(),
+                    params: ty.params().collect(),
+                    results: ty.results().collect(),
+                })
+            })
+            .collect::<Vec<_>>();
+        imports.sort_by_key(|import| (import.namespace, import.name));
+        RegisteredImports { imports }
+    }
+
+    /// Whether an import `namespace::name` has been registered.
+    pub(crate) fn provides(&self, namespace: &'static str, name: &'static str) -> bool {
+        self.registered.contains_key(&(namespace, name))
+    }
+
+    pub(crate) fn import_doc(&self, namespace: &str, name: &str) -> Option<&ImportDoc> {
+        self.docs
+            .iter()
+            .find(|(key, _)| **key == (namespace, name))
+            .map(|(_, doc)| doc)
+    }
+}
+
+impl Runtime for HostLinker {
+    type Trap = crate::WasmTrap;
+
+    fn is_async(&self) -> bool {
+        HostLinker::is_async(self)
+    }
+
+    fn is_memory64(&self) -> bool {
+        HostLinker::is_memory64(self)
+    }
+
+    fn define_import<P: ImportParams, F: HostFunc<P>>(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        func: F,
+    ) -> Result<(), InstantiationError> {
+        HostLinker::define_import(self, module, namespace, name, func)
+    }
+
+    fn alias(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        alias: ImportAlias,
+    ) -> Result<(), InstantiationError> {
+        HostLinker::alias(self, module, namespace, alias)
+    }
+
+    fn describe_import(
+        &mut self,
+        namespace: &'static str,
+        name: &'static str,
+        params: &[ImportParam],
+        returns: ImportReturn,
+    ) {
+        HostLinker::describe_import(self, namespace, name, params, returns)
+    }
+}
+
+/// An import registered with a [`HostLinker`], as listed by
+/// [`HostLinker::registered_imports`].
+#[derive(Clone, Debug)]
+pub struct RegisteredImport {
+    pub namespace: &'static str,
+    pub name: ImportName,
+    /// [`HostModule::name`] of the module that registered the import.
+    pub module: &'static str,
+    /// The host module whose import of the same name the embedder replaced
+    /// with this one, see [`Overrides`].
+    pub overrides: Option<&'static str>,
+    pub params: Vec<wasmtime::ValType>,
+    pub results: Vec<wasmtime::ValType>,
+}
+
+impl RegisteredImport {
+    /// Number of wasm level parameters.
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+}
+
+impl PartialEq for RegisteredImport {
+    fn eq(&self, other: &Self) -> bool {
+        self.namespace == other.namespace
+            && self.name == other.name
+            && self.module == other.module
+            && self.overrides == other.overrides
+            && crate::validation::types_eq(&self.params, &other.params)
+            && crate::validation::types_eq(&self.results, &other.results)
+    }
+}
+
+impl Eq for RegisteredImport {}
+
+impl fmt::Display for RegisteredImport {
+    /// `namespace::name(params) -> results [module]`, with `, overrides
+    /// {module}` in the brackets for overridden imports.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}::{}", self.namespace, self.name)?;
+        crate::validation::write_signature(f, &self.params, &self.results)?;
+        match self.overrides {
+            Some(overridden) => write!(f, " [{}, overrides {}]", self.module, overridden),
+            None => write!(f, " [{}]", self.module),
+        }
+    }
+}
+
+/// Every import registered with a [`HostLinker`], sorted by namespace and
+/// name. Displays as one import per line, for logging at startup or diffing
+/// against the imports of a guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegisteredImports {
+    imports: Vec<RegisteredImport>,
+}
+
+impl RegisteredImports {
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredImport> + '_ {
+        self.imports.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.imports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.imports.is_empty()
+    }
+
+    pub fn get(&self, namespace: &str, name: &str) -> Option<&RegisteredImport> {
+        self.imports
+            .iter()
+            .find(|import| import.namespace == namespace && import.name == name)
+    }
+
+    /// The imports registered by the host module named `module`.
+    pub fn of_module<'s>(
+        &'s self,
+        module: &'s str,
+    ) -> impl Iterator<Item = &'s RegisteredImport> + 's {
+        self.imports
+            .iter()
+            .filter(move |import| import.module == module)
+    }
+}
+
+impl fmt::Display for RegisteredImports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for import in &self.imports {
+            writeln!(f, "{}", import)?;
+        }
+        Ok(())
+    }
+}
+
+/// Imports of host modules that the embedder replaces with its own
+/// functions, e.g. `time__unix_ms` with a frozen clock for replay.
+///
+/// After [`register_host_modules`] registered the host modules, each of these
+/// imports can be registered once more on the linker, replacing the host
+/// module's function. Registering any other import twice still fails with
+/// [`InstantiationError::DuplicateImport`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Overrides(BTreeSet<(&'static str, &'static str)>);
+
+impl Overrides {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Allows replacing the import `namespace::name`.
+    pub fn with(mut self, namespace: &'static str, name: &'static str) -> Self {
+        self.0.insert((namespace, name));
+        self
+    }
+
+    pub fn contains(&self, namespace: &str, name: &str) -> bool {
+        self.0
+            .iter()
+            .any(|&(known_namespace, known)| known_namespace == namespace && known == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+type RegisterFn = fn(&mut HostLinker) -> Result<(), InstantiationError>;
+
+#[derive(Clone)]
+struct RegisteredModule {
+    name: &'static str,
+    register: RegisterFn,
+    error_code_range: Option<Range<u32>>,
+    error_codes: &'static [ErrorCode],
+}
+
+/// The set of host modules whose imports [`register_host_modules`] adds to a
+/// linker. Downstream crates can add their own modules with
+/// [`ModuleRegistry::with_module`].
+#[derive(Clone)]
+pub struct ModuleRegistry {
+    modules: Vec<RegisteredModule>,
+}
+
+impl Default for ModuleRegistry {
+    /// All host modules provided by this crate.
+    fn default() -> Self {
+        Self::empty()
+            .with_module::<crate::MLApiHost>()
+            .with_module::<crate::StorageApiHost>()
+            .with_module::<crate::LoggingApiHost>()
+            .with_module::<crate::TimeApiHost>()
+            .with_module::<crate::RandomApiHost>()
+            .with_module::<crate::MetricsApiHost>()
+            .with_module::<crate::DatasetApiHost>()
+            .with_module::<crate::ModelApiHost>()
+    }
+}
+
+impl ModuleRegistry {
+    pub fn empty() -> Self {
+        Self {
+            modules: Vec::new(),
+        }
+    }
+
+    pub fn with_module<M>(mut self) -> Self
+    where
+        M: HostModule
+            + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+    {
+        let register: RegisterFn = |linker| {
+            M::imports(linker)?;
+            let (namespace, prefix) = M::namespace();
+            if linker.is_memory64() {
+                register_error_imports::<u64>(linker, M::name(), namespace, prefix)?;
+            } else {
+                register_error_imports::<u32>(linker, M::name(), namespace, prefix)?;
+            }
+            let name = ImportName::join(prefix, REMAINING_FUEL).as_str();
+            linker.func_wrap(M::name(), namespace, name, remaining_fuel)?;
+            linker.describe_import(namespace, name, &[], ImportReturn::Value);
+            Ok(())
+        };
+        self.modules.push(RegisteredModule {
+            name: M::name(),
+            register,
+            error_code_range: M::error_code_range(),
+            error_codes: M::error_codes(),
+        });
+        self
+    }
+
+    pub fn module_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.modules.iter().map(|module| module.name)
+    }
+
+    /// The error code range each module reserved, see
+    /// [`HostModule::error_code_range`].
+    pub fn error_code_ranges(&self) -> impl Iterator<Item = (&'static str, Range<u32>)> + '_ {
+        self.modules.iter().filter_map(|module| {
+            let range = module.error_code_range.clone()?;
+            Some((module.name, range))
+        })
+    }
+
+    /// Checks that the error code ranges of the modules don't overlap each
+    /// other or the common codes, and that every module only returns codes
+    /// that are common or in its own range.
+    pub fn check_error_codes(&self) -> Result<(), InstantiationError> {
+        let overlap = |a: &Range<u32>, b: &Range<u32>| a.start < b.end && b.start < a.end;
+        for (i, module) in self.modules.iter().enumerate() {
+            if let Some(range) = &module.error_code_range {
+                let other = if overlap(range, &error_code_ranges::COMMON) {
+                    Some(None)
+                } else {
+                    self.modules[i + 1..]
+                        .iter()
+                        .find(|other| {
+                            other
+                                .error_code_range
+                                .as_ref()
+                                .is_some_and(|other| overlap(range, other))
+                        })
+                        .map(|other| Some(other.name))
+                };
+                if let Some(other) = other {
+                    return Err(InstantiationError::ErrorCodeRangeOverlap {
+                        module: module.name,
+                        other,
+                        range: range.clone(),
+                    });
+                }
+            }
+            let reserved = |code: ErrorCode| {
+                module
+                    .error_code_range
+                    .as_ref()
+                    .is_some_and(|range| range.contains(&(code as u32)))
+            };
+            let outside = module
+                .error_codes
+                .iter()
+                .find(|&&code| code.is_common() || !reserved(code));
+            if let Some(&code) = outside {
+                return Err(InstantiationError::ErrorCodeOutOfRange {
+                    module: module.name,
+                    code,
+                    range: module.error_code_range.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers the imports of every module in `modules`, attributing failures
+/// to the module that caused them. The embedder can replace the imports in
+/// `overrides` afterwards.
+pub fn register_host_modules(
+    linker: &mut HostLinker,
+    modules: &ModuleRegistry,
+    overrides: &Overrides,
+) -> Result<(), InstantiationError> {
+    modules.check_error_codes()?;
+    for module in &modules.modules {
+        (module.register)(linker).map_err(|err| InstantiationError::HostModule {
+            module: module.name,
+            source: Box::new(err),
+        })?;
+    }
+    // Shared by every registry, so only the first call adds them
+    let namespace = "env";
+    if !linker.provides(namespace, host_imports::HAS_CAPABILITY) {
+        if linker.is_memory64() {
+            linker.func_wrap(
+                host_imports::PREFIX,
+                namespace,
+                host_imports::HAS_CAPABILITY,
+                has_capability::<u64>,
+            )?;
+        } else {
+            linker.func_wrap(
+                host_imports::PREFIX,
+                namespace,
+                host_imports::HAS_CAPABILITY,
+                has_capability::<u32>,
+            )?;
+        }
+        linker.describe_import(
+            namespace,
+            host_imports::HAS_CAPABILITY,
+            &[
+                ImportParam::new("name_ptr", ParamRole::StrPtr),
+                ImportParam::new("name_len", ParamRole::Len),
+            ],
+            ImportReturn::Value,
+        );
+        linker.func_wrap(
+            host_imports::PREFIX,
+            namespace,
+            host_imports::ABI_VERSION,
+            abi_version,
+        )?;
+        linker.describe_import(
+            namespace,
+            host_imports::ABI_VERSION,
+            &[],
+            ImportReturn::Value,
+        );
+    }
+    linker.overrides.0.extend(overrides.iter());
+    Ok(())
+}
+
+/// Guest address width, `u32` or `u64` for memory64 guests.
+trait GuestAddr: PlainOldData + Default + Into<u64> + TryFrom<u64> + wasmtime::WasmTy + Send {}
+
+impl GuestAddr for u32 {}
+impl GuestAddr for u64 {}
+
+fn register_error_imports<A: GuestAddr>(
+    linker: &mut HostLinker,
+    module: &'static str,
+    namespace: &'static str,
+    prefix: &str,
+) -> Result<(), InstantiationError> {
+    let name = ImportName::join(prefix, GET_LAST_ERROR).as_str();
+    linker.func_wrap(module, namespace, name, get_last_error::<A>)?;
+    linker.describe_import(
+        namespace,
+        name,
+        &[
+            ImportParam::new("buf_ptr", ParamRole::SlicePtr { elem: "u8" }),
+            ImportParam::new("buf_len", ParamRole::Len),
+        ],
+        ImportReturn::Value,
+    );
+    let alloc_name = ImportName::join(prefix, GET_LAST_ERROR_ALLOC).as_str();
+    // The allocator is guest code, which async stores can only call
+    // asynchronously
+    if linker.is_async() {
+        linker.func_wrap_async(module, namespace, alloc_name, |linker| {
+            linker.func_wrap_async(
+                namespace,
+                alloc_name,
+                |caller, (ptr_out, len_out): (A, A)| {
+                    get_last_error_alloc_async(caller, ptr_out, len_out)
+                },
+            )
+        })?;
+    } else {
+        linker.func_wrap(module, namespace, alloc_name, get_last_error_alloc::<A>)?;
+    }
+    let pointee = std::any::type_name::<A>();
+    linker.describe_import(
+        namespace,
+        alloc_name,
+        &[
+            ImportParam::new("ptr_out", ParamRole::Out { pointee }),
+            ImportParam::new("len_out", ParamRole::Out { pointee }),
+        ],
+        ImportReturn::ErrorCode,
+    );
+    Ok(())
+}
+
+/// `get_last_error(buf_ptr: u32, buf_len: u32) -> u32`: copies the message of
+/// the instance's last error into the guest buffer and returns its length, `0`
+/// if the last call succeeded. If the buffer is too small nothing is written
+/// and the required length is returned instead. Reading the message doesn't
+/// clear it, so the guest can retry with a larger buffer.
+fn get_last_error<A: GuestAddr>(
+    mut caller: wasmtime::Caller<'_, ModuleContext>,
+    buf_ptr: A,
+    buf_len: A,
+) -> Result<u32, crate::WasmTrap> {
+    let trap = |err: crate::ApiError| anyhow::Error::msg(err.display().to_string());
+    let (mut memory, host_context) = guest_memory(&mut caller).map_err(trap)?;
+    let message = match host_context.last_error() {
+        Some(err) => err.guest_message(),
+        None => return Ok(0),
+    };
+    if message.len() as u64 <= buf_len.into() {
+        memory
+            .write_pod_slice(buf_ptr.into(), message.as_bytes())
+            .map_err(trap)?;
+    }
+    Ok(u32::try_from(message.len()).unwrap_or(u32::MAX))
+}
+
+/// `host__has_capability(name_ptr: u32, name_len: u32) -> u32`: `1` if the
+/// instance may call the host module of that name, else `0`, see
+/// [`Capabilities`](crate::Capabilities).
+fn has_capability<A: GuestAddr>(
+    mut caller: wasmtime::Caller<'_, ModuleContext>,
+    name_ptr: A,
+    name_len: A,
+) -> Result<u32, crate::WasmTrap> {
+    let trap = |err: crate::ApiError| anyhow::Error::msg(err.display().to_string());
+    let (memory, host_context) = guest_memory(&mut caller).map_err(trap)?;
+    let name = memory
+        .read_str(name_ptr.into(), name_len.into())
+        .map_err(trap)?;
+    Ok(u32::from(host_context.capabilities().allows(name)))
+}
+
+/// `host__abi_version() -> u32`: the host's [`AbiVersion`](crate::AbiVersion)
+/// as `major << 16 | minor`.
+fn abi_version() -> u32 {
+    crate::AbiVersion::HOST.raw()
+}
+
+fn record_memories(host_context: &mut ModuleContext, module: &wasmtime::Module) {
+    let memories = module
+        .exports()
+        .filter(|export| matches!(export.ty(), wasmtime::ExternType::Memory(_)))
+        .map(|export| export.name().into())
+        .collect();
+    host_context.guest_memories = Some(memories);
+}
+
+/// `get_last_error_alloc(ptr_out: u32, len_out: u32) -> u32`: copies the
+/// message of the instance's last error into a buffer from the guest's
+/// [`host_alloc`](crate::GUEST_ALLOC_EXPORT) export and writes its address and
+/// length, both `0` if the last call succeeded. Returns an error code,
+/// `InvalidArgument` for guests without an allocator, which can use
+/// `get_last_error` instead. Like that, it doesn't clear the message.
+///
+/// The address and length are `u64`s for memory64 guests.
+fn get_last_error_alloc<A: GuestAddr>(
+    mut caller: wasmtime::Caller<'_, ModuleContext>,
+    ptr_out: A,
+    len_out: A,
+) -> Result<u32, crate::WasmTrap> {
+    let message = match prepare_alloc_out(&mut caller, ptr_out, len_out) {
+        Ok(message) => message,
+        Err(err) => return alloc_out_code(&mut caller, err),
+    };
+    let copied = copy_to_guest_alloc(&mut caller, message.as_bytes(), 1);
+    finish_alloc_out(&mut caller, copied, message.len(), ptr_out, len_out)
+}
+
+fn get_last_error_alloc_async<'c, A: GuestAddr>(
+    mut caller: wasmtime::Caller<'c, ModuleContext>,
+    ptr_out: A,
+    len_out: A,
+) -> Box<dyn std::future::Future<Output = Result<u32, crate::WasmTrap>> + Send + 'c> {
+    Box::new(async move {
+        let message = match prepare_alloc_out(&mut caller, ptr_out, len_out) {
+            Ok(message) => message,
+            Err(err) => return alloc_out_code(&mut caller, err),
+        };
+        let copied = copy_to_guest_alloc_async(&mut caller, message.as_bytes(), 1).await;
+        finish_alloc_out(&mut caller, copied, message.len(), ptr_out, len_out)
+    })
+}
+
+/// The last error message, after checking that the output pointers can be
+/// written so a guest allocation is never lost.
+fn prepare_alloc_out<A: GuestAddr>(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    ptr_out: A,
+    len_out: A,
+) -> Result<String, ApiError> {
+    let (mut memory, host_context) = guest_memory(caller)?;
+    memory.write_pod(ptr_out.into(), &A::default())?;
+    memory.write_pod(len_out.into(), &A::default())?;
+    Ok(host_context
+        .last_error()
+        .map(ApiError::guest_message)
+        .unwrap_or_default())
+}
+
+fn finish_alloc_out<A: GuestAddr>(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    copied: Result<u64, ApiError>,
+    len: usize,
+    ptr_out: A,
+    len_out: A,
+) -> Result<u32, crate::WasmTrap> {
+    let written = copied.and_then(|ptr| {
+        // The allocation is in guest memory, so its address and length only
+        // don't fit an `A` if the allocator is of the other width
+        let narrow = |value: u64| {
+            A::try_from(value).map_err(|_| {
+                ApiError::new(
+                    ErrorCode::OutOfBounds,
+                    format!("{:#x} doesn't fit the guest's pointer width", value),
+                )
+            })
+        };
+        let (ptr, len) = (narrow(ptr)?, narrow(len as u64)?);
+        let (mut memory, _) = guest_memory(caller)?;
+        memory.write_pod(ptr_out.into(), &ptr)?;
+        memory.write_pod(len_out.into(), &len)
+    });
+    match written {
+        Ok(()) => Ok(ErrorCode::Success as u32),
+        Err(err) => alloc_out_code(caller, err),
+    }
+}
+
+fn alloc_out_code(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    err: ApiError,
+) -> Result<u32, crate::WasmTrap> {
+    if err.severity() == Severity::Fatal {
+        caller.data_mut().fatal_error = Some(err.clone());
+        return Err(anyhow::Error::msg(err.display().to_string()));
+    }
+    Ok(err.code() as u32)
+}
+
+/// `remaining_fuel() -> u64`: the fuel the guest has left, `u64::MAX` if the
+/// store doesn't consume fuel.
+fn remaining_fuel(caller: wasmtime::Caller<'_, ModuleContext>) -> u64 {
+    ModuleContext::remaining_fuel(caller).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Guest};
+
+    /// Registers a `() -> i32` import on behalf of `module`.
+    fn define(
+        linker: &mut HostLinker,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+    ) -> Result<(), InstantiationError> {
+        linker.func_wrap_async(module, namespace, name, |linker| {
+            linker.func_wrap(namespace, name, || 0_i32)
+        })
+    }
+
+    #[test]
+    fn duplicates_name_the_module_that_registered_first() {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        define(&mut linker, "first", "env", "shared__ping").unwrap();
+        // Same name in another namespace, or another name in the same one
+        define(&mut linker, "second", "other", "shared__ping").unwrap();
+        define(&mut linker, "second", "env", "shared__pong").unwrap();
+
+        for module in ["second", "first"] {
+            match define(&mut linker, module, "env", "shared__ping") {
+                Err(InstantiationError::DuplicateImport { module, name }) => {
+                    assert_eq!((module, name.as_str()), ("first", "env::shared__ping"));
+                }
+                res => panic!("`{}` registered a duplicate: {:?}", module, res),
+            }
+        }
+    }
+
+    #[test]
+    fn aliases_share_the_import_but_not_its_name() {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        define(&mut linker, "first", "env", "shared__ping").unwrap();
+        define(&mut linker, "first", "env", "shared__pong").unwrap();
+        let alias = ImportAlias::new("shared__ping_v1", "shared__ping");
+        linker.alias("first", "env", alias).unwrap();
+        let imports = linker.registered_imports();
+        let aliased = imports.get("env", "shared__ping_v1").unwrap();
+        assert_eq!(aliased.module, "first");
+        assert!(
+            matches!(aliased.results[..], [wasmtime::ValType::I32]),
+            "{:?}",
+            aliased.results
+        );
+
+        for taken in ["shared__pong", "shared__ping_v1"] {
+            let alias = ImportAlias::new(taken, "shared__ping");
+            match linker.alias("second", "env", alias) {
+                Err(InstantiationError::DuplicateImport { module, name }) => {
+                    assert_eq!(module, "first");
+                    assert_eq!(name, format!("env::{}", taken));
+                }
+                res => panic!("aliased onto `{}`: {:?}", taken, res),
+            }
+        }
+        let alias = ImportAlias::new("shared__missing_v1", "shared__missing");
+        assert!(matches!(
+            linker.alias("first", "env", alias),
+            Err(InstantiationError::Import(_))
+        ));
+    }
+
+    #[test]
+    fn ml_api_imports_are_listed_with_their_signatures() {
+        let linker = test_support::linker::<crate::MLApiHost>(&wasmtime::Engine::default());
+        let imports = linker.registered_imports();
+
+        let start = imports.get("env", "ml__start_training").unwrap();
+        assert_eq!(start.module, "ml_api");
+        assert_eq!(start.overrides, None);
+        assert_eq!(start.arity(), 15);
+        assert!(
+            matches!(start.results[..], [wasmtime::ValType::I32]),
+            "{:?}",
+            start.results
+        );
+        assert!(imports.get("env", "ml__poll_future").is_some());
+        assert!(imports.get("env", "ml__no_such_import").is_none());
+        assert!(imports.of_module("ml_api").count() > 1);
+        assert_eq!(imports.of_module("ml_api").count() + 2, imports.len());
+
+        let names: Vec<_> = imports
+            .iter()
+            .map(|import| (import.namespace, import.name.as_str()))
+            .collect();
+        let mut sorted = names.clone();
+        sorted.sort_unstable();
+        assert_eq!(names, sorted);
+        let listing = imports.to_string();
+        assert_eq!(listing.lines().count(), imports.len());
+        assert!(
+            listing.contains("env::ml__get_last_error(i32, i32) -> i32 [ml_api]\n"),
+            "{}",
+            listing
+        );
+    }
+
+    #[test]
+    fn empty_linkers_list_no_imports() {
+        let linker = HostLinker::new(&wasmtime::Engine::default());
+        let imports = linker.registered_imports();
+        assert!(imports.is_empty());
+        assert_eq!(imports.to_string(), "");
+    }
+
+    /// A guest importing a real and an unknown ML API function.
+    const HALF_IMPLEMENTED: &str = r#"
+        (module
+          (import "env" "ml__get_last_error"
+            (func $get_last_error (param i32 i32) (result i32)))
+          (import "env" "ml__export_model"
+            (func $export_model (param i32 i64) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "last_error") (result i32)
+            (call $get_last_error (i32.const 0) (i32.const 0)))
+          (func (export "export_model") (result i32)
+            (call $export_model (i32.const 0) (i64.const 0))))
+    "#;
+
+    fn ml_api_linker(engine: &wasmtime::Engine, stub: bool) -> HostLinker {
+        let mut linker = HostLinker::new(engine).with_unknown_import_stubs(stub);
+        test_support::register::<crate::MLApiHost>(&mut linker);
+        linker
+    }
+
+    #[test]
+    fn unknown_imports_trap_only_when_called() {
+        let engine = wasmtime::Engine::default();
+        let linker = ml_api_linker(&engine, true);
+        assert!(linker.stubs_unknown_imports());
+        let context = ModuleContext::builder().with_module(crate::MLApiHost::default());
+        let (mut store, instance) =
+            test_support::instantiate(&engine, &linker, HALF_IMPLEMENTED, context);
+
+        let len: u32 = ModuleContext::call_export(&mut store, &instance, "last_error", ()).unwrap();
+        assert_eq!(len, 0);
+        let err = ModuleContext::call_export::<(), u32>(&mut store, &instance, "export_model", ())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "host function 'ml__export_model' is not implemented in this host build \
+                 (imported from 'env')"
+            ),
+            "{}",
+            err
+        );
+        // The stubs only exist for this instantiation
+        assert!(linker
+            .registered_imports()
+            .get("env", "ml__export_model")
+            .is_none());
+    }
+
+    #[test]
+    fn unknown_imports_fail_without_stubs() {
+        let engine = wasmtime::Engine::default();
+        let linker = ml_api_linker(&engine, false);
+        let module = wasmtime::Module::new(&engine, HALF_IMPLEMENTED).unwrap();
+        let mut store = ModuleContext::builder()
+            .build()
+            .unwrap()
+            .into_store(&engine);
+        match linker.instantiate(&mut store, &module) {
+            Err(InstantiationError::MissingImport { name, .. }) => {
+                assert_eq!(name, "ml__export_model")
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn stubs_dont_hide_signature_mismatches() {
+        let wat = r#"
+            (module
+              (import "env" "ml__get_last_error" (func (param i32) (result i32)))
+              (import "env" "ml__export_model" (func))
+              (memory (export "memory") 1))
+        "#;
+        let engine = wasmtime::Engine::default();
+        let linker = ml_api_linker(&engine, true);
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = ModuleContext::builder()
+            .build()
+            .unwrap()
+            .into_store(&engine);
+        match linker.instantiate(&mut store, &module) {
+            Err(InstantiationError::SignatureMismatch { name, .. }) => {
+                assert_eq!(name, "env::ml__get_last_error")
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    /// Instantiates `wat` against the ML API with a default context.
+    fn instantiate_ml_api(wat: &str) -> Result<wasmtime::Instance, InstantiationError> {
+        let engine = wasmtime::Engine::default();
+        let linker = ml_api_linker(&engine, false);
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = ModuleContext::builder()
+            .build()
+            .unwrap()
+            .into_store(&engine);
+        linker.instantiate(&mut store, &module)
+    }
+
+    #[test]
+    fn instantiation_errors_name_the_guest() {
+        let missing = r#"
+            (module $trainer
+              (import "env" "ml__export_model" (func)))
+        "#;
+        match instantiate_ml_api(missing) {
+            Err(InstantiationError::MissingImport {
+                guest,
+                namespace,
+                name,
+            }) => {
+                assert_eq!(guest, "trainer");
+                assert_eq!(namespace, "env");
+                assert_eq!(name, "ml__export_model");
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let mismatched = r#"
+            (module $trainer
+              (import "env" "host__abi_version" (func (result i64))))
+        "#;
+        match instantiate_ml_api(mismatched) {
+            Err(InstantiationError::SignatureMismatch {
+                guest,
+                name,
+                expected,
+                found,
+            }) => {
+                assert_eq!(guest, "trainer");
+                assert_eq!(name, "env::host__abi_version");
+                assert!(
+                    matches!(expected.results[..], [wasmtime::ValType::I32]),
+                    "{:?}",
+                    expected.results
+                );
+                assert!(
+                    matches!(found.results[..], [wasmtime::ValType::I64]),
+                    "{:?}",
+                    found.results
+                );
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn pointer_imports_need_the_memory_export() {
+        let renamed = r#"
+            (module $trainer
+              (import "env" "ml__get_last_error" (func (param i32 i32) (result i32)))
+              (memory (export "heap") 1))
+        "#;
+        match instantiate_ml_api(renamed) {
+            Err(InstantiationError::MissingMemoryExport {
+                guest,
+                name,
+                exports,
+            }) => {
+                assert_eq!(guest, "trainer");
+                assert_eq!(name, "memory");
+                assert_eq!(exports, ["heap"]);
+            }
+            res => panic!("{:?}", res),
+        }
+
+        // Imports without pointers don't access memory
+        let no_pointers = r#"
+            (module
+              (import "env" "host__abi_version" (func (result i32))))
+        "#;
+        assert!(instantiate_ml_api(no_pointers).is_ok());
+    }
+
+    #[test]
+    fn module_errors_wrap_instantiation_errors() {
+        let missing = r#"(module (import "env" "ml__export_model" (func)))"#;
+        let err = crate::ModuleError::from(instantiate_ml_api(missing).unwrap_err());
+        assert!(matches!(
+            err,
+            crate::ModuleError::Instantiation(InstantiationError::MissingImport { .. })
+        ));
+    }
+
+    #[test]
+    fn last_error_messages_are_copied_until_a_call_succeeds() {
+        let wat = r#"
+            (module
+              (import "env" "ml__start_training"
+                (func $start_training
+                  (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+                  (result i32)))
+              (import "env" "ml__get_last_error"
+                (func $get_last_error (param i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 64) "mnist")
+              (func (export "start") (param $epochs i32) (result i32)
+                (call $start_training
+                  (i32.const 64) (i32.const 5)
+                  (local.get $epochs)
+                  (i32.const 64) (i32.const 5)
+                  (i32.const 0) (i32.const 0)
+                  (i32.const 0)
+                  (i32.const 0) (i32.const 0)
+                  (i32.const 0) (i32.const 0)
+                  (i64.const 42)
+                  (i32.const 0)
+                  (i32.const 32)))
+              (func (export "last_error") (param $len i32) (result i32)
+                (call $get_last_error (i32.const 128) (local.get $len))))
+        "#;
+        let (mut store, instance) = test_support::guest::<crate::MLApiHost>(
+            wat,
+            ModuleContext::builder()
+                .with_module(crate::MLApiHost::default())
+                .with_job_threads(1),
+        );
+        let mut call = |name: &str, arg: u32| -> u32 {
+            ModuleContext::call_export(&mut store, &instance, name, arg).unwrap()
+        };
+        assert_eq!(call("last_error", 256), 0);
+
+        assert_eq!(call("start", 0), crate::ErrorCode::InvalidArgument as u32);
+        let message = "InvalidArgument(1): epochs must not be zero";
+        let len = message.len() as u32;
+        assert_eq!(call("last_error", len - 1), len);
+        assert_eq!(call("last_error", len), len);
+        assert_eq!(call("last_error", 256), len);
+
+        assert_eq!(call("start", 3), crate::ErrorCode::Success as u32);
+        assert_eq!(call("last_error", 256), 0);
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let written = &memory.data(&store)[128..128 + message.len() + 1];
+        assert_eq!(&written[..message.len()], message.as_bytes());
+        assert_eq!(written[message.len()], 0);
+    }
+
+    /// A guest with a bump allocator exported as `ALLOC`, whose `$mode` makes
+    /// it hand out null (`1`), out of bounds (`2`) or trap (`3`) instead.
+    const ALLOC_GUEST: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (import "env" "ml__get_last_error_alloc"
+            (func $get_last_error_alloc (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "mnist")
+          (global $next (mut i32) (i32.const 1024))
+          (global $mode (mut i32) (i32.const 0))
+          (func (export "ALLOC") (param $len i32) (param $align i32) (result i32)
+            (local $ptr i32)
+            (if (i32.eq (global.get $mode) (i32.const 1)) (then (return (i32.const 0))))
+            (if (i32.eq (global.get $mode) (i32.const 2)) (then (return (i32.const 0x10000))))
+            (if (i32.eq (global.get $mode) (i32.const 3)) (then (unreachable)))
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "set_mode") (param $mode i32)
+            (global.set $mode (local.get $mode)))
+          (func (export "fail") (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (i32.const 0)
+              (i32.const 64) (i32.const 5)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (i32.const 32)))
+          (func (export "last_error") (result i32)
+            (call $get_last_error_alloc (i32.const 16) (i32.const 20))))
+    "#;
+
+    /// Instantiates [`ALLOC_GUEST`] with its allocator exported as `alloc`.
+    fn alloc_guest(alloc: &str) -> Guest {
+        test_support::guest::<crate::MLApiHost>(
+            ALLOC_GUEST.replace("ALLOC", alloc),
+            ModuleContext::builder()
+                .with_module(crate::MLApiHost::default())
+                .with_job_threads(1),
+        )
+    }
+
+    /// The address and length `get_last_error_alloc` wrote.
+    fn alloc_out(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+    ) -> (u32, u32) {
+        let memory = instance.get_memory(&mut *store, "memory").unwrap();
+        let data = memory.data(&*store);
+        let word = |at: usize| {
+            u32::from_le_bytes(std::convert::TryInto::try_into(&data[at..at + 4]).unwrap())
+        };
+        (word(16), word(20))
+    }
+
+    #[test]
+    fn last_error_messages_are_copied_into_guest_allocations() {
+        let (mut store, instance) = alloc_guest(crate::GUEST_ALLOC_EXPORT);
+        let last_error = |store: &mut wasmtime::Store<ModuleContext>| -> u32 {
+            ModuleContext::call_export(store, &instance, "last_error", ()).unwrap()
+        };
+        assert_eq!(last_error(&mut store), crate::ErrorCode::Success as u32);
+        assert_eq!(alloc_out(&mut store, &instance), (0, 0));
+
+        let failed: u32 = ModuleContext::call_export(&mut store, &instance, "fail", ()).unwrap();
+        assert_eq!(failed, crate::ErrorCode::InvalidArgument as u32);
+        let message = "InvalidArgument(1): epochs must not be zero";
+        assert_eq!(last_error(&mut store), crate::ErrorCode::Success as u32);
+        assert_eq!(
+            alloc_out(&mut store, &instance),
+            (1024, message.len() as u32)
+        );
+        // Every call allocates a buffer of its own.
+        assert_eq!(last_error(&mut store), crate::ErrorCode::Success as u32);
+        let (ptr, len) = alloc_out(&mut store, &instance);
+        assert_eq!(ptr, 1024 + message.len() as u32);
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let copied = &memory.data(&store)[ptr as usize..(ptr + len) as usize];
+        assert_eq!(copied, message.as_bytes());
+    }
+
+    #[test]
+    fn bad_guest_allocations_are_reported() {
+        for (mode, code) in [
+            (1, crate::ErrorCode::OutOfBounds),
+            (2, crate::ErrorCode::OutOfBounds),
+        ] {
+            let (mut store, instance) = alloc_guest(crate::GUEST_ALLOC_EXPORT);
+            let _: u32 = ModuleContext::call_export(&mut store, &instance, "fail", ()).unwrap();
+            ModuleContext::call_export::<u32, ()>(&mut store, &instance, "set_mode", mode).unwrap();
+            let got: u32 =
+                ModuleContext::call_export(&mut store, &instance, "last_error", ()).unwrap();
+            assert_eq!(got, code as u32, "mode {}", mode);
+            assert_eq!(alloc_out(&mut store, &instance), (0, 0), "mode {}", mode);
+        }
+
+        let (mut store, instance) = alloc_guest("not_an_allocator");
+        let _: u32 = ModuleContext::call_export(&mut store, &instance, "fail", ()).unwrap();
+        let got: u32 = ModuleContext::call_export(&mut store, &instance, "last_error", ()).unwrap();
+        assert_eq!(got, crate::ErrorCode::InvalidArgument as u32);
+    }
+
+    #[test]
+    fn trapping_guest_allocators_are_fatal() {
+        let (mut store, instance) = alloc_guest(crate::GUEST_ALLOC_EXPORT);
+        let _: u32 = ModuleContext::call_export(&mut store, &instance, "fail", ()).unwrap();
+        ModuleContext::call_export::<u32, ()>(&mut store, &instance, "set_mode", 3).unwrap();
+        let err = ModuleContext::call_export::<(), u32>(&mut store, &instance, "last_error", ())
+            .unwrap_err();
+        match err {
+            crate::ModuleError::HostError(err) => {
+                assert!(
+                    err.display().to_string().contains("`host_alloc` trapped"),
+                    "{}",
+                    err.display()
+                )
+            }
+            err => panic!("trapping allocator wasn't fatal: {}", err),
+        }
+    }
+
+    /// A memory64 guest starting a training of the model named at `$name`.
+    const MEMORY64_GUEST: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i64 i64 i32 i64 i64 i64 i64 i32 i64 i64 i64 i64 i64 i64 i64)
+              (result i32)))
+          (import "env" "ml__get_last_error"
+            (func $get_last_error (param i64 i64) (result i32)))
+          (memory (export "memory") i64 1)
+          (data (i64.const 64) "mnist")
+          (func (export "start") (param $name i64) (param $epochs i32) (result i32)
+            (call $start_training
+              (local.get $name) (i64.const 5)
+              (local.get $epochs)
+              (i64.const 64) (i64.const 5)
+              (i64.const 0) (i64.const 0)
+              (i32.const 0)
+              (i64.const 0) (i64.const 0)
+              (i64.const 0) (i64.const 0)
+              (i64.const 42)
+              (i64.const 0)
+              (i64.const 32)))
+          (func (export "last_error") (result i32)
+            (call $get_last_error (i64.const 128) (i64.const 256))))
+    "#;
+
+    #[test]
+    fn memory64_guests_start_trainings() {
+        let mut config = wasmtime::Config::new();
+        config.wasm_memory64(true);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        let mut linker = HostLinker::new(&engine).with_memory64(true);
+        test_support::register::<crate::MLApiHost>(&mut linker);
+        let context = ModuleContext::builder()
+            .with_module(crate::MLApiHost::default())
+            .with_job_threads(1);
+        let (mut store, instance) =
+            test_support::instantiate(&engine, &linker, MEMORY64_GUEST, context);
+        let mut start = |name: u64, epochs: u32| -> u32 {
+            ModuleContext::call_export(&mut store, &instance, "start", (name, epochs)).unwrap()
+        };
+
+        assert_eq!(start(64, 3), crate::ErrorCode::Success as u32);
+        // An address past 4 GiB mustn't wrap around to the name at 64.
+        assert_eq!(
+            start((1 << 32) + 64, 3),
+            crate::ErrorCode::OutOfBounds as u32
+        );
+        assert_eq!(start(64, 0), crate::ErrorCode::InvalidArgument as u32);
+        let len: u32 = ModuleContext::call_export(&mut store, &instance, "last_error", ()).unwrap();
+        let message = "InvalidArgument(1): epochs must not be zero";
+        assert_eq!(len, message.len() as u32);
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let data = memory.data(&store);
+        assert_eq!(&data[128..128 + message.len()], message.as_bytes());
+        assert_ne!(&data[32..40], &[0; 8], "no future handle written");
+    }
+
+    #[test]
+    fn memory64_linkers_register_u64_pointers() {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine).with_memory64(true);
+        assert!(linker.is_memory64());
+        test_support::register::<crate::MLApiHost>(&mut linker);
+        let manifest = linker.manifest();
+        assert_eq!(manifest.pointer_type, "i64");
+        let start = manifest.namespaces["env"]
+            .iter()
+            .find(|import| import.name == "ml__start_training")
+            .unwrap();
+        let types: Vec<_> = start.params.iter().map(|param| param.ty).collect();
+        assert_eq!(&types[..3], ["i64", "i64", "i32"]);
+    }
+
+    #[test]
+    fn guests_see_their_remaining_fuel() {
+        let wat = r#"
+            (module
+              (import "env" "ml__remaining_fuel" (func $remaining_fuel (result i64)))
+              (memory (export "memory") 1)
+              (func (export "spent") (result i64)
+                (local $before i64)
+                (local $n i32)
+                (local.set $before (call $remaining_fuel))
+                (local.set $n (i32.const 100))
+                (loop $next
+                  (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+                  (br_if $next (local.get $n)))
+                (i64.sub (local.get $before) (call $remaining_fuel)))
+              (func (export "remaining") (result i64)
+                (call $remaining_fuel)))
+        "#;
+        let spent = |builder: crate::ModuleContextBuilder| {
+            let mut config = wasmtime::Config::new();
+            builder.configure_engine(&mut config);
+            let engine = wasmtime::Engine::new(&config).unwrap();
+            let linker = test_support::linker::<crate::MLApiHost>(&engine);
+            let context = builder
+                .with_module(crate::MLApiHost::default())
+                .with_job_threads(1);
+            let (mut store, instance) = test_support::instantiate(&engine, &linker, wat, context);
+            let remaining: u64 =
+                ModuleContext::call_export(&mut store, &instance, "remaining", ()).unwrap();
+            let spent: u64 =
+                ModuleContext::call_export(&mut store, &instance, "spent", ()).unwrap();
+            (remaining, spent)
+        };
+
+        let (remaining, spent_on_loop) = spent(ModuleContext::builder().with_fuel(100_000));
+        assert!(remaining < 100_000 && remaining > 99_000, "{}", remaining);
+        assert!(spent_on_loop > 100, "{}", spent_on_loop);
+        assert_eq!(
+            spent(ModuleContext::builder().with_fuel(100_000)).1,
+            spent_on_loop
+        );
+        // Without metering there is no limit
+        assert_eq!(spent(ModuleContext::builder()), (u64::MAX, 0));
+    }
+}
@@ -0,0 +1,205 @@
+use std::borrow::Cow;
+
+use crate::{
+    error_code_ranges, ApiError, ErrorCode, HostLinker, HostModule, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod logging_imports = "log" {
+    WRITE = "write",
+});
+
+/// Host module forwarding guest log lines to the `log` crate.
+///
+/// Guest lines are logged with the target `{prefix}::{guest target}`, by
+/// default `guest::…`, so embedders can filter them separately from host
+/// logs. Rate limits can be applied with [`HostModule::check_call`] like for
+/// any other import.
+pub struct LoggingApiHost {
+    target_prefix: Cow<'static, str>,
+    max_message_len: usize,
+}
+
+impl Default for LoggingApiHost {
+    fn default() -> Self {
+        Self {
+            target_prefix: Cow::Borrowed("guest"),
+            max_message_len: Self::DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+impl LoggingApiHost {
+    pub const DEFAULT_MAX_MESSAGE_LEN: usize = 4096;
+
+    /// Prefix of the `log` target of every guest line, e.g. the name of the
+    /// instance.
+    pub fn with_target_prefix(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        self.target_prefix = prefix.into();
+        self
+    }
+
+    /// Messages longer than `len` bytes are truncated with an ellipsis.
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len;
+        self
+    }
+
+    /// The `log` target guest lines with `target` are logged with.
+    pub fn target(&self, target: &str) -> String {
+        if target.is_empty() {
+            self.target_prefix.to_string()
+        } else {
+            format!("{}::{}", self.target_prefix, target)
+        }
+    }
+
+    pub fn write(&self, level: u32, target: &str, message: &str) -> Result<(), ApiError> {
+        let level = level_from_raw(level)?;
+        let target = self.target(target);
+        log::log!(target: &target, level, "{}", truncate(message, self.max_message_len));
+        Ok(())
+    }
+}
+
+impl HostModule for LoggingApiHost {
+    fn name() -> &'static str {
+        "logging_api"
+    }
+    fn log_target() -> &'static str {
+        "host::logging_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::LOGGING)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for LoggingApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", logging_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        // A mangled message is more useful than none, so only the target has
+        // to be valid UTF-8
+        host_import!(linker, LoggingApiHost, logging_imports::WRITE, (
+            level: u32,
+            target: GuestStr,
+            message: GuestStr,
+        ) => |host, memory| {
+            host.write(level, target.read(memory)?, &message.read_lossy(memory)?)
+        })?;
+        Ok(())
+    }
+}
+
+/// Guest levels are the `log::Level` discriminants, `1` for error to `5` for
+/// trace.
+fn level_from_raw(level: u32) -> Result<log::Level, ApiError> {
+    match level {
+        1 => Ok(log::Level::Error),
+        2 => Ok(log::Level::Warn),
+        3 => Ok(log::Level::Info),
+        4 => Ok(log::Level::Debug),
+        5 => Ok(log::Level::Trace),
+        _ => Err(ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!("log level must be 1 (error) to 5 (trace), got {}", level),
+        )),
+    }
+}
+
+fn truncate(message: &str, max_len: usize) -> Cow<'_, str> {
+    if message.len() <= max_len {
+        return Cow::Borrowed(message);
+    }
+    let mut end = max_len;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}…", &message[..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::guest;
+
+    #[test]
+    fn levels_map_to_the_log_crate() {
+        let levels: Vec<_> = (1..=5).map(|raw| level_from_raw(raw).unwrap()).collect();
+        assert_eq!(
+            levels,
+            [
+                log::Level::Error,
+                log::Level::Warn,
+                log::Level::Info,
+                log::Level::Debug,
+                log::Level::Trace
+            ]
+        );
+        for raw in [0, 6, u32::MAX] {
+            let err = level_from_raw(raw).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn long_messages_are_truncated_at_a_char_boundary() {
+        assert_eq!(truncate("short", 5), "short");
+        assert!(matches!(truncate("short", 5), Cow::Borrowed(_)));
+        assert_eq!(truncate("longer", 4), "long…");
+        // `é` takes two bytes, so cutting at 2 would split it
+        assert_eq!(truncate("aéb", 2), "a…");
+        assert_eq!(truncate("aéb", 0), "…");
+    }
+
+    #[test]
+    fn targets_are_prefixed() {
+        let host = LoggingApiHost::default();
+        assert_eq!(host.target("train"), "guest::train");
+        assert_eq!(host.target(""), "guest");
+        let host = host.with_target_prefix("instance-3");
+        assert_eq!(host.target("train"), "instance-3::train");
+    }
+
+    /// Writes the target at 16 and the message at 32 of the given lengths at
+    /// `level`.
+    const WRITE: &str = r#"
+        (module
+          (import "env" "log__write" (func $write (param i32 i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "train\ff")
+          (data (i32.const 32) "loss \ff")
+          (func (export "write") (param $level i32) (param $target_len i32) (param $len i32)
+            (result i32)
+            (call $write (local.get $level)
+              (i32.const 16) (local.get $target_len)
+              (i32.const 32) (local.get $len))))
+    "#;
+
+    #[test]
+    fn guests_write_lines_with_valid_levels_and_targets() {
+        let context = ModuleContext::builder().with_module(LoggingApiHost::default());
+        let (mut store, instance) = guest::<LoggingApiHost>(WRITE, context);
+        let mut write = |params: (u32, u32, u32)| -> u32 {
+            ModuleContext::call_export(&mut store, &instance, "write", params).unwrap()
+        };
+
+        assert_eq!(write((3, 5, 5)), ErrorCode::Success as u32);
+        // Messages are read lossily, targets have to be valid UTF-8
+        assert_eq!(write((1, 5, 6)), ErrorCode::Success as u32);
+        assert_eq!(write((1, 6, 5)), ErrorCode::InvalidUtf8 as u32);
+        assert_eq!(write((0, 5, 5)), ErrorCode::InvalidArgument as u32);
+    }
+}
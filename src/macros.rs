@@ -0,0 +1,1008 @@
+/// Declares a module of static import names sharing a prefix, so that the
+/// names registered with the linker and the ones used for logging and stats
+/// are the same `&'static str`.
+///
+/// ```
+/// rustc_nightly_reduction::import_names!(pub mod example = "ex" {
+///     DO_THING = "do_thing",
+/// });
+/// assert_eq!(example::DO_THING, "ex__do_thing");
+/// ```
+#[macro_export]
+macro_rules! import_names {
+    ($vis:vis mod $module:ident = $prefix:literal { $($name:ident = $function:literal,)* }) => {
+        $vis mod $module {
+            pub const PREFIX: &str = $prefix;
+            $(pub const $name: &str = concat!($prefix, "__", $function);)*
+        }
+    };
+}
+
+/// Registers a host function with a [`HostLinker`](crate::HostLinker),
+/// generating the wasm level signature and the marshalling of its arguments.
+///
+/// Parameters are declared with the type the shim sees:
+///
+/// - `name: str` is passed by the guest as a `(ptr, len)` pair and bound as
+///   `&str`,
+/// - `config: &T` is a pointer to a [`FromGuest`](crate::FromGuest)
+///   value, usually [`PlainOldData`](crate::PlainOldData), that is read out of
+///   guest memory and bound as `&T`,
+/// - `config: Option<&T>` is the same, bound as `Option<T>` that is `None`
+///   for a null pointer,
+/// - `out: *mut T` is a pointer the `Ok` value of the body is written to
+///   on success, without it the body must return `Ok(())`. A null pointer is
+///   rejected before the body runs,
+/// - `data: GuestPtr<T>`, `data: GuestSlice<T>` and `name: GuestStr` are
+///   bound as the typed [`GuestPtr`](crate::GuestPtr) wrappers without
+///   touching guest memory, for bodies that take the memory handle with
+///   `|host, memory|` to access it themselves (as `&mut WasmMemoryHandle`,
+///   so they can't be combined with `str` or `&T` parameters),
+/// - integer and float parameters are passed through as is.
+///
+/// Pointers and lengths are `u32`s at the wasm level, or `u64`s for
+/// memory64 guests if the linker was created
+/// [`with_memory64`](crate::HostLinker::with_memory64). The body sees them
+/// as `u64`s either way.
+///
+/// Null pointers are rejected with `InvalidArgument`, except for empty
+/// strings and slices and `Option<&T>` parameters.
+///
+/// Marshalling failures are returned to the guest as error codes through
+/// [`HostModule::log_call`](crate::HostModule::log_call), like errors returned
+/// by the body.
+/// A panicking body traps the guest and poisons its
+/// [`ModuleContext`](crate::ModuleContext).
+///
+/// With `=> async |host| body` the import is registered through
+/// [`HostLinker::func_wrap_async`](crate::HostLinker::func_wrap_async) and
+/// `body` can `.await`, e.g. an async shim method. Such imports can only be
+/// registered with a [`HostLinker::new_async`](crate::HostLinker::new_async)
+/// linker.
+///
+/// The body is compiled into a standalone function, so `$module` has to name
+/// the host module type rather than `Self`.
+///
+/// The parameter names and kinds are recorded with
+/// [`HostLinker::describe_import`](crate::HostLinker::describe_import) for the
+/// linker's [`manifest`](crate::HostLinker::manifest). `str` and
+/// `GuestSlice<T>` parameters appear as `{name}_ptr` and `{name}_len`.
+///
+/// ```ignore
+/// host_import!(linker, MLApiHost, ml_imports::EVALUATE, (
+///     model_name: str,
+///     steps: u32,
+///     protocol: &ProtocolConfig,
+///     out: *mut FutureHandle,
+/// ) => |host| host.evaluate(model_name, steps, protocol));
+/// ```
+#[macro_export]
+macro_rules! host_import {
+    ($linker:expr, $module:ty, $import:expr, ($($params:tt)*)
+        => |$host:ident $(, $memory:ident)?| $body:expr) => {{
+        let result = $crate::host_import!(@munch (memory, value)
+            ($linker, $module, $import, sync, [$host $(, $memory)?], $body, [$($params)*])
+            [] [] [] [] [] [let () = value;] $($params)*);
+        $crate::host_import!(@describe result, $linker, $module, $import, $($params)*)
+    }};
+    ($linker:expr, $module:ty, $import:expr, ($($params:tt)*)
+        => async |$host:ident $(, $memory:ident)?| $body:expr) => {{
+        let result = $crate::host_import!(@munch (memory, value)
+            ($linker, $module, $import, async, [$host $(, $memory)?], $body, [$($params)*])
+            [] [] [] [] [] [let () = value;] $($params)*);
+        $crate::host_import!(@describe result, $linker, $module, $import, $($params)*)
+    }};
+
+    // Describes the registered import for `HostLinker::manifest`, from the
+    // same parameter list its signature was generated from
+    (@describe $result:ident, $linker:expr, $module:ty, $import:expr, $($params:tt)*) => {{
+        if $result.is_ok() {
+            let (namespace, _prefix) = <$module as $crate::Shim<'_>>::namespace();
+            $crate::Runtime::describe_import(
+                &mut *$linker,
+                namespace,
+                $import,
+                &$crate::host_import!(@doc [] $($params)*),
+                $crate::ImportReturn::ErrorCode,
+            );
+        }
+        $result
+    }};
+    (@doc [$($d:tt)*]) => { [$($d)*] };
+    (@doc [$($d:tt)*] $arg:ident: GuestStr $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(concat!(stringify!($arg), "_ptr"), $crate::ParamRole::StrPtr),
+            $crate::ImportParam::new(concat!(stringify!($arg), "_len"), $crate::ParamRole::Len),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: GuestSlice<$ty:ty> $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(
+                concat!(stringify!($arg), "_ptr"),
+                $crate::ParamRole::SlicePtr { elem: stringify!($ty) },
+            ),
+            $crate::ImportParam::new(concat!(stringify!($arg), "_len"), $crate::ParamRole::Len),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: GuestPtr<$ty:ty> $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(
+                stringify!($arg),
+                $crate::ParamRole::Ptr { pointee: stringify!($ty) },
+            ),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: str $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*] $arg: GuestStr $(, $($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: Option<&$ty:ty> $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(
+                stringify!($arg),
+                $crate::ParamRole::In { pointee: stringify!($ty), nullable: true },
+            ),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: &$ty:ty $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(
+                stringify!($arg),
+                $crate::ParamRole::In { pointee: stringify!($ty), nullable: false },
+            ),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: *mut $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(
+                stringify!($arg),
+                $crate::ParamRole::Out { pointee: stringify!($ty) },
+            ),
+        ] $($($rest)*)?)
+    };
+    (@doc [$($d:tt)*] $arg:ident: $ty:ident $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@doc [$($d)*
+            $crate::ImportParam::new(stringify!($arg), $crate::ParamRole::Value),
+        ] $($($rest)*)?)
+    };
+
+    // Every rule appends to the wasm parameters of 32-bit guests, those of
+    // memory64 guests, which the body takes as well, the statements widening
+    // the former to the latter, the arguments passed to the body and the
+    // statements decoding them
+    (@munch $idents:tt $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*] [$($d:tt)*] $o:tt
+        $arg:ident: GuestStr $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@munch $idents $ctx
+            [$($p)* ptr: u32, len: u32,]
+            [$($q)* ptr: u64, len: u64,]
+            [$($w)* let ptr = u64::from(ptr); let len = u64::from(len);]
+            [$($a)* ptr, len,]
+            [$($d)* let $arg = $crate::GuestStr::new(ptr, len);]
+            $o $($($rest)*)?)
+    };
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: GuestSlice<$ty:ty>
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@slice $idents $ctx $p $q $w $a $d $o $arg $ty; $($($rest)*)?)
+    };
+    (@slice $idents:tt $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*] [$($d:tt)*] $o:tt
+        $arg:ident $ty:ty; $($rest:tt)*) => {
+        $crate::host_import!(@munch $idents $ctx
+            [$($p)* ptr: u32, len: u32,]
+            [$($q)* ptr: u64, len: u64,]
+            [$($w)* let ptr = u64::from(ptr); let len = u64::from(len);]
+            [$($a)* ptr, len,]
+            [$($d)* let $arg = $crate::GuestSlice::<$ty>::new(ptr, len);]
+            $o $($rest)*)
+    };
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: GuestPtr<$ty:ty>
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@ptr $idents $ctx $p $q $w $a $d $o $arg $ty; $($($rest)*)?)
+    };
+    (@ptr $idents:tt $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*] [$($d:tt)*] $o:tt
+        $arg:ident $ty:ty; $($rest:tt)*) => {
+        $crate::host_import!(@munch $idents $ctx
+            [$($p)* ptr: u32,]
+            [$($q)* ptr: u64,]
+            [$($w)* let ptr = u64::from(ptr);]
+            [$($a)* ptr,]
+            [$($d)* let $arg = $crate::GuestPtr::<$ty>::new(ptr);]
+            $o $($rest)*)
+    };
+
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: str
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@str $idents $ctx $p $q $w $a $d $o $arg $($($rest)*)?)
+    };
+    (@str ($mem:ident, $value:ident) $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*]
+        [$($d:tt)*] $o:tt $arg:ident $($rest:tt)*) => {
+        $crate::host_import!(@munch ($mem, $value) $ctx
+            [$($p)* ptr: u32, len: u32,]
+            [$($q)* ptr: u64, len: u64,]
+            [$($w)* let ptr = u64::from(ptr); let len = u64::from(len);]
+            [$($a)* ptr, len,]
+            [$($d)* let $arg = $crate::GuestStr::new(ptr, len).read(&$mem)?;]
+            $o $($rest)*)
+    };
+
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: Option<&$ty:ty>
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@opt_ref $idents $ctx $p $q $w $a $d $o $arg $ty; $($($rest)*)?)
+    };
+    (@opt_ref ($mem:ident, $value:ident) $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*]
+        [$($d:tt)*] $o:tt $arg:ident $ty:ty; $($rest:tt)*) => {
+        $crate::host_import!(@munch ($mem, $value) $ctx
+            [$($p)* ptr: u32,]
+            [$($q)* ptr: u64,]
+            [$($w)* let ptr = u64::from(ptr);]
+            [$($a)* ptr,]
+            [$($d)* let $arg = match $crate::GuestPtr::<$ty>::new(ptr) {
+                ptr if ptr.is_null() => None,
+                ptr => Some(<$ty as $crate::FromGuest>::from_guest(&$mem, ptr)?),
+            };]
+            $o $($rest)*)
+    };
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: &$ty:ty
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@ref $idents $ctx $p $q $w $a $d $o $arg $ty; $($($rest)*)?)
+    };
+    (@ref ($mem:ident, $value:ident) $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*]
+        [$($d:tt)*] $o:tt $arg:ident $ty:ty; $($rest:tt)*) => {
+        $crate::host_import!(@munch ($mem, $value) $ctx
+            [$($p)* ptr: u32,]
+            [$($q)* ptr: u64,]
+            [$($w)* let ptr = u64::from(ptr);]
+            [$($a)* ptr,]
+            [$($d)* let $arg = &<$ty as $crate::FromGuest>::from_guest(&$mem, $crate::GuestPtr::new(ptr))?;]
+            $o $($rest)*)
+    };
+
+    (@munch $idents:tt $ctx:tt $p:tt $q:tt $w:tt $a:tt $d:tt $o:tt $arg:ident: *mut $ty:ty
+        $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@out $idents $ctx $p $q $w $a $d $o $arg $ty; $($($rest)*)?)
+    };
+    (@out ($mem:ident, $value:ident) $ctx:tt [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*]
+        [$($d:tt)*] $o:tt $arg:ident $ty:ty; $($rest:tt)*) => {
+        $crate::host_import!(@munch ($mem, $value) $ctx
+            [$($p)* ptr: u32,]
+            [$($q)* ptr: u64,]
+            [$($w)* let ptr = u64::from(ptr);]
+            [$($a)* ptr,]
+            [$($d)* let ptr = $crate::GuestPtr::<$ty>::new(ptr).non_null()?;]
+            [ptr.write(&mut $mem, &$value)?;]
+            $($rest)*)
+    };
+
+    (@munch $idents:tt $ctx:tt [$($p:tt)*] [$($q:tt)*] $w:tt [$($a:tt)*] $d:tt $o:tt
+        $arg:ident: $ty:ident $(, $($rest:tt)*)?) => {
+        $crate::host_import!(@munch $idents $ctx [$($p)* $arg: $ty,] [$($q)* $arg: $ty,] $w
+            [$($a)* $arg,] $d $o $($($rest)*)?)
+    };
+
+    (@munch ($mem:ident, $value:ident)
+        ($linker:expr, $module:ty, $import:expr, sync, [$host:ident $(, $memory:ident)?], $body:expr,
+            $params:tt)
+        [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*] [$($d:tt)*] [$($o:tt)*]) => {{
+        // The real body lives in a non-generic, out of line function taking
+        // the caller of any `Runtime`, so that the closure handed to the
+        // runtime, which wasmtime monomorphizes into a large `IntoFunc`
+        // instantiation per import, stays a thin trampoline.
+        #[inline(never)]
+        #[allow(clippy::too_many_arguments)]
+        fn body(
+            caller: &mut dyn $crate::HostCaller,
+            site: $crate::CallSite,
+            $($q)*
+        ) -> $crate::HostResult {
+            $crate::__host_call_span!(sync $import, site, { $crate::__audited!(sync caller, site, $params, [$($a)*], {
+                if let Some(code) = caller.context_mut().admit_call(site) {
+                    return Ok(code);
+                }
+                let started = caller.context().call_started();
+                // Unwinding into the runtime isn't supported everywhere, and
+                // the host state of the instance can't be trusted after a panic
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| -> Result<(), $crate::ApiError> {
+                    caller.context().check_poisoned()?;
+                    <$module as $crate::HostModule>::check_call(caller.context(), site)?;
+                    let _lease = caller.context().lease_module(site)?;
+                    caller.context_mut().enter_call(site);
+                    #[allow(unused_mut, unused_variables)]
+                    let (mut $mem, host_context) = caller.memory()?;
+                    $($d)*
+                    let $host = <$module as $crate::HostModule>::get(host_context)?;
+                    $(let $memory = &mut $mem;)?
+                    let $value = $body?;
+                    $($o)*
+                    Ok(())
+                }));
+                caller.context_mut().call_finished(site, started);
+                let result = match result {
+                    Ok(result) => result,
+                    Err(payload) => return Err(caller.context_mut().poison(site, payload)),
+                };
+                <$module as $crate::HostModule>::log_call(caller.context_mut(), site, result)
+            }) })
+        }
+
+        let site = $crate::CallSite::register(<$module as $crate::HostModule>::name(), $import);
+        let (namespace, _prefix) = <$module as $crate::Shim<'_>>::namespace();
+        if $crate::Runtime::is_memory64(&*$linker) {
+            $crate::Runtime::define_import(
+                &mut *$linker,
+                <$module as $crate::HostModule>::name(),
+                namespace,
+                $import,
+                move |caller: &mut dyn $crate::HostCaller, $($q)*| body(caller, site, $($a)*),
+            )
+        } else {
+            $crate::Runtime::define_import(
+                &mut *$linker,
+                <$module as $crate::HostModule>::name(),
+                namespace,
+                $import,
+                move |caller: &mut dyn $crate::HostCaller, $($p)*| {
+                    $($w)*
+                    body(caller, site, $($a)*)
+                },
+            )
+        }
+    }};
+
+    (@munch ($mem:ident, $value:ident)
+        ($linker:expr, $module:ty, $import:expr, async, [$host:ident $(, $memory:ident)?], $body:expr,
+            $params:tt)
+        [$($p:tt)*] [$($q:tt)*] [$($w:tt)*] [$($a:tt)*] [$($d:tt)*] [$($o:tt)*]) => {{
+        #[allow(clippy::too_many_arguments)]
+        async fn body(
+            mut caller: $crate::__wasmtime::Caller<'_, $crate::ModuleContext>,
+            site: $crate::CallSite,
+            $($q)*
+        ) -> Result<u32, $crate::WasmTrap> {
+            let result: $crate::HostResult = async { $crate::__host_call_span!(async $import, site, { $crate::__audited!(async caller, site, $params, [$($a)*], {
+                if let Some(code) = caller.data_mut().admit_call(site) {
+                    return Ok(code);
+                }
+                let started = caller.data().call_started();
+                let result = $crate::catch_unwind_async(async {
+                    caller.data().check_poisoned()?;
+                    <$module as $crate::HostModule>::check_call(caller.data(), site)?;
+                    let _lease = caller.data().lease_module(site)?;
+                    caller.data_mut().enter_call(site);
+                    #[allow(unused_mut, unused_variables)]
+                    let (mut $mem, host_context) = $crate::guest_memory(&mut caller)?;
+                    $($d)*
+                    let $host = <$module as $crate::HostModule>::get(host_context)?;
+                    $(let $memory = &mut $mem;)?
+                    let $value = $body?;
+                    $($o)*
+                    Ok::<(), $crate::ApiError>(())
+                })
+                .await;
+                caller.data_mut().call_finished(site, started);
+                let result = match result {
+                    Ok(result) => result,
+                    Err(payload) => return Err(caller.data_mut().poison(site, payload)),
+                };
+                <$module as $crate::HostModule>::log_call(caller.data_mut(), site, result)
+            }) }) }.await;
+            Ok(result?)
+        }
+
+        let site = $crate::CallSite::register(<$module as $crate::HostModule>::name(), $import);
+        let (namespace, _prefix) = <$module as $crate::Shim<'_>>::namespace();
+        if $linker.is_memory64() {
+            $linker.func_wrap_async(
+                <$module as $crate::HostModule>::name(),
+                namespace,
+                $import,
+                |linker| linker.func_wrap_async(namespace, $import,
+                    move |caller: $crate::__wasmtime::Caller<'_, $crate::ModuleContext>,
+                        ($($a)*): $crate::host_import!(@tuple $($q)*)| {
+                        Box::new(body(caller, site, $($a)*))
+                    }),
+            )
+        } else {
+            $linker.func_wrap_async(
+                <$module as $crate::HostModule>::name(),
+                namespace,
+                $import,
+                |linker| linker.func_wrap_async(namespace, $import,
+                    move |caller: $crate::__wasmtime::Caller<'_, $crate::ModuleContext>,
+                        ($($a)*): $crate::host_import!(@tuple $($p)*)| {
+                        $($w)*
+                        Box::new(body(caller, site, $($a)*))
+                    }),
+            )
+        }
+    }};
+
+    // The tuple of wasm level parameter types async imports are wrapped
+    // with
+    (@tuple $($arg:ident: $ty:ty,)*) => {
+        ($($ty,)*)
+    };
+}
+
+/// Runs the body of a [`host_import!`] function, recording the call with the
+/// instance's [`AuditSink`](crate::AuditSink) once it returned.
+#[cfg(feature = "audit")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __audited {
+    (sync $caller:ident, $site:ident, [$($params:tt)*], [$($a:ident,)*], $call:block) => {{
+        const PARAMS: &[$crate::ImportParam] = &$crate::host_import!(@doc [] $($params)*);
+        let result = (|| -> $crate::HostResult { $call })();
+        $caller.context().audit($site, PARAMS, &[$(&$a as &dyn std::fmt::Display),*], &result);
+        result
+    }};
+    (async $caller:ident, $site:ident, [$($params:tt)*], [$($a:ident,)*], $call:block) => {{
+        const PARAMS: &[$crate::ImportParam] = &$crate::host_import!(@doc [] $($params)*);
+        let result: $crate::HostResult = async { $call }.await;
+        $caller.data().audit($site, PARAMS, &[$(&$a as &dyn std::fmt::Display),*], &result);
+        result
+    }};
+}
+
+/// Without the `audit` feature host calls aren't recorded at all.
+#[cfg(not(feature = "audit"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __audited {
+    ($kind:ident $caller:ident, $site:ident, $params:tt, $a:tt, $call:block) => {
+        $call
+    };
+}
+
+/// Runs the body of a [`host_import!`] function in a span named after the
+/// import, a child of the span the guest was called from. Failures are
+/// recorded in it as events with the `error_code` and the error as message.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __host_call_span {
+    (sync $import:expr, $site:ident, $call:block) => {{
+        let span = $crate::__tracing::debug_span!(
+            $import,
+            host_module = $site.module(),
+            function = $site.function()
+        );
+        let _entered = span.enter();
+        $call
+    }};
+    (async $import:expr, $site:ident, $call:block) => {{
+        let span = $crate::__tracing::debug_span!(
+            $import,
+            host_module = $site.module(),
+            function = $site.function()
+        );
+        $crate::__tracing::Instrument::instrument(async $call, span).await
+    }};
+}
+
+/// Without the `tracing` feature host calls are logged through `log`.
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __host_call_span {
+    ($kind:ident $import:expr, $site:ident, $call:block) => {
+        $call
+    };
+}
+
+/// Declares a guest handle into a [`SlotTable`](crate::handles::SlotTable),
+/// packing the slot into the low and the slot's generation into the high 32
+/// bits of a `u64` that is never `0`.
+macro_rules! slot_handle {
+    ($(#[$attr:meta])* pub struct $name:ident = $kind:literal;) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(transparent)]
+        pub struct $name(u64);
+
+        impl $name {
+            pub fn raw(self) -> u64 {
+                self.0
+            }
+
+            pub fn slot(self) -> u32 {
+                self.0 as u32
+            }
+
+            pub fn generation(self) -> u32 {
+                (self.0 >> 32) as u32
+            }
+        }
+
+        impl $crate::handles::SlotHandle for $name {
+            const KIND: &'static str = $kind;
+
+            fn new(slot: u32, generation: u32) -> Self {
+                Self(u64::from(generation) << 32 | u64::from(slot))
+            }
+
+            fn raw(self) -> u64 {
+                self.0
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    .field("slot", &self.slot())
+                    .field("generation", &self.generation())
+                    .finish()
+            }
+        }
+
+        const _: () = {
+            assert!(std::mem::size_of::<$name>() == 8);
+            assert!(std::mem::align_of::<$name>() == 8);
+        };
+
+        // SAFETY: `repr(transparent)` over a `u64`
+        unsafe impl $crate::PlainOldData for $name {}
+    };
+}
+
+/// Declares [`ErrorCode`](crate::ErrorCode) along with `ALL`, `from_raw`
+/// and `name`, so a new code can't be missing from any of them.
+macro_rules! error_codes {
+    (
+        $(#[$attr:meta])*
+        pub enum $name:ident {
+            $($(#[$doc:meta])* $variant:ident = $raw:literal,)*
+        }
+    ) => {
+        $(#[$attr])*
+        pub enum $name {
+            $($(#[$doc])* $variant = $raw,)*
+        }
+
+        impl $name {
+            /// Every defined code, in discriminant order.
+            pub const ALL: &'static [$name] = &[$(Self::$variant,)*];
+
+            /// Converts a raw code as seen by the guest back into an
+            /// `ErrorCode`, returning `None` for values this host doesn't know
+            /// about.
+            pub fn from_raw(raw: u32) -> Option<Self> {
+                match raw {
+                    $($raw => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Name of the variant, as printed by `Display` and parsed by
+            /// `FromStr`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$variant => stringify!($variant),)*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ApiError, ErrorCode, HostLinker, HostModule, InstantiationError, ModuleContext, Shim,
+        WasmMemoryHandle,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Scale {
+        factor: u32,
+        offset: u32,
+    }
+
+    // SAFETY: `repr(C)` of two `u32`s, so there is no padding
+    unsafe impl crate::PlainOldData for Scale {}
+
+    /// A module with an import of each kind of signature.
+    #[derive(Default)]
+    struct Echo {
+        scaled: Vec<Scale>,
+    }
+
+    impl HostModule for Echo {
+        fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+            host_context.module_mut::<Self>()
+        }
+
+        fn name() -> &'static str {
+            "echo"
+        }
+    }
+
+    impl<'t> Shim<'t> for Echo {
+        type Err = ApiError;
+        type Memory = WasmMemoryHandle<'t>;
+        type Context = ModuleContext;
+        type ImportTable = &'t mut HostLinker;
+        type ImportError = InstantiationError;
+        type WasmTrap = crate::WasmTrap;
+
+        fn namespace() -> (&'static str, &'static str) {
+            ("env", "echo")
+        }
+
+        fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+            host_import!(linker, Echo, "echo__len", (text: str, out: *mut u32)
+                => |host| host.len(text))?;
+            host_import!(linker, Echo, "echo__add", (a: u32, b: i64, out: *mut u64)
+                => |host| host.add(a, b))?;
+            host_import!(linker, Echo, "echo__scale", (scale: &Scale, enabled: u32)
+                => |host| host.scale(scale, enabled))?;
+            host_import!(linker, Echo, "echo__repeat", (index: u32)
+                => |host| host.repeat(index))
+        }
+    }
+
+    impl Echo {
+        fn len(&mut self, text: &str) -> Result<u32, ApiError> {
+            Ok(text.len() as u32)
+        }
+
+        fn add(&mut self, a: u32, b: i64) -> Result<u64, ApiError> {
+            Ok(u64::from(a).wrapping_add(b as u64))
+        }
+
+        fn scale(&mut self, scale: &Scale, enabled: u32) -> Result<(), ApiError> {
+            if enabled == 0 {
+                return Err(ApiError::new(ErrorCode::InvalidArgument, "disabled"));
+            }
+            self.scaled.push(*scale);
+            Ok(())
+        }
+
+        /// Scales again by the scale at `index`, panicking if there is none.
+        fn repeat(&mut self, index: u32) -> Result<(), ApiError> {
+            self.scaled.push(self.scaled[index as usize]);
+            Ok(())
+        }
+    }
+
+    const GUEST: &str = r#"
+        (module
+          (import "env" "echo__len" (func $len (param i32 i32 i32) (result i32)))
+          (import "env" "echo__add" (func $add (param i32 i64 i32) (result i32)))
+          (import "env" "echo__scale" (func $scale (param i32 i32) (result i32)))
+          (import "env" "echo__repeat" (func $repeat (param i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "hello")
+          (data (i32.const 32) "\03\00\00\00\04\00\00\00")
+          (func (export "len") (param $ptr i32) (param $len i32) (param $out i32) (result i32)
+            (call $len (local.get $ptr) (local.get $len) (local.get $out)))
+          (func (export "add") (param $out i32) (result i32)
+            (call $add (i32.const 2) (i64.const 40) (local.get $out)))
+          (func (export "scale") (param $ptr i32) (param $enabled i32) (result i32)
+            (call $scale (local.get $ptr) (local.get $enabled)))
+          (func (export "repeat") (param $index i32) (result i32)
+            (call $repeat (local.get $index))))
+    "#;
+
+    struct Guest {
+        store: wasmtime::Store<ModuleContext>,
+        instance: wasmtime::Instance,
+    }
+
+    impl Guest {
+        fn new() -> Self {
+            Self::with_context(ModuleContext::builder())
+        }
+
+        fn with_context(builder: crate::ModuleContextBuilder) -> Self {
+            let engine = wasmtime::Engine::default();
+            let mut linker = HostLinker::new(&engine);
+            Echo::imports(&mut linker).unwrap();
+            let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+            let context = builder
+                .with_module(Echo::default())
+                .with_job_threads(1)
+                .build()
+                .unwrap();
+            let mut store = context.into_store(&engine);
+            let instance = linker.instantiate(&mut store, &module).unwrap();
+            Self { store, instance }
+        }
+
+        fn call<P: wasmtime::WasmParams>(&mut self, name: &str, params: P) -> u32 {
+            ModuleContext::call_export(&mut self.store, &self.instance, name, params).unwrap()
+        }
+
+        fn memory(&mut self, addr: usize, len: usize) -> Vec<u8> {
+            let memory = self.instance.get_memory(&mut self.store, "memory").unwrap();
+            memory.data(&self.store)[addr..addr + len].to_vec()
+        }
+    }
+
+    #[test]
+    fn strings_are_split_into_pointer_and_length() {
+        let mut guest = Guest::new();
+        assert_eq!(guest.call("len", (16, 5, 64)), ErrorCode::Success as u32);
+        assert_eq!(guest.memory(64, 4), 5_u32.to_le_bytes());
+        // Empty strings may be null
+        assert_eq!(guest.call("len", (0, 0, 64)), ErrorCode::Success as u32);
+        assert_eq!(guest.memory(64, 4), 0_u32.to_le_bytes());
+        assert_eq!(
+            guest.call("len", (65_530, 16, 64)),
+            ErrorCode::OutOfBounds as u32
+        );
+        assert_eq!(
+            guest.call("len", (16, 5, 0)),
+            ErrorCode::InvalidArgument as u32
+        );
+    }
+
+    #[test]
+    fn scalars_are_passed_through() {
+        let mut guest = Guest::new();
+        assert_eq!(guest.call("add", 72), ErrorCode::Success as u32);
+        assert_eq!(guest.memory(72, 8), 42_u64.to_le_bytes());
+        // The output is bounds and alignment checked before it is written
+        assert_eq!(guest.call("add", 65_536), ErrorCode::OutOfBounds as u32);
+        assert_eq!(guest.call("add", 73), ErrorCode::InvalidArgument as u32);
+    }
+
+    #[test]
+    fn pointees_are_read_out_of_guest_memory() {
+        let mut guest = Guest::new();
+        assert_eq!(guest.call("scale", (32, 1)), ErrorCode::Success as u32);
+        let code = guest.call("scale", (32, 0));
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert_eq!(
+            guest.store.data().last_error().unwrap().guest_message(),
+            "InvalidArgument(1): disabled"
+        );
+        assert_eq!(
+            guest.call("scale", (0, 1)),
+            ErrorCode::InvalidArgument as u32
+        );
+        assert_eq!(
+            guest.call("scale", (65_532, 1)),
+            ErrorCode::OutOfBounds as u32
+        );
+        let scaled = &guest.store.data().module::<Echo>().unwrap().scaled;
+        assert_eq!(
+            scaled,
+            &[Scale {
+                factor: 3,
+                offset: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn panics_trap_and_poison_the_instance() {
+        let mut guest = Guest::new();
+        assert_eq!(guest.call("scale", (32, 1)), ErrorCode::Success as u32);
+        assert_eq!(guest.call("repeat", 0), ErrorCode::Success as u32);
+        let res =
+            ModuleContext::call_export::<_, u32>(&mut guest.store, &guest.instance, "repeat", 5);
+        match res {
+            Err(crate::ModuleError::HostError(err)) => {
+                assert_eq!(err.code(), ErrorCode::Internal);
+                assert!(
+                    err.guest_message().starts_with(
+                        "Internal(7): host call panicked: module=echo function=echo__repeat: \
+                         index out of bounds"
+                    ),
+                    "{}",
+                    err.guest_message()
+                );
+            }
+            res => panic!("the panic didn't trap: {:?}", res),
+        }
+        assert!(guest.store.data().is_poisoned());
+
+        // No more calls are served, not even those that can't panic
+        assert_eq!(guest.call("repeat", 0), ErrorCode::Internal as u32);
+        assert_eq!(guest.call("len", (16, 5, 64)), ErrorCode::Internal as u32);
+        assert_eq!(
+            guest.store.data().last_error().unwrap().guest_message(),
+            "Internal(7): instance is poisoned, host function `echo__repeat` panicked"
+        );
+        assert_eq!(guest.memory(64, 4), [0; 4]);
+        assert_eq!(guest.store.data().module::<Echo>().unwrap().scaled.len(), 2);
+    }
+
+    /// Clock moving ahead by a fixed step every time it is read, so that
+    /// every timed host call takes exactly one step.
+    struct SteppingClock {
+        now: std::sync::Mutex<Duration>,
+        step: Duration,
+    }
+
+    impl SteppingClock {
+        fn new(step: Duration) -> Self {
+            Self {
+                now: std::sync::Mutex::new(Duration::ZERO),
+                step,
+            }
+        }
+    }
+
+    impl crate::ClockSource for SteppingClock {
+        fn monotonic(&self) -> Duration {
+            let mut now = self.now.lock().unwrap();
+            *now += self.step;
+            *now
+        }
+
+        fn unix(&self) -> Duration {
+            self.monotonic()
+        }
+    }
+
+    /// Calls `add` `calls` times, returning the stats of `echo__add`.
+    fn timed_adds(builder: crate::ModuleContextBuilder, calls: usize) -> crate::CallStat {
+        let mut guest = Guest::with_context(builder);
+        for _ in 0..calls {
+            assert_eq!(guest.call("add", 72), ErrorCode::Success as u32);
+        }
+        let mut stats = guest.store.data().call_stats();
+        assert_eq!(stats.len(), 1);
+        stats.remove(0)
+    }
+
+    #[test]
+    fn host_calls_are_timed_with_the_call_clock() {
+        let step = Duration::from_micros(3000);
+        let builder = ModuleContext::builder().with_call_clock(SteppingClock::new(step));
+        let stat = timed_adds(builder, 5);
+        assert_eq!(stat.function, "echo__add");
+        assert_eq!(stat.calls, 5);
+
+        let durations = stat.durations;
+        assert_eq!(durations.count(), 5);
+        let buckets: Vec<_> = durations
+            .buckets()
+            .filter(|(_, calls)| *calls > 0)
+            .collect();
+        assert_eq!(buckets, [(Duration::from_micros(4096), 5)]);
+        assert_eq!(durations.p50(), Some(step));
+        assert_eq!(durations.p95(), Some(step));
+        assert_eq!(durations.max(), Some(step));
+    }
+
+    #[test]
+    fn host_calls_can_go_untimed() {
+        let builder = ModuleContext::builder()
+            .with_call_clock(SteppingClock::new(Duration::from_millis(1)))
+            .with_call_timing(false);
+        let stat = timed_adds(builder, 3);
+        assert_eq!(stat.calls, 3);
+        assert_eq!(stat.durations.count(), 0);
+        assert_eq!(stat.durations.p50(), None);
+        assert_eq!(stat.durations.max(), None);
+    }
+
+    /// Recorded fields, as name and debug-formatted value.
+    #[cfg(feature = "tracing")]
+    type FieldList = Vec<(String, String)>;
+
+    /// Spans and events recorded by [`Recorder`].
+    #[cfg(feature = "tracing")]
+    #[derive(Debug, Default)]
+    struct Recorded {
+        /// Name, fields and parent name of every span.
+        spans: Vec<(String, FieldList, Option<String>)>,
+        /// Fields and the name of the span of every event.
+        events: Vec<(FieldList, Option<String>)>,
+    }
+
+    /// A layer recording the fields of spans and events.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Recorded>>);
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Fields(Vec<(String, String)>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .push((field.name().to_owned(), format!("{:?}", value)));
+        }
+
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.push((field.name().to_owned(), value.to_owned()));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for Recorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|parent| parent.name().to_owned());
+            let name = span.name().to_owned();
+            self.0.lock().unwrap().spans.push((name, fields.0, parent));
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let span = ctx.event_span(event).map(|span| span.name().to_owned());
+            self.0.lock().unwrap().events.push((fields.0, span));
+        }
+    }
+
+    /// Runs `scale` with `enabled` in a span `embedder`, returning what was
+    /// recorded.
+    #[cfg(feature = "tracing")]
+    fn traced_scale(enabled: u32) -> Recorded {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let mut guest = Guest::new();
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _entered = tracing::info_span!("embedder").entered();
+            guest.call("scale", (32, enabled));
+        });
+        let recorded = std::mem::take(&mut *recorder.0.lock().unwrap());
+        recorded
+    }
+
+    #[cfg(feature = "tracing")]
+    fn field<'f>(fields: &'f [(String, String)], name: &str) -> Option<&'f str> {
+        fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn host_calls_run_in_spans_under_the_embedders() {
+        let recorded = traced_scale(1);
+        let (name, fields, parent) = &recorded.spans[1];
+        assert_eq!(name, "echo__scale");
+        assert_eq!(field(fields, "host_module"), Some("echo"));
+        assert_eq!(field(fields, "function"), Some("echo__scale"));
+        assert_eq!(parent.as_deref(), Some("embedder"));
+        assert_eq!(recorded.spans.len(), 2);
+        assert!(recorded.events.is_empty(), "{:?}", recorded.events);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn failed_host_calls_record_their_error_code() {
+        let recorded = traced_scale(0);
+        assert_eq!(recorded.spans[1].0, "echo__scale");
+        if !cfg!(feature = "host-logging") {
+            assert!(recorded.events.is_empty());
+            return;
+        }
+        let (fields, span) = &recorded.events[0];
+        let code = (ErrorCode::InvalidArgument as u32).to_string();
+        assert_eq!(field(fields, "error_code"), Some(code.as_str()));
+        // The message carries a backtrace when `RUST_BACKTRACE` is set.
+        let message = field(fields, "message").unwrap_or_default();
+        assert!(
+            message.starts_with("InvalidArgument(1): disabled"),
+            "{}",
+            message
+        );
+        assert_eq!(span.as_deref(), Some("echo__scale"));
+        assert_eq!(recorded.events.len(), 1);
+    }
+}
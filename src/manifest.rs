@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use crate::HostLinker;
+
+/// How an import interprets one of its wasm level parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ParamRole {
+    /// Passed through as is.
+    Value,
+    /// Address of a UTF-8 string, followed by its length in bytes.
+    StrPtr,
+    /// Address of an array of `elem`, followed by its element count.
+    SlicePtr { elem: &'static str },
+    /// Length of the string or array of the preceding parameter.
+    Len,
+    /// Address of a `pointee` read by the host, which may be `0` if
+    /// `nullable`.
+    In {
+        pointee: &'static str,
+        nullable: bool,
+    },
+    /// Address the host writes a `pointee` to if the call succeeds.
+    Out { pointee: &'static str },
+    /// Address of a `pointee` the import reads or writes as it documents.
+    Ptr { pointee: &'static str },
+}
+
+/// Name and role of a wasm level import parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct ImportParam {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub role: ParamRole,
+}
+
+impl ImportParam {
+    pub const fn new(name: &'static str, role: ParamRole) -> Self {
+        Self { name, role }
+    }
+}
+
+/// What the result of an import means.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportReturn {
+    /// An [`ErrorCode`](crate::ErrorCode), `0` on success. The message of a
+    /// failure is read with the module's `get_last_error` import.
+    ErrorCode,
+    /// A value as the import documents, such as a length or a time.
+    Value,
+}
+
+/// Parameter names and roles of a registered import, see
+/// [`HostLinker::describe_import`].
+#[derive(Clone, Debug)]
+pub(crate) struct ImportDoc {
+    pub(crate) params: Vec<ImportParam>,
+    pub(crate) returns: ImportReturn,
+}
+
+impl ImportDoc {
+    /// Whether the import accesses guest memory through its parameters.
+    pub(crate) fn takes_pointers(&self) -> bool {
+        self.params
+            .iter()
+            .any(|param| !matches!(param.role, ParamRole::Value | ParamRole::Len))
+    }
+}
+
+/// A wasm level parameter of an import in an [`ImportManifest`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ManifestParam {
+    pub name: String,
+    /// Wasm value type, such as `i32`.
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    /// `None` for imports registered without a description.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub role: Option<ParamRole>,
+}
+
+/// An import of an [`ImportManifest`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ManifestImport {
+    pub name: &'static str,
+    /// Host module that registered the import.
+    pub module: &'static str,
+    pub params: Vec<ManifestParam>,
+    pub results: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub returns: Option<ImportReturn>,
+}
+
+/// Every import registered with a [`HostLinker`], grouped by namespace and
+/// sorted by name, for guest SDKs to generate their bindings from.
+///
+/// Wasm signatures are read back from the linker, so they always match what
+/// guests link against.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct ImportManifest {
+    /// Wasm type of guest pointers and lengths, `i64` for memory64 guests.
+    pub pointer_type: &'static str,
+    pub namespaces: BTreeMap<&'static str, Vec<ManifestImport>>,
+}
+
+impl ImportManifest {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("manifests serialize to JSON")
+    }
+}
+
+impl HostLinker {
+    /// Describes the imports registered so far.
+    pub fn manifest(&self) -> ImportManifest {
+        let mut namespaces = BTreeMap::<_, Vec<_>>::new();
+        for import in self.registered_imports().iter() {
+            // Descriptions of a different arity than the import are ignored
+            // rather than misattributed
+            let doc = self
+                .import_doc(import.namespace, import.name.as_str())
+                .filter(|doc| doc.params.len() == import.arity());
+            let params = import
+                .params
+                .iter()
+                .enumerate()
+                .map(|(i, param)| {
+                    let described = doc.map(|doc| doc.params[i]);
+                    ManifestParam {
+                        name: described.map_or_else(|| format!("p{}", i), |p| p.name.to_owned()),
+                        ty: wasm_type(param),
+                        role: described.map(|p| p.role),
+                    }
+                })
+                .collect();
+            namespaces
+                .entry(import.namespace)
+                .or_default()
+                .push(ManifestImport {
+                    name: import.name.as_str(),
+                    module: import.module,
+                    params,
+                    results: import.results.iter().map(wasm_type).collect(),
+                    returns: doc.map(|doc| doc.returns),
+                });
+        }
+        ImportManifest {
+            pointer_type: if self.is_memory64() { "i64" } else { "i32" },
+            namespaces,
+        }
+    }
+}
+
+fn wasm_type(ty: &wasmtime::ValType) -> &'static str {
+    match ty {
+        wasmtime::ValType::I32 => "i32",
+        wasmtime::ValType::I64 => "i64",
+        wasmtime::ValType::F32 => "f32",
+        wasmtime::ValType::F64 => "f64",
+        wasmtime::ValType::V128 => "v128",
+        ty if ty.is_externref() => "externref",
+        ty if ty.is_funcref() => "funcref",
+        // Typed references of the function references and GC proposals
+        wasmtime::ValType::Ref(_) => "ref",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, MLApiHost};
+
+    /// The manifest of [`MLApiHost`] that guest SDKs are generated from.
+    const ML_API_SNAPSHOT: &str = "tests/fixtures/ml_api_manifest.json";
+
+    fn ml_api_linker() -> HostLinker {
+        test_support::linker::<MLApiHost>(&wasmtime::Engine::default())
+    }
+
+    #[test]
+    fn ml_api_manifest_matches_its_snapshot() {
+        let json = ml_api_linker().manifest().to_json() + "\n";
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(ML_API_SNAPSHOT);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, &json).unwrap();
+        }
+        let snapshot = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            json == snapshot,
+            "the `MLApiHost` guest interface changed, rerun with `UPDATE_SNAPSHOTS=1` \
+             if that is intended:\n{}",
+            json
+        );
+    }
+
+    #[test]
+    fn described_parameters_keep_their_names_and_roles() {
+        let manifest = ml_api_linker().manifest();
+        assert_eq!(manifest.pointer_type, "i32");
+        let get_last_error = manifest.namespaces["env"]
+            .iter()
+            .find(|import| import.name == "ml__get_last_error")
+            .unwrap();
+        assert_eq!(get_last_error.module, "ml_api");
+        assert_eq!(
+            get_last_error.params,
+            [
+                ManifestParam {
+                    name: "buf_ptr".to_owned(),
+                    ty: "i32",
+                    role: Some(ParamRole::SlicePtr { elem: "u8" }),
+                },
+                ManifestParam {
+                    name: "buf_len".to_owned(),
+                    ty: "i32",
+                    role: Some(ParamRole::Len),
+                },
+            ]
+        );
+        assert_eq!(get_last_error.results, ["i32"]);
+        assert_eq!(get_last_error.returns, Some(ImportReturn::Value));
+    }
+
+    #[test]
+    fn undescribed_imports_get_positional_names() {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        linker
+            .func_wrap("test", "env", "add", |a: i32, b: i64| a as i64 + b)
+            .unwrap();
+        // A description of the wrong arity isn't attributed to the import
+        linker.describe_import(
+            "env",
+            "add",
+            &[ImportParam::new("a", ParamRole::Value)],
+            ImportReturn::Value,
+        );
+        let manifest = linker.manifest();
+        let add = &manifest.namespaces["env"][0];
+        let params: Vec<_> = add
+            .params
+            .iter()
+            .map(|param| (param.name.as_str(), param.ty, param.role))
+            .collect();
+        assert_eq!(params, [("p0", "i32", None), ("p1", "i64", None)]);
+        assert_eq!(add.results, ["i64"]);
+        assert_eq!(add.returns, None);
+    }
+}
@@ -0,0 +1,909 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{ApiError, ErrorCode, ModuleContext, Severity};
+
+// Wasm memory is little-endian and PODs are copied to and from it as is.
+#[cfg(target_endian = "big")]
+compile_error!(
+    "guest memory access requires a little-endian host, `PlainOldData` values are copied without byte swapping"
+);
+
+/// Guest linear memory as seen by a host call.
+pub struct WasmMemoryHandle<'a>(&'a mut [u8]);
+
+/// Types that can be copied to and from guest memory byte for byte: every bit
+/// pattern must be a valid value and the type must not contain padding.
+///
+/// The host's in-memory layout is the guest encoding, little-endian fields at
+/// their `repr(C)` offsets, so the layout of an implementor is part of the
+/// guest ABI. Types crossing the boundary pin their size, alignment and field
+/// offsets with `const` assertions next to the impl. With the `derive`
+/// feature `#[derive(PlainOldData)]` checks the requirements below at compile
+/// time instead.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` or `#[repr(transparent)]` (or a
+/// primitive) and made up only of `PlainOldData` fields without padding.
+pub unsafe trait PlainOldData: 'static + Copy + Sized + Send + Sync {
+    /// Checks invariants beyond the bit pattern, every value read from guest
+    /// memory is validated before the host sees it.
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_plain_old_data {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl PlainOldData for $ty {})*
+    };
+}
+
+impl_plain_old_data!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+unsafe impl<T: PlainOldData, const N: usize> PlainOldData for [T; N] {}
+
+impl<'a> WasmMemoryHandle<'a> {
+    pub fn new(memory: &'a mut [u8]) -> Self {
+        Self(memory)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Bounds checks in `u64`, so the addresses of 64-bit guests are never
+    /// truncated before they are checked.
+    fn range(&self, ptr: u64, len: u64) -> Result<Range<usize>, ApiError> {
+        match ptr.checked_add(len) {
+            // In bounds, so both fit a `usize`
+            Some(end) if end <= self.0.len() as u64 => Ok(ptr as usize..end as usize),
+            _ => Err(ApiError::new(
+                ErrorCode::OutOfBounds,
+                format!(
+                    "{} bytes at {:#x} are outside of guest memory ({} bytes)",
+                    len,
+                    ptr,
+                    self.0.len()
+                ),
+            )),
+        }
+    }
+
+    /// Reads a guest `(ptr, len)` string.
+    pub fn read_str(&self, ptr: u64, len: u64) -> Result<&str, ApiError> {
+        let range = self.range(ptr, len)?;
+        std::str::from_utf8(&self.0[range]).map_err(|err| {
+            ApiError::new(
+                ErrorCode::InvalidUtf8,
+                format!(
+                    "string at {:#x} is not valid UTF-8 after {} bytes",
+                    ptr,
+                    err.valid_up_to()
+                ),
+            )
+        })
+    }
+
+    /// Reads a guest `(ptr, len)` string, replacing invalid UTF-8 with
+    /// `U+FFFD`. Meant for diagnostics such as guest log messages, where a
+    /// mangled string is more useful than an error.
+    pub fn read_str_lossy(&self, ptr: u64, len: u64) -> Result<Cow<'_, str>, ApiError> {
+        let range = self.range(ptr, len)?;
+        Ok(String::from_utf8_lossy(&self.0[range]))
+    }
+
+    /// Borrows `len` bytes at `ptr` for the host to fill in place, such as a
+    /// guest buffer that is read into.
+    pub fn bytes_mut(&mut self, ptr: u64, len: u64) -> Result<&mut [u8], ApiError> {
+        let range = self.range(ptr, len)?;
+        Ok(&mut self.0[range])
+    }
+
+    /// Checks that a `T` at guest address `ptr` is in bounds and naturally
+    /// aligned.
+    fn pod_range<T: PlainOldData>(&self, ptr: u64) -> Result<Range<usize>, ApiError> {
+        if ptr & (std::mem::align_of::<T>() as u64 - 1) != 0 {
+            return Err(Self::misaligned::<T>(ptr));
+        }
+        self.range(ptr, std::mem::size_of::<T>() as u64)
+    }
+
+    /// Reads a naturally aligned `T` from the guest.
+    pub fn read_pod<T: PlainOldData>(&self, ptr: u64) -> Result<T, ApiError> {
+        let range = self.pod_range::<T>(ptr)?;
+        let mut value = std::mem::MaybeUninit::<T>::uninit();
+        // SAFETY: the range is in bounds and exactly `size_of::<T>()` bytes
+        // long, and every bit pattern is a valid `PlainOldData`
+        let value = unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.0[range].as_ptr(),
+                value.as_mut_ptr().cast::<u8>(),
+                std::mem::size_of::<T>(),
+            );
+            value.assume_init()
+        };
+        value.validate()?;
+        Ok(value)
+    }
+
+    /// Writes a naturally aligned `T` to the guest.
+    pub fn write_pod<T: PlainOldData>(&mut self, ptr: u64, value: &T) -> Result<(), ApiError> {
+        let range = self.pod_range::<T>(ptr)?;
+        // SAFETY: the range is in bounds and exactly `size_of::<T>()` bytes
+        // long, and `PlainOldData` types have no padding
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (value as *const T).cast::<u8>(),
+                self.0[range].as_mut_ptr(),
+                std::mem::size_of::<T>(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Byte range of `count` `T`s at `ptr`, guarding against the byte length
+    /// overflowing before it is bounds checked.
+    fn slice_range<T: PlainOldData>(&self, ptr: u64, count: u64) -> Result<Range<usize>, ApiError> {
+        let len = count
+            .checked_mul(std::mem::size_of::<T>() as u64)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::OutOfBounds,
+                    format!(
+                        "{} `{}`s at {:#x} are outside of guest memory ({} bytes)",
+                        count,
+                        std::any::type_name::<T>(),
+                        ptr,
+                        self.0.len()
+                    ),
+                )
+            })?;
+        self.range(ptr, len)
+    }
+
+    fn misaligned<T: PlainOldData>(ptr: u64) -> ApiError {
+        ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "{:#x} is not aligned to {} bytes for `{}`",
+                ptr,
+                std::mem::align_of::<T>(),
+                std::any::type_name::<T>()
+            ),
+        )
+    }
+
+    /// Borrows `count` elements of `T` at `ptr`. The buffer must be aligned
+    /// for `T`, use [`read_pod_vec`](Self::read_pod_vec) for guest buffers
+    /// that might not be.
+    pub fn read_pod_slice<T: PlainOldData>(&self, ptr: u64, count: u64) -> Result<&[T], ApiError> {
+        let range = self.slice_range::<T>(ptr, count)?;
+        let bytes = &self.0[range];
+        if bytes.as_ptr() as usize & (std::mem::align_of::<T>() - 1) != 0 {
+            return Err(Self::misaligned::<T>(ptr));
+        }
+        // SAFETY: the bytes are in bounds, aligned for `T` and exactly
+        // `count` elements long, and every bit pattern is a valid
+        // `PlainOldData`
+        let values =
+            unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast::<T>(), count as usize) };
+        values.iter().try_for_each(T::validate)?;
+        Ok(values)
+    }
+
+    /// Copies `count` elements of `T` at `ptr` out of the guest, regardless
+    /// of the buffer's alignment.
+    pub fn read_pod_vec<T: PlainOldData>(&self, ptr: u64, count: u64) -> Result<Vec<T>, ApiError> {
+        let range = self.slice_range::<T>(ptr, count)?;
+        let mut values = Vec::<T>::with_capacity(count as usize);
+        // SAFETY: the source is in bounds and exactly `count` elements long,
+        // the destination has capacity for them, and every bit pattern is a
+        // valid `PlainOldData`
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.0[range.clone()].as_ptr(),
+                values.as_mut_ptr().cast::<u8>(),
+                range.len(),
+            );
+            values.set_len(count as usize);
+        }
+        values.iter().try_for_each(T::validate)?;
+        Ok(values)
+    }
+
+    /// Copies `values` into the guest at `ptr`, regardless of the buffer's
+    /// alignment.
+    pub fn write_pod_slice<T: PlainOldData>(
+        &mut self,
+        ptr: u64,
+        values: &[T],
+    ) -> Result<(), ApiError> {
+        let range = self.slice_range::<T>(ptr, values.len() as u64)?;
+        // SAFETY: the destination is in bounds and exactly as long as
+        // `values`, and `PlainOldData` types have no padding
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                values.as_ptr().cast::<u8>(),
+                self.0[range.clone()].as_mut_ptr(),
+                range.len(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Name of the memory export host functions access guest memory through,
+/// unless the context names another with
+/// [`with_memory_export`](crate::ModuleContextBuilder::with_memory_export).
+pub const GUEST_MEMORY_EXPORT: &str = "memory";
+
+/// Which of the guest's memory exports [`guest_memory_in`] borrows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemorySelector<'n> {
+    /// The context's [default memory export](ModuleContext::memory_export).
+    Default,
+    /// The export at this index of
+    /// [`ModuleContext::memory_exports`](ModuleContext::memory_exports).
+    Index(usize),
+    /// The export with this name, whether the context lists it or not.
+    Name(&'n str),
+}
+
+/// Names of the guest's memory exports, the first being the default one.
+#[derive(Clone, Debug)]
+pub(crate) struct MemoryExports(pub(crate) Vec<Arc<str>>);
+
+impl Default for MemoryExports {
+    fn default() -> Self {
+        Self(vec![Arc::from(GUEST_MEMORY_EXPORT)])
+    }
+}
+
+/// Splits the borrow of a caller into the guest's exported memory and the
+/// host context, so shims can use both at the same time. A guest without a
+/// memory export can't be served, so that error is fatal.
+pub fn guest_memory<'a>(
+    caller: &'a mut wasmtime::Caller<'_, ModuleContext>,
+) -> Result<(WasmMemoryHandle<'a>, &'a mut ModuleContext), ApiError> {
+    guest_memory_in(caller, MemorySelector::Default)
+}
+
+/// Like [`guest_memory`], for guests exporting several memories.
+pub fn guest_memory_in<'a>(
+    caller: &'a mut wasmtime::Caller<'_, ModuleContext>,
+    memory: MemorySelector<'_>,
+) -> Result<(WasmMemoryHandle<'a>, &'a mut ModuleContext), ApiError> {
+    let index = match memory {
+        MemorySelector::Name(name) => return memory_named(caller, name),
+        MemorySelector::Default => 0,
+        MemorySelector::Index(index) => index,
+    };
+    let exports = &caller.data().memory_exports.0;
+    let name = exports.get(index).cloned().ok_or_else(|| {
+        ApiError::fatal(
+            ErrorCode::Internal,
+            format!(
+                "no memory export at index {}, the context lists {}",
+                index,
+                export_list(exports.iter())
+            ),
+        )
+    })?;
+    memory_named(caller, &name)
+}
+
+fn memory_named<'a>(
+    caller: &'a mut wasmtime::Caller<'_, ModuleContext>,
+    name: &str,
+) -> Result<(WasmMemoryHandle<'a>, &'a mut ModuleContext), ApiError> {
+    let memory = match caller.get_export(name) {
+        Some(wasmtime::Extern::Memory(memory)) => memory,
+        Some(_) => {
+            return Err(ApiError::fatal(
+                ErrorCode::Internal,
+                format!("guest export `{}` is not a memory", name),
+            ))
+        }
+        None => {
+            let present = present_memories(caller);
+            return Err(ApiError::fatal(
+                ErrorCode::Internal,
+                format!(
+                    "guest doesn't export a memory named `{}`, its memory exports are {}",
+                    name,
+                    export_list(present.iter())
+                ),
+            ));
+        }
+    };
+    let (data, host_context) = memory.data_and_store_mut(caller);
+    Ok((WasmMemoryHandle(data), host_context))
+}
+
+/// Memory exports of the guest, as recorded at instantiation or otherwise
+/// those listed by the context that it has.
+fn present_memories(caller: &mut wasmtime::Caller<'_, ModuleContext>) -> Vec<Arc<str>> {
+    if let Some(recorded) = &caller.data().guest_memories {
+        return recorded.clone();
+    }
+    let candidates = caller.data().memory_exports.0.clone();
+    candidates
+        .into_iter()
+        .filter(|name| matches!(caller.get_export(name), Some(wasmtime::Extern::Memory(_))))
+        .collect()
+}
+
+pub(crate) fn export_list<N: AsRef<str>>(names: impl Iterator<Item = N>) -> String {
+    let names: Vec<_> = names.map(|name| format!("`{}`", name.as_ref())).collect();
+    if names.is_empty() {
+        "none".to_owned()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// Name of the `host_alloc(len: u32, align: u32) -> u32` export host
+/// functions allocate guest buffers with, see [`copy_to_guest_alloc`].
+/// memory64 guests export it as `host_alloc(len: u64, align: u64) -> u64`.
+pub const GUEST_ALLOC_EXPORT: &str = "host_alloc";
+
+/// Copies `bytes` into a buffer allocated with the guest's
+/// [`GUEST_ALLOC_EXPORT`] and returns its address, for results whose size the
+/// guest can't know upfront. The buffer belongs to the guest afterwards.
+/// Empty payloads aren't allocated and are returned at address `0`.
+///
+/// Fails with `InvalidArgument` if the guest doesn't export an allocator, so
+/// imports can tell it to fall back to passing its own buffer, and with
+/// `OutOfBounds` if the allocator returned null or a buffer that is
+/// misaligned or outside of guest memory. A trapping allocator is fatal.
+///
+/// Calls into the guest, so async stores have to use
+/// [`copy_to_guest_alloc_async`] instead.
+pub fn copy_to_guest_alloc(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    bytes: &[u8],
+    align: u32,
+) -> Result<u64, ApiError> {
+    let ptr = match guest_alloc(caller, bytes, align)? {
+        None => return Ok(0),
+        Some(GuestAlloc::Wasm32(alloc, len)) => {
+            alloc.call(&mut *caller, (len, align)).map(u64::from)
+        }
+        Some(GuestAlloc::Wasm64(alloc, len)) => alloc.call(&mut *caller, (len, u64::from(align))),
+    };
+    write_guest_alloc(caller, ptr.map_err(alloc_trapped)?, bytes, align)
+}
+
+/// Async variant of [`copy_to_guest_alloc`].
+pub async fn copy_to_guest_alloc_async(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    bytes: &[u8],
+    align: u32,
+) -> Result<u64, ApiError> {
+    let ptr = match guest_alloc(caller, bytes, align)? {
+        None => return Ok(0),
+        Some(GuestAlloc::Wasm32(alloc, len)) => alloc
+            .call_async(&mut *caller, (len, align))
+            .await
+            .map(u64::from),
+        Some(GuestAlloc::Wasm64(alloc, len)) => {
+            alloc
+                .call_async(&mut *caller, (len, u64::from(align)))
+                .await
+        }
+    };
+    write_guest_alloc(caller, ptr.map_err(alloc_trapped)?, bytes, align)
+}
+
+/// The guest's allocator and the length to allocate.
+enum GuestAlloc {
+    Wasm32(wasmtime::TypedFunc<(u32, u32), u32>, u32),
+    Wasm64(wasmtime::TypedFunc<(u64, u64), u64>, u64),
+}
+
+/// The guest's allocator, `None` if there is nothing to allocate.
+fn guest_alloc(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    bytes: &[u8],
+    align: u32,
+) -> Result<Option<GuestAlloc>, ApiError> {
+    if !align.is_power_of_two() {
+        return Err(ApiError::internal(format!(
+            "guest allocation alignment {} is not a power of two",
+            align
+        )));
+    }
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let alloc = match caller.get_export(GUEST_ALLOC_EXPORT) {
+        Some(wasmtime::Extern::Func(alloc)) => alloc,
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!("guest doesn't export a `{}` function", GUEST_ALLOC_EXPORT),
+            ))
+        }
+    };
+    if let Ok(alloc64) = alloc.typed::<(u64, u64), u64>(&*caller) {
+        return Ok(Some(GuestAlloc::Wasm64(alloc64, bytes.len() as u64)));
+    }
+    let alloc = alloc.typed::<(u32, u32), u32>(&*caller).map_err(|err| {
+        ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!("guest export `{}` {}", GUEST_ALLOC_EXPORT, err),
+        )
+    })?;
+    let len = u32::try_from(bytes.len()).map_err(|_| {
+        ApiError::new(
+            ErrorCode::OutOfBounds,
+            format!("{} bytes don't fit into guest memory", bytes.len()),
+        )
+    })?;
+    Ok(Some(GuestAlloc::Wasm32(alloc, len)))
+}
+
+fn write_guest_alloc(
+    caller: &mut wasmtime::Caller<'_, ModuleContext>,
+    ptr: u64,
+    bytes: &[u8],
+    align: u32,
+) -> Result<u64, ApiError> {
+    if ptr == 0 || ptr & (u64::from(align) - 1) != 0 {
+        return Err(ApiError::new(
+            ErrorCode::OutOfBounds,
+            format!(
+                "guest allocator returned {:#x} for {} bytes aligned to {}",
+                ptr,
+                bytes.len(),
+                align
+            ),
+        ));
+    }
+    let (mut memory, _) = guest_memory(caller)?;
+    memory.write_pod_slice(ptr, bytes)?;
+    Ok(ptr)
+}
+
+fn alloc_trapped(trap: crate::WasmTrap) -> ApiError {
+    // Keeps the code of the trap, so interrupts stay graceful
+    let err = ApiError::from_guest_call(trap)
+        .context(format!("guest export `{}` trapped", GUEST_ALLOC_EXPORT));
+    ApiError {
+        severity: Severity::Fatal,
+        ..err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Pair {
+        a: u32,
+        b: u16,
+        c: u16,
+    }
+
+    // SAFETY: `repr(C)` of `PlainOldData` fields without padding
+    unsafe impl PlainOldData for Pair {}
+
+    const PAIR: Pair = Pair { a: 1, b: 2, c: 3 };
+
+    #[test]
+    fn pods_round_trip_at_aligned_offsets() {
+        let mut bytes = [0; 32];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        memory.write_pod(8, &PAIR).unwrap();
+        assert_eq!(memory.read_pod::<Pair>(8).unwrap(), PAIR);
+        assert_eq!(&bytes[8..16], &[1, 0, 0, 0, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn misaligned_pods_are_invalid_arguments() {
+        let mut bytes = [0; 32];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let err = memory.read_pod::<Pair>(6).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        let err = memory.write_pod(2, &PAIR).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert_eq!(bytes, [0; 32]);
+    }
+
+    #[test]
+    fn pods_fit_at_the_very_end_of_memory() {
+        let mut bytes = [0; 32];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        memory.write_pod(24, &PAIR).unwrap();
+        assert_eq!(memory.read_pod::<Pair>(24).unwrap(), PAIR);
+        let err = memory.read_pod::<Pair>(28).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+        let err = memory.write_pod(32, &PAIR).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn zero_length_strings_are_empty_anywhere_in_bounds() {
+        let mut bytes = *b"hello";
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        assert_eq!(memory.read_str(0, 0).unwrap(), "");
+        assert_eq!(memory.read_str(5, 0).unwrap(), "");
+        let err = memory.read_str(6, 0).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn strings_may_end_at_the_end_of_memory() {
+        let mut bytes = *b"hello";
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        assert_eq!(memory.read_str(0, 5).unwrap(), "hello");
+        assert_eq!(memory.read_str(3, 2).unwrap(), "lo");
+        let err = memory.read_str(3, 3).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn overflowing_string_ranges_are_out_of_bounds() {
+        let mut bytes = *b"hello";
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        let ranges = [
+            (u64::from(u32::MAX), 2),
+            (2, u64::from(u32::MAX)),
+            (u64::MAX, 1),
+            (1, u64::MAX),
+        ];
+        for (ptr, len) in ranges {
+            let err = memory.read_str(ptr, len).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::OutOfBounds, "{:#x}+{}", ptr, len);
+            let err = memory.read_str_lossy(ptr, len).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::OutOfBounds, "{:#x}+{}", ptr, len);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_reports_where_it_starts() {
+        let mut bytes = *b"\0hi\xffthere";
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        let err = memory.read_str(1, 7).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidUtf8);
+        assert!(
+            err.guest_message()
+                .ends_with("string at 0x1 is not valid UTF-8 after 2 bytes"),
+            "{}",
+            err.guest_message()
+        );
+        assert_eq!(memory.read_str_lossy(1, 7).unwrap(), "hi\u{fffd}ther");
+        assert!(matches!(
+            memory.read_str_lossy(1, 2).unwrap(),
+            Cow::Borrowed("hi")
+        ));
+    }
+
+    /// Guest memory of `len` bytes, aligned like wasm memory is for every
+    /// `PlainOldData` the tests use.
+    fn aligned_memory(len: usize) -> Vec<u64> {
+        vec![0; len.div_ceil(8)]
+    }
+
+    fn bytes(words: &mut [u64]) -> &mut [u8] {
+        // SAFETY: `u8` has no alignment and every bit pattern is valid
+        unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), words.len() * 8) }
+    }
+
+    #[test]
+    fn large_f32_slices_round_trip() {
+        const COUNT: usize = 1 << 20;
+        let values: Vec<f32> = (0..COUNT).map(|i| i as f32 * 0.5).collect();
+        let mut words = aligned_memory(COUNT * 4 + 8);
+        let mut memory = WasmMemoryHandle::new(bytes(&mut words));
+        memory.write_pod_slice(8, &values).unwrap();
+        assert_eq!(
+            memory.read_pod_slice::<f32>(8, COUNT as u64).unwrap(),
+            &values[..]
+        );
+        assert_eq!(memory.read_pod_vec::<f32>(8, COUNT as u64).unwrap(), values);
+        let err = memory.read_pod_slice::<f32>(12, COUNT as u64).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn overflowing_slice_lengths_are_out_of_bounds() {
+        let mut words = aligned_memory(64);
+        let mut memory = WasmMemoryHandle::new(bytes(&mut words));
+        for count in [u64::MAX, u64::MAX / 4 + 1, u64::MAX / 2] {
+            let err = memory.read_pod_slice::<f32>(0, count).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::OutOfBounds, "{}", count);
+            let err = memory.read_pod_vec::<u32>(0, count).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::OutOfBounds, "{}", count);
+        }
+        let err = memory.write_pod_slice(u64::MAX - 3, &[1_u32]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    }
+
+    #[test]
+    fn misaligned_slices_are_copied_out() {
+        let mut words = aligned_memory(32);
+        let mut memory = WasmMemoryHandle::new(bytes(&mut words));
+        memory.write_pod_slice(3, &[PAIR, PAIR]).unwrap();
+        let err = memory.read_pod_slice::<Pair>(3, 2).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert_eq!(memory.read_pod_vec::<Pair>(3, 2).unwrap(), [PAIR, PAIR]);
+        assert!(memory.read_pod_slice::<Pair>(8, 0).unwrap().is_empty());
+    }
+
+    /// Instantiates `wat` with an `env::probe` import that borrows guest
+    /// memory, returning what the calls to its `probe` export saw: the size
+    /// of the memory or the error.
+    fn probe(wat: &str) -> Result<usize, ApiError> {
+        probe_in(wat, ModuleContext::builder(), MemorySelector::Default)
+    }
+
+    /// Like [`probe`], borrowing the `memory` selected in a context built
+    /// with `context`.
+    fn probe_in(
+        wat: &str,
+        context: crate::ModuleContextBuilder,
+        memory: MemorySelector<'static>,
+    ) -> Result<usize, ApiError> {
+        let engine =
+            wasmtime::Engine::new(context.configure_engine(&mut wasmtime::Config::new())).unwrap();
+        let seen = Arc::new(std::sync::Mutex::new(None));
+        let mut linker = wasmtime::Linker::new(&engine);
+        let probed = seen.clone();
+        linker
+            .func_wrap(
+                "env",
+                "probe",
+                move |mut caller: wasmtime::Caller<'_, ModuleContext>| {
+                    let res = guest_memory_in(&mut caller, memory).map(|(memory, _)| memory.len());
+                    *probed.lock().unwrap() = Some(res);
+                },
+            )
+            .unwrap();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut store = context.build().unwrap().into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let probe = instance
+            .get_typed_func::<(), ()>(&mut store, "probe")
+            .unwrap();
+        probe.call(&mut store, ()).unwrap();
+        let seen = seen.lock().unwrap().take();
+        seen.expect("`env::probe` wasn't called")
+    }
+
+    #[test]
+    fn memory_is_borrowed_from_the_memory_export() {
+        let wat = r#"
+            (module
+              (import "env" "probe" (func $probe))
+              (memory (export "memory") 2)
+              (func (export "probe") (call $probe)))
+        "#;
+        assert_eq!(probe(wat).unwrap(), 2 * 65_536);
+    }
+
+    #[test]
+    fn guests_without_a_memory_export_are_fatal() {
+        let wat = r#"
+            (module
+              (import "env" "probe" (func $probe))
+              (memory (export "heap") 1)
+              (func (export "probe") (call $probe)))
+        "#;
+        let err = probe(wat).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(err.severity(), crate::Severity::Fatal);
+        assert!(
+            err.guest_message().ends_with(
+                "guest doesn't export a memory named `memory`, its memory exports are none"
+            ),
+            "{}",
+            err.guest_message()
+        );
+    }
+
+    #[test]
+    fn memory_exports_that_are_not_memories_are_fatal() {
+        let wat = r#"
+            (module
+              (import "env" "probe" (func $probe))
+              (func (export "memory"))
+              (func (export "probe") (call $probe)))
+        "#;
+        let err = probe(wat).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(err.severity(), crate::Severity::Fatal);
+        assert!(
+            err.guest_message()
+                .ends_with("guest export `memory` is not a memory"),
+            "{}",
+            err.guest_message()
+        );
+    }
+
+    /// Exports a 1 page `small` and a 3 page `big` memory.
+    const TWO_MEMORIES: &str = r#"
+        (module
+          (import "env" "probe" (func $probe))
+          (memory (export "small") 1)
+          (memory (export "big") 3)
+          (func (export "probe") (call $probe)))
+    "#;
+
+    /// Builds a context for [`TWO_MEMORIES`].
+    fn two_memories() -> crate::ModuleContextBuilder {
+        ModuleContext::builder().with_memory_exports(vec!["small", "big"])
+    }
+
+    #[test]
+    fn renamed_memory_exports_are_borrowed() {
+        let wat = r#"
+            (module
+              (import "env" "probe" (func $probe))
+              (memory (export "heap") 2)
+              (func (export "probe") (call $probe)))
+        "#;
+        let context = ModuleContext::builder().with_memory_export("heap");
+        assert_eq!(
+            probe_in(wat, context, MemorySelector::Default).unwrap(),
+            2 * 65_536
+        );
+    }
+
+    #[test]
+    fn memories_are_selected_by_index_or_name() {
+        let engine = wasmtime::Engine::default();
+        let context = two_memories().build().unwrap();
+        assert_eq!(context.memory_export(), "small");
+        assert_eq!(
+            context.memory_exports().collect::<Vec<_>>(),
+            ["small", "big"]
+        );
+        drop(context.into_store(&engine));
+
+        let size = |memory| probe_in(TWO_MEMORIES, two_memories(), memory).unwrap();
+        assert_eq!(size(MemorySelector::Default), 65_536);
+        assert_eq!(size(MemorySelector::Index(0)), 65_536);
+        assert_eq!(size(MemorySelector::Index(1)), 3 * 65_536);
+        assert_eq!(size(MemorySelector::Name("big")), 3 * 65_536);
+        // Names don't have to be listed by the context.
+        let context = ModuleContext::builder().with_memory_exports(vec!["small", "other"]);
+        let unlisted = probe_in(TWO_MEMORIES, context, MemorySelector::Name("big"));
+        assert_eq!(unlisted.unwrap(), 3 * 65_536);
+    }
+
+    #[test]
+    fn empty_memory_export_lists_keep_the_default() {
+        let context = ModuleContext::builder()
+            .with_memory_export("heap")
+            .with_memory_exports(Vec::<String>::new())
+            .build()
+            .unwrap();
+        assert_eq!(context.memory_exports().collect::<Vec<_>>(), ["heap"]);
+    }
+
+    #[test]
+    fn missing_memories_name_the_ones_present() {
+        let err = probe_in(TWO_MEMORIES, two_memories(), MemorySelector::Index(2)).unwrap_err();
+        assert_eq!(err.severity(), crate::Severity::Fatal);
+        assert!(
+            err.guest_message()
+                .ends_with("no memory export at index 2, the context lists `small`, `big`"),
+            "{}",
+            err.guest_message()
+        );
+
+        let context = ModuleContext::builder().with_memory_exports(vec!["memory", "big"]);
+        let err = probe_in(TWO_MEMORIES, context, MemorySelector::Default).unwrap_err();
+        assert!(
+            err.guest_message().ends_with(
+                "guest doesn't export a memory named `memory`, its memory exports are `big`"
+            ),
+            "{}",
+            err.guest_message()
+        );
+    }
+
+    /// Writes `value` at `8` and returns its encoding in guest memory.
+    fn encode<T: PlainOldData>(value: &T) -> Vec<u8> {
+        let mut bytes = [0; 64];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        memory.write_pod(8, value).unwrap();
+        bytes[8..8 + std::mem::size_of::<T>()].to_vec()
+    }
+
+    // The golden encodings of the boundary types, which guests have been
+    // compiled against. A change to any of them breaks the guest ABI.
+
+    #[test]
+    fn future_handles_encode_slot_then_generation() {
+        use crate::handles::SlotHandle;
+        let handle = crate::FutureHandle::new(3, 7);
+        assert_eq!(encode(&handle), [3, 0, 0, 0, 7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn slot_handles_encode_slot_then_generation() {
+        use crate::handles::SlotHandle;
+        let encoding = [3, 0, 0, 0, 7, 0, 0, 0];
+        assert_eq!(encode(&crate::DatasetHandle::new(3, 7)), encoding);
+        assert_eq!(encode(&crate::UploadHandle::new(3, 7)), encoding);
+        assert_eq!(encode(&crate::DownloadHandle::new(3, 7)), encoding);
+    }
+
+    #[test]
+    fn future_statuses_encode_state_then_result_length() {
+        let status = crate::FutureStatus {
+            state: crate::FutureStatus::COMPLETED,
+            result_len: 0x0102,
+        };
+        assert_eq!(encode(&status), [1, 0, 0, 0, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn training_metrics_encode_in_field_order() {
+        let metrics = crate::TrainingMetrics {
+            epoch: 2,
+            total_epochs: 5,
+            loss: 0.5,
+            samples_per_sec: 1024.0,
+        };
+        assert_eq!(
+            encode(&metrics),
+            [2, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0x3f, 0, 0, 0x80, 0x44]
+        );
+    }
+
+    #[test]
+    fn protocol_configs_decode_from_their_encodings() {
+        let v1 = crate::ProtocolConfigV1 {
+            version: 1,
+            batch_size: 64,
+            learning_rate: 0.25,
+            flags: crate::ProtocolConfig::SHUFFLE,
+        };
+        let v1_bytes = [1, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0x80, 0x3e, 1, 0, 0, 0];
+        assert_eq!(encode(&v1), v1_bytes);
+
+        let v2 = crate::ProtocolConfigV2 {
+            version: 2,
+            batch_size: 64,
+            learning_rate: 0.25,
+            flags: crate::ProtocolConfig::SHUFFLE,
+            warmup_steps: 100,
+            weight_decay: 0.5,
+        };
+        let v2_bytes = [
+            2, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0x80, 0x3e, 1, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0x3f,
+        ];
+        assert_eq!(encode(&v2), v2_bytes);
+
+        let mut bytes = [0; 64];
+        bytes[8..36].copy_from_slice(&[
+            3, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0x80, 0x3e, 1, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0x3f,
+            0xe8, 0x03, 0, 0,
+        ]);
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        let config = crate::ProtocolConfig::read_from(&memory, crate::GuestPtr::new(8)).unwrap();
+        let expected = crate::ProtocolConfig {
+            version: 3,
+            batch_size: 64,
+            learning_rate: 0.25,
+            flags: crate::ProtocolConfig::SHUFFLE,
+            warmup_steps: 100,
+            weight_decay: 0.5,
+            timeout_ms: 1_000,
+        };
+        assert_eq!(config, expected);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    error_code_ranges, ApiError, ErrorCode, HostLinker, HostModule, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod metrics_imports = "metrics" {
+    COUNTER_ADD = "counter_add",
+    GAUGE_SET = "gauge_set",
+});
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    /// Sum of every delta added, saturating at `u64::MAX`.
+    Counter(u64),
+    /// Last value set.
+    Gauge(f64),
+}
+
+/// A named value reported by the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub value: MetricValue,
+}
+
+/// Metrics of a single instance, bounded to a number of distinct names so a
+/// guest can't grow it without limit.
+#[derive(Debug)]
+pub struct MetricsRegistry {
+    values: BTreeMap<String, MetricValue>,
+    max_metrics: usize,
+}
+
+impl Default for MetricsRegistry {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_METRICS)
+    }
+}
+
+impl MetricsRegistry {
+    pub const DEFAULT_MAX_METRICS: usize = 256;
+    /// Longest metric name in bytes.
+    pub const MAX_NAME_LEN: usize = 128;
+
+    pub fn new(max_metrics: usize) -> Self {
+        Self {
+            values: BTreeMap::new(),
+            max_metrics,
+        }
+    }
+
+    pub fn max_metrics(&self) -> usize {
+        self.max_metrics
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<MetricValue> {
+        self.values.get(name).copied()
+    }
+
+    /// Every metric, ordered by name.
+    pub fn snapshot(&self) -> Vec<Metric> {
+        self.values
+            .iter()
+            .map(|(name, value)| Metric {
+                name: name.clone(),
+                value: *value,
+            })
+            .collect()
+    }
+
+    pub fn counter_add(&mut self, name: &str, delta: u64) -> Result<(), ApiError> {
+        match self.entry(name, MetricValue::Counter(0))? {
+            MetricValue::Counter(count) => {
+                *count = count.saturating_add(delta);
+                Ok(())
+            }
+            MetricValue::Gauge(_) => Err(kind_mismatch(name, "gauge")),
+        }
+    }
+
+    pub fn gauge_set(&mut self, name: &str, value: f64) -> Result<(), ApiError> {
+        match self.entry(name, MetricValue::Gauge(value))? {
+            MetricValue::Gauge(gauge) => {
+                *gauge = value;
+                Ok(())
+            }
+            MetricValue::Counter(_) => Err(kind_mismatch(name, "counter")),
+        }
+    }
+
+    fn entry(&mut self, name: &str, initial: MetricValue) -> Result<&mut MetricValue, ApiError> {
+        validate_name(name)?;
+        if !self.values.contains_key(name) {
+            if self.values.len() >= self.max_metrics {
+                return Err(ApiError::new(
+                    ErrorCode::Busy,
+                    format!(
+                        "metric `{}` exceeds the limit of {} metrics",
+                        name, self.max_metrics
+                    ),
+                ));
+            }
+            self.values.insert(name.to_owned(), initial);
+        }
+        Ok(self.values.get_mut(name).expect("inserted above"))
+    }
+}
+
+/// Host module collecting metrics reported by the guest, such as the loss of
+/// every epoch, for the embedder to scrape with
+/// [`registry`](Self::registry).
+#[derive(Debug, Default)]
+pub struct MetricsApiHost {
+    registry: MetricsRegistry,
+}
+
+impl MetricsApiHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the guest to `max_metrics` distinct names, further names fail
+    /// with [`ErrorCode::Busy`].
+    pub fn with_max_metrics(mut self, max_metrics: usize) -> Self {
+        self.registry.max_metrics = max_metrics;
+        self
+    }
+
+    pub fn registry(&self) -> &MetricsRegistry {
+        &self.registry
+    }
+
+    pub fn registry_mut(&mut self) -> &mut MetricsRegistry {
+        &mut self.registry
+    }
+}
+
+impl HostModule for MetricsApiHost {
+    fn name() -> &'static str {
+        "metrics_api"
+    }
+    fn log_target() -> &'static str {
+        "host::metrics_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::METRICS)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for MetricsApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", metrics_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, MetricsApiHost, metrics_imports::COUNTER_ADD, (
+            name: str,
+            delta: u64,
+        ) => |host| host.registry.counter_add(name, delta))?;
+        // `value_bits` is the `f64::to_bits` of the value
+        host_import!(linker, MetricsApiHost, metrics_imports::GAUGE_SET, (
+            name: str,
+            value_bits: u64,
+        ) => |host| host.registry.gauge_set(name, f64::from_bits(value_bits)))?;
+        Ok(())
+    }
+}
+
+/// Names are 1 to [`MetricsRegistry::MAX_NAME_LEN`] ASCII letters, digits,
+/// `_`, `.`, `:` or `-`.
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.is_empty() || name.len() > MetricsRegistry::MAX_NAME_LEN {
+        return Err(ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "metric names must be 1 to {} bytes long, got {}",
+                MetricsRegistry::MAX_NAME_LEN,
+                name.len()
+            ),
+        ));
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b':' | b'-'))
+    {
+        return Err(ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!("metric name `{}` contains invalid characters", name),
+        ));
+    }
+    Ok(())
+}
+
+fn kind_mismatch(name: &str, kind: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidArgument,
+        format!("metric `{}` is a {}", name, kind),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{call, guest};
+
+    /// Trains for `epochs`, setting the `loss` gauge to `1 / epoch` and
+    /// counting `epochs` every epoch. Returns the first failed code.
+    const TRAINING_LOOP: &str = r#"
+        (module
+          (import "env" "metrics__counter_add" (func $counter_add (param i32 i32 i64) (result i32)))
+          (import "env" "metrics__gauge_set" (func $gauge_set (param i32 i32 i64) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 16) "loss")
+          (data (i32.const 32) "epochs")
+          (func (export "train") (param $epochs i32) (result i32)
+            (local $epoch i32)
+            (local $code i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $epoch) (local.get $epochs)))
+                (local.set $epoch (i32.add (local.get $epoch) (i32.const 1)))
+                (local.set $code
+                  (call $gauge_set (i32.const 16) (i32.const 4)
+                    (i64.reinterpret_f64
+                      (f64.div (f64.const 1) (f64.convert_i32_u (local.get $epoch))))))
+                (br_if $done (local.get $code))
+                (local.set $code
+                  (call $counter_add (i32.const 32) (i32.const 6) (i64.const 1)))
+                (br_if $done (local.get $code))
+                (br $next)))
+            (local.get $code)))
+    "#;
+
+    #[test]
+    fn guests_report_a_loss_every_epoch() {
+        let context = ModuleContext::builder().with_module(MetricsApiHost::new());
+        let (mut store, instance) = guest::<MetricsApiHost>(TRAINING_LOOP, context);
+
+        let code = call(&mut store, &instance, "train", 4);
+        assert_eq!(code, ErrorCode::Success as u32);
+        let host = store.data_mut().module_mut::<MetricsApiHost>().unwrap();
+        assert_eq!(
+            host.registry().snapshot(),
+            [
+                Metric {
+                    name: "epochs".into(),
+                    value: MetricValue::Counter(4)
+                },
+                Metric {
+                    name: "loss".into(),
+                    value: MetricValue::Gauge(0.25)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn counters_saturate_and_gauges_keep_the_last_value() {
+        let mut metrics = MetricsRegistry::default();
+        metrics.counter_add("steps", u64::MAX - 1).unwrap();
+        metrics.counter_add("steps", 5).unwrap();
+        assert_eq!(metrics.get("steps"), Some(MetricValue::Counter(u64::MAX)));
+        metrics.gauge_set("lr", 0.1).unwrap();
+        metrics.gauge_set("lr", f64::NAN).unwrap();
+        assert!(matches!(metrics.get("lr"), Some(MetricValue::Gauge(lr)) if lr.is_nan()));
+        assert_eq!(metrics.get("missing"), None);
+    }
+
+    #[test]
+    fn metrics_keep_their_kind() {
+        let mut metrics = MetricsRegistry::default();
+        metrics.counter_add("steps", 1).unwrap();
+        metrics.gauge_set("loss", 1.0).unwrap();
+        let err = metrics.gauge_set("steps", 1.0).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert!(err.guest_message().ends_with("metric `steps` is a counter"));
+        let err = metrics.counter_add("loss", 1).unwrap_err();
+        assert!(err.guest_message().ends_with("metric `loss` is a gauge"));
+        assert_eq!(metrics.get("steps"), Some(MetricValue::Counter(1)));
+    }
+
+    #[test]
+    fn names_are_validated() {
+        let mut metrics = MetricsRegistry::default();
+        let long = "m".repeat(MetricsRegistry::MAX_NAME_LEN + 1);
+        for name in ["", long.as_str(), "with space", "naïve", "a/b"] {
+            let err = metrics.counter_add(name, 1).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{:?}", name);
+        }
+        let longest = "m".repeat(MetricsRegistry::MAX_NAME_LEN);
+        for name in [longest.as_str(), "train.loss:p50-ema_2"] {
+            metrics.counter_add(name, 1).unwrap();
+        }
+        assert_eq!(metrics.len(), 2);
+    }
+
+    #[test]
+    fn distinct_names_are_bounded() {
+        let mut host = MetricsApiHost::new().with_max_metrics(2);
+        let metrics = host.registry_mut();
+        metrics.counter_add("a", 1).unwrap();
+        metrics.gauge_set("b", 1.0).unwrap();
+        let err = metrics.counter_add("c", 1).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+        assert!(err
+            .guest_message()
+            .ends_with("metric `c` exceeds the limit of 2 metrics"));
+        // Existing names can still be updated
+        metrics.counter_add("a", 1).unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(host.registry().max_metrics(), 2);
+    }
+}
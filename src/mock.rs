@@ -0,0 +1,327 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::handles::unknown;
+use crate::{
+    error_code_ranges, ml_imports, ApiError, ErrorCode, FutureHandle, FutureState, FutureStatus,
+    GuestSlice, HostLinker, HostModule, InstantiationError, ModuleContext, ProtocolConfig, Shim,
+    TrainingRequest, WasmMemoryHandle,
+};
+
+/// A `start_training` call received by [`MockMLApi`], copied out of guest
+/// memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedTraining {
+    pub model_name: String,
+    pub epochs: u32,
+    pub dataset_uri: String,
+    pub checkpoint_path: String,
+    pub eval_interval: u32,
+    pub optimizer: String,
+    pub run_name: String,
+    pub seed: u64,
+    /// The guest's config, or the instance's defaults if it passed none.
+    pub protocol: ProtocolConfig,
+}
+
+impl RecordedTraining {
+    fn new(req: &TrainingRequest<'_>, protocol: &ProtocolConfig) -> Self {
+        Self {
+            model_name: req.model_name.to_owned(),
+            epochs: req.epochs,
+            dataset_uri: req.dataset_uri.to_owned(),
+            checkpoint_path: req.checkpoint_path.to_owned(),
+            eval_interval: req.eval_interval,
+            optimizer: req.optimizer.to_owned(),
+            run_name: req.run_name.to_owned(),
+            seed: req.seed,
+            protocol: *protocol,
+        }
+    }
+
+    /// The call as the request [`MLApiHost`](crate::MLApiHost) would have
+    /// started.
+    pub fn request(&self) -> TrainingRequest<'_> {
+        TrainingRequest {
+            model_name: &self.model_name,
+            epochs: self.epochs,
+            dataset_uri: &self.dataset_uri,
+            checkpoint_path: &self.checkpoint_path,
+            eval_interval: self.eval_interval,
+            optimizer: &self.optimizer,
+            run_name: &self.run_name,
+            seed: self.seed,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockFuture {
+    polls: u32,
+    state: FutureState,
+}
+
+/// Stand-in for [`MLApiHost`](crate::MLApiHost) to test guests against,
+/// without a training backend.
+///
+/// Registers the same `start_training`, `poll_future`, `cancel_training`,
+/// `free_future` and `get_future_result` imports, under the same module
+/// name, so it replaces `MLApiHost` in a [`ModuleRegistry`](crate::ModuleRegistry)
+/// rather than sitting next to it. Every training the guest starts is
+/// recorded in [`calls`](Self::calls). `start_training` returns the
+/// responses queued with [`with_handle`](Self::with_handle) and
+/// [`with_error`](Self::with_error) in order, then fresh handles once the
+/// queue is empty. Futures stay pending until they have been polled the
+/// number of times given to [`with_completion_after`](Self::with_completion_after),
+/// or forever if that was never set.
+///
+/// Once the instance is running, the mock is reached through
+/// [`ModuleContext::module`] and [`ModuleContext::module_mut`].
+#[derive(Debug, Default)]
+pub struct MockMLApi {
+    calls: Vec<RecordedTraining>,
+    responses: VecDeque<Result<FutureHandle, ApiError>>,
+    complete_after: Option<u32>,
+    result: Vec<u8>,
+    futures: HashMap<FutureHandle, MockFuture>,
+    last_handle: u64,
+    protocol_defaults: ProtocolConfig,
+}
+
+impl MockMLApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `handle` as the response to a `start_training` call, after
+    /// the responses queued before it.
+    pub fn with_handle(mut self, handle: FutureHandle) -> Self {
+        self.push_handle(handle);
+        self
+    }
+
+    /// Queues `err` as the response to a `start_training` call, which then
+    /// fails without starting a future.
+    pub fn with_error(mut self, err: ApiError) -> Self {
+        self.push_error(err);
+        self
+    }
+
+    /// Completes futures on their `polls`-th poll, with `0` completing them
+    /// as they are started.
+    pub fn with_completion_after(mut self, polls: u32) -> Self {
+        self.complete_after = Some(polls);
+        self
+    }
+
+    /// Result payload of completed futures, empty by default.
+    pub fn with_result(mut self, result: impl Into<Vec<u8>>) -> Self {
+        self.result = result.into();
+        self
+    }
+
+    pub fn push_handle(&mut self, handle: FutureHandle) {
+        self.responses.push_back(Ok(handle));
+    }
+
+    pub fn push_error(&mut self, err: ApiError) {
+        self.responses.push_back(Err(err));
+    }
+
+    /// Every `start_training` call in the order the guest made them,
+    /// including those answered with an error.
+    pub fn calls(&self) -> &[RecordedTraining] {
+        &self.calls
+    }
+
+    /// How often the guest polled `handle`, `None` if it isn't a live
+    /// future of the mock.
+    pub fn polls(&self, handle: FutureHandle) -> Option<u32> {
+        self.futures.get(&handle).map(|future| future.polls)
+    }
+
+    /// State of the future `handle`, `None` if it isn't a live future of
+    /// the mock.
+    pub fn future(&self, handle: FutureHandle) -> Option<&FutureState> {
+        self.futures.get(&handle).map(|future| &future.state)
+    }
+
+    /// Sets the state of the future `handle`, such as failing it, whether
+    /// or not the guest started it.
+    pub fn set_future(&mut self, handle: FutureHandle, state: FutureState) {
+        self.futures
+            .entry(handle)
+            .or_insert(MockFuture {
+                polls: 0,
+                state: FutureState::Pending,
+            })
+            .state = state;
+    }
+
+    fn next_handle(&mut self) -> FutureHandle {
+        loop {
+            self.last_handle += 1;
+            let handle = FutureHandle(self.last_handle);
+            if !self.futures.contains_key(&handle) {
+                return handle;
+            }
+        }
+    }
+
+    fn lookup(&mut self, handle: FutureHandle) -> Result<&mut MockFuture, ApiError> {
+        self.futures.get_mut(&handle).ok_or_else(|| unknown(handle))
+    }
+}
+
+impl HostModule for MockMLApi {
+    fn name() -> &'static str {
+        "ml_api"
+    }
+    fn log_target() -> &'static str {
+        "host::mock_ml_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::ML)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        let protocol_defaults = *host_context.protocol_defaults();
+        let host = host_context.module_mut::<Self>()?;
+        host.protocol_defaults = protocol_defaults;
+        Ok(host)
+    }
+
+    fn reset(state: &mut Self) {
+        state.futures.clear();
+    }
+}
+
+impl<'t> Shim<'t> for MockMLApi {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", ml_imports::PREFIX)
+    }
+
+    fn start_training_shim(
+        &mut self,
+        req: TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
+    ) -> Result<FutureHandle, Self::Err> {
+        self.calls.push(RecordedTraining::new(&req, protocol));
+        let handle = match self.responses.pop_front() {
+            Some(response) => response?,
+            None => self.next_handle(),
+        };
+        let state = match self.complete_after {
+            Some(0) => FutureState::Completed(self.result.clone()),
+            _ => FutureState::Pending,
+        };
+        self.futures.insert(handle, MockFuture { polls: 0, state });
+        Ok(handle)
+    }
+
+    fn poll_future_shim(&mut self, handle: FutureHandle) -> Result<FutureStatus, Self::Err> {
+        let future = self
+            .futures
+            .get_mut(&handle)
+            .ok_or_else(|| unknown(handle))?;
+        future.polls = future.polls.saturating_add(1);
+        if let (FutureState::Pending, Some(polls)) = (&future.state, self.complete_after) {
+            if future.polls >= polls {
+                future.state = FutureState::Completed(self.result.clone());
+            }
+        }
+        Ok(future.state.status())
+    }
+
+    fn cancel_training_shim(&mut self, handle: FutureHandle) -> Result<(), Self::Err> {
+        let future = self.lookup(handle)?;
+        if let FutureState::Pending = future.state {
+            future.state = FutureState::Cancelled;
+        }
+        Ok(())
+    }
+
+    fn future_result_shim(&mut self, handle: FutureHandle) -> Result<&[u8], Self::Err> {
+        match &self.lookup(handle)?.state {
+            FutureState::Completed(result) => Ok(result),
+            FutureState::Failed(err) => Err(err.clone()),
+            FutureState::Pending => Err(ApiError::not_found(format!(
+                "training {} hasn't completed yet",
+                handle.raw()
+            ))),
+            FutureState::Cancelled => Err(ApiError::not_found(format!(
+                "training {} was cancelled",
+                handle.raw()
+            ))),
+        }
+    }
+
+    fn free_future_shim(&mut self, handle: FutureHandle) -> Result<(), Self::Err> {
+        self.lookup(handle)?;
+        self.futures.remove(&handle);
+        Ok(())
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        // Validates like `MLApiHost`, so guests see the same errors for
+        // invalid requests, which aren't recorded
+        host_import!(linker, MockMLApi, ml_imports::START_TRAINING, (
+            model_name: str,
+            epochs: u32,
+            dataset_uri: str,
+            checkpoint_path: str,
+            eval_interval: u32,
+            optimizer: str,
+            run_name: str,
+            seed: u64,
+            protocol: Option<&ProtocolConfig>,
+            output: *mut FutureHandle,
+        ) => |host| {
+            let req = TrainingRequest::from_import_args(
+                model_name,
+                epochs,
+                dataset_uri,
+                checkpoint_path,
+                eval_interval,
+                optimizer,
+                run_name,
+                seed,
+            )?;
+            let protocol = protocol.unwrap_or(host.protocol_defaults);
+            host.start_training_shim(req, &protocol)
+        })?;
+        host_import!(linker, MockMLApi, ml_imports::POLL_FUTURE, (
+            handle: u64,
+            status_out: *mut FutureStatus,
+        ) => |host| host.poll_future_shim(FutureHandle(handle)))?;
+        host_import!(linker, MockMLApi, ml_imports::CANCEL_TRAINING, (handle: u64)
+            => |host| host.cancel_training_shim(FutureHandle(handle)))?;
+        host_import!(linker, MockMLApi, ml_imports::FREE_FUTURE, (handle: u64)
+            => |host| host.free_future_shim(FutureHandle(handle)))?;
+        host_import!(linker, MockMLApi, ml_imports::GET_FUTURE_RESULT, (
+            handle: u64,
+            buf: GuestSlice<u8>,
+        ) => |host, memory| {
+            let result = host.future_result_shim(FutureHandle(handle))?;
+            if result.len() as u64 > buf.len() {
+                Err(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "result of training {} needs {} bytes, the buffer only has {}",
+                        handle,
+                        result.len(),
+                        buf.len()
+                    ),
+                ))
+            } else {
+                GuestSlice::new(buf.ptr().raw(), result.len() as u64).write(memory, result)
+            }
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use std::convert::TryFrom;
+
+use crate::handles::SlotTable;
+use crate::{
+    error_code_ranges, storage, ApiError, ErrorCode, FutureHandle, HostLinker, HostModule,
+    InstantiationError, MemoryStorage, ModuleContext, Shim, ShutdownPolicy, StorageBackend,
+    WasmMemoryHandle,
+};
+
+import_names!(pub mod model_imports = "model" {
+    UPLOAD_BEGIN = "upload_begin",
+    UPLOAD_CHUNK = "upload_chunk",
+    UPLOAD_COMMIT = "upload_commit",
+    DOWNLOAD_BEGIN = "download_begin",
+    DOWNLOAD_CHUNK = "download_chunk",
+    DOWNLOAD_END = "download_end",
+});
+
+slot_handle! {
+    /// Handle of a model upload in progress, never `0`.
+    pub struct UploadHandle = "upload";
+}
+
+slot_handle! {
+    /// Handle of a model download in progress, never `0`.
+    pub struct DownloadHandle = "download";
+}
+
+struct Upload {
+    name: String,
+    total_len: usize,
+    data: Vec<u8>,
+}
+
+struct Download {
+    data: Vec<u8>,
+    /// Bytes already sent to the guest.
+    offset: usize,
+}
+
+/// Host module transferring model weights between guest and host in chunks,
+/// for guests handing back trained models or fetching base models.
+///
+/// Models are stored in a [`StorageBackend`] under their name, so the same
+/// backends as for [`StorageApiHost`](crate::StorageApiHost) can be used.
+pub struct ModelApiHost {
+    backend: Box<dyn StorageBackend>,
+    uploads: SlotTable<UploadHandle, Upload>,
+    downloads: SlotTable<DownloadHandle, Download>,
+    max_model_len: usize,
+}
+
+impl Default for ModelApiHost {
+    /// Backed by a [`MemoryStorage`].
+    fn default() -> Self {
+        Self::new(MemoryStorage::new())
+    }
+}
+
+impl ModelApiHost {
+    /// Number of uploads and of downloads the guest can have in progress.
+    pub const DEFAULT_CAPACITY: usize = 16;
+    pub const DEFAULT_MAX_MODEL_LEN: usize = 1 << 30;
+
+    pub fn new(backend: impl StorageBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            uploads: SlotTable::with_capacity(Self::DEFAULT_CAPACITY),
+            downloads: SlotTable::with_capacity(Self::DEFAULT_CAPACITY),
+            max_model_len: Self::DEFAULT_MAX_MODEL_LEN,
+        }
+    }
+
+    /// Caps the number of uploads and of downloads in progress, further ones
+    /// fail with [`ErrorCode::Busy`].
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.uploads.set_capacity(capacity);
+        self.downloads.set_capacity(capacity);
+        self
+    }
+
+    /// Largest model in bytes the guest can upload.
+    pub fn with_max_model_len(mut self, len: usize) -> Self {
+        self.max_model_len = len;
+        self
+    }
+
+    pub fn uploads_in_progress(&self) -> usize {
+        self.uploads.len()
+    }
+
+    pub fn downloads_in_progress(&self) -> usize {
+        self.downloads.len()
+    }
+
+    pub fn backend(&mut self) -> &mut dyn StorageBackend {
+        &mut *self.backend
+    }
+
+    /// Starts uploading `total_len` bytes of the model `name`, which only
+    /// replaces a stored model once [committed](Self::upload_commit).
+    pub fn upload_begin(&mut self, name: &str, total_len: u64) -> Result<UploadHandle, ApiError> {
+        storage::validate_key(name)?;
+        let total_len = usize::try_from(total_len)
+            .ok()
+            .filter(|len| *len <= self.max_model_len)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "model `{}` of {} bytes is larger than the limit of {} bytes",
+                        name, total_len, self.max_model_len
+                    ),
+                )
+            })?;
+        self.uploads.insert(Upload {
+            name: name.to_owned(),
+            total_len,
+            data: Vec::new(),
+        })
+    }
+
+    pub fn upload_chunk(&mut self, handle: UploadHandle, chunk: &[u8]) -> Result<(), ApiError> {
+        let upload = self.uploads.get_mut(handle)?;
+        if upload.data.len() + chunk.len() > upload.total_len {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "chunk of {} bytes overruns the {} bytes announced for model `{}`",
+                    chunk.len(),
+                    upload.total_len,
+                    upload.name
+                ),
+            ));
+        }
+        upload.data.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// Stores the uploaded model. The handle is stale afterwards, even if the
+    /// upload didn't receive all of its bytes, in which case it is dropped.
+    pub fn upload_commit(&mut self, handle: UploadHandle) -> Result<(), ApiError> {
+        let upload = self.uploads.remove(handle)?;
+        if upload.data.len() != upload.total_len {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "model `{}` received {} of {} bytes",
+                    upload.name,
+                    upload.data.len(),
+                    upload.total_len
+                ),
+            ));
+        }
+        self.backend.put(&upload.name, &upload.data)
+    }
+
+    /// Starts downloading the model `name`, returning its size in bytes.
+    pub fn download_begin(&mut self, name: &str) -> Result<(DownloadHandle, u64), ApiError> {
+        storage::validate_key(name)?;
+        self.downloads.check_capacity()?;
+        let data = self.backend.get(name)?;
+        let len = data.len() as u64;
+        let handle = self.downloads.insert(Download { data, offset: 0 })?;
+        Ok((handle, len))
+    }
+
+    /// Copies the next chunk of the model into `buf`, returning how many
+    /// bytes were copied. `0` means the model has been downloaded.
+    pub fn download_chunk(
+        &mut self,
+        handle: DownloadHandle,
+        buf: &mut [u8],
+    ) -> Result<usize, ApiError> {
+        let download = self.downloads.get_mut(handle)?;
+        let chunk = &download.data[download.offset..];
+        let len = chunk.len().min(buf.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        download.offset += len;
+        Ok(len)
+    }
+
+    pub fn download_end(&mut self, handle: DownloadHandle) -> Result<(), ApiError> {
+        self.downloads.remove(handle).map(drop)
+    }
+}
+
+impl HostModule for ModelApiHost {
+    fn name() -> &'static str {
+        "model_api"
+    }
+    fn log_target() -> &'static str {
+        "host::model_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::MODELS)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+
+    /// Drops the transfers the guest didn't finish, uncommitted uploads
+    /// don't change the stored models.
+    fn shutdown(state: &mut Self, _policy: ShutdownPolicy) -> Vec<FutureHandle> {
+        let uploads = state.uploads.clear();
+        let downloads = state.downloads.clear();
+        if !uploads.is_empty() || !downloads.is_empty() {
+            log::debug!(
+                target: Self::log_target(),
+                "reaped {} abandoned uploads and {} downloads",
+                uploads.len(),
+                downloads.len()
+            );
+        }
+        Vec::new()
+    }
+}
+
+impl<'t> Shim<'t> for ModelApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", model_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, ModelApiHost, model_imports::UPLOAD_BEGIN, (
+            name: str,
+            total_len: u64,
+            handle_out: *mut UploadHandle,
+        ) => |host| host.upload_begin(name, total_len))?;
+        host_import!(linker, ModelApiHost, model_imports::UPLOAD_CHUNK, (
+            handle: u64,
+            chunk: GuestSlice<u8>,
+        ) => |host, memory| host.upload_chunk(UploadHandle(handle), chunk.read(memory)?))?;
+        host_import!(linker, ModelApiHost, model_imports::UPLOAD_COMMIT, (handle: u64)
+            => |host| host.upload_commit(UploadHandle(handle)))?;
+        host_import!(linker, ModelApiHost, model_imports::DOWNLOAD_BEGIN, (
+            name: GuestStr,
+            handle_out: GuestPtr<DownloadHandle>,
+            len_out: GuestPtr<u64>,
+        ) => |host, memory| {
+            let (handle, len) = host.download_begin(name.read(memory)?)?;
+            let written = len_out
+                .write(memory, &len)
+                .and_then(|()| handle_out.write(memory, &handle));
+            // The guest never learns about a handle it couldn't be given
+            if written.is_err() {
+                host.download_end(handle)?;
+            }
+            written
+        })?;
+        host_import!(linker, ModelApiHost, model_imports::DOWNLOAD_CHUNK, (
+            handle: u64,
+            buf: GuestSlice<u8>,
+            read_out: GuestPtr<u32>,
+        ) => |host, memory| {
+            // Copies at most `u32::MAX` bytes, the most `read_out` can report
+            let buf = buf.bytes_mut(memory)?;
+            let len = buf.len().min(u32::MAX as usize);
+            let read = host.download_chunk(DownloadHandle(handle), &mut buf[..len])?;
+            read_out.write(memory, &(read as u32))
+        })?;
+        host_import!(linker, ModelApiHost, model_imports::DOWNLOAD_END, (handle: u64)
+            => |host| host.download_end(DownloadHandle(handle)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{call, guest, Guest};
+
+    /// Uploads `total` bytes from 64 KiB on as the model "base" and
+    /// downloads it back to 64 KiB + 1 MiB on, in chunks of `chunk` bytes.
+    /// Handles go to 8 and 24, the model's size to 32 and chunk sizes to 40.
+    const TRANSFER: &str = r#"
+        (module
+          (import "env" "model__upload_begin"
+            (func $upload_begin (param i32 i32 i64 i32) (result i32)))
+          (import "env" "model__upload_chunk"
+            (func $upload_chunk (param i64 i32 i32) (result i32)))
+          (import "env" "model__upload_commit" (func $upload_commit (param i64) (result i32)))
+          (import "env" "model__download_begin"
+            (func $download_begin (param i32 i32 i32 i32) (result i32)))
+          (import "env" "model__download_chunk"
+            (func $download_chunk (param i64 i32 i32 i32) (result i32)))
+          (import "env" "model__download_end" (func $download_end (param i64) (result i32)))
+          ;; A page past the download, for the buffer of its final empty chunk
+          (memory (export "memory") 34)
+          (data (i32.const 16) "base")
+          (func (export "upload") (param $total i32) (param $chunk i32) (result i32)
+            (local $code i32)
+            (local $offset i32)
+            (local $len i32)
+            (local.set $code
+              (call $upload_begin (i32.const 16) (i32.const 4)
+                (i64.extend_i32_u (local.get $total)) (i32.const 8)))
+            (if (local.get $code) (then (return (local.get $code))))
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $offset) (local.get $total)))
+                (local.set $len (i32.sub (local.get $total) (local.get $offset)))
+                (if (i32.gt_u (local.get $len) (local.get $chunk))
+                  (then (local.set $len (local.get $chunk))))
+                (local.set $code
+                  (call $upload_chunk (i64.load (i32.const 8))
+                    (i32.add (i32.const 65536) (local.get $offset)) (local.get $len)))
+                (if (local.get $code) (then (return (local.get $code))))
+                (local.set $offset (i32.add (local.get $offset) (local.get $len)))
+                (br $next)))
+            (call $upload_commit (i64.load (i32.const 8))))
+          (func (export "download") (param $chunk i32) (result i32)
+            (local $code i32)
+            (local $offset i32)
+            (local.set $code
+              (call $download_begin (i32.const 16) (i32.const 4) (i32.const 24) (i32.const 32)))
+            (if (local.get $code) (then (return (local.get $code))))
+            (loop $next
+              (local.set $code
+                (call $download_chunk (i64.load (i32.const 24))
+                  (i32.add (i32.const 1114112) (local.get $offset))
+                  (local.get $chunk)
+                  (i32.const 40)))
+              (if (local.get $code) (then (return (local.get $code))))
+              (local.set $offset (i32.add (local.get $offset) (i32.load (i32.const 40))))
+              (br_if $next (i32.load (i32.const 40))))
+            (call $download_end (i64.load (i32.const 24)))))
+    "#;
+
+    const MIB: usize = 1 << 20;
+
+    fn blob() -> Vec<u8> {
+        (0..MIB as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect()
+    }
+
+    fn transfer_guest(host: ModelApiHost) -> Guest {
+        guest::<ModelApiHost>(TRANSFER, ModuleContext::builder().with_module(host))
+    }
+
+    #[test]
+    fn models_round_trip_in_chunks() {
+        let (mut store, instance) = transfer_guest(ModelApiHost::default());
+        let blob = blob();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        memory.data_mut(&mut store)[65_536..65_536 + MIB].copy_from_slice(&blob);
+
+        assert_eq!(
+            call(&mut store, &instance, "upload", (MIB as u32, 4096)),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "download", 4096),
+            ErrorCode::Success as u32
+        );
+        let data = memory.data(&store);
+        assert_eq!(data[32..40], (MIB as u64).to_le_bytes());
+        assert!(data[1_114_112..1_114_112 + MIB] == blob[..]);
+        let host = store.data_mut().module_mut::<ModelApiHost>().unwrap();
+        assert_eq!(host.backend().get("base").unwrap(), blob);
+        assert_eq!(
+            (host.uploads_in_progress(), host.downloads_in_progress()),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn commits_need_every_announced_byte() {
+        let mut host = ModelApiHost::default();
+        let upload = host.upload_begin("base", 8).unwrap();
+        host.upload_chunk(upload, b"weig").unwrap();
+        let err = host.upload_chunk(upload, b"hts!!").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert!(err
+            .guest_message()
+            .ends_with("chunk of 5 bytes overruns the 8 bytes announced for model `base`"));
+
+        let err = host.upload_commit(upload).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        assert!(err
+            .guest_message()
+            .ends_with("model `base` received 4 of 8 bytes"));
+        // The failed commit dropped the upload without storing anything
+        let err = host.upload_chunk(upload, b"hts!").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        assert_eq!(
+            host.backend().get("base").unwrap_err().code(),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[test]
+    fn uploads_are_validated_when_they_begin() {
+        let mut host = ModelApiHost::default()
+            .with_max_model_len(16)
+            .with_capacity(1);
+        let err = host.upload_begin("base", 17).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        let err = host.upload_begin("", 1).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        host.upload_begin("base", 16).unwrap();
+        let err = host.upload_begin("other", 1).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Busy);
+        let err = host.download_begin("missing").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn uncommitted_uploads_are_reaped_on_shutdown() {
+        let mut backend = MemoryStorage::new();
+        backend.put("base", b"old").unwrap();
+        let mut context = ModuleContext::builder()
+            .with_module(ModelApiHost::new(backend))
+            .build()
+            .unwrap();
+        let host = context.module_mut::<ModelApiHost>().unwrap();
+        let upload = host.upload_begin("base", 3).unwrap();
+        host.upload_chunk(upload, b"new").unwrap();
+        let (download, len) = host.download_begin("base").unwrap();
+        assert_eq!(len, 3);
+
+        context.shutdown(ShutdownPolicy::CancelAll(std::time::Duration::ZERO));
+        let host = context.module_mut::<ModelApiHost>().unwrap();
+        assert_eq!(
+            (host.uploads_in_progress(), host.downloads_in_progress()),
+            (0, 0)
+        );
+        let err = host.upload_commit(upload).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        let err = host.download_chunk(download, &mut [0; 3]).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::StaleHandle);
+        assert_eq!(host.backend().get("base").unwrap(), b"old");
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::engine::DeprecatedCall;
+use crate::{
+    ApiError, HostCaller, HostFunc, HostResult, HostTrap, ImportAlias, ImportParams,
+    InstantiationError, ModuleContext, Runtime, WasmMemoryHandle,
+};
+
+/// A wasm level argument of a [`NativeLinker`] import.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NativeValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl From<u32> for NativeValue {
+    fn from(value: u32) -> Self {
+        Self::I32(value as i32)
+    }
+}
+
+impl From<i32> for NativeValue {
+    fn from(value: i32) -> Self {
+        Self::I32(value)
+    }
+}
+
+impl From<u64> for NativeValue {
+    fn from(value: u64) -> Self {
+        Self::I64(value as i64)
+    }
+}
+
+impl From<i64> for NativeValue {
+    fn from(value: i64) -> Self {
+        Self::I64(value)
+    }
+}
+
+impl From<f32> for NativeValue {
+    fn from(value: f32) -> Self {
+        Self::F32(value)
+    }
+}
+
+impl From<f64> for NativeValue {
+    fn from(value: f64) -> Self {
+        Self::F64(value)
+    }
+}
+
+type NativeFunc = Arc<dyn Fn(&mut dyn HostCaller, &[NativeValue]) -> HostResult + Send + Sync>;
+
+/// A [`Runtime`] without any wasm, for testing shims: there is no guest
+/// module, its imports are called directly by the test through a
+/// [`NativeInstance`], whose guest memory is a plain byte buffer.
+///
+/// Only sync imports can be registered, see [`PortableImports`](crate::PortableImports).
+#[derive(Clone, Default)]
+pub struct NativeLinker {
+    /// Every registered import and the host module that registered it.
+    imports: HashMap<(&'static str, &'static str), (&'static str, NativeFunc)>,
+    memory64: bool,
+}
+
+impl NativeLinker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes host modules register imports taking `u64` pointers and
+    /// lengths, like [`HostLinker::with_memory64`](crate::HostLinker::with_memory64).
+    pub fn with_memory64(mut self, memory64: bool) -> Self {
+        self.memory64 = memory64;
+        self
+    }
+
+    /// Whether an import `namespace::name` has been registered.
+    pub fn provides(&self, namespace: &str, name: &str) -> bool {
+        self.imports
+            .keys()
+            .any(|&(known_namespace, known)| known_namespace == namespace && known == name)
+    }
+
+    /// An instance of the registered imports for `host_context`, with
+    /// `memory_len` bytes of zeroed guest memory.
+    pub fn instantiate(&self, host_context: ModuleContext, memory_len: usize) -> NativeInstance {
+        NativeInstance {
+            imports: self.imports.clone(),
+            host_context,
+            memory: vec![0; memory_len],
+        }
+    }
+
+    fn check_unregistered(
+        &self,
+        namespace: &'static str,
+        name: &'static str,
+    ) -> Result<(), InstantiationError> {
+        match self.imports.get(&(namespace, name)) {
+            Some((first, _)) => Err(InstantiationError::DuplicateImport {
+                module: first,
+                name: format!("{}::{}", namespace, name),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Runtime for NativeLinker {
+    type Trap = HostTrap;
+
+    fn is_async(&self) -> bool {
+        false
+    }
+
+    fn is_memory64(&self) -> bool {
+        self.memory64
+    }
+
+    fn define_import<P: ImportParams, F: HostFunc<P>>(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        name: &'static str,
+        func: F,
+    ) -> Result<(), InstantiationError> {
+        self.check_unregistered(namespace, name)?;
+        let func: NativeFunc = Arc::new(move |caller, args| {
+            let params = P::from_native(args).ok_or_else(|| {
+                HostTrap::new(format!(
+                    "arguments {:?} don't match the signature of `{}::{}`",
+                    args, namespace, name
+                ))
+            })?;
+            func.call(caller, params)
+        });
+        self.imports.insert((namespace, name), (module, func));
+        Ok(())
+    }
+
+    fn alias(
+        &mut self,
+        module: &'static str,
+        namespace: &'static str,
+        alias: ImportAlias,
+    ) -> Result<(), InstantiationError> {
+        self.check_unregistered(namespace, alias.alias)?;
+        let (_, mut func) = self
+            .imports
+            .get(&(namespace, alias.import))
+            .cloned()
+            .ok_or_else(|| {
+                InstantiationError::Import(anyhow::anyhow!(
+                    "can't alias unknown import `{}::{}`",
+                    namespace,
+                    alias.import
+                ))
+            })?;
+        if let Some(deprecated) = DeprecatedCall::new(module, &alias) {
+            let aliased = func;
+            func = Arc::new(move |caller, args| {
+                caller.context_mut().record_deprecated_call(&deprecated);
+                aliased(caller, args)
+            });
+        }
+        self.imports
+            .insert((namespace, alias.alias), (module, func));
+        Ok(())
+    }
+}
+
+/// The host side of a guest instance of a [`NativeLinker`], calling its
+/// imports as the guest would.
+pub struct NativeInstance {
+    imports: HashMap<(&'static str, &'static str), (&'static str, NativeFunc)>,
+    host_context: ModuleContext,
+    memory: Vec<u8>,
+}
+
+impl NativeInstance {
+    /// Calls the import `namespace::name`, returning its error code or the
+    /// trap it raised. Unknown imports trap.
+    pub fn call(&mut self, namespace: &str, name: &str, args: &[NativeValue]) -> HostResult {
+        let func = self
+            .imports
+            .iter()
+            .find(|(&(known_namespace, known), _)| known_namespace == namespace && known == name)
+            .map(|(_, (_, func))| func.clone())
+            .ok_or_else(|| HostTrap::new(format!("unknown import `{}::{}`", namespace, name)))?;
+        let mut caller = NativeCaller {
+            host_context: &mut self.host_context,
+            memory: &mut self.memory,
+        };
+        func(&mut caller, args)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn context(&self) -> &ModuleContext {
+        &self.host_context
+    }
+
+    pub fn context_mut(&mut self) -> &mut ModuleContext {
+        &mut self.host_context
+    }
+
+    pub fn into_context(self) -> ModuleContext {
+        self.host_context
+    }
+}
+
+struct NativeCaller<'a> {
+    host_context: &'a mut ModuleContext,
+    memory: &'a mut [u8],
+}
+
+impl HostCaller for NativeCaller<'_> {
+    fn context(&self) -> &ModuleContext {
+        self.host_context
+    }
+
+    fn context_mut(&mut self) -> &mut ModuleContext {
+        self.host_context
+    }
+
+    fn memory(&mut self) -> Result<(WasmMemoryHandle<'_>, &mut ModuleContext), ApiError> {
+        Ok((WasmMemoryHandle::new(self.memory), self.host_context))
+    }
+}
@@ -0,0 +1,482 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{
+    HostLinker, InstantiationError, ModuleContext, ModuleContextError, ModuleError, ShutdownPolicy,
+};
+
+/// What [`InstancePool::checkout`] does when every instance is checked out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PoolExhaustion {
+    /// Fail with [`PoolError::Exhausted`] right away.
+    Fail,
+    /// Wait up to the timeout for an instance to be returned, then fail with
+    /// [`PoolError::Timeout`].
+    Wait(Duration),
+}
+
+/// How a returned instance is prepared for its next checkout.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PoolReset {
+    /// [`ModuleContext::reset`] with the policy, keeping the guest's memory
+    /// and globals. Instances that are poisoned or whose guest trapped are
+    /// re-instantiated instead.
+    Context(ShutdownPolicy),
+    /// Replaces the store and instance with new ones, so guest memory and
+    /// globals start over too.
+    Reinstantiate,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PoolError {
+    #[error("Failed to create the context of a pooled instance")]
+    Context(#[from] ModuleContextError),
+    #[error("Failed to instantiate a pooled instance")]
+    Instantiation(#[from] InstantiationError),
+    #[error("All {size} pooled instances are checked out")]
+    Exhausted { size: usize },
+    #[error("No pooled instance was returned within {timeout:?}")]
+    Timeout { timeout: Duration },
+}
+
+type ContextFactory = dyn Fn() -> Result<ModuleContext, ModuleContextError> + Send + Sync;
+
+/// Instances of one guest module, created up front against a shared
+/// [`HostLinker`] and reused across guest sessions, so that a session doesn't
+/// pay for setting up a store and instance.
+///
+/// The linker has to be a synchronous one, pooled instances are created with
+/// [`HostLinker::instantiate`].
+#[derive(Clone)]
+pub struct InstancePool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    linker: Arc<HostLinker>,
+    module: wasmtime::Module,
+    context: Box<ContextFactory>,
+    size: usize,
+    exhaustion: PoolExhaustion,
+    reset: PoolReset,
+    idle: Mutex<Idle>,
+    returned: Condvar,
+}
+
+struct Idle {
+    instances: Vec<Pooled>,
+    /// Instances that couldn't be re-instantiated when they were returned,
+    /// created again by the next checkout that needs one.
+    missing: usize,
+}
+
+struct Pooled {
+    store: wasmtime::Store<ModuleContext>,
+    instance: wasmtime::Instance,
+}
+
+impl InstancePool {
+    pub fn builder() -> InstancePoolBuilder {
+        InstancePoolBuilder::default()
+    }
+
+    /// Number of instances, checked out or not.
+    pub fn size(&self) -> usize {
+        self.shared.size
+    }
+
+    /// Number of instances ready to be checked out.
+    pub fn idle(&self) -> usize {
+        self.shared.lock().instances.len()
+    }
+
+    /// Takes an instance out of the pool until the returned guard is dropped,
+    /// handling exhaustion as configured with
+    /// [`with_exhaustion`](InstancePoolBuilder::with_exhaustion).
+    pub fn checkout(&self) -> Result<PooledInstance, PoolError> {
+        let shared = &self.shared;
+        let deadline = match shared.exhaustion {
+            PoolExhaustion::Wait(timeout) => Some((Instant::now() + timeout, timeout)),
+            PoolExhaustion::Fail => None,
+        };
+        let mut idle = shared.lock();
+        let pooled = loop {
+            if let Some(pooled) = idle.instances.pop() {
+                break pooled;
+            }
+            if idle.missing > 0 {
+                idle.missing -= 1;
+                drop(idle);
+                let created = shared.instantiate();
+                if created.is_err() {
+                    shared.lock().missing += 1;
+                }
+                break created?;
+            }
+            let (deadline, timeout) = match deadline {
+                Some(deadline) => deadline,
+                None => return Err(PoolError::Exhausted { size: shared.size }),
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PoolError::Timeout { timeout });
+            }
+            idle = shared
+                .returned
+                .wait_timeout(idle, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        };
+        Ok(PooledInstance {
+            pooled: Some(pooled),
+            shared: Arc::clone(shared),
+            discard: false,
+        })
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Idle> {
+        self.idle.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn instantiate(&self) -> Result<Pooled, PoolError> {
+        let mut store = (self.context)()?.into_store(self.linker.linker().engine());
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        Ok(Pooled { store, instance })
+    }
+
+    /// Prepares a returned instance for its next checkout and puts it back.
+    fn release(&self, mut pooled: Pooled, discard: bool) {
+        let reinstantiate = match self.reset {
+            PoolReset::Context(_) if discard || pooled.store.data().is_poisoned() => true,
+            PoolReset::Context(policy) => {
+                pooled.store.data_mut().reset(policy);
+                false
+            }
+            PoolReset::Reinstantiate => true,
+        };
+        let pooled = if reinstantiate {
+            // Dropping the old context shuts it down
+            drop(pooled);
+            match self.instantiate() {
+                Ok(pooled) => Some(pooled),
+                Err(err) => {
+                    log::error!("failed to re-instantiate a pooled instance: {}", err);
+                    None
+                }
+            }
+        } else {
+            Some(pooled)
+        };
+        let mut idle = self.lock();
+        match pooled {
+            Some(pooled) => idle.instances.push(pooled),
+            None => idle.missing += 1,
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// An instance checked out of an [`InstancePool`], returned to it when
+/// dropped.
+pub struct PooledInstance {
+    pooled: Option<Pooled>,
+    shared: Arc<Shared>,
+    discard: bool,
+}
+
+impl PooledInstance {
+    pub fn instance(&self) -> wasmtime::Instance {
+        self.pooled().instance
+    }
+
+    pub fn store(&self) -> &wasmtime::Store<ModuleContext> {
+        &self.pooled().store
+    }
+
+    pub fn store_mut(&mut self) -> &mut wasmtime::Store<ModuleContext> {
+        &mut self.pooled_mut().store
+    }
+
+    pub fn context(&self) -> &ModuleContext {
+        self.store().data()
+    }
+
+    pub fn context_mut(&mut self) -> &mut ModuleContext {
+        self.store_mut().data_mut()
+    }
+
+    /// [`ModuleContext::call_export`] on this instance. If the guest traps
+    /// or fails otherwise than by a missing export, the instance is
+    /// [discarded](Self::discard), as its memory may be left inconsistent.
+    pub fn call_export<Params, Results>(
+        &mut self,
+        function: &str,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        let pooled = self.pooled.as_mut().expect("instance is checked out");
+        let result =
+            ModuleContext::call_export(&mut pooled.store, &pooled.instance, function, params);
+        if let Err(err) = &result {
+            if !matches!(err, ModuleError::MissingExport { .. }) {
+                self.discard();
+            }
+        }
+        result
+    }
+
+    /// Replaces the instance with a new one when it is returned, rather
+    /// than resetting it.
+    pub fn discard(&mut self) {
+        self.discard = true;
+    }
+
+    fn pooled(&self) -> &Pooled {
+        self.pooled.as_ref().expect("instance is checked out")
+    }
+
+    fn pooled_mut(&mut self) -> &mut Pooled {
+        self.pooled.as_mut().expect("instance is checked out")
+    }
+}
+
+impl Drop for PooledInstance {
+    fn drop(&mut self) {
+        if let Some(pooled) = self.pooled.take() {
+            self.shared.release(pooled, self.discard);
+        }
+    }
+}
+
+/// Configures an [`InstancePool`], by default of 8 instances that are
+/// re-instantiated when returned and failing checkouts when exhausted.
+pub struct InstancePoolBuilder {
+    size: usize,
+    exhaustion: PoolExhaustion,
+    reset: PoolReset,
+}
+
+impl Default for InstancePoolBuilder {
+    fn default() -> Self {
+        Self {
+            size: 8,
+            exhaustion: PoolExhaustion::Fail,
+            reset: PoolReset::Reinstantiate,
+        }
+    }
+}
+
+impl InstancePoolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_exhaustion(mut self, exhaustion: PoolExhaustion) -> Self {
+        self.exhaustion = exhaustion;
+        self
+    }
+
+    pub fn with_reset(mut self, reset: PoolReset) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// Creates every instance of `module`, each with a context from
+    /// `context`, which is also called for every re-instantiation.
+    pub fn build<F>(
+        self,
+        linker: Arc<HostLinker>,
+        module: wasmtime::Module,
+        context: F,
+    ) -> Result<InstancePool, PoolError>
+    where
+        F: Fn() -> Result<ModuleContext, ModuleContextError> + Send + Sync + 'static,
+    {
+        let shared = Shared {
+            linker,
+            module,
+            context: Box::new(context),
+            size: self.size,
+            exhaustion: self.exhaustion,
+            reset: self.reset,
+            idle: Mutex::new(Idle {
+                instances: Vec::with_capacity(self.size),
+                missing: 0,
+            }),
+            returned: Condvar::new(),
+        };
+        for _ in 0..self.size {
+            let pooled = shared.instantiate()?;
+            shared.lock().instances.push(pooled);
+        }
+        Ok(InstancePool {
+            shared: Arc::new(shared),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, ErrorCode, MLApiHost};
+
+    /// A guest counting its `bump` calls in a global, failing to start a
+    /// training in `fail` and trapping in `crash`.
+    const GUEST: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "mnist")
+          (global $bumps (mut i32) (i32.const 0))
+          (func (export "bump") (result i32)
+            (global.set $bumps (i32.add (global.get $bumps) (i32.const 1)))
+            (global.get $bumps))
+          (func (export "fail") (result i32)
+            (call $start_training
+              (i32.const 64) (i32.const 5)
+              (i32.const 0)
+              (i32.const 64) (i32.const 5)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i32.const 0) (i32.const 0)
+              (i64.const 42)
+              (i32.const 0)
+              (i32.const 32)))
+          (func (export "crash") (unreachable)))
+    "#;
+
+    fn guest_pool(builder: InstancePoolBuilder) -> InstancePool {
+        let engine = wasmtime::Engine::default();
+        let linker = test_support::linker::<MLApiHost>(&engine);
+        let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+        builder
+            .build(Arc::new(linker), module, || {
+                ModuleContext::builder()
+                    .with_module(MLApiHost::default())
+                    .with_job_threads(1)
+                    .build()
+            })
+            .unwrap()
+    }
+
+    fn bump(instance: &mut PooledInstance) -> i32 {
+        instance.call_export("bump", ()).unwrap()
+    }
+
+    /// Fails a host call, leaving a last error and call stats behind.
+    fn fail(instance: &mut PooledInstance) {
+        let code: u32 = instance.call_export("fail", ()).unwrap();
+        assert_eq!(code, ErrorCode::InvalidArgument as u32);
+        assert!(instance.context().last_error().is_some());
+    }
+
+    #[test]
+    fn reinstantiated_instances_start_over() {
+        let pool = guest_pool(InstancePool::builder().with_size(1));
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!((bump(&mut instance), bump(&mut instance)), (1, 2));
+        fail(&mut instance);
+        drop(instance);
+
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!(bump(&mut instance), 1);
+        assert!(instance.context().last_error().is_none());
+        assert_eq!(instance.context().call_stats().len(), 0);
+    }
+
+    #[test]
+    fn reset_contexts_keep_guest_state_but_not_host_state() {
+        let reset = PoolReset::Context(ShutdownPolicy::CancelAll(Duration::from_millis(10)));
+        let pool = guest_pool(InstancePool::builder().with_size(1).with_reset(reset));
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!(bump(&mut instance), 1);
+        fail(&mut instance);
+        drop(instance);
+
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!(bump(&mut instance), 2);
+        assert!(instance.context().last_error().is_none());
+        assert_eq!(instance.context().call_stats().len(), 0);
+    }
+
+    #[test]
+    fn trapped_instances_are_replaced() {
+        let reset = PoolReset::Context(ShutdownPolicy::CancelAll(Duration::from_millis(10)));
+        let pool = guest_pool(InstancePool::builder().with_size(1).with_reset(reset));
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!(bump(&mut instance), 1);
+        assert!(matches!(
+            instance.call_export::<(), ()>("crash", ()),
+            Err(ModuleError::Trap { .. })
+        ));
+        drop(instance);
+        assert_eq!(bump(&mut pool.checkout().unwrap()), 1);
+
+        // Missing exports don't discard the instance
+        let mut instance = pool.checkout().unwrap();
+        assert_eq!(bump(&mut instance), 2);
+        assert!(instance.call_export::<(), ()>("nope", ()).is_err());
+        drop(instance);
+        assert_eq!(bump(&mut pool.checkout().unwrap()), 3);
+    }
+
+    #[test]
+    fn exhausted_pools_fail_right_away() {
+        let pool = guest_pool(InstancePool::builder().with_size(2));
+        assert_eq!((pool.size(), pool.idle()), (2, 2));
+        let first = pool.checkout().unwrap();
+        let _second = pool.checkout().unwrap();
+        assert_eq!(pool.idle(), 0);
+        assert!(matches!(
+            pool.checkout(),
+            Err(PoolError::Exhausted { size: 2 })
+        ));
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+        assert!(pool.checkout().is_ok());
+    }
+
+    #[test]
+    fn exhausted_pools_wait_for_returned_instances() {
+        let timeout = Duration::from_millis(50);
+        let pool = guest_pool(
+            InstancePool::builder()
+                .with_size(1)
+                .with_exhaustion(PoolExhaustion::Wait(timeout)),
+        );
+        let _instance = pool.checkout().unwrap();
+        let started = Instant::now();
+        match pool.checkout() {
+            Err(PoolError::Timeout { timeout: waited }) => assert_eq!(waited, timeout),
+            res => panic!("{:?}", res.map(|_| ()).map_err(|e| e.to_string())),
+        }
+        assert!(started.elapsed() >= timeout);
+
+        let pool = guest_pool(
+            InstancePool::builder()
+                .with_size(1)
+                .with_exhaustion(PoolExhaustion::Wait(Duration::from_secs(10))),
+        );
+        let instance = pool.checkout().unwrap();
+        let returner = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(instance);
+        });
+        assert!(pool.checkout().is_ok());
+        returner.join().unwrap();
+    }
+}
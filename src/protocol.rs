@@ -0,0 +1,457 @@
+use std::time::Duration;
+
+use crate::{ApiError, ErrorCode, FromGuest, GuestPtr, PlainOldData, WasmMemoryHandle};
+
+/// Training protocol settings passed by the guest to `start_training`.
+///
+/// Guests pass a pointer to one of the versioned layouts in their own memory,
+/// each starting with its `u32` version. This struct is the current layout,
+/// older ones such as [`ProtocolConfigV2`] are upgraded when read. Guests that
+/// can't lay out C structs pass the config as JSON instead, see
+/// [`ProtocolConfig::from_json`].
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[repr(C)]
+pub struct ProtocolConfig {
+    /// Layout version, [`ProtocolConfig::VERSION`] once read.
+    pub version: u32,
+    pub batch_size: u32,
+    pub learning_rate: f32,
+    /// Bitwise or of the `ProtocolConfig::*` flags.
+    pub flags: u32,
+    /// Number of steps the learning rate is ramped up over, `0` for none.
+    /// Added in version 2.
+    pub warmup_steps: u32,
+    /// L2 weight decay factor. Added in version 2.
+    pub weight_decay: f32,
+    /// Milliseconds a training may take from being started, including time
+    /// spent queued, before it fails with [`ErrorCode::TimedOut`], `0` for no
+    /// limit. Added in version 3.
+    pub timeout_ms: u32,
+}
+
+/// Version 2 of the [`ProtocolConfig`] guest layout. It is read without a
+/// timeout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct ProtocolConfigV2 {
+    pub version: u32,
+    pub batch_size: u32,
+    pub learning_rate: f32,
+    pub flags: u32,
+    pub warmup_steps: u32,
+    pub weight_decay: f32,
+}
+
+/// Version 1 of the [`ProtocolConfig`] guest layout. It is read with
+/// `warmup_steps` set to `0` and `weight_decay` to `0.0`, which is how
+/// version 1 hosts trained.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct ProtocolConfigV1 {
+    pub version: u32,
+    pub batch_size: u32,
+    pub learning_rate: f32,
+    pub flags: u32,
+}
+
+// The guest ABI of every layout version
+const _: () = {
+    use std::mem::{align_of, offset_of, size_of};
+    assert!(size_of::<ProtocolConfigV1>() == 16);
+    assert!(align_of::<ProtocolConfigV1>() == 4);
+    assert!(offset_of!(ProtocolConfigV1, version) == 0);
+    assert!(offset_of!(ProtocolConfigV1, batch_size) == 4);
+    assert!(offset_of!(ProtocolConfigV1, learning_rate) == 8);
+    assert!(offset_of!(ProtocolConfigV1, flags) == 12);
+
+    assert!(size_of::<ProtocolConfigV2>() == 24);
+    assert!(align_of::<ProtocolConfigV2>() == 4);
+    assert!(offset_of!(ProtocolConfigV2, version) == 0);
+    assert!(offset_of!(ProtocolConfigV2, batch_size) == 4);
+    assert!(offset_of!(ProtocolConfigV2, learning_rate) == 8);
+    assert!(offset_of!(ProtocolConfigV2, flags) == 12);
+    assert!(offset_of!(ProtocolConfigV2, warmup_steps) == 16);
+    assert!(offset_of!(ProtocolConfigV2, weight_decay) == 20);
+
+    assert!(size_of::<ProtocolConfig>() == 28);
+    assert!(align_of::<ProtocolConfig>() == 4);
+    assert!(offset_of!(ProtocolConfig, version) == 0);
+    assert!(offset_of!(ProtocolConfig, batch_size) == 4);
+    assert!(offset_of!(ProtocolConfig, learning_rate) == 8);
+    assert!(offset_of!(ProtocolConfig, flags) == 12);
+    assert!(offset_of!(ProtocolConfig, warmup_steps) == 16);
+    assert!(offset_of!(ProtocolConfig, weight_decay) == 20);
+    assert!(offset_of!(ProtocolConfig, timeout_ms) == 24);
+};
+
+// SAFETY: `repr(C)` and only 4 byte fields, so there is no padding
+unsafe impl PlainOldData for ProtocolConfigV1 {}
+
+// SAFETY: `repr(C)` and only 4 byte fields, so there is no padding
+unsafe impl PlainOldData for ProtocolConfigV2 {}
+
+/// The current layout as it is read from guest memory. `ProtocolConfig`
+/// itself isn't `PlainOldData`, as reading it has to go through the version
+/// check.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+struct CurrentLayout(ProtocolConfig);
+
+// SAFETY: `ProtocolConfig` is `repr(C)` and only has 4 byte fields
+unsafe impl PlainOldData for CurrentLayout {}
+
+impl From<ProtocolConfigV1> for ProtocolConfig {
+    fn from(v1: ProtocolConfigV1) -> Self {
+        Self {
+            version: Self::VERSION,
+            batch_size: v1.batch_size,
+            learning_rate: v1.learning_rate,
+            flags: v1.flags,
+            warmup_steps: 0,
+            weight_decay: 0.0,
+            timeout_ms: 0,
+        }
+    }
+}
+
+impl From<ProtocolConfigV2> for ProtocolConfig {
+    fn from(v2: ProtocolConfigV2) -> Self {
+        Self {
+            version: Self::VERSION,
+            batch_size: v2.batch_size,
+            learning_rate: v2.learning_rate,
+            flags: v2.flags,
+            warmup_steps: v2.warmup_steps,
+            weight_decay: v2.weight_decay,
+            timeout_ms: 0,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    /// The newest layout version this host understands.
+    pub const VERSION: u32 = 3;
+
+    /// Shuffle the dataset between epochs.
+    pub const SHUFFLE: u32 = 1 << 0;
+    /// Train with mixed f16/f32 precision.
+    pub const MIXED_PRECISION: u32 = 1 << 1;
+    /// Write a checkpoint after every epoch.
+    pub const CHECKPOINT_EVERY_EPOCH: u32 = 1 << 2;
+
+    const KNOWN_FLAGS: u32 = Self::SHUFFLE | Self::MIXED_PRECISION | Self::CHECKPOINT_EVERY_EPOCH;
+
+    /// Reads a config of any supported layout version from guest memory and
+    /// validates it.
+    pub fn read_from(memory: &WasmMemoryHandle<'_>, ptr: GuestPtr<Self>) -> Result<Self, ApiError> {
+        let ptr = ptr.non_null()?.raw();
+        let version = GuestPtr::<u32>::new(ptr).read(memory)?;
+        Self::check_version(version)?;
+        let config = match version {
+            1 => GuestPtr::<ProtocolConfigV1>::new(ptr).read(memory)?.into(),
+            2 => GuestPtr::<ProtocolConfigV2>::new(ptr).read(memory)?.into(),
+            _ => GuestPtr::<CurrentLayout>::new(ptr).read(memory)?.0,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses and validates a JSON config. Missing fields take their
+    /// [`Default`] value, unknown fields are rejected.
+    pub fn from_json(json: &str) -> Result<Self, ApiError> {
+        let mut config: Self = serde_json::from_str(json).map_err(|err| {
+            ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!("invalid protocol config JSON: {}", err),
+            )
+        })?;
+        Self::check_version(config.version)?;
+        config.version = Self::VERSION;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn check_version(version: u32) -> Result<(), ApiError> {
+        if version > Self::VERSION {
+            Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "protocol config version {} is newer than the supported version {}",
+                    version,
+                    Self::VERSION
+                ),
+            ))
+        } else if version == 0 {
+            Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "protocol config version 0 is invalid",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: String| Err(ApiError::new(ErrorCode::InvalidArgument, message));
+        if self.batch_size == 0 {
+            return invalid("batch size must not be zero".to_owned());
+        }
+        if self.learning_rate.is_nan() {
+            return invalid("learning rate must not be NaN".to_owned());
+        }
+        if self.flags & !Self::KNOWN_FLAGS != 0 {
+            return invalid(format!(
+                "unknown protocol flags {:#x}",
+                self.flags & !Self::KNOWN_FLAGS
+            ));
+        }
+        if self.weight_decay.is_nan() || self.weight_decay < 0.0 {
+            return invalid(format!(
+                "weight decay must not be negative or NaN, got {}",
+                self.weight_decay
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(u64::from(ms))),
+        }
+    }
+}
+
+impl FromGuest for ProtocolConfig {
+    fn from_guest(memory: &WasmMemoryHandle<'_>, ptr: GuestPtr<Self>) -> Result<Self, ApiError> {
+        Self::read_from(memory, ptr)
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            version: Self::VERSION,
+            batch_size: 32,
+            learning_rate: 1e-3,
+            flags: 0,
+            warmup_steps: 0,
+            weight_decay: 0.0,
+            timeout_ms: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a config from a guest memory holding `layout` at `8`.
+    fn read<T: PlainOldData>(layout: T) -> Result<ProtocolConfig, ApiError> {
+        let mut bytes = [0; 64];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        memory.write_pod(8, &layout).unwrap();
+        ProtocolConfig::read_from(&memory, GuestPtr::new(8))
+    }
+
+    fn current() -> CurrentLayout {
+        CurrentLayout(ProtocolConfig {
+            batch_size: 64,
+            learning_rate: 0.25,
+            flags: ProtocolConfig::SHUFFLE | ProtocolConfig::CHECKPOINT_EVERY_EPOCH,
+            warmup_steps: 100,
+            weight_decay: 0.5,
+            timeout_ms: 1_000,
+            ..ProtocolConfig::default()
+        })
+    }
+
+    fn invalid_argument(
+        res: Result<ProtocolConfig, ApiError>,
+        message: &str,
+    ) -> Result<(), String> {
+        match res {
+            Err(err) if err.code() == ErrorCode::InvalidArgument => {
+                if err.guest_message().ends_with(message) {
+                    Ok(())
+                } else {
+                    Err(err.guest_message())
+                }
+            }
+            res => Err(format!("{:?}", res)),
+        }
+    }
+
+    #[test]
+    fn configs_are_read_as_laid_out() {
+        let config = read(current()).unwrap();
+        assert_eq!(config, current().0);
+        assert!(config.has_flag(ProtocolConfig::SHUFFLE));
+        assert!(!config.has_flag(ProtocolConfig::MIXED_PRECISION));
+        assert_eq!(config.timeout(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn malformed_configs_are_invalid_arguments() {
+        let mut layout = current();
+        layout.0.batch_size = 0;
+        invalid_argument(read(layout), "batch size must not be zero").unwrap();
+
+        let mut layout = current();
+        layout.0.learning_rate = f32::NAN;
+        invalid_argument(read(layout), "learning rate must not be NaN").unwrap();
+
+        let mut layout = current();
+        layout.0.flags |= 1 << 3 | 1 << 31;
+        invalid_argument(read(layout), "unknown protocol flags 0x80000008").unwrap();
+
+        let mut layout = current();
+        layout.0.weight_decay = -1.0;
+        invalid_argument(read(layout), "must not be negative or NaN, got -1").unwrap();
+    }
+
+    #[test]
+    fn configs_are_bounds_checked() {
+        let mut bytes = [0; 64];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        memory.write_pod(36, &current()).unwrap();
+        assert!(ProtocolConfig::read_from(&memory, GuestPtr::new(36)).is_ok());
+        // A version 1 config whose version fits, but not the rest
+        memory.write_pod(60, &1_u32).unwrap();
+        let err = ProtocolConfig::read_from(&memory, GuestPtr::new(60)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::OutOfBounds);
+        let err = ProtocolConfig::read_from(&memory, GuestPtr::new(10)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+        let err = ProtocolConfig::read_from(&memory, GuestPtr::null()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn json_and_struct_configs_are_the_same() {
+        let json = r#"{
+            "version": 3,
+            "batch_size": 64,
+            "learning_rate": 0.25,
+            "flags": 5,
+            "warmup_steps": 100,
+            "weight_decay": 0.5,
+            "timeout_ms": 1000
+        }"#;
+        assert_eq!(
+            ProtocolConfig::from_json(json).unwrap(),
+            read(current()).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_configs_default_missing_fields() {
+        let config = ProtocolConfig::from_json(r#"{ "version": 1, "batch_size": 8 }"#).unwrap();
+        assert_eq!(
+            config,
+            ProtocolConfig {
+                batch_size: 8,
+                ..ProtocolConfig::default()
+            }
+        );
+        assert_eq!(config.version, ProtocolConfig::VERSION);
+    }
+
+    #[test]
+    fn malformed_json_names_the_parse_error() {
+        let cases = [
+            (
+                r#"{ "batch_size": 8, "epochs": 3 }"#,
+                "unknown field `epochs`",
+            ),
+            (r#"{ "batch_size": -1 }"#, "invalid value: integer `-1`"),
+            ("{ \"batch_size\": 8 ", "EOF while parsing an object"),
+            (r#""mnist""#, "invalid type: string"),
+        ];
+        for (json, error) in cases {
+            let err = ProtocolConfig::from_json(json).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{}", json);
+            let message = err.guest_message();
+            assert!(
+                message.starts_with("InvalidArgument(1): invalid protocol config JSON: ")
+                    && message.contains(error),
+                "{}",
+                message
+            );
+        }
+        let res = ProtocolConfig::from_json(r#"{ "batch_size": 0 }"#);
+        invalid_argument(res, "batch size must not be zero").unwrap();
+    }
+
+    #[test]
+    fn older_layouts_are_upgraded() {
+        let v1 = ProtocolConfigV1 {
+            version: 1,
+            batch_size: 16,
+            learning_rate: 0.5,
+            flags: ProtocolConfig::MIXED_PRECISION,
+        };
+        let expected = ProtocolConfig {
+            batch_size: 16,
+            learning_rate: 0.5,
+            flags: ProtocolConfig::MIXED_PRECISION,
+            warmup_steps: 0,
+            weight_decay: 0.0,
+            timeout_ms: 0,
+            ..ProtocolConfig::default()
+        };
+        assert_eq!(read(v1).unwrap(), expected);
+
+        let v2 = ProtocolConfigV2 {
+            version: 2,
+            batch_size: 16,
+            learning_rate: 0.5,
+            flags: ProtocolConfig::MIXED_PRECISION,
+            warmup_steps: 10,
+            weight_decay: 0.25,
+        };
+        let expected = ProtocolConfig {
+            warmup_steps: 10,
+            weight_decay: 0.25,
+            ..expected
+        };
+        let config = read(v2).unwrap();
+        assert_eq!(config, expected);
+        assert_eq!(config.version, ProtocolConfig::VERSION);
+        assert_eq!(config.timeout(), None);
+    }
+
+    #[test]
+    fn upgraded_layouts_are_validated() {
+        let v1 = ProtocolConfigV1 {
+            version: 1,
+            batch_size: 0,
+            learning_rate: 0.5,
+            flags: 0,
+        };
+        invalid_argument(read(v1), "batch size must not be zero").unwrap();
+    }
+
+    #[test]
+    fn unsupported_versions_name_both_versions() {
+        let mut newer = current();
+        newer.0.version = ProtocolConfig::VERSION + 1;
+        invalid_argument(
+            read(newer),
+            "protocol config version 4 is newer than the supported version 3",
+        )
+        .unwrap();
+        let mut zero = current();
+        zero.0.version = 0;
+        invalid_argument(read(zero), "protocol config version 0 is invalid").unwrap();
+        invalid_argument(
+            ProtocolConfig::from_json(r#"{ "version": 4 }"#),
+            "protocol config version 4 is newer than the supported version 3",
+        )
+        .unwrap();
+    }
+}
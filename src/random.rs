@@ -0,0 +1,257 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::{
+    error_code_ranges, ApiError, CallSite, ErrorCode, HostLinker, HostModule, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod random_imports = "random" {
+    FILL = "fill",
+    RESEED = "reseed",
+});
+
+/// Host module giving guests a seedable random byte stream.
+///
+/// Each instance has its own stream, seeded from
+/// [`ModuleContextBuilder::with_seed`](crate::ModuleContextBuilder::with_seed)
+/// so runs can be replayed, or from host entropy if no seed was given. The
+/// generator is SplitMix64: fast and stable across releases, but not
+/// suitable for cryptography.
+#[derive(Debug, Default)]
+pub struct RandomApiHost {
+    /// Seeded on the first call, once the context's seed is known.
+    rng: Option<SplitMix64>,
+    seed: Option<u64>,
+}
+
+impl RandomApiHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the stream currently in use started from, `None` before the
+    /// first call. Recording it is enough to replay the guest's randomness.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        if self.rng.is_none() {
+            // Only reached when used outside of a `ModuleContext`
+            self.reseed(entropy());
+        }
+        let rng = self.rng.as_mut().expect("seeded above");
+        for chunk in buf.chunks_mut(8) {
+            let bytes = rng.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Restarts the stream from `seed`.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng = Some(SplitMix64(seed));
+    }
+}
+
+impl HostModule for RandomApiHost {
+    fn name() -> &'static str {
+        "random_api"
+    }
+    fn log_target() -> &'static str {
+        "host::random_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::RANDOM)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        let seed = host_context.seed();
+        let host = host_context.module_mut::<Self>()?;
+        if host.rng.is_none() {
+            host.reseed(seed.unwrap_or_else(entropy));
+        }
+        Ok(host)
+    }
+
+    /// The next session's stream starts from the context's seed again.
+    fn reset(state: &mut Self) {
+        state.rng = None;
+        state.seed = None;
+    }
+
+    fn check_call(host_context: &ModuleContext, site: CallSite) -> Result<(), ApiError> {
+        if site.function() == random_imports::RESEED && !host_context.reseed_allowed() {
+            return Err(ApiError::new(
+                ErrorCode::PermissionDenied,
+                "reseeding is disabled for this instance",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'t> Shim<'t> for RandomApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", random_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, RandomApiHost, random_imports::FILL, (
+            buf: GuestSlice<u8>,
+        ) => |host, memory| {
+            // Bounds checked before anything is allocated for the guest
+            let mut bytes = vec![0; buf.read(memory)?.len()];
+            host.fill(&mut bytes);
+            buf.write(memory, &bytes)
+        })?;
+        host_import!(linker, RandomApiHost, random_imports::RESEED, (
+            seed: u64,
+        ) => |host| {
+            host.reseed(seed);
+            Ok::<(), ApiError>(())
+        })?;
+        Ok(())
+    }
+}
+
+/// <https://prng.di.unimi.it/splitmix64.c>
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// A seed for instances without one, from the randomly keyed std hasher.
+fn entropy() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, call, guest_bytes};
+
+    /// Fills `len` bytes at 16, or reseeds with `seed`.
+    const RANDOM: &str = r#"
+        (module
+          (import "env" "random__fill" (func $fill (param i32 i32) (result i32)))
+          (import "env" "random__reseed" (func $reseed (param i64) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "fill") (param $len i32) (result i32)
+            (call $fill (i32.const 16) (local.get $len)))
+          (func (export "reseed") (param $seed i64) (result i32)
+            (call $reseed (local.get $seed))))
+    "#;
+
+    struct Guest {
+        store: wasmtime::Store<ModuleContext>,
+        instance: wasmtime::Instance,
+    }
+
+    impl Guest {
+        fn new(context: crate::ModuleContextBuilder) -> Self {
+            let context = context.with_module(RandomApiHost::new());
+            let (store, instance) = test_support::guest::<RandomApiHost>(RANDOM, context);
+            Self { store, instance }
+        }
+
+        /// The bytes the guest was filled with.
+        fn fill(&mut self, len: u32) -> Vec<u8> {
+            let code = call(&mut self.store, &self.instance, "fill", len);
+            assert_eq!(code, ErrorCode::Success as u32);
+            guest_bytes(&mut self.store, &self.instance, 16, len as usize)
+        }
+
+        fn reseed(&mut self, seed: u64) -> u32 {
+            call(&mut self.store, &self.instance, "reseed", seed)
+        }
+    }
+
+    #[test]
+    fn equal_seeds_replay_the_stream() {
+        let stream = |seed| {
+            let mut guest = Guest::new(ModuleContext::builder().with_seed(seed));
+            [guest.fill(13), guest.fill(8), guest.fill(3)].concat()
+        };
+        let replayed = stream(42);
+        assert_eq!(replayed.len(), 24);
+        assert_eq!(stream(42), replayed);
+        assert_ne!(stream(43), replayed);
+
+        let guest = Guest::new(ModuleContext::builder().with_seed(42));
+        assert_eq!(guest.store.data().seed(), Some(42));
+    }
+
+    #[test]
+    fn instances_have_streams_of_their_own() {
+        let mut first = Guest::new(ModuleContext::builder().with_seed(7));
+        let mut second = Guest::new(ModuleContext::builder().with_seed(7));
+        let head = first.fill(16);
+        assert_eq!(second.fill(16), head);
+        assert_eq!(first.fill(16), second.fill(16));
+    }
+
+    #[test]
+    fn reseeding_needs_permission() {
+        let mut guest = Guest::new(ModuleContext::builder().with_seed(1));
+        assert_eq!(guest.reseed(2), ErrorCode::PermissionDenied as u32);
+        let err = guest.store.data().last_error().unwrap();
+        assert!(
+            err.guest_message()
+                .ends_with("reseeding is disabled for this instance"),
+            "{}",
+            err.guest_message()
+        );
+        let mut reference = Guest::new(ModuleContext::builder().with_seed(1));
+        assert_eq!(guest.fill(8), reference.fill(8));
+
+        let mut guest = Guest::new(ModuleContext::builder().with_seed(1).with_reseed(true));
+        guest.fill(8);
+        assert_eq!(guest.reseed(2), ErrorCode::Success as u32);
+        let mut reseeded = Guest::new(ModuleContext::builder().with_seed(2));
+        assert_eq!(guest.fill(8), reseeded.fill(8));
+        let host = guest
+            .store
+            .data_mut()
+            .module_mut::<RandomApiHost>()
+            .unwrap();
+        assert_eq!(host.seed(), Some(2));
+    }
+
+    #[test]
+    fn unseeded_hosts_draw_a_seed() {
+        let mut host = RandomApiHost::new();
+        assert_eq!(host.seed(), None);
+        let mut bytes = [0; 5];
+        host.fill(&mut bytes);
+        let seed = host.seed().unwrap();
+        let mut replay = RandomApiHost::new();
+        replay.reseed(seed);
+        let mut replayed = [0; 5];
+        replay.fill(&mut replayed);
+        assert_eq!(replayed, bytes);
+    }
+
+    #[test]
+    fn splitmix_matches_the_reference() {
+        // First outputs of the reference implementation seeded with 0
+        let mut rng = SplitMix64(0);
+        assert_eq!(rng.next(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rng.next(), 0x6e78_9e6a_a1b9_65f4);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::CallSite;
+
+/// A token bucket: up to `burst` calls at once, refilled by `per_second`
+/// calls every second. A rate of `0` allows `burst` calls and no more.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.limit.per_second;
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.burst));
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Bucket a call site draws from, resolved on the site's first call.
+#[derive(Copy, Clone, Debug)]
+enum SiteLimit {
+    Unresolved,
+    Unlimited,
+    Bucket(usize),
+}
+
+/// Rate limits of an instance's imports, see
+/// [`ModuleContextBuilder::with_rate_limit`](crate::ModuleContextBuilder::with_rate_limit).
+///
+/// Buckets are indexed by call site, so a call is a lookup and a refill.
+/// Only the first call of an import resolves and allocates its bucket.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    /// Limits of single imports, by import name.
+    imports: HashMap<&'static str, RateLimit>,
+    /// Buckets shared by the imports of a module without a limit of their
+    /// own, by module name.
+    modules: HashMap<&'static str, usize>,
+    buckets: Vec<Bucket>,
+    sites: Vec<SiteLimit>,
+}
+
+impl RateLimiter {
+    pub(crate) fn limit_import(&mut self, import: &'static str, limit: RateLimit) {
+        self.imports.insert(import, limit);
+    }
+
+    pub(crate) fn limit_module(&mut self, module: &'static str, limit: RateLimit) {
+        match self.modules.get(module) {
+            Some(&bucket) => self.buckets[bucket] = Bucket::new(limit),
+            None => {
+                self.modules.insert(module, self.buckets.len());
+                self.buckets.push(Bucket::new(limit));
+            }
+        }
+    }
+
+    /// Whether a call of `site` may go ahead, taking a token if it is
+    /// limited.
+    pub(crate) fn admit(&mut self, site: CallSite) -> bool {
+        if self.buckets.is_empty() && self.imports.is_empty() {
+            return true;
+        }
+        let limit = match self.sites.get(site.slot()) {
+            Some(SiteLimit::Unresolved) | None => self.resolve(site),
+            Some(limit) => *limit,
+        };
+        match limit {
+            SiteLimit::Bucket(bucket) => self.buckets[bucket].take(),
+            _ => true,
+        }
+    }
+
+    fn resolve(&mut self, site: CallSite) -> SiteLimit {
+        let limit = match self.imports.get(site.function()) {
+            Some(&limit) => {
+                self.buckets.push(Bucket::new(limit));
+                SiteLimit::Bucket(self.buckets.len() - 1)
+            }
+            None => match self.modules.get(site.module()) {
+                Some(&bucket) => SiteLimit::Bucket(bucket),
+                None => SiteLimit::Unlimited,
+            },
+        };
+        if site.slot() >= self.sites.len() {
+            self.sites.resize(site.slot() + 1, SiteLimit::Unresolved);
+        }
+        self.sites[site.slot()] = limit;
+        limit
+    }
+
+    /// Fills every bucket up to its burst again.
+    pub(crate) fn refill(&mut self) {
+        for bucket in &mut self.buckets {
+            *bucket = Bucket::new(bucket.limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, ErrorCode, MLApiHost, ModuleContext};
+
+    #[test]
+    fn unlimited_sites_are_always_admitted() {
+        let mut limiter = RateLimiter::default();
+        let site = CallSite::register("limits", "limits__free");
+        assert!((0..1_000).all(|_| limiter.admit(site)));
+    }
+
+    #[test]
+    fn bursts_are_admitted_then_refused() {
+        let mut limiter = RateLimiter::default();
+        let limited = CallSite::register("limits", "limits__burst");
+        let free = CallSite::register("limits", "limits__other");
+        limiter.limit_import("limits__burst", RateLimit::new(3, 0.0));
+        let admitted: Vec<_> = (0..5).map(|_| limiter.admit(limited)).collect();
+        assert_eq!(admitted, [true, true, true, false, false]);
+        assert!(limiter.admit(free));
+
+        limiter.refill();
+        assert!(limiter.admit(limited));
+    }
+
+    #[test]
+    fn module_limits_are_shared_by_its_imports() {
+        let mut limiter = RateLimiter::default();
+        let first = CallSite::register("shared_limits", "shared_limits__first");
+        let second = CallSite::register("shared_limits", "shared_limits__second");
+        let own = CallSite::register("shared_limits", "shared_limits__own");
+        limiter.limit_module("shared_limits", RateLimit::new(2, 0.0));
+        limiter.limit_import("shared_limits__own", RateLimit::new(1, 0.0));
+        assert!(limiter.admit(first));
+        assert!(limiter.admit(second));
+        assert!(!limiter.admit(first));
+        assert!(!limiter.admit(second));
+        // Imports with a limit of their own don't draw from the module's
+        assert!(limiter.admit(own));
+        assert!(!limiter.admit(own));
+
+        // Limiting the module again replaces its bucket
+        limiter.limit_module("shared_limits", RateLimit::new(1, 0.0));
+        assert!(limiter.admit(second));
+        assert!(!limiter.admit(first));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut bucket = Bucket::new(RateLimit::new(1, 1_000.0));
+        assert!(bucket.take());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(bucket.take());
+    }
+
+    /// Polls an unknown future until the call is rate limited, returning
+    /// how many calls went through, or `-1` if none was limited.
+    const SPINNING: &str = r#"
+        (module
+          (import "env" "ml__poll_future"
+            (func $poll_future (param i64 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "spin") (param $limited i32) (result i32)
+            (local $calls i32)
+            (loop $next
+              (if (i32.eq
+                    (call $poll_future (i64.const 12345) (i32.const 16))
+                    (local.get $limited))
+                (then (return (local.get $calls))))
+              (local.set $calls (i32.add (local.get $calls) (i32.const 1)))
+              (br_if $next (i32.lt_u (local.get $calls) (i32.const 10000))))
+            (i32.const -1)))
+    "#;
+
+    fn spin(context: crate::ModuleContextBuilder) -> (i32, ModuleContext) {
+        let context = context.with_module(MLApiHost::default());
+        let (mut store, instance) = test_support::guest::<MLApiHost>(SPINNING, context);
+        let limited = ErrorCode::RateLimited as u32;
+        let calls = ModuleContext::call_export(&mut store, &instance, "spin", limited).unwrap();
+        (calls, store.into_data())
+    }
+
+    #[test]
+    fn tight_guest_loops_are_rate_limited_after_the_burst() {
+        let limit = RateLimit::new(50, 0.0);
+        let context =
+            ModuleContext::builder().with_rate_limit(crate::ml_imports::POLL_FUTURE, limit);
+        let (calls, context) = spin(context);
+        assert_eq!(calls, 50);
+        let err = context.last_error().unwrap();
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+        assert!(err.code().is_graceful());
+
+        let context = ModuleContext::builder().with_module_rate_limit::<MLApiHost>(limit);
+        assert_eq!(spin(context).0, 50);
+    }
+
+    #[test]
+    fn imports_are_unlimited_by_default() {
+        assert_eq!(spin(ModuleContext::builder()).0, -1);
+    }
+}
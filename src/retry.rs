@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::training::{run_training, OwnedTrainingRequest};
+use crate::{
+    ApiError, ErrorCode, FutureCompleter, FutureHandle, FutureState, JobQueue, ProtocolConfig,
+    TrainingBackend, TrainingProgress, TrainingStart,
+};
+
+/// How host modules retry calls into an embedder's backend that failed with
+/// a transient error, such as a full queue, before reporting the error to
+/// the guest. Set for an instance with
+/// [`ModuleContextBuilder::with_retry_policy`](crate::ModuleContextBuilder::with_retry_policy),
+/// by default calls aren't retried.
+///
+/// Async imports wait out the backoff with [`JobQueue::sleep`], which
+/// neither blocks the thread running the guest nor takes up a worker of the
+/// executor. Sync imports can't wait without blocking the guest's thread, so
+/// they hand the guest a pending future instead and repeat the call on the
+/// executor once the backoff has passed, see [`JobQueue::submit_after`].
+/// Either way, retries need the instance's executor: without one, calls
+/// fail after their first attempt.
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, `1` never retries.
+    pub max_attempts: u32,
+    /// Wait before the `attempt`-th attempt, starting at `2` for the first
+    /// retry.
+    pub backoff: fn(u32) -> Duration,
+    /// Whether a failed attempt is worth repeating.
+    pub retry_on: fn(&ApiError) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl RetryPolicy {
+    /// Retries transient errors with exponential backoff.
+    pub const fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Self::exponential_backoff,
+            retry_on: Self::is_transient,
+        }
+    }
+
+    pub const fn with_backoff(mut self, backoff: fn(u32) -> Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub const fn with_retry_on(mut self, retry_on: fn(&ApiError) -> bool) -> Self {
+        self.retry_on = retry_on;
+        self
+    }
+
+    /// 50 ms before the first retry, doubling with every further one up to
+    /// 5 s.
+    pub fn exponential_backoff(attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(2).min(7);
+        Duration::from_millis(50 << doublings).min(Duration::from_secs(5))
+    }
+
+    /// `Busy` and `TimedOut` errors, which the backend may not return on a
+    /// later attempt.
+    pub fn is_transient(err: &ApiError) -> bool {
+        matches!(err.code(), ErrorCode::Busy | ErrorCode::TimedOut)
+    }
+
+    /// The wait before the next attempt, after `attempts` attempts ended in
+    /// `err`. `None` if the call should fail with `err`.
+    pub fn next_attempt(&self, attempts: u32, err: &ApiError) -> Option<Duration> {
+        if attempts >= self.max_attempts || !(self.retry_on)(err) {
+            return None;
+        }
+        Some((self.backoff)(attempts + 1))
+    }
+
+    /// The error a call fails with after `attempts` attempts, which records
+    /// their number if the call was retried.
+    pub fn give_up(&self, err: ApiError, attempts: u32) -> ApiError {
+        if attempts > 1 {
+            err.context(format!("failed after {} attempts", attempts))
+        } else {
+            err
+        }
+    }
+}
+
+/// The [`TrainingBackend`] of an [`MLApiHost`](crate::MLApiHost), shared
+/// with the retries of trainings it failed to start, which run on the
+/// executor.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBackend(Arc<Mutex<BackendState>>);
+
+#[derive(Default)]
+struct BackendState {
+    backend: Option<Box<dyn TrainingBackend>>,
+    /// Trainings waiting for their start to be retried, dropped once they
+    /// are cancelled.
+    retrying: HashSet<FutureHandle>,
+}
+
+impl SharedBackend {
+    pub(crate) fn new(backend: Box<dyn TrainingBackend>) -> Self {
+        Self(Arc::new(Mutex::new(BackendState {
+            backend: Some(backend),
+            retrying: HashSet::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BackendState> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Calls `f` with the backend, `None` if there is none.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut dyn TrainingBackend) -> R) -> Option<R> {
+        let mut state = self.lock();
+        state.backend.as_mut().map(|backend| f(backend.as_mut()))
+    }
+
+    /// Stops a training, including the retries of its start.
+    pub(crate) fn cancel(&self, handle: FutureHandle) {
+        let mut state = self.lock();
+        state.retrying.remove(&handle);
+        if let Some(backend) = &mut state.backend {
+            backend.cancel(handle);
+        }
+    }
+
+    /// Takes the backend out for a call that has to hold on to it across
+    /// awaits, which the lock can't be. It is put back once the
+    /// [`LentBackend`] is dropped.
+    pub(crate) fn lend(&self) -> LentBackend<'_> {
+        let backend = self.lock().backend.take();
+        LentBackend {
+            shared: self,
+            backend,
+        }
+    }
+}
+
+pub(crate) struct LentBackend<'a> {
+    shared: &'a SharedBackend,
+    backend: Option<Box<dyn TrainingBackend>>,
+}
+
+impl LentBackend<'_> {
+    pub(crate) fn get(&mut self) -> Option<&mut dyn TrainingBackend> {
+        match &mut self.backend {
+            Some(backend) => Some(backend.as_mut()),
+            None => None,
+        }
+    }
+}
+
+impl Drop for LentBackend<'_> {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            self.shared.lock().backend = Some(backend);
+        }
+    }
+}
+
+/// A training whose start failed with an error its [`RetryPolicy`] retries,
+/// started again on the executor once the backoff has passed. The future
+/// stays pending until the training is started or the policy gives up.
+pub(crate) struct StartRetry {
+    pub(crate) handle: FutureHandle,
+    pub(crate) request: OwnedTrainingRequest,
+    pub(crate) protocol: ProtocolConfig,
+    pub(crate) policy: RetryPolicy,
+    pub(crate) backend: SharedBackend,
+    pub(crate) jobs: JobQueue,
+    pub(crate) completer: FutureCompleter,
+    pub(crate) progress: TrainingProgress,
+    /// Attempts made so far.
+    pub(crate) attempts: u32,
+}
+
+impl StartRetry {
+    /// Retries the start after the first attempt failed with `err`, or fails
+    /// the training if the policy gives up right away.
+    pub(crate) fn start(self, err: ApiError) {
+        self.backend.lock().retrying.insert(self.handle);
+        self.retry_or_fail(err);
+    }
+
+    /// Schedules the next attempt after the last one failed with `err`, or
+    /// fails the training if the policy gives up.
+    fn retry_or_fail(self, err: ApiError) {
+        let wait = match self.policy.next_attempt(self.attempts, &err) {
+            Some(wait) => wait,
+            None => return self.fail(err),
+        };
+        let jobs = self.jobs.clone();
+        let retry = Arc::new(Mutex::new(Some(self)));
+        let scheduled = retry.clone();
+        let submitted = jobs.submit_after(wait, move || {
+            if let Some(retry) = scheduled.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                retry.run();
+            }
+        });
+        if submitted.is_err() {
+            // The executor is shut down, so the retry was dropped
+            if let Some(retry) = retry.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                retry.fail(err);
+            }
+        }
+    }
+
+    fn run(mut self) {
+        self.attempts += 1;
+        let started = {
+            let mut state = self.backend.lock();
+            // Cancelled while waiting for the backoff
+            if !state.retrying.contains(&self.handle) {
+                return;
+            }
+            let started = match &mut state.backend {
+                Some(backend) => backend.start(self.handle, self.request.request(), &self.protocol),
+                None => Err(ApiError::internal("the training backend is in use")),
+            };
+            if started.is_ok() {
+                state.retrying.remove(&self.handle);
+            }
+            started
+        };
+        match started {
+            Ok(TrainingStart::State(state)) => self.completer.complete(self.handle, state),
+            Ok(TrainingStart::Job(job)) => {
+                let state = run_training(self.handle, job, &self.progress);
+                self.completer.complete(self.handle, state);
+            }
+            Err(err) => self.retry_or_fail(err),
+        }
+    }
+
+    fn fail(self, err: ApiError) {
+        self.backend.lock().retrying.remove(&self.handle);
+        let err = self.policy.give_up(err, self.attempts);
+        self.completer
+            .complete(self.handle, FutureState::Failed(err));
+    }
+}
@@ -0,0 +1,383 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    AbiVersion, HostLinker, ImportStatus, InstantiationError, ModuleContext, ModuleError,
+    ShutdownPolicy, GUEST_ABI_EXPORT,
+};
+
+/// What [`HostRuntime::reload_module`] does with the host side state of the
+/// guest being replaced.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReloadPolicy {
+    /// Cancels pending work like [`ShutdownPolicy::CancelAll`] with the
+    /// timeout, then forgets the futures and other handles of the old guest,
+    /// which has no way to refer to them anymore.
+    CancelPending(Duration),
+    /// Keeps every future and handle, for new guests that pick up the old
+    /// one's work through handles they get from the host.
+    CarryOver,
+}
+
+/// A guest instance and its store, whose module can be replaced while the
+/// [`ModuleContext`] with its call stats, futures and host module state is
+/// kept, for picking up rebuilt guests during development.
+///
+/// Guest memory and globals belong to the module's instance and start over
+/// with every reload.
+pub struct HostRuntime {
+    linker: Arc<HostLinker>,
+    store: wasmtime::Store<ModuleContext>,
+    module: wasmtime::Module,
+    instance: wasmtime::Instance,
+    reloads: u64,
+    /// ABI version of the current guest, once the handshake passed.
+    guest_abi: Option<AbiVersion>,
+}
+
+impl HostRuntime {
+    /// Compiles `wasm`, which may also be in the text format, and
+    /// instantiates it with `context` in a store of the linker's engine.
+    pub fn new(
+        linker: Arc<HostLinker>,
+        context: ModuleContext,
+        wasm: &[u8],
+    ) -> Result<Self, ModuleError> {
+        let module =
+            wasmtime::Module::new(linker.linker().engine(), wasm).map_err(ModuleError::Compile)?;
+        Self::with_module(linker, context, module)
+    }
+
+    pub fn with_module(
+        linker: Arc<HostLinker>,
+        context: ModuleContext,
+        module: wasmtime::Module,
+    ) -> Result<Self, ModuleError> {
+        let mut store = context.into_store(linker.linker().engine());
+        let instance = linker.instantiate(&mut store, &module)?;
+        Ok(Self {
+            linker,
+            store,
+            module,
+            instance,
+            reloads: 0,
+            guest_abi: None,
+        })
+    }
+
+    pub fn module(&self) -> &wasmtime::Module {
+        &self.module
+    }
+
+    pub fn instance(&self) -> wasmtime::Instance {
+        self.instance
+    }
+
+    pub fn store(&self) -> &wasmtime::Store<ModuleContext> {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut wasmtime::Store<ModuleContext> {
+        &mut self.store
+    }
+
+    pub fn context(&self) -> &ModuleContext {
+        self.store.data()
+    }
+
+    pub fn context_mut(&mut self) -> &mut ModuleContext {
+        self.store.data_mut()
+    }
+
+    /// Number of successful [`reload_module`](Self::reload_module) calls.
+    pub fn reloads(&self) -> u64 {
+        self.reloads
+    }
+
+    /// ABI version of the current guest, known after its first
+    /// [`call_entry_point`](Self::call_entry_point).
+    pub fn guest_abi(&self) -> Option<AbiVersion> {
+        self.guest_abi
+    }
+
+    /// [`ModuleContext::call_export`] on the current instance.
+    pub fn call_export<Params, Results>(
+        &mut self,
+        function: &str,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        ModuleContext::call_export(&mut self.store, &self.instance, function, params)
+    }
+
+    /// [`call_export`](Self::call_export) for the guest's entry points.
+    /// Before the first one runs, the ABI version the guest exports as
+    /// [`GUEST_ABI_EXPORT`] is checked against [`AbiVersion::HOST`], failing
+    /// with [`InstantiationError::AbiMismatch`] if their major versions
+    /// differ. Guests without the export are taken to be
+    /// [`AbiVersion::UNVERSIONED`].
+    pub fn call_entry_point<Params, Results>(
+        &mut self,
+        function: &str,
+        params: Params,
+    ) -> Result<Results, ModuleError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        if self.guest_abi.is_none() {
+            let guest = self.read_guest_abi()?;
+            if !guest.is_compatible_with(AbiVersion::HOST) {
+                return Err(InstantiationError::AbiMismatch {
+                    host: AbiVersion::HOST,
+                    guest,
+                }
+                .into());
+            }
+            self.guest_abi = Some(guest);
+        }
+        self.call_export(function, params)
+    }
+
+    fn read_guest_abi(&mut self) -> Result<AbiVersion, ModuleError> {
+        let export = self.instance.get_export(&mut self.store, GUEST_ABI_EXPORT);
+        let raw = match export {
+            Some(wasmtime::Extern::Global(global)) => match global.get(&mut self.store) {
+                wasmtime::Val::I32(raw) => raw as u32,
+                other => {
+                    // Only fails for unrooted GC references
+                    let ty = other
+                        .ty(&self.store)
+                        .map_or_else(|err| err.to_string(), |ty| ty.to_string());
+                    return Err(ModuleError::MissingExport {
+                        function: GUEST_ABI_EXPORT.to_owned(),
+                        source: anyhow::anyhow!(
+                            "the ABI version global is a `{}`, not an `i32`",
+                            ty
+                        ),
+                    });
+                }
+            },
+            Some(_) => self.call_export(GUEST_ABI_EXPORT, ())?,
+            None => {
+                log::warn!(
+                    "guest doesn't export `{}`, assuming ABI version {}",
+                    GUEST_ABI_EXPORT,
+                    AbiVersion::UNVERSIONED
+                );
+                return Ok(AbiVersion::UNVERSIONED);
+            }
+        };
+        Ok(AbiVersion::from_raw(raw))
+    }
+
+    /// Replaces the guest with `new_wasm`, which may also be in the text
+    /// format, so that the next call runs the new code. The host side
+    /// state is kept, futures as `policy` says.
+    ///
+    /// The new guest starts with fresh memory and globals, nothing of the
+    /// old guest's memory is carried over. The old instance stays in the
+    /// store until the runtime is dropped though, counting towards the
+    /// store's memory and instance limits.
+    ///
+    /// The new module is validated against the linker's imports before
+    /// anything changes. If it can't be compiled, validated or
+    /// instantiated, the old guest stays in place with its state untouched.
+    pub fn reload_module(
+        &mut self,
+        new_wasm: &[u8],
+        policy: ReloadPolicy,
+    ) -> Result<(), ModuleError> {
+        let module = wasmtime::Module::new(self.linker.linker().engine(), new_wasm)
+            .map_err(ModuleError::Compile)?;
+        self.validate(&module)?;
+        // Instantiating records the new guest's memories, which have to be
+        // restored if it fails
+        let memories = self.store.data().guest_memories.clone();
+        let instance = match self.linker.instantiate(&mut self.store, &module) {
+            Ok(instance) => instance,
+            Err(err) => {
+                self.store.data_mut().guest_memories = memories;
+                return Err(err.into());
+            }
+        };
+        if let ReloadPolicy::CancelPending(timeout) = policy {
+            let context = self.store.data_mut();
+            context.shutdown(ShutdownPolicy::CancelAll(timeout));
+            context.reset_modules();
+        }
+        self.module = module;
+        self.instance = instance;
+        self.reloads += 1;
+        self.guest_abi = None;
+        Ok(())
+    }
+
+    /// [`HostLinker::validate_module`], where missing imports are fine if the
+    /// linker stubs them.
+    fn validate(&self, module: &wasmtime::Module) -> Result<(), InstantiationError> {
+        match self.linker.validate_module(module) {
+            Err(InstantiationError::InvalidImports { guest, diagnostics })
+                if self.linker.stubs_unknown_imports() =>
+            {
+                let diagnostics: Vec<_> = diagnostics
+                    .into_iter()
+                    .filter(|diagnostic| diagnostic.status != ImportStatus::Missing)
+                    .collect();
+                if diagnostics.is_empty() {
+                    return Ok(());
+                }
+                Err(InstantiationError::InvalidImports { guest, diagnostics })
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, ErrorCode, MLApiHost};
+
+    /// Version 1 of a guest, starting a training in `start` and keeping the
+    /// number of starts in a global.
+    const V1: &str = r#"
+        (module
+          (import "env" "ml__start_training"
+            (func $start_training
+              (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+              (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 64) "mnist")
+          (global $starts (mut i32) (i32.const 0))
+          (func (export "version") (result i32) (i32.const 1))
+          (func (export "starts") (result i32) (global.get $starts))
+          (func (export "start") (result i64)
+            (global.set $starts (i32.add (global.get $starts) (i32.const 1)))
+            (drop
+              (call $start_training
+                (i32.const 64) (i32.const 5)
+                (i32.const 3)
+                (i32.const 64) (i32.const 5)
+                (i32.const 0) (i32.const 0)
+                (i32.const 0)
+                (i32.const 0) (i32.const 0)
+                (i32.const 0) (i32.const 0)
+                (i64.const 42)
+                (i32.const 0)
+                (i32.const 32)))
+            (i64.load (i32.const 32))))
+    "#;
+
+    /// Version 2, polling the trainings version 1 started.
+    const V2: &str = r#"
+        (module
+          (import "env" "ml__poll_future"
+            (func $poll_future (param i64 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "version") (result i32) (i32.const 2))
+          (func (export "poll") (param $handle i64) (result i32)
+            (call $poll_future (local.get $handle) (i32.const 48))))
+    "#;
+
+    fn runtime() -> HostRuntime {
+        let engine = wasmtime::Engine::default();
+        let linker = test_support::linker::<MLApiHost>(&engine);
+        let context = ModuleContext::builder()
+            .with_module(MLApiHost::default())
+            .with_job_threads(1)
+            .build()
+            .unwrap();
+        HostRuntime::new(Arc::new(linker), context, V1.as_bytes()).unwrap()
+    }
+
+    fn futures(runtime: &HostRuntime) -> usize {
+        runtime
+            .context()
+            .module::<MLApiHost>()
+            .unwrap()
+            .futures()
+            .len()
+    }
+
+    #[test]
+    fn reloads_keep_host_state_and_replace_the_guest() {
+        let mut runtime = runtime();
+        let handle: u64 = runtime.call_export("start", ()).unwrap();
+        assert_eq!(runtime.call_export::<(), i32>("starts", ()).unwrap(), 1);
+        assert_eq!(futures(&runtime), 1);
+
+        runtime
+            .reload_module(V2.as_bytes(), ReloadPolicy::CarryOver)
+            .unwrap();
+        assert_eq!(runtime.reloads(), 1);
+        assert_eq!(runtime.call_export::<(), i32>("version", ()).unwrap(), 2);
+        assert_eq!(futures(&runtime), 1);
+        let code: u32 = runtime.call_export("poll", handle).unwrap();
+        assert_eq!(code, ErrorCode::Success as u32);
+        let stats = runtime.context().call_stats();
+        let calls: Vec<_> = stats
+            .iter()
+            .map(|stat| (stat.function, stat.calls))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                (crate::ml_imports::START_TRAINING, 1),
+                (crate::ml_imports::POLL_FUTURE, 1)
+            ]
+        );
+
+        // Guest globals start over
+        runtime
+            .reload_module(V1.as_bytes(), ReloadPolicy::CarryOver)
+            .unwrap();
+        assert_eq!(runtime.call_export::<(), i32>("starts", ()).unwrap(), 0);
+        assert_eq!(runtime.reloads(), 2);
+    }
+
+    #[test]
+    fn cancelling_reloads_forget_the_futures() {
+        let mut runtime = runtime();
+        let handle: u64 = runtime.call_export("start", ()).unwrap();
+        runtime
+            .reload_module(
+                V2.as_bytes(),
+                ReloadPolicy::CancelPending(Duration::from_millis(10)),
+            )
+            .unwrap();
+        assert_eq!(futures(&runtime), 0);
+        let code: u32 = runtime.call_export("poll", handle).unwrap();
+        assert_eq!(code, ErrorCode::StaleHandle as u32);
+        // Call stats are host state that is kept either way
+        assert_eq!(runtime.context().call_stats().len(), 2);
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_old_guest() {
+        let mut runtime = runtime();
+        let handle: u64 = runtime.call_export("start", ()).unwrap();
+        assert!(matches!(
+            runtime.reload_module(b"(module (func", ReloadPolicy::CarryOver),
+            Err(ModuleError::Compile(_))
+        ));
+        let unknown = r#"(module (import "env" "ml__export_model" (func)))"#;
+        assert!(matches!(
+            runtime.reload_module(
+                unknown.as_bytes(),
+                ReloadPolicy::CancelPending(Duration::from_millis(10))
+            ),
+            Err(ModuleError::Instantiation(
+                InstantiationError::InvalidImports { .. }
+            ))
+        ));
+        assert_eq!(runtime.reloads(), 0);
+        assert_eq!(runtime.call_export::<(), i32>("version", ()).unwrap(), 1);
+        assert_eq!(runtime.call_export::<(), i32>("starts", ()).unwrap(), 1);
+        assert_eq!(futures(&runtime), 1);
+        assert_ne!(handle, 0);
+    }
+}
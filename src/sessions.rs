@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use crate::handles::SlotTable;
+use crate::{ApiError, FutureHandle, FutureState, MLApiHost, ProtocolConfig, ShutdownPolicy};
+
+slot_handle! {
+    /// Handle of a training session the guest created on an [`MLApiHost`],
+    /// never `0`. Imports taking a session treat `0` as the default session,
+    /// which every instance has and trainings started without a session
+    /// belong to.
+    pub struct SessionHandle = "session";
+}
+
+impl SessionHandle {
+    /// The session a guest refers to as `raw`, `None` for the default
+    /// session.
+    pub(crate) fn from_guest(raw: u64) -> Option<Self> {
+        match raw {
+            0 => None,
+            raw => Some(Self(raw)),
+        }
+    }
+}
+
+/// State of a session besides its trainings, which are tracked with the
+/// rest in the [`MLApiHost`]'s future table.
+pub(crate) struct Session {
+    /// Protocol config of trainings started in the session without one.
+    protocol: ProtocolConfig,
+}
+
+pub(crate) type SessionTable = SlotTable<SessionHandle, Session>;
+
+impl MLApiHost {
+    pub const DEFAULT_SESSION_LIMIT: usize = 8;
+
+    /// Caps the number of sessions the guest can have at once, not counting
+    /// the default session. Further ones fail with [`ErrorCode::Busy`](crate::ErrorCode::Busy).
+    pub fn with_session_limit(mut self, limit: usize) -> Self {
+        self.sessions.set_capacity(limit);
+        self
+    }
+
+    /// How [`destroy_session`](Self::destroy_session) treats the pending
+    /// trainings of the session, cancelling them right away by default.
+    pub fn with_session_shutdown(mut self, policy: ShutdownPolicy) -> Self {
+        self.session_shutdown = policy;
+        self
+    }
+
+    /// Number of sessions the guest has created and not destroyed yet.
+    pub fn open_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// The session `handle` was started in, `None` for the default session.
+    pub fn training_session(&self, handle: FutureHandle) -> Option<SessionHandle> {
+        self.training_sessions.get(&handle).copied()
+    }
+
+    /// Creates a session whose trainings default to `protocol`, or to the
+    /// instance's defaults if it is `None`.
+    pub fn create_session(
+        &mut self,
+        protocol: Option<ProtocolConfig>,
+    ) -> Result<SessionHandle, ApiError> {
+        let protocol = protocol.unwrap_or(self.protocol_defaults);
+        self.sessions.insert(Session { protocol })
+    }
+
+    /// Destroys a session, winding down its pending trainings with the
+    /// [session shutdown policy](Self::with_session_shutdown) and freeing
+    /// all of its futures. Returns the trainings that were still pending.
+    pub fn destroy_session(
+        &mut self,
+        session: SessionHandle,
+    ) -> Result<Vec<FutureHandle>, ApiError> {
+        self.sessions.get_mut(session)?;
+        self.update();
+        let mut trainings: Vec<_> = self
+            .training_sessions
+            .iter()
+            .filter(|(_, &owner)| owner == session)
+            .map(|(&handle, _)| handle)
+            .collect();
+        trainings.sort_by_key(|handle| handle.raw());
+        let pending = trainings
+            .iter()
+            .copied()
+            .filter(|&handle| matches!(self.futures.get(handle), Ok(FutureState::Pending)))
+            .collect();
+        let unresolved = self.shutdown_trainings(self.session_shutdown, pending);
+        for handle in trainings {
+            self.forget(handle);
+        }
+        self.sessions.remove(session)?;
+        Ok(unresolved)
+    }
+
+    /// Protocol config of trainings started in `session` without one.
+    pub fn session_protocol(
+        &mut self,
+        session: Option<SessionHandle>,
+    ) -> Result<ProtocolConfig, ApiError> {
+        match session {
+            Some(session) => Ok(self.sessions.get_mut(session)?.protocol),
+            None => Ok(self.protocol_defaults),
+        }
+    }
+
+    /// Fails with [`ErrorCode::NotFound`](crate::ErrorCode::NotFound) unless
+    /// `handle` was started in `session`.
+    pub(crate) fn check_session(
+        &mut self,
+        session: Option<SessionHandle>,
+        handle: FutureHandle,
+    ) -> Result<(), ApiError> {
+        if let Some(session) = session {
+            self.sessions.get_mut(session)?;
+        }
+        self.futures.get(handle)?;
+        if self.training_session(handle) == session {
+            return Ok(());
+        }
+        Err(ApiError::not_found(match session {
+            Some(session) => format!(
+                "training {} isn't part of session {}",
+                handle.raw(),
+                session.raw()
+            ),
+            None => format!(
+                "training {} isn't part of the default session",
+                handle.raw()
+            ),
+        }))
+    }
+
+    /// Drops a future and everything tracked about it, if it is still
+    /// there.
+    pub(crate) fn forget(&mut self, handle: FutureHandle) {
+        let _ = self.futures.remove(handle);
+        self.progress.remove(&handle);
+        self.training_sessions.remove(&handle);
+    }
+}
+
+/// Pending trainings of a destroyed session are cancelled without waiting.
+pub(crate) const DEFAULT_SESSION_SHUTDOWN: ShutdownPolicy =
+    ShutdownPolicy::CancelAll(Duration::ZERO);
@@ -0,0 +1,441 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Every import name interned so far. A name is leaked at most once and
+/// reused by every linker it is registered with.
+static IMPORT_NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+/// The name of an import, interned at registration so that the linker
+/// entry, the [`CallSite`] captured by its trampoline and with it the call
+/// stats, logs and audit records of the import all share one
+/// `&'static str`, and no call has to format it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ImportName(&'static str);
+
+impl ImportName {
+    /// The interned name equal to `name`, which is `name` itself unless an
+    /// equal one was interned before.
+    pub fn new(name: &'static str) -> Self {
+        let mut names = IMPORT_NAMES.lock().unwrap_or_else(|err| err.into_inner());
+        match names.get(name) {
+            Some(known) => Self(known),
+            None => {
+                names.insert(name);
+                Self(name)
+            }
+        }
+    }
+
+    /// The interned `{prefix}__{function}`, for names built at runtime.
+    pub fn join(prefix: &str, function: &str) -> Self {
+        let name = format!("{}__{}", prefix, function);
+        let mut names = IMPORT_NAMES.lock().unwrap_or_else(|err| err.into_inner());
+        match names.get(name.as_str()) {
+            Some(known) => Self(known),
+            None => {
+                let name = Box::leak(name.into_boxed_str());
+                names.insert(name);
+                Self(name)
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for ImportName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl PartialEq<str> for ImportName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for ImportName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// Process wide list of every host function that has been registered with a
+/// linker. A function keeps its slot for the lifetime of the process, so
+/// registering the same module into many linkers doesn't grow this table.
+static CALL_SITES: Mutex<Vec<(&'static str, ImportName)>> = Mutex::new(Vec::new());
+
+/// A registered host function, captured by its import closure so that
+/// counting a call is a plain index into the [`ModuleContext`](crate::ModuleContext)
+/// counter table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CallSite {
+    slot: usize,
+    module: &'static str,
+    function: ImportName,
+}
+
+impl CallSite {
+    /// `function` is the full, static import name the function is registered
+    /// with, e.g. [`ml_imports::START_TRAINING`](crate::ml_imports::START_TRAINING).
+    pub fn register(module: &'static str, function: &'static str) -> Self {
+        let function = ImportName::new(function);
+        let mut sites = CALL_SITES.lock().unwrap_or_else(|err| err.into_inner());
+        let slot = match sites.iter().position(|site| *site == (module, function)) {
+            Some(slot) => slot,
+            None => {
+                sites.push((module, function));
+                sites.len() - 1
+            }
+        };
+        Self {
+            slot,
+            module,
+            function,
+        }
+    }
+
+    pub fn module(&self) -> &'static str {
+        self.module
+    }
+
+    pub fn function(&self) -> &'static str {
+        self.function.as_str()
+    }
+
+    pub fn name(&self) -> ImportName {
+        self.function
+    }
+
+    /// Index of the function in per instance tables.
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct CallCounts {
+    calls: u64,
+    failures: u64,
+    leaked: u64,
+    deprecated: u64,
+    durations: CallDurations,
+}
+
+/// Per instance call counters, indexed by [`CallSite`] slot.
+#[derive(Default)]
+pub(crate) struct CallCounters(Vec<CallCounts>);
+
+impl CallCounters {
+    pub(crate) fn record(&mut self, site: CallSite, failed: bool) {
+        let counts = self.counts(site);
+        counts.calls += 1;
+        counts.failures += u64::from(failed);
+    }
+
+    pub(crate) fn record_duration(&mut self, site: CallSite, duration: Duration) {
+        self.counts(site).durations.record(duration);
+    }
+
+    pub(crate) fn record_leak(&mut self, site: CallSite) {
+        self.counts(site).leaked += 1;
+    }
+
+    pub(crate) fn record_deprecated(&mut self, site: CallSite) {
+        self.counts(site).deprecated += 1;
+    }
+
+    fn counts(&mut self, site: CallSite) -> &mut CallCounts {
+        if site.slot >= self.0.len() {
+            self.0.resize(site.slot + 1, CallCounts::default());
+        }
+        &mut self.0[site.slot]
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<CallStat> {
+        let sites = CALL_SITES.lock().unwrap_or_else(|err| err.into_inner());
+        self.0
+            .iter()
+            .zip(sites.iter())
+            .filter(|(counts, _)| counts.calls > 0)
+            .map(|(counts, (module, function))| CallStat {
+                module,
+                function: function.as_str(),
+                calls: counts.calls,
+                failures: counts.failures,
+                leaked: counts.leaked,
+                deprecated_calls: counts.deprecated,
+                durations: counts.durations,
+            })
+            .collect()
+    }
+}
+
+/// Set of [`CallSite`]s of an instance, a bit per slot.
+#[cfg(feature = "host-logging")]
+#[derive(Default)]
+pub(crate) struct CallSiteSet(Vec<u64>);
+
+#[cfg(feature = "host-logging")]
+impl CallSiteSet {
+    /// Adds `site`, returning whether it wasn't in the set yet.
+    pub(crate) fn insert(&mut self, site: CallSite) -> bool {
+        let (word, bit) = (site.slot / 64, 1 << (site.slot % 64));
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+        let added = self.0[word] & bit == 0;
+        self.0[word] |= bit;
+        added
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Snapshot of how often a host function was called by an instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallStat {
+    pub module: &'static str,
+    pub function: &'static str,
+    /// Total number of calls, including failed ones.
+    pub calls: u64,
+    pub failures: u64,
+    /// Number of futures created by the function that were still live when
+    /// a session finished, see
+    /// [`ModuleContext::finish_session`](crate::ModuleContext::finish_session).
+    pub leaked: u64,
+    /// Number of calls through a [deprecated](crate::ImportAlias::deprecated)
+    /// alias of the function.
+    pub deprecated_calls: u64,
+    /// How long the calls took, unless the instance was built
+    /// [`with_call_timing(false)`](crate::ModuleContextBuilder::with_call_timing).
+    pub durations: CallDurations,
+}
+
+/// Number of [`CallDurations`] buckets: one per power of two microseconds
+/// from 1µs to 2^20µs, about a second, and one for longer calls.
+const DURATION_BUCKETS: usize = 22;
+
+/// Histogram of host call durations with log-scaled buckets, the first
+/// counting calls up to 1µs and each next one calls up to twice as long,
+/// up to about a second.
+///
+/// Quantiles are reported as the upper bound of their bucket, so they are
+/// accurate to a factor of two, but never more than the longest call.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallDurations {
+    buckets: [u64; DURATION_BUCKETS],
+    max: Duration,
+}
+
+impl CallDurations {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let micros = duration.as_nanos().saturating_sub(1) / 1000;
+        // Bucket `b` counts calls longer than 2^(b-1)µs, up to 2^bµs
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(DURATION_BUCKETS - 1)] += 1;
+        self.max = self.max.max(duration);
+    }
+
+    /// Number of timed calls.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bounds of the buckets and the number of calls in each, the
+    /// last one being [`Duration::MAX`].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(bucket, &count)| (bucket_bound(bucket), count))
+    }
+
+    /// Duration `quantile` of the calls took at most, `None` if no call was
+    /// timed.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, calls) in self.buckets() {
+            seen += calls;
+            if seen >= rank {
+                return Some(bound.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.quantile(0.5)
+    }
+
+    pub fn p95(&self) -> Option<Duration> {
+        self.quantile(0.95)
+    }
+
+    /// Duration of the longest call.
+    pub fn max(&self) -> Option<Duration> {
+        (self.count() > 0).then_some(self.max)
+    }
+}
+
+fn bucket_bound(bucket: usize) -> Duration {
+    if bucket == DURATION_BUCKETS - 1 {
+        Duration::MAX
+    } else {
+        Duration::from_micros(1 << bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_names_are_interned() {
+        let name = ImportName::new("stats__interned");
+        let copy: &'static str = Box::leak(String::from("stats__interned").into_boxed_str());
+        assert!(std::ptr::eq(ImportName::new(copy).as_str(), name.as_str()));
+        let joined = ImportName::join("stats", "interned");
+        assert!(std::ptr::eq(joined.as_str(), name.as_str()));
+        assert_eq!(joined, "stats__interned");
+        assert_eq!(joined.to_string(), "stats__interned");
+    }
+
+    #[test]
+    fn call_sites_share_the_interned_name() {
+        let name = ImportName::join("stats", "site_name");
+        let site = CallSite::register("stats", "stats__site_name");
+        assert!(std::ptr::eq(site.function(), name.as_str()));
+        assert_eq!(site.name(), name);
+    }
+
+    #[test]
+    fn call_sites_are_registered_once() {
+        let site = CallSite::register("stats", "stats__once");
+        assert_eq!(CallSite::register("stats", "stats__once"), site);
+        assert_ne!(
+            CallSite::register("stats", "stats__other").slot(),
+            site.slot()
+        );
+        assert_ne!(
+            CallSite::register("other", "stats__once").slot(),
+            site.slot()
+        );
+    }
+
+    #[test]
+    fn calls_and_failures_are_counted_per_site() {
+        let start = CallSite::register("stats", "stats__start");
+        let poll = CallSite::register("stats", "stats__poll");
+        let _never_called = CallSite::register("stats", "stats__free");
+        let mut counters = CallCounters::default();
+        counters.record(start, false);
+        counters.record(poll, true);
+        counters.record(start, true);
+        counters.record(start, false);
+
+        let counts: Vec<_> = counters
+            .snapshot()
+            .into_iter()
+            .map(|stat| (stat.module, stat.function, stat.calls, stat.failures))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("stats", "stats__start", 3, 1),
+                ("stats", "stats__poll", 1, 1),
+            ]
+        );
+    }
+
+    fn durations(micros: &[u64]) -> CallDurations {
+        let mut durations = CallDurations::default();
+        for &micros in micros {
+            durations.record(Duration::from_micros(micros));
+        }
+        durations
+    }
+
+    #[test]
+    fn durations_are_bucketed_by_powers_of_two() {
+        let durations = durations(&[0, 1, 2, 3, 4, 5, 1000, 1 << 20, (1 << 20) + 1, 5_000_000]);
+        let buckets: Vec<_> = durations
+            .buckets()
+            .filter(|(_, calls)| *calls > 0)
+            .collect();
+        assert_eq!(
+            buckets,
+            [
+                (Duration::from_micros(1), 2),
+                (Duration::from_micros(2), 1),
+                (Duration::from_micros(4), 2),
+                (Duration::from_micros(8), 1),
+                (Duration::from_micros(1024), 1),
+                (Duration::from_micros(1 << 20), 1),
+                (Duration::MAX, 2),
+            ]
+        );
+        assert_eq!(durations.buckets().count(), DURATION_BUCKETS);
+        assert_eq!(durations.count(), 10);
+    }
+
+    #[test]
+    fn quantiles_are_bucket_bounds_capped_by_the_longest_call() {
+        let mut micros = vec![10; 90];
+        micros.extend([100; 9]);
+        micros.push(300);
+        let durations = durations(&micros);
+        assert_eq!(durations.p50(), Some(Duration::from_micros(16)));
+        assert_eq!(durations.p95(), Some(Duration::from_micros(128)));
+        assert_eq!(durations.quantile(1.0), Some(Duration::from_micros(300)));
+        assert_eq!(durations.quantile(0.0), Some(Duration::from_micros(16)));
+        assert_eq!(durations.max(), Some(Duration::from_micros(300)));
+
+        let slow = self::durations(&[5_000_000]);
+        assert_eq!(slow.p50(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn untimed_durations_have_no_quantiles() {
+        let durations = CallDurations::default();
+        assert_eq!(durations.count(), 0);
+        assert_eq!(durations.p50(), None);
+        assert_eq!(durations.p95(), None);
+        assert_eq!(durations.max(), None);
+    }
+
+    #[test]
+    fn durations_are_recorded_per_site() {
+        let read = CallSite::register("stats", "stats__timed_read");
+        let write = CallSite::register("stats", "stats__timed_write");
+        let mut counters = CallCounters::default();
+        counters.record(read, false);
+        counters.record_duration(read, Duration::from_micros(3));
+        counters.record(write, false);
+
+        let stats = counters.snapshot();
+        let read = stats
+            .iter()
+            .find(|stat| stat.function == "stats__timed_read");
+        assert_eq!(
+            read.unwrap().durations.max(),
+            Some(Duration::from_micros(3))
+        );
+        let write = stats
+            .iter()
+            .find(|stat| stat.function == "stats__timed_write");
+        assert_eq!(write.unwrap().durations.count(), 0);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::{
+    error_code_ranges, ApiError, ErrorCode, GuestSlice, HostLinker, HostModule, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod storage_imports = "storage" {
+    PUT = "put",
+    GET_SIZE = "get_size",
+    GET = "get",
+});
+
+/// Durable storage behind [`StorageApiHost`], e.g. for training checkpoints.
+///
+/// Keys are validated by the host before they reach the backend, see
+/// [`StorageApiHost::MAX_KEY_LEN`].
+pub trait StorageBackend: Send {
+    /// Stores `data` under `key`, replacing any previous value.
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), ApiError>;
+
+    /// The value stored under `key`, failing with [`ErrorCode::NotFound`] if
+    /// there is none.
+    fn get(&mut self, key: &str) -> Result<Vec<u8>, ApiError>;
+
+    /// Size of the value stored under `key` in bytes. Backends that can tell
+    /// without reading the value should override this.
+    fn size(&mut self, key: &str) -> Result<usize, ApiError> {
+        self.get(key).map(|data| data.len())
+    }
+}
+
+/// Keeps every value in memory, for tests and short lived instances.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    values: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), ApiError> {
+        self.values.insert(key.to_owned(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Vec<u8>, ApiError> {
+        self.values.get(key).cloned().ok_or_else(|| missing(key))
+    }
+
+    fn size(&mut self, key: &str) -> Result<usize, ApiError> {
+        self.values
+            .get(key)
+            .map(Vec::len)
+            .ok_or_else(|| missing(key))
+    }
+}
+
+/// Stores every value as a file named after its key in a directory.
+///
+/// Keys that aren't plain file names, such as ones containing path
+/// separators, are rejected with [`ErrorCode::InvalidArgument`].
+#[cfg(feature = "storage-fs")]
+#[derive(Debug)]
+pub struct FileStorage {
+    root: std::path::PathBuf,
+}
+
+#[cfg(feature = "storage-fs")]
+impl FileStorage {
+    /// Creates `root` if it doesn't exist yet.
+    pub fn new(root: impl Into<std::path::PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<std::path::PathBuf, ApiError> {
+        if key.starts_with('.') || key.contains(|c| std::path::is_separator(c) || c == ':') {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                format!("storage key `{}` is not a valid file name", key),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[cfg(feature = "storage-fs")]
+impl StorageBackend for FileStorage {
+    fn put(&mut self, key: &str, data: &[u8]) -> Result<(), ApiError> {
+        let path = self.path(key)?;
+        // Written next to the destination and renamed, so a crash never
+        // leaves a partial checkpoint behind
+        let staging = self.root.join(format!(".{}.tmp", key));
+        std::fs::write(&staging, data)?;
+        std::fs::rename(&staging, &path)?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Vec<u8>, ApiError> {
+        Ok(std::fs::read(self.path(key)?)?)
+    }
+
+    fn size(&mut self, key: &str) -> Result<usize, ApiError> {
+        let metadata = std::fs::metadata(self.path(key)?)?;
+        Ok(usize::try_from(metadata.len()).unwrap_or(usize::MAX))
+    }
+}
+
+/// Host module giving guests key value storage, in its own `storage`
+/// namespace next to [`MLApiHost`](crate::MLApiHost).
+pub struct StorageApiHost {
+    backend: Box<dyn StorageBackend>,
+}
+
+impl Default for StorageApiHost {
+    /// Backed by a [`MemoryStorage`].
+    fn default() -> Self {
+        Self::new(MemoryStorage::new())
+    }
+}
+
+impl StorageApiHost {
+    /// Longest key in bytes the guest can use.
+    pub const MAX_KEY_LEN: usize = 256;
+
+    pub fn new(backend: impl StorageBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+        }
+    }
+
+    pub fn put(&mut self, key: &str, data: &[u8]) -> Result<(), ApiError> {
+        validate_key(key)?;
+        self.backend.put(key, data)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Vec<u8>, ApiError> {
+        validate_key(key)?;
+        self.backend.get(key)
+    }
+
+    pub fn size(&mut self, key: &str) -> Result<usize, ApiError> {
+        validate_key(key)?;
+        self.backend.size(key)
+    }
+}
+
+impl HostModule for StorageApiHost {
+    fn name() -> &'static str {
+        "storage_api"
+    }
+    fn log_target() -> &'static str {
+        "host::storage_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::STORAGE)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for StorageApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", storage_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        host_import!(linker, StorageApiHost, storage_imports::PUT, (
+            key: GuestStr,
+            data: GuestSlice<u8>,
+        ) => |host, memory| host.put(key.read(memory)?, data.read(memory)?))?;
+        host_import!(linker, StorageApiHost, storage_imports::GET_SIZE, (
+            key: str,
+            size_out: *mut u32,
+        ) => |host| {
+            let size = host.size(key)?;
+            u32::try_from(size).map_err(|_| {
+                ApiError::new(
+                    ErrorCode::Internal,
+                    format!("value of `{}` is too large for the guest ({} bytes)", key, size),
+                )
+            })
+        })?;
+        // The guest sizes `buf` with `get_size`
+        host_import!(linker, StorageApiHost, storage_imports::GET, (
+            key: GuestStr,
+            buf: GuestSlice<u8>,
+        ) => |host, memory| {
+            let key = key.read(memory)?.to_owned();
+            let data = host.get(&key)?;
+            if data.len() as u64 > buf.len() {
+                Err(ApiError::new(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "value of `{}` needs {} bytes, the buffer only has {}",
+                        key,
+                        data.len(),
+                        buf.len()
+                    ),
+                ))
+            } else {
+                GuestSlice::new(buf.ptr().raw(), data.len() as u64).write(memory, &data)
+            }
+        })?;
+        Ok(())
+    }
+}
+
+pub(crate) fn validate_key(key: &str) -> Result<(), ApiError> {
+    if key.is_empty() || key.len() > StorageApiHost::MAX_KEY_LEN {
+        return Err(ApiError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "storage keys must be 1 to {} bytes long, got {}",
+                StorageApiHost::MAX_KEY_LEN,
+                key.len()
+            ),
+        ));
+    }
+    if key.chars().any(char::is_control) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidArgument,
+            "storage keys must not contain control characters",
+        ));
+    }
+    Ok(())
+}
+
+fn missing(key: &str) -> ApiError {
+    ApiError::not_found(format!("no value stored under `{}`", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{call, guest, guest_bytes, Guest};
+
+    /// Stores "weights!" under "ckpt", reads back the size of the key at
+    /// `key` to 32 and the value to 64. "nope" is never stored.
+    const CHECKPOINT: &str = r#"
+        (module
+          (import "env" "storage__put" (func $put (param i32 i32 i32 i32) (result i32)))
+          (import "env" "storage__get_size" (func $get_size (param i32 i32 i32) (result i32)))
+          (import "env" "storage__get" (func $get (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 4) "ckpt")
+          (data (i32.const 8) "nope")
+          (data (i32.const 16) "weights!")
+          (func (export "save") (result i32)
+            (call $put (i32.const 4) (i32.const 4) (i32.const 16) (i32.const 8)))
+          (func (export "size") (param $key i32) (result i32)
+            (call $get_size (local.get $key) (i32.const 4) (i32.const 32)))
+          (func (export "load") (param $len i32) (result i32)
+            (call $get (i32.const 4) (i32.const 4) (i32.const 64) (local.get $len))))
+    "#;
+
+    fn checkpoint_guest() -> Guest {
+        let context = ModuleContext::builder().with_module(StorageApiHost::default());
+        guest::<StorageApiHost>(CHECKPOINT, context)
+    }
+
+    #[test]
+    fn checkpoints_round_trip_through_the_guest() {
+        let (mut store, instance) = checkpoint_guest();
+        assert_eq!(
+            call(&mut store, &instance, "size", 4),
+            ErrorCode::NotFound as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "save", ()),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(
+            call(&mut store, &instance, "size", 4),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(
+            guest_bytes(&mut store, &instance, 32, 4),
+            8_u32.to_le_bytes()
+        );
+        assert_eq!(
+            call(&mut store, &instance, "load", 8),
+            ErrorCode::Success as u32
+        );
+        assert_eq!(guest_bytes(&mut store, &instance, 64, 8), b"weights!");
+        let host = store.data_mut().module_mut::<StorageApiHost>().unwrap();
+        assert_eq!(host.get("ckpt").unwrap(), b"weights!");
+    }
+
+    #[test]
+    fn small_buffers_are_rejected_without_a_partial_write() {
+        let (mut store, instance) = checkpoint_guest();
+        call(&mut store, &instance, "save", ());
+        assert_eq!(
+            call(&mut store, &instance, "load", 7),
+            ErrorCode::InvalidArgument as u32
+        );
+        assert_eq!(guest_bytes(&mut store, &instance, 64, 8), [0; 8]);
+        let err = store.data().last_error().unwrap().guest_message();
+        assert!(
+            err.ends_with("value of `ckpt` needs 8 bytes, the buffer only has 7"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn missing_keys_are_graceful() {
+        let (mut store, instance) = checkpoint_guest();
+        assert_eq!(
+            call(&mut store, &instance, "size", 8),
+            ErrorCode::NotFound as u32
+        );
+        let err = StorageApiHost::default().get("nope").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.code().is_graceful());
+        assert_eq!(
+            err.guest_message(),
+            "NotFound(6): no value stored under `nope`"
+        );
+    }
+
+    #[test]
+    fn keys_are_validated_before_the_backend() {
+        let mut host = StorageApiHost::default();
+        let long = "k".repeat(StorageApiHost::MAX_KEY_LEN + 1);
+        for key in ["", long.as_str(), "line\nbreak"] {
+            let err = host.put(key, b"value").unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{:?}", key);
+            assert_eq!(
+                host.size(key).unwrap_err().code(),
+                ErrorCode::InvalidArgument
+            );
+        }
+        let longest = "k".repeat(StorageApiHost::MAX_KEY_LEN);
+        host.put(&longest, b"value").unwrap();
+        assert_eq!(host.size(&longest).unwrap(), 5);
+    }
+
+    #[cfg(feature = "storage-fs")]
+    #[test]
+    fn files_are_named_after_their_keys() {
+        let root = std::env::temp_dir().join(format!("storage-fs-{}", std::process::id()));
+        let mut storage = FileStorage::new(&root).unwrap();
+        storage.put("ckpt", b"weights!").unwrap();
+        assert_eq!(std::fs::read(root.join("ckpt")).unwrap(), b"weights!");
+        assert_eq!(storage.size("ckpt").unwrap(), 8);
+        assert_eq!(
+            storage.get("missing").unwrap_err().code(),
+            ErrorCode::NotFound
+        );
+        for key in ["../escape", ".hidden", "c:drive"] {
+            let err = storage.put(key, b"").unwrap_err();
+            assert_eq!(err.code(), ErrorCode::InvalidArgument, "{:?}", key);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! Guests the unit tests instantiate against a single host module, and reads
+//! of what the host wrote to their memory, like `tests/common` does for the
+//! integration tests.
+
+use crate::{
+    register_host_modules, HostLinker, HostModule, InstantiationError, ModuleContext,
+    ModuleContextBuilder, ModuleRegistry, Overrides, Shim,
+};
+
+/// Store and instance of a guest.
+pub(crate) type Guest = (wasmtime::Store<ModuleContext>, wasmtime::Instance);
+
+/// Registers the imports of `M` with `linker` the way hosts do, along with
+/// the error imports of its namespace.
+pub(crate) fn register<M>(linker: &mut HostLinker)
+where
+    M: HostModule
+        + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+{
+    let registry = ModuleRegistry::empty().with_module::<M>();
+    register_host_modules(linker, &registry, &Overrides::none()).unwrap();
+}
+
+/// A linker with only the imports of `M`.
+pub(crate) fn linker<M>(engine: &wasmtime::Engine) -> HostLinker
+where
+    M: HostModule
+        + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+{
+    let mut linker = HostLinker::new(engine);
+    register::<M>(&mut linker);
+    linker
+}
+
+/// `wat` instantiated with `linker`, which was created for `engine`, with
+/// the state of `context`.
+pub(crate) fn instantiate(
+    engine: &wasmtime::Engine,
+    linker: &HostLinker,
+    wat: impl AsRef<[u8]>,
+    context: ModuleContextBuilder,
+) -> Guest {
+    let module = wasmtime::Module::new(engine, wat).unwrap();
+    let mut store = context.build().unwrap().into_store(engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    (store, instance)
+}
+
+/// `wat` instantiated against the imports of `M`, with the state of
+/// `context`.
+pub(crate) fn guest<M>(wat: impl AsRef<[u8]>, context: ModuleContextBuilder) -> Guest
+where
+    M: HostModule
+        + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+{
+    let engine = wasmtime::Engine::default();
+    let linker = linker::<M>(&engine);
+    instantiate(&engine, &linker, wat, context)
+}
+
+/// Calls the export `name`, returning the error code of the import it
+/// called.
+pub(crate) fn call(
+    store: &mut wasmtime::Store<ModuleContext>,
+    instance: &wasmtime::Instance,
+    name: &str,
+    params: impl wasmtime::WasmParams,
+) -> u32 {
+    ModuleContext::call_export(&mut *store, instance, name, params)
+        .unwrap_or_else(|err| panic!("calling `{}` failed: {}", name, err))
+}
+
+/// `len` bytes of the guest's memory at `at`.
+pub(crate) fn guest_bytes(
+    store: &mut wasmtime::Store<ModuleContext>,
+    instance: &wasmtime::Instance,
+    at: usize,
+    len: usize,
+) -> Vec<u8> {
+    let memory = instance.get_memory(&mut *store, "memory").unwrap();
+    memory.data(&*store)[at..at + len].to_vec()
+}
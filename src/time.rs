@@ -0,0 +1,266 @@
+use std::convert::TryFrom;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    error_code_ranges, ApiError, HostLinker, HostModule, ImportReturn, InstantiationError,
+    ModuleContext, Shim, WasmMemoryHandle,
+};
+
+import_names!(pub mod time_imports = "time" {
+    MONOTONIC_NS = "monotonic_ns",
+    UNIX_MS = "unix_ms",
+});
+
+/// Where [`TimeApiHost`] reads the time from. Tests and deterministic replay
+/// inject their own clock with [`TimeApiHost::with_clock`].
+pub trait ClockSource: Send {
+    /// Time since an arbitrary, fixed point.
+    fn monotonic(&self) -> Duration;
+
+    /// Time since the Unix epoch.
+    fn unix(&self) -> Duration;
+}
+
+/// The host's clocks, with the monotonic one starting when the clock is
+/// created.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl ClockSource for SystemClock {
+    fn monotonic(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn unix(&self) -> Duration {
+        // A clock set before 1970 is reported as the epoch
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Host module giving guests monotonic and wall clock time.
+pub struct TimeApiHost {
+    clock: Box<dyn ClockSource>,
+    /// Last monotonic time handed to the guest.
+    last_monotonic_ns: u64,
+}
+
+impl Default for TimeApiHost {
+    /// Reads the [`SystemClock`].
+    fn default() -> Self {
+        Self::with_clock(SystemClock::default())
+    }
+}
+
+impl TimeApiHost {
+    pub fn with_clock(clock: impl ClockSource + 'static) -> Self {
+        Self {
+            clock: Box::new(clock),
+            last_monotonic_ns: 0,
+        }
+    }
+
+    /// Nanoseconds of the monotonic clock. Never goes backwards within an
+    /// instance, even if the clock source does.
+    pub fn monotonic_ns(&mut self) -> u64 {
+        let now = saturating_u64(self.clock.monotonic().as_nanos());
+        self.last_monotonic_ns = self.last_monotonic_ns.max(now);
+        self.last_monotonic_ns
+    }
+
+    /// Milliseconds since the Unix epoch. Follows the clock source, so it can
+    /// jump in either direction.
+    pub fn unix_ms(&self) -> u64 {
+        saturating_u64(self.clock.unix().as_millis())
+    }
+}
+
+impl HostModule for TimeApiHost {
+    fn name() -> &'static str {
+        "time_api"
+    }
+    fn log_target() -> &'static str {
+        "host::time_api"
+    }
+    fn error_code_range() -> Option<std::ops::Range<u32>> {
+        Some(error_code_ranges::TIME)
+    }
+    fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+        host_context.module_mut::<Self>()
+    }
+}
+
+impl<'t> Shim<'t> for TimeApiHost {
+    type Err = ApiError;
+    type Memory = WasmMemoryHandle<'t>;
+    type Context = ModuleContext;
+    type ImportTable = &'t mut HostLinker;
+    type ImportError = InstantiationError;
+    type WasmTrap = crate::WasmTrap;
+
+    fn namespace() -> (&'static str, &'static str) {
+        ("env", time_imports::PREFIX)
+    }
+
+    fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+        let (namespace, _prefix) = Self::namespace();
+        linker.func_wrap(
+            Self::name(),
+            namespace,
+            time_imports::MONOTONIC_NS,
+            monotonic_ns,
+        )?;
+        linker.func_wrap(Self::name(), namespace, time_imports::UNIX_MS, unix_ms)?;
+        for import in [time_imports::MONOTONIC_NS, time_imports::UNIX_MS] {
+            linker.describe_import(namespace, import, &[], ImportReturn::Value);
+        }
+        Ok(())
+    }
+}
+
+/// `monotonic_ns() -> u64`. The clock has no error to report, so these
+/// imports return the time directly and trap if the module is missing.
+fn monotonic_ns(mut caller: wasmtime::Caller<'_, ModuleContext>) -> Result<u64, crate::WasmTrap> {
+    Ok(time_host(&mut caller)?.monotonic_ns())
+}
+
+/// `unix_ms() -> u64`.
+fn unix_ms(mut caller: wasmtime::Caller<'_, ModuleContext>) -> Result<u64, crate::WasmTrap> {
+    Ok(time_host(&mut caller)?.unix_ms())
+}
+
+fn time_host<'c>(
+    caller: &'c mut wasmtime::Caller<'_, ModuleContext>,
+) -> Result<&'c mut TimeApiHost, crate::WasmTrap> {
+    TimeApiHost::get(caller.data_mut()).map_err(|err| anyhow::Error::msg(err.display().to_string()))
+}
+
+fn saturating_u64(value: u128) -> u64 {
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_support::{guest, Guest};
+
+    /// Clock reading whatever the test last set, in milliseconds.
+    #[derive(Clone, Default)]
+    struct SteppedClock(Arc<AtomicU64>);
+
+    impl SteppedClock {
+        fn set(&self, ms: u64) {
+            self.0.store(ms, Ordering::SeqCst);
+        }
+    }
+
+    impl ClockSource for SteppedClock {
+        fn monotonic(&self) -> Duration {
+            Duration::from_millis(self.0.load(Ordering::SeqCst))
+        }
+
+        fn unix(&self) -> Duration {
+            Duration::from_millis(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    const CLOCKS: &str = r#"
+        (module
+          (import "env" "time__monotonic_ns" (func $monotonic_ns (result i64)))
+          (import "env" "time__unix_ms" (func $unix_ms (result i64)))
+          (memory (export "memory") 1)
+          (func (export "monotonic_ns") (result i64) (call $monotonic_ns))
+          (func (export "unix_ms") (result i64) (call $unix_ms)))
+    "#;
+
+    fn clocks_guest(context: crate::ModuleContextBuilder) -> Guest {
+        guest::<TimeApiHost>(CLOCKS, context)
+    }
+
+    fn read(
+        store: &mut wasmtime::Store<ModuleContext>,
+        instance: &wasmtime::Instance,
+        clock: &str,
+    ) -> Result<u64, crate::ModuleError> {
+        ModuleContext::call_export(&mut *store, instance, clock, ())
+    }
+
+    #[test]
+    fn guests_read_the_injected_clock() {
+        let clock = SteppedClock::default();
+        let context = ModuleContext::builder().with_module(TimeApiHost::with_clock(clock.clone()));
+        let (mut store, instance) = clocks_guest(context);
+
+        clock.set(1_500);
+        assert_eq!(
+            read(&mut store, &instance, "monotonic_ns").unwrap(),
+            1_500_000_000
+        );
+        assert_eq!(read(&mut store, &instance, "unix_ms").unwrap(), 1_500);
+        // Only wall clock time follows the clock back
+        clock.set(1_000);
+        assert_eq!(
+            read(&mut store, &instance, "monotonic_ns").unwrap(),
+            1_500_000_000
+        );
+        assert_eq!(read(&mut store, &instance, "unix_ms").unwrap(), 1_000);
+        clock.set(2_000);
+        assert_eq!(
+            read(&mut store, &instance, "monotonic_ns").unwrap(),
+            2_000_000_000
+        );
+    }
+
+    #[test]
+    fn the_system_clock_moves_forward() {
+        let context = ModuleContext::builder().with_module(TimeApiHost::default());
+        let (mut store, instance) = clocks_guest(context);
+        let before = read(&mut store, &instance, "monotonic_ns").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let after = read(&mut store, &instance, "monotonic_ns").unwrap();
+        assert!(after - before >= 5_000_000, "{} -> {}", before, after);
+
+        let unix_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let guest_ms = read(&mut store, &instance, "unix_ms").unwrap();
+        assert!(
+            guest_ms.abs_diff(unix_ms) < 60_000,
+            "{} vs {}",
+            guest_ms,
+            unix_ms
+        );
+    }
+
+    #[test]
+    fn clocks_trap_without_the_module() {
+        let (mut store, instance) = clocks_guest(ModuleContext::builder());
+        match read(&mut store, &instance, "unix_ms") {
+            Err(err) => assert!(err.to_string().contains("ModuleNotRegistered"), "{}", err),
+            Ok(ms) => panic!("read {} without a time module", ms),
+        }
+    }
+
+    #[test]
+    fn overflowing_clocks_saturate() {
+        assert_eq!(saturating_u64(u128::from(u64::MAX) + 1), u64::MAX);
+        let mut host = TimeApiHost::with_clock(SteppedClock(Arc::new(AtomicU64::new(u64::MAX))));
+        assert_eq!(host.monotonic_ns(), u64::MAX);
+        assert_eq!(host.unix_ms(), u64::MAX);
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    ApiError, ErrorCode, FutureHandle, FutureState, PlainOldData, ProtocolConfig, TrainingBackend,
+};
+
+/// The arguments of `start_training`, borrowed from guest memory.
+///
+/// On the wire these are the positional parameters of the import, in field
+/// order, with strings passed as `(ptr, len)` pairs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrainingRequest<'a> {
+    pub model_name: &'a str,
+    pub epochs: u32,
+    pub dataset_uri: &'a str,
+    /// Where checkpoints are written to, empty to not write any.
+    pub checkpoint_path: &'a str,
+    /// Number of epochs between evaluations, `0` to only evaluate at the end.
+    pub eval_interval: u32,
+    pub optimizer: &'a str,
+    /// Name the run shows up as in metrics, empty for a generated one.
+    pub run_name: &'a str,
+    pub seed: u64,
+}
+
+impl<'a> TrainingRequest<'a> {
+    /// The validated request of the positional arguments of a
+    /// `start_training` import, see [`validate`](Self::validate).
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn from_import_args(
+        model_name: &'a str,
+        epochs: u32,
+        dataset_uri: &'a str,
+        checkpoint_path: &'a str,
+        eval_interval: u32,
+        optimizer: &'a str,
+        run_name: &'a str,
+        seed: u64,
+    ) -> Result<Self, ApiError> {
+        let req = Self {
+            model_name,
+            epochs,
+            dataset_uri,
+            checkpoint_path,
+            eval_interval,
+            optimizer,
+            run_name,
+            seed,
+        };
+        req.validate()?;
+        Ok(req)
+    }
+
+    /// Checks the request independently of how the protocol config was
+    /// passed, so every `start_training` variant rejects the same requests.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.model_name.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "model name must not be empty",
+            ));
+        }
+        if self.epochs == 0 {
+            return Err(ApiError::new(
+                ErrorCode::InvalidArgument,
+                "epochs must not be zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A [`TrainingRequest`] copied out of guest memory, for starts that are
+/// retried after the host call returned.
+#[derive(Clone, Debug)]
+pub(crate) struct OwnedTrainingRequest {
+    model_name: String,
+    epochs: u32,
+    dataset_uri: String,
+    checkpoint_path: String,
+    eval_interval: u32,
+    optimizer: String,
+    run_name: String,
+    seed: u64,
+}
+
+impl OwnedTrainingRequest {
+    pub(crate) fn new(req: &TrainingRequest<'_>) -> Self {
+        Self {
+            model_name: req.model_name.to_owned(),
+            epochs: req.epochs,
+            dataset_uri: req.dataset_uri.to_owned(),
+            checkpoint_path: req.checkpoint_path.to_owned(),
+            eval_interval: req.eval_interval,
+            optimizer: req.optimizer.to_owned(),
+            run_name: req.run_name.to_owned(),
+            seed: req.seed,
+        }
+    }
+
+    pub(crate) fn request(&self) -> TrainingRequest<'_> {
+        TrainingRequest {
+            model_name: &self.model_name,
+            epochs: self.epochs,
+            dataset_uri: &self.dataset_uri,
+            checkpoint_path: &self.checkpoint_path,
+            eval_interval: self.eval_interval,
+            optimizer: &self.optimizer,
+            run_name: &self.run_name,
+            seed: self.seed,
+        }
+    }
+}
+
+/// Background work of a training, returning its result payload. Jobs report
+/// how far they got through the [`TrainingProgress`] they are called with.
+pub type TrainingJob = Box<dyn FnOnce(&TrainingProgress) -> Result<Vec<u8>, ApiError> + Send>;
+
+/// Runs the job of the training `handle`, with the state it leaves the
+/// future in.
+pub(crate) fn run_training(
+    handle: FutureHandle,
+    job: TrainingJob,
+    progress: &TrainingProgress,
+) -> FutureState {
+    let run = || job(progress);
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(run)) {
+        Ok(Ok(result)) => FutureState::Completed(result),
+        Ok(Err(err)) => FutureState::Failed(err),
+        Err(_) => FutureState::Failed(ApiError::internal(format!(
+            "training {} panicked",
+            handle.raw()
+        ))),
+    }
+}
+
+/// Progress of a training, as reported to the guest by
+/// `get_training_metrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct TrainingMetrics {
+    /// Number of epochs completed so far.
+    pub epoch: u32,
+    pub total_epochs: u32,
+    /// Loss of the latest epoch, `0.0` before the first one.
+    pub loss: f32,
+    pub samples_per_sec: f32,
+}
+
+// The guest ABI
+const _: () = {
+    use std::mem::{align_of, offset_of, size_of};
+    assert!(size_of::<TrainingMetrics>() == 16);
+    assert!(align_of::<TrainingMetrics>() == 4);
+    assert!(offset_of!(TrainingMetrics, epoch) == 0);
+    assert!(offset_of!(TrainingMetrics, total_epochs) == 4);
+    assert!(offset_of!(TrainingMetrics, loss) == 8);
+    assert!(offset_of!(TrainingMetrics, samples_per_sec) == 12);
+};
+
+// SAFETY: `repr(C)` and only 4 byte fields, so there is no padding
+unsafe impl PlainOldData for TrainingMetrics {}
+
+/// Where a [`TrainingJob`] records its [`TrainingMetrics`]. The guest keeps
+/// seeing the last recorded values once the training has finished.
+#[derive(Clone, Debug, Default)]
+pub struct TrainingProgress(Arc<Mutex<TrainingMetrics>>);
+
+impl TrainingProgress {
+    pub fn new(total_epochs: u32) -> Self {
+        Self(Arc::new(Mutex::new(TrainingMetrics {
+            total_epochs,
+            ..TrainingMetrics::default()
+        })))
+    }
+
+    /// Records that `epoch` epochs have been completed.
+    pub fn report(&self, epoch: u32, loss: f32, samples_per_sec: f32) {
+        let mut metrics = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        metrics.epoch = epoch;
+        metrics.loss = loss;
+        metrics.samples_per_sec = samples_per_sec;
+    }
+
+    pub fn metrics(&self) -> TrainingMetrics {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// How a [`TrainingBackend`](crate::TrainingBackend) started a training.
+pub enum TrainingStart {
+    /// The initial state of the future, e.g. `Completed` for backends that
+    /// train synchronously.
+    State(FutureState),
+    /// Work to run on the instance's [`JobExecutor`](crate::JobExecutor),
+    /// which completes the future with its outcome. A panicking job fails it
+    /// with [`ErrorCode::Internal`].
+    Job(TrainingJob),
+}
+
+impl From<FutureState> for TrainingStart {
+    fn from(state: FutureState) -> Self {
+        Self::State(state)
+    }
+}
+
+/// Backend for tests: inference returns its input unchanged and trainings
+/// complete right away with an empty result.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EchoBackend;
+
+impl TrainingBackend for EchoBackend {
+    fn start(
+        &mut self,
+        _handle: FutureHandle,
+        _req: TrainingRequest<'_>,
+        _protocol: &ProtocolConfig,
+    ) -> Result<TrainingStart, ApiError> {
+        Ok(FutureState::Completed(Vec::new()).into())
+    }
+
+    fn infer(
+        &mut self,
+        _model: &str,
+        input: &[f32],
+        output: &mut [f32],
+    ) -> Result<usize, ApiError> {
+        if let Some(output) = output.get_mut(..input.len()) {
+            output.copy_from_slice(input);
+        }
+        Ok(input.len())
+    }
+}
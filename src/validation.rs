@@ -0,0 +1,347 @@
+use std::fmt;
+
+use crate::{HostLinker, InstantiationError, RegisteredImports};
+
+/// Parameter and result types of a function.
+#[derive(Clone, Debug)]
+pub struct FuncSignature {
+    pub params: Box<[wasmtime::ValType]>,
+    pub results: Box<[wasmtime::ValType]>,
+}
+
+impl PartialEq for FuncSignature {
+    fn eq(&self, other: &Self) -> bool {
+        types_eq(&self.params, &other.params) && types_eq(&self.results, &other.results)
+    }
+}
+
+impl Eq for FuncSignature {}
+
+/// Whether two lists of wasm types are the same. `ValType` isn't
+/// `PartialEq`, as reference types compare through their engine.
+pub(crate) fn types_eq(a: &[wasmtime::ValType], b: &[wasmtime::ValType]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| wasmtime::ValType::eq(a, b))
+}
+
+impl From<&wasmtime::FuncType> for FuncSignature {
+    fn from(ty: &wasmtime::FuncType) -> Self {
+        Self {
+            params: ty.params().collect(),
+            results: ty.results().collect(),
+        }
+    }
+}
+
+impl fmt::Display for FuncSignature {
+    /// `(i32, i64) -> i32`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_signature(f, &self.params, &self.results)
+    }
+}
+
+pub(crate) fn write_signature(
+    f: &mut fmt::Formatter<'_>,
+    params: &[wasmtime::ValType],
+    results: &[wasmtime::ValType],
+) -> fmt::Result {
+    let list = |types: &[wasmtime::ValType]| {
+        types
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    write!(f, "({})", list(params))?;
+    match results {
+        [] => Ok(()),
+        [result] => write!(f, " -> {}", result),
+        results => write!(f, " -> ({})", list(results)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImportStatus {
+    /// The host provides the import with the signature the guest expects.
+    Satisfied,
+    /// The host doesn't provide the import. Imports of memories, tables and
+    /// globals are always missing, host modules only provide functions.
+    Missing,
+    /// The host provides a function of that name, but with a different
+    /// signature, e.g. because the guest was built against another version
+    /// of the host.
+    SignatureMismatch {
+        /// Signature of the host function.
+        expected: FuncSignature,
+        /// Signature the guest imports it with.
+        found: FuncSignature,
+    },
+}
+
+/// How an import of a guest module is satisfied by the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportDiagnostic {
+    /// Import module, the namespace of host imports such as `env`.
+    pub module: String,
+    pub name: String,
+    pub status: ImportStatus,
+    /// Host module providing the import, `None` if it is missing.
+    pub host_module: Option<&'static str>,
+}
+
+impl fmt::Display for ImportDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}::{}` ", self.module, self.name)?;
+        match &self.status {
+            ImportStatus::Satisfied => write!(f, "is provided")?,
+            ImportStatus::Missing => return write!(f, "is not provided by the host"),
+            ImportStatus::SignatureMismatch { expected, found } => write!(
+                f,
+                "is imported as `{}` but provided as `{}`",
+                found, expected
+            )?,
+        }
+        match self.host_module {
+            Some(module) => write!(f, " by host module `{}`", module),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Classifies every import of `module` against the host's `imports`, in the
+/// order the module declares them.
+pub fn diagnose_module(
+    module: &wasmtime::Module,
+    imports: &RegisteredImports,
+) -> Vec<ImportDiagnostic> {
+    diagnose(module, |namespace, name| {
+        let import = imports.get(namespace, name)?;
+        let signature = FuncSignature {
+            params: import.params.as_slice().into(),
+            results: import.results.as_slice().into(),
+        };
+        Some((signature, Some(import.module)))
+    })
+}
+
+/// Classifies the imports of `module` against the signatures and host
+/// modules of the functions `provided` looks up.
+pub(crate) fn diagnose(
+    module: &wasmtime::Module,
+    mut provided: impl FnMut(&str, &str) -> Option<(FuncSignature, Option<&'static str>)>,
+) -> Vec<ImportDiagnostic> {
+    module
+        .imports()
+        .map(|import| {
+            let name = import.name();
+            let (status, host_module) = match (import.ty(), provided(import.module(), name)) {
+                (wasmtime::ExternType::Func(ty), Some((expected, host_module))) => {
+                    let found = FuncSignature::from(&ty);
+                    if found == expected {
+                        (ImportStatus::Satisfied, host_module)
+                    } else {
+                        (
+                            ImportStatus::SignatureMismatch { expected, found },
+                            host_module,
+                        )
+                    }
+                }
+                _ => (ImportStatus::Missing, None),
+            };
+            ImportDiagnostic {
+                module: import.module().to_owned(),
+                name: name.to_owned(),
+                status,
+                host_module,
+            }
+        })
+        .collect()
+}
+
+impl ImportDiagnostic {
+    /// The error instantiating the guest module `guest` fails with because
+    /// of this import, `None` if it is satisfied.
+    pub fn to_error(&self, guest: &str) -> Option<InstantiationError> {
+        match &self.status {
+            ImportStatus::Satisfied => None,
+            ImportStatus::Missing => Some(InstantiationError::MissingImport {
+                guest: guest.to_owned(),
+                namespace: self.module.clone(),
+                name: self.name.clone(),
+            }),
+            ImportStatus::SignatureMismatch { expected, found } => {
+                Some(InstantiationError::SignatureMismatch {
+                    guest: guest.to_owned(),
+                    name: format!("{}::{}", self.module, self.name),
+                    expected: expected.clone(),
+                    found: found.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// Checks that the host provides every import of `module`, returning each
+/// one that is missing or mismatched rather than only the first like
+/// instantiation.
+pub fn validate_module(
+    module: &wasmtime::Module,
+    imports: &RegisteredImports,
+) -> Result<(), Vec<ImportDiagnostic>> {
+    let problems = diagnose_module(module, imports)
+        .into_iter()
+        .filter(|diagnostic| diagnostic.status != ImportStatus::Satisfied)
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+impl HostLinker {
+    /// [`validate_module`] against the imports registered with this linker,
+    /// to be called before instantiating a guest of unknown provenance.
+    pub fn validate_module(&self, module: &wasmtime::Module) -> Result<(), InstantiationError> {
+        validate_module(module, &self.registered_imports()).map_err(|diagnostics| {
+            InstantiationError::InvalidImports {
+                guest: guest_name(module),
+                diagnostics,
+            }
+        })
+    }
+}
+
+/// Name of a guest module for errors, from its name section.
+pub(crate) fn guest_name(module: &wasmtime::Module) -> String {
+    module.name().unwrap_or("<unnamed>").to_owned()
+}
+
+/// One diagnostic per line, for [`InstantiationError::InvalidImports`].
+pub(crate) fn list(diagnostics: &[ImportDiagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| format!("\n  {}", diagnostic))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support, MLApiHost};
+    use wasmtime::ValType::{I32, I64};
+
+    /// A guest `$guest` with a satisfied, a missing, a mismatched and a
+    /// memory import, in that order.
+    const GUEST: &str = r#"
+        (module $guest
+          (import "env" "ml__get_last_error" (func (param i32 i32) (result i32)))
+          (import "env" "ml__not_provided" (func))
+          (import "env" "ml__poll_future" (func (param i32) (result i32)))
+          (import "env" "memory" (memory 1)))
+    "#;
+
+    #[test]
+    fn every_import_is_classified_in_declaration_order() {
+        let engine = wasmtime::Engine::default();
+        let linker = test_support::linker::<MLApiHost>(&engine);
+        let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+        let diagnostics = diagnose_module(&module, &linker.registered_imports());
+
+        let names: Vec<_> = diagnostics.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "ml__get_last_error",
+                "ml__not_provided",
+                "ml__poll_future",
+                "memory"
+            ]
+        );
+        assert_eq!(diagnostics[0].status, ImportStatus::Satisfied);
+        assert_eq!(diagnostics[0].host_module, Some("ml_api"));
+        assert_eq!(diagnostics[1].status, ImportStatus::Missing);
+        assert_eq!(diagnostics[1].host_module, None);
+        match &diagnostics[2].status {
+            ImportStatus::SignatureMismatch { expected, found } => {
+                assert!(matches!(found.params[..], [I32]), "{:?}", found.params);
+                assert!(matches!(found.results[..], [I32]), "{:?}", found.results);
+                assert!(matches!(expected.params[0], I64), "{:?}", expected.params);
+            }
+            status => panic!("`ml__poll_future` is {:?}", status),
+        }
+        assert_eq!(diagnostics[2].host_module, Some("ml_api"));
+        assert_eq!(diagnostics[3].status, ImportStatus::Missing);
+    }
+
+    #[test]
+    fn diagnostics_describe_the_problem() {
+        let diagnostic = |status| ImportDiagnostic {
+            module: "env".to_owned(),
+            name: "ml__poll_future".to_owned(),
+            status,
+            host_module: Some("ml_api"),
+        };
+        let mismatch = ImportStatus::SignatureMismatch {
+            expected: FuncSignature {
+                params: vec![I64, I32].into(),
+                results: vec![I32].into(),
+            },
+            found: FuncSignature {
+                params: vec![I32].into(),
+                results: vec![].into(),
+            },
+        };
+        assert_eq!(
+            diagnostic(mismatch).to_string(),
+            "`env::ml__poll_future` is imported as `(i32)` but provided as \
+             `(i64, i32) -> i32` by host module `ml_api`"
+        );
+        assert_eq!(
+            diagnostic(ImportStatus::Satisfied).to_string(),
+            "`env::ml__poll_future` is provided by host module `ml_api`"
+        );
+        let missing = ImportDiagnostic {
+            host_module: None,
+            ..diagnostic(ImportStatus::Missing)
+        };
+        assert_eq!(
+            missing.to_string(),
+            "`env::ml__poll_future` is not provided by the host"
+        );
+        assert!(diagnostic(ImportStatus::Satisfied)
+            .to_error("guest")
+            .is_none());
+        assert!(matches!(
+            missing.to_error("guest"),
+            Some(InstantiationError::MissingImport { .. })
+        ));
+    }
+
+    #[test]
+    fn validation_reports_every_problem_at_once() {
+        let engine = wasmtime::Engine::default();
+        let linker = test_support::linker::<MLApiHost>(&engine);
+        let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+        let problems = validate_module(&module, &linker.registered_imports()).unwrap_err();
+        assert_eq!(problems.len(), 3);
+        assert!(problems
+            .iter()
+            .all(|problem| problem.status != ImportStatus::Satisfied));
+
+        match linker.validate_module(&module) {
+            Err(InstantiationError::InvalidImports { guest, diagnostics }) => {
+                assert_eq!(guest, "guest");
+                assert_eq!(diagnostics, problems);
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let satisfied = r#"
+            (module
+              (import "env" "ml__get_last_error" (func (param i32 i32) (result i32))))
+        "#;
+        let module = wasmtime::Module::new(&engine, satisfied).unwrap();
+        assert!(linker.validate_module(&module).is_ok());
+        assert_eq!(guest_name(&module), "<unnamed>");
+    }
+}
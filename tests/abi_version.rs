@@ -0,0 +1,87 @@
+//! The ABI version handshake of `HostRuntime::call_entry_point`.
+
+mod common;
+
+use std::sync::Arc;
+
+use rustc_nightly_reduction::{
+    register_host_modules, AbiVersion, HostLinker, HostRuntime, InstantiationError, ModuleError,
+    ModuleRegistry, Overrides,
+};
+
+fn runtime(fixture: &str) -> HostRuntime {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let context = common::context().build().unwrap();
+    HostRuntime::new(
+        Arc::new(linker),
+        context,
+        common::fixture(fixture).as_bytes(),
+    )
+    .unwrap_or_else(|err| panic!("failed to instantiate fixture `{}`: {}", fixture, err))
+}
+
+fn run(runtime: &mut HostRuntime) -> Result<u32, ModuleError> {
+    runtime.call_entry_point::<(), u32>("run", ())
+}
+
+#[test]
+fn matching_versions_are_accepted() {
+    let mut runtime = runtime("abi_function");
+    assert_eq!(runtime.guest_abi(), None);
+    assert_eq!(run(&mut runtime).unwrap(), 7);
+    assert_eq!(runtime.guest_abi(), Some(AbiVersion::HOST));
+}
+
+#[test]
+fn minor_mismatches_are_accepted() {
+    let mut runtime = runtime("abi_minor");
+    assert_eq!(run(&mut runtime).unwrap(), 7);
+    assert_eq!(runtime.guest_abi(), Some(AbiVersion::new(1, 7)));
+}
+
+#[test]
+fn major_mismatches_are_rejected() {
+    let mut runtime = runtime("abi_major");
+    let err = run(&mut runtime).unwrap_err();
+    match &err {
+        ModuleError::Instantiation(InstantiationError::AbiMismatch { host, guest }) => {
+            assert_eq!(*host, AbiVersion::HOST);
+            assert_eq!(*guest, AbiVersion::new(2, 0));
+        }
+        other => panic!("expected an ABI mismatch, got {:?}", other),
+    }
+    assert_eq!(runtime.guest_abi(), None);
+    // Every entry point call is rejected, not just the first
+    assert!(run(&mut runtime).is_err());
+    // Plain export calls skip the handshake
+    assert_eq!(runtime.call_export::<(), u32>("run", ()).unwrap(), 7);
+}
+
+#[test]
+fn guests_without_the_export_are_v1() {
+    let mut runtime = runtime("abi_unversioned");
+    assert_eq!(run(&mut runtime).unwrap(), 7);
+    assert_eq!(runtime.guest_abi(), Some(AbiVersion::UNVERSIONED));
+    assert_eq!(AbiVersion::UNVERSIONED.major, 1);
+}
+
+#[test]
+fn reloads_repeat_the_handshake() {
+    let mut runtime = runtime("abi_minor");
+    run(&mut runtime).unwrap();
+    runtime
+        .reload_module(
+            common::fixture("abi_major").as_bytes(),
+            rustc_nightly_reduction::ReloadPolicy::CarryOver,
+        )
+        .unwrap();
+    assert_eq!(runtime.guest_abi(), None);
+    assert!(matches!(
+        run(&mut runtime),
+        Err(ModuleError::Instantiation(
+            InstantiationError::AbiMismatch { .. }
+        ))
+    ));
+}
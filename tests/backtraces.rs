@@ -0,0 +1,32 @@
+//! Backtraces of `ApiError`, in its own test binary as the standard library
+//! reads `RUST_BACKTRACE` only once per process.
+
+use rustc_nightly_reduction::{ApiError, ErrorCode};
+
+#[test]
+#[cfg(any(debug_assertions, feature = "debug-errors"))]
+fn internal_errors_capture_a_backtrace() {
+    std::env::set_var("RUST_BACKTRACE", "1");
+    let err = ApiError::internal("backend crashed");
+    assert!(err.backtrace().is_some());
+    let displayed = err.display().to_string();
+    assert!(
+        displayed.starts_with("Internal(7): backend crashed\nbacktrace:\n"),
+        "{}",
+        displayed
+    );
+    assert!(
+        displayed.contains("internal_errors_capture_a_backtrace"),
+        "{}",
+        displayed
+    );
+    // Clones share the backtrace
+    assert_eq!(err.clone().display().to_string(), displayed);
+
+    let err = ApiError::new(ErrorCode::NotFound, "no such model");
+    assert!(
+        err.backtrace().is_none(),
+        "graceful errors have no backtrace"
+    );
+    assert_eq!(err.display().to_string(), "NotFound(6): no such model");
+}
@@ -0,0 +1,126 @@
+//! Instantiates the WAT guests in `tests/fixtures` against the host modules
+//! and reads back what the host wrote to guest memory.
+
+#![allow(dead_code)]
+
+pub mod rng;
+
+use std::path::PathBuf;
+
+use rustc_nightly_reduction::{
+    register_host_modules, ApiError, HostLinker, InstantiationError, MLApiHost, ModuleContext,
+    ModuleContextBuilder, ModuleRegistry, Overrides,
+};
+
+/// Text of `tests/fixtures/{name}.wat`.
+pub fn fixture(name: &str) -> String {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+        .iter()
+        .collect::<PathBuf>()
+        .with_extension("wat");
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("failed to read fixture `{}`: {}", path.display(), err))
+}
+
+/// A context with the state of [`MLApiHost`], which most fixtures call.
+pub fn context() -> ModuleContextBuilder {
+    ModuleContext::builder()
+        .with_module(MLApiHost::default())
+        .with_job_threads(1)
+}
+
+/// A fixture instantiated with every host module of this crate registered.
+pub struct Guest {
+    pub store: wasmtime::Store<ModuleContext>,
+    pub instance: wasmtime::Instance,
+}
+
+impl Guest {
+    pub fn new(name: &str) -> Self {
+        Self::with_context(name, context())
+    }
+
+    pub fn with_context(name: &str, context: ModuleContextBuilder) -> Self {
+        Self::try_with_context(name, context)
+            .unwrap_or_else(|err| panic!("failed to instantiate fixture `{}`: {}", name, err))
+    }
+
+    pub fn try_new(name: &str) -> Result<Self, InstantiationError> {
+        Self::try_with_context(name, context())
+    }
+
+    pub fn try_with_context(
+        name: &str,
+        context: ModuleContextBuilder,
+    ) -> Result<Self, InstantiationError> {
+        Self::try_with_registry(name, &ModuleRegistry::default(), context)
+    }
+
+    /// Instantiates the fixture with only the host modules of `registry`.
+    pub fn try_with_registry(
+        name: &str,
+        registry: &ModuleRegistry,
+        context: ModuleContextBuilder,
+    ) -> Result<Self, InstantiationError> {
+        let engine = wasmtime::Engine::default();
+        let mut linker = HostLinker::new(&engine);
+        register_host_modules(&mut linker, registry, &Overrides::none())?;
+        let module = wasmtime::Module::new(&engine, fixture(name))
+            .unwrap_or_else(|err| panic!("fixture `{}` doesn't compile: {:#}", name, err));
+        let mut store = context.build().unwrap().into_store(&engine);
+        let instance = linker.instantiate(&mut store, &module)?;
+        Ok(Self { store, instance })
+    }
+
+    /// Calls the export `name` taking no arguments, returning the code of
+    /// the import it called.
+    pub fn call(&mut self, name: &str) -> u32 {
+        ModuleContext::call_export::<(), u32>(&mut self.store, &self.instance, name, ())
+            .unwrap_or_else(|err| panic!("calling `{}` failed: {}", name, err))
+    }
+
+    pub fn last_error(&self) -> Option<&ApiError> {
+        self.store.data().last_error()
+    }
+
+    pub fn memory(&mut self) -> &[u8] {
+        self.instance
+            .get_memory(&mut self.store, "memory")
+            .expect("fixtures export their memory")
+            .data(&self.store)
+    }
+
+    pub fn read_bytes(&mut self, addr: usize, len: usize) -> Vec<u8> {
+        self.memory()[addr..addr + len].to_vec()
+    }
+
+    pub fn read_u32(&mut self, addr: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.memory()[addr..addr + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn read_u64(&mut self, addr: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.memory()[addr..addr + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Asserts that guest memory at `addr` holds `expected`.
+    #[track_caller]
+    pub fn assert_memory(&mut self, addr: usize, expected: &[u8]) {
+        assert_eq!(
+            self.read_bytes(addr, expected.len()),
+            expected,
+            "guest memory at {:#x}",
+            addr
+        );
+    }
+
+    /// Asserts that `len` bytes of guest memory at `addr` are zero, such as
+    /// an out parameter the host shouldn't have written.
+    #[track_caller]
+    pub fn assert_zeroed(&mut self, addr: usize, len: usize) {
+        self.assert_memory(addr, &vec![0; len]);
+    }
+}
@@ -0,0 +1,39 @@
+//! Seeded random inputs for tests that generate their own, e.g. the fuzz
+//! smoke test.
+
+use std::time::SystemTime;
+
+/// SplitMix64, as the inputs only need to vary, not be unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A value in `0..bound`, `0` if `bound` is.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64().checked_rem(bound).unwrap_or(0)
+    }
+}
+
+/// The hex seed in the environment variable `name`, or a new one from the
+/// clock.
+pub fn seed(name: &str) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => u64::from_str_radix(value.trim_start_matches("0x"), 16)
+            .unwrap_or_else(|_| panic!("`{}` must be a hex number", name)),
+        Err(_) => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64,
+    }
+}
@@ -0,0 +1,91 @@
+//! Warnings and call stats of guests calling deprecated import aliases.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use rustc_nightly_reduction::{
+    ml_imports, register_host_modules, HostLinker, HostModule, HostRuntime, ImportAlias,
+    InstantiationError, MLApiHost, ModuleRegistry, Overrides,
+};
+
+const OLD_NAME: &str = "ml__poll_future_v0";
+
+/// Collects the messages of every warning logged by the host.
+struct WarningLog(Mutex<Vec<String>>);
+
+impl log::Log for WarningLog {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static WARNINGS: WarningLog = WarningLog(Mutex::new(Vec::new()));
+
+fn deprecation_warnings() -> Vec<String> {
+    let warnings = WARNINGS.0.lock().unwrap();
+    warnings
+        .iter()
+        .filter(|warning| warning.contains(OLD_NAME))
+        .cloned()
+        .collect()
+}
+
+fn linker(alias: ImportAlias) -> Result<HostLinker, InstantiationError> {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none())?;
+    linker.alias(MLApiHost::name(), "env", alias)?;
+    Ok(linker)
+}
+
+#[test]
+fn deprecated_aliases_warn_once_and_count_every_call() {
+    let _ = log::set_logger(&WARNINGS);
+    log::set_max_level(log::LevelFilter::Warn);
+    let alias = ImportAlias::new(OLD_NAME, ml_imports::POLL_FUTURE)
+        .deprecated("0.2.0", ml_imports::POLL_FUTURE);
+    let linker = Arc::new(linker(alias).unwrap());
+    let context = common::context().build().unwrap();
+    let wasm = common::fixture("deprecated_alias");
+    let mut runtime = HostRuntime::new(linker, context, wasm.as_bytes()).unwrap();
+
+    for function in ["poll_old", "poll_old", "poll_new"] {
+        runtime.call_export::<(), u32>(function, ()).unwrap();
+    }
+    // Calls are counted the same without `host-logging`, only not warned
+    // about
+    let expected: &[String] = &[format!(
+        "guest called `{}`, which is deprecated since 0.2.0, use `{}` instead",
+        OLD_NAME,
+        ml_imports::POLL_FUTURE
+    )];
+    let expected = if cfg!(feature = "host-logging") {
+        expected
+    } else {
+        &[]
+    };
+    assert_eq!(deprecation_warnings(), expected);
+    let stats = runtime.context().call_stats();
+    let poll = stats
+        .iter()
+        .find(|stat| stat.function == ml_imports::POLL_FUTURE)
+        .unwrap();
+    assert_eq!(poll.calls, 3);
+    assert_eq!(poll.deprecated_calls, 2);
+}
+
+#[test]
+fn only_defined_imports_can_be_deprecated() {
+    // `host__abi_version` is a plain wasmtime function
+    let alias = ImportAlias::new("host__version", "host__abi_version").deprecated("0.2.0", "");
+    assert!(matches!(linker(alias), Err(InstantiationError::Import(_))));
+}
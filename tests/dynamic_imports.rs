@@ -0,0 +1,255 @@
+//! Shims registered as `DynHostFunction`s behave like `host_import!` ones.
+
+mod common;
+
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
+
+use rustc_nightly_reduction::{
+    register_host_modules, wasm_shim, ApiError, ErrorCode, HostLinker, HostModule, HostRuntime,
+    ImportAlias, InstantiationError, ModuleContext, ModuleRegistry, Overrides, PlainOldData, Shim,
+    WasmMemoryHandle,
+};
+
+#[derive(Clone, Copy, PlainOldData)]
+#[repr(C)]
+struct Pair {
+    a: u32,
+    b: u32,
+}
+
+/// The `calc` module of the `calculator` fixture, registering its shims
+/// through `host_import!`, or dynamically with `$mode` set to `dynamic`.
+macro_rules! calculator {
+    ($ty:ident $(, $mode:ident)?) => {
+        #[derive(Default)]
+        struct $ty {
+            counted: u64,
+        }
+
+        #[wasm_shim(prefix = "calc" $(, $mode)?)]
+        impl $ty {
+            fn add_shim(&mut self, a: u32, b: u32) -> Result<u32, ApiError> {
+                a.checked_add(b)
+                    .ok_or_else(|| ApiError::new(ErrorCode::InvalidArgument, "sum overflows"))
+            }
+
+            fn scale_shim(&mut self, value: f64, factor: f32) -> Result<f64, ApiError> {
+                Ok(value * f64::from(factor))
+            }
+
+            fn count_shim(&mut self, text: &str) -> Result<u64, ApiError> {
+                if text.is_empty() {
+                    return Err(ApiError::not_found("nothing to count"));
+                }
+                self.counted += text.chars().count() as u64;
+                Ok(self.counted)
+            }
+
+            fn sum_shim(&mut self, pair: &Pair) -> Result<u64, ApiError> {
+                Ok(u64::from(pair.a) + u64::from(pair.b))
+            }
+
+            fn reset_shim(&mut self) -> Result<(), ApiError> {
+                self.counted = 0;
+                Ok(())
+            }
+
+            fn explode_shim(&mut self, code: i32) -> Result<(), ApiError> {
+                panic!("exploded with {}", code)
+            }
+        }
+
+        impl HostModule for $ty {
+            fn name() -> &'static str {
+                "calc"
+            }
+            fn error_code_range() -> Option<Range<u32>> {
+                None
+            }
+            fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+                host_context.module_mut::<Self>()
+            }
+        }
+
+        impl<'t> Shim<'t> for $ty {
+            type Err = ApiError;
+            type Memory = WasmMemoryHandle<'t>;
+            type Context = ModuleContext;
+            type ImportTable = &'t mut HostLinker;
+            type ImportError = InstantiationError;
+            type WasmTrap = rustc_nightly_reduction::WasmTrap;
+
+            fn namespace() -> (&'static str, &'static str) {
+                ("env", "calc")
+            }
+
+            fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+                Self::shim_imports(linker)
+            }
+        }
+    };
+}
+
+calculator!(TypedCalculator);
+calculator!(DynamicCalculator, dynamic);
+
+/// The exports of the fixture, in the order they are called.
+const EXPORTS: &[&str] = &[
+    "add",
+    "add_overflow",
+    "add_out_of_bounds",
+    "add_null",
+    "scale",
+    "count",
+    "count",
+    "count_empty",
+    "count_invalid_utf8",
+    "count_out_of_bounds",
+    "reset",
+    "count",
+    "sum",
+    "sum_out_of_bounds",
+    "explode",
+    "add",
+];
+
+/// What a call of an export did: its code or whether it trapped, the
+/// instance's last error and the results written to guest memory.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    export: &'static str,
+    code: Option<u32>,
+    last_error: Option<ErrorCode>,
+    memory: Vec<u8>,
+}
+
+fn linker<M>() -> HostLinker
+where
+    M: HostModule
+        + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+{
+    let mut linker = HostLinker::new(&wasmtime::Engine::default());
+    let registry = ModuleRegistry::empty().with_module::<M>();
+    register_host_modules(&mut linker, &registry, &Overrides::none()).unwrap();
+    linker
+}
+
+fn run<M>() -> (Vec<Outcome>, HostRuntime)
+where
+    M: HostModule
+        + Default
+        + Send
+        + 'static
+        + for<'t> Shim<'t, ImportTable = &'t mut HostLinker, ImportError = InstantiationError>,
+{
+    let context = ModuleContext::builder().with_module(M::default());
+    let wasm = common::fixture("calculator");
+    let mut runtime = HostRuntime::new(
+        Arc::new(linker::<M>()),
+        context.build().unwrap(),
+        wasm.as_bytes(),
+    )
+    .unwrap();
+    let outcomes = EXPORTS
+        .iter()
+        .map(|&export| {
+            let code = runtime.call_export::<(), u32>(export, ()).ok();
+            let last_error = runtime.context().last_error().map(ApiError::code);
+            let instance = runtime.instance();
+            let memory = instance
+                .get_memory(runtime.store_mut(), "memory")
+                .expect("the fixture exports its memory");
+            let memory = memory.data(runtime.store())[0x100..0x120].to_vec();
+            Outcome {
+                export,
+                code,
+                last_error,
+                memory,
+            }
+        })
+        .collect();
+    (outcomes, runtime)
+}
+
+#[test]
+fn dynamic_imports_match_typed_ones() {
+    let (typed, typed_runtime) = run::<TypedCalculator>();
+    let (dynamic, dynamic_runtime) = run::<DynamicCalculator>();
+    assert_eq!(typed, dynamic);
+
+    let codes: Vec<_> = dynamic.iter().map(|outcome| outcome.code).collect();
+    let code = |code: ErrorCode| Some(code as u32);
+    assert_eq!(
+        codes,
+        [
+            code(ErrorCode::Success),
+            code(ErrorCode::InvalidArgument),
+            code(ErrorCode::OutOfBounds),
+            code(ErrorCode::InvalidArgument),
+            code(ErrorCode::Success),
+            code(ErrorCode::Success),
+            code(ErrorCode::Success),
+            code(ErrorCode::NotFound),
+            code(ErrorCode::InvalidUtf8),
+            code(ErrorCode::OutOfBounds),
+            code(ErrorCode::Success),
+            code(ErrorCode::Success),
+            code(ErrorCode::Success),
+            code(ErrorCode::OutOfBounds),
+            // The panic traps and poisons the instance
+            None,
+            code(ErrorCode::Internal),
+        ]
+    );
+    let memory = &dynamic.last().unwrap().memory;
+    let read = |at: usize| u64::from_le_bytes(memory[at..at + 8].try_into().unwrap());
+    assert_eq!(read(0x00) as u32, 5);
+    assert_eq!(f64::from_bits(read(0x08)), -3.0);
+    assert_eq!(read(0x10), 11);
+    assert_eq!(read(0x18), 42);
+
+    let stats = |runtime: &HostRuntime| {
+        let mut stats: Vec<_> = runtime
+            .context()
+            .call_stats()
+            .into_iter()
+            .map(|stat| (stat.function, stat.calls, stat.failures))
+            .collect();
+        stats.sort_unstable();
+        stats
+    };
+    assert_eq!(stats(&typed_runtime), stats(&dynamic_runtime));
+}
+
+#[test]
+fn dynamic_imports_have_the_typed_signatures() {
+    let typed = linker::<TypedCalculator>();
+    let dynamic = linker::<DynamicCalculator>();
+    let imports = |linker: &HostLinker| {
+        linker
+            .registered_imports()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(imports(&typed), imports(&dynamic));
+    assert_eq!(typed.manifest(), dynamic.manifest());
+}
+
+#[test]
+fn dynamic_imports_can_be_deprecated() {
+    let mut linker = linker::<DynamicCalculator>();
+    linker
+        .alias(
+            DynamicCalculator::name(),
+            "env",
+            ImportAlias::new("calc__plus", "calc__add").deprecated("0.2.0", "calc__add"),
+        )
+        .unwrap();
+    assert!(linker
+        .registered_imports()
+        .get("env", "calc__plus")
+        .is_some());
+}
@@ -0,0 +1,245 @@
+//! The partition of the error code space between host modules.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use rustc_nightly_reduction::{
+    error_code_ranges, ApiError, DatasetApiHost, ErrorCode, HostLinker, HostModule,
+    InstantiationError, LoggingApiHost, MLApiHost, MetricsApiHost, ModelApiHost, ModuleContext,
+    ModuleRegistry, ParseErrorCodeError, RandomApiHost, Shim, StorageApiHost, TimeApiHost,
+    UnknownErrorCode, WasmMemoryHandle,
+};
+
+/// Name, reserved range and codes of a host module.
+type ModuleCodes = (&'static str, Option<Range<u32>>, &'static [ErrorCode]);
+
+/// The codes of every host module of this crate.
+fn modules() -> Vec<ModuleCodes> {
+    fn module<M: HostModule>() -> ModuleCodes {
+        (M::name(), M::error_code_range(), M::error_codes())
+    }
+    vec![
+        module::<MLApiHost>(),
+        module::<StorageApiHost>(),
+        module::<LoggingApiHost>(),
+        module::<TimeApiHost>(),
+        module::<RandomApiHost>(),
+        module::<MetricsApiHost>(),
+        module::<DatasetApiHost>(),
+        module::<ModelApiHost>(),
+    ]
+}
+
+#[test]
+fn codes_are_unique_and_in_range() {
+    let mut seen = HashMap::new();
+    for &code in ErrorCode::ALL {
+        let raw = code as u32;
+        if let Some(other) = seen.insert(raw, code) {
+            panic!("{:?} and {:?} are both {}", other, code, raw);
+        }
+        assert_eq!(ErrorCode::from_raw(raw), Some(code));
+        if code.is_common() {
+            continue;
+        }
+        let owners: Vec<_> = modules()
+            .into_iter()
+            .filter(|(_, range, _)| range.as_ref().is_some_and(|range| range.contains(&raw)))
+            .collect();
+        assert_eq!(
+            owners.len(),
+            1,
+            "{:?} ({}) isn't in exactly one module's range",
+            code,
+            raw
+        );
+        let (name, _, codes) = &owners[0];
+        assert!(codes.contains(&code), "`{}` doesn't list {:?}", name, code);
+    }
+    for (name, range, codes) in modules() {
+        for code in codes {
+            assert!(
+                ErrorCode::ALL.contains(code),
+                "`{}` lists {:?}, which isn't in `ALL`",
+                name,
+                code
+            );
+            let raw = *code as u32;
+            assert!(
+                range.as_ref().is_some_and(|range| range.contains(&raw)),
+                "`{}` lists {:?} ({}) outside of its range {:?}",
+                name,
+                code,
+                raw,
+                range
+            );
+        }
+    }
+}
+
+#[test]
+fn ranges_are_disjoint() {
+    let registry = ModuleRegistry::default();
+    registry.check_error_codes().unwrap();
+    let ranges: Vec<_> = registry.error_code_ranges().collect();
+    assert_eq!(
+        ranges.len(),
+        modules().len(),
+        "every module reserves a range"
+    );
+    for (name, range) in &ranges {
+        assert!(
+            range.start >= error_code_ranges::COMMON.end,
+            "`{}` {:?}",
+            name,
+            range
+        );
+    }
+}
+
+/// A host module without imports, reserving `$range` and returning `$codes`.
+macro_rules! custom_module {
+    ($ty:ident, $name:literal, $range:expr, $codes:expr) => {
+        struct $ty;
+
+        impl HostModule for $ty {
+            fn name() -> &'static str {
+                $name
+            }
+            fn error_code_range() -> Option<Range<u32>> {
+                $range
+            }
+            fn error_codes() -> &'static [ErrorCode] {
+                $codes
+            }
+            fn get(host_context: &mut ModuleContext) -> Result<&mut Self, ApiError> {
+                host_context.module_mut::<Self>()
+            }
+        }
+
+        impl<'t> Shim<'t> for $ty {
+            type Err = ApiError;
+            type Memory = WasmMemoryHandle<'t>;
+            type Context = ModuleContext;
+            type ImportTable = &'t mut HostLinker;
+            type ImportError = InstantiationError;
+            type WasmTrap = rustc_nightly_reduction::WasmTrap;
+
+            fn namespace() -> (&'static str, &'static str) {
+                ("env", $name)
+            }
+
+            fn imports(_linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+                Ok(())
+            }
+        }
+    };
+}
+
+custom_module!(TakesMl, "takes_ml", Some(1500..2500), &[]);
+custom_module!(TakesCommon, "takes_common", Some(900..1000), &[]);
+custom_module!(
+    ReturnsCommon,
+    "returns_common",
+    Some(100_000..101_000),
+    &[ErrorCode::Busy]
+);
+
+#[test]
+fn overlapping_ranges_are_rejected() {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    let registry = ModuleRegistry::default().with_module::<TakesMl>();
+    match rustc_nightly_reduction::register_host_modules(
+        &mut linker,
+        &registry,
+        &rustc_nightly_reduction::Overrides::none(),
+    ) {
+        Err(InstantiationError::ErrorCodeRangeOverlap {
+            module,
+            other,
+            range,
+        }) => {
+            assert_eq!((module, other), ("ml_api", Some("takes_ml")));
+            assert_eq!(range, error_code_ranges::ML);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    let registry = ModuleRegistry::empty().with_module::<TakesCommon>();
+    match registry.check_error_codes() {
+        Err(InstantiationError::ErrorCodeRangeOverlap { module, other, .. }) => {
+            assert_eq!((module, other), ("takes_common", None));
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn codes_outside_of_the_range_are_rejected() {
+    let registry = ModuleRegistry::empty().with_module::<ReturnsCommon>();
+    let err = registry.check_error_codes().unwrap_err();
+    match &err {
+        InstantiationError::ErrorCodeOutOfRange { module, code, .. } => {
+            assert_eq!((*module, *code), ("returns_common", ErrorCode::Busy));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+    assert!(err.to_string().contains("Busy(9)"), "{}", err);
+}
+
+#[test]
+fn display_round_trips() {
+    for &code in ErrorCode::ALL {
+        let displayed = code.to_string();
+        assert_eq!(displayed, format!("{:?}({})", code, code as u32));
+        assert_eq!(displayed.parse::<ErrorCode>(), Ok(code));
+        assert_eq!(format!("{:?}", code).parse::<ErrorCode>(), Ok(code));
+        assert_eq!((code as u32).to_string().parse::<ErrorCode>(), Ok(code));
+    }
+}
+
+#[test]
+fn parse_failures() {
+    let parse = |s: &str| s.parse::<ErrorCode>().unwrap_err();
+    // Codes of newer hosts stay recognizable
+    assert_eq!(
+        parse("999"),
+        ParseErrorCodeError::Unknown(UnknownErrorCode(999))
+    );
+    assert_eq!(
+        parse("QuotaExceeded(12)"),
+        ParseErrorCodeError::Unknown(UnknownErrorCode(12))
+    );
+    assert_eq!(
+        parse("QuotaExceeded"),
+        ParseErrorCodeError::UnknownName("QuotaExceeded".to_owned())
+    );
+    assert_eq!(
+        parse("NotFound(7)"),
+        ParseErrorCodeError::Mismatch {
+            code: ErrorCode::NotFound,
+            found: 7
+        }
+    );
+    assert_eq!(
+        parse("NotFound(7)").to_string(),
+        "error code NotFound is 6, not 7"
+    );
+    for invalid in [
+        "",
+        "-1",
+        "not found",
+        "NotFound(",
+        "NotFound(x)",
+        "(6)",
+        "NotFound (6)",
+    ] {
+        assert_eq!(
+            parse(invalid),
+            ParseErrorCodeError::Invalid(invalid.to_owned()),
+            "{:?}",
+            invalid
+        );
+    }
+}
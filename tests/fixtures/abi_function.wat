@@ -0,0 +1,8 @@
+;; Declares the host's own ABI version through a function export, as
+;; returned by `host__abi_version`.
+(module
+  (import "env" "host__abi_version" (func $abi_version (result i32)))
+  (func (export "__host_abi_version") (result i32)
+    (call $abi_version))
+  (func (export "run") (result i32)
+    (i32.const 7)))
@@ -0,0 +1,5 @@
+;; Built for ABI version 2.0, whose marshalling the host doesn't know.
+(module
+  (global (export "__host_abi_version") i32 (i32.const 0x20000))
+  (func (export "run") (result i32)
+    (i32.const 7)))
@@ -0,0 +1,5 @@
+;; Built for ABI version 1.7, a newer minor version than the host's.
+(module
+  (global (export "__host_abi_version") i32 (i32.const 0x10007))
+  (func (export "run") (result i32)
+    (i32.const 7)))
@@ -0,0 +1,4 @@
+;; Predates the ABI handshake and doesn't declare a version.
+(module
+  (func (export "run") (result i32)
+    (i32.const 7)))
@@ -0,0 +1,40 @@
+;; Calls every import of the `calc` test module, with valid arguments and
+;; with the ways a call can fail. Results are written from 0x100 on.
+(module
+  (import "env" "calc__add" (func $add (param i32 i32 i32) (result i32)))
+  (import "env" "calc__scale" (func $scale (param f64 f32 i32) (result i32)))
+  (import "env" "calc__count" (func $count (param i32 i32 i32) (result i32)))
+  (import "env" "calc__sum" (func $sum (param i32 i32) (result i32)))
+  (import "env" "calc__reset" (func $reset (result i32)))
+  (import "env" "calc__explode" (func $explode (param i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0x200) "hello world")
+  (data (i32.const 0x220) "\ff\fe")
+  ;; A `Pair { a: 7, b: 35 }`
+  (data (i32.const 0x300) "\07\00\00\00\23\00\00\00")
+  (func (export "add") (result i32)
+    (call $add (i32.const 2) (i32.const 3) (i32.const 0x100)))
+  (func (export "add_overflow") (result i32)
+    (call $add (i32.const -1) (i32.const 1) (i32.const 0x100)))
+  (func (export "add_out_of_bounds") (result i32)
+    (call $add (i32.const 2) (i32.const 3) (i32.const 0x20000)))
+  (func (export "add_null") (result i32)
+    (call $add (i32.const 2) (i32.const 3) (i32.const 0)))
+  (func (export "scale") (result i32)
+    (call $scale (f64.const 1.5) (f32.const -2) (i32.const 0x108)))
+  (func (export "count") (result i32)
+    (call $count (i32.const 0x200) (i32.const 11) (i32.const 0x110)))
+  (func (export "count_empty") (result i32)
+    (call $count (i32.const 0) (i32.const 0) (i32.const 0x110)))
+  (func (export "count_invalid_utf8") (result i32)
+    (call $count (i32.const 0x220) (i32.const 2) (i32.const 0x110)))
+  (func (export "count_out_of_bounds") (result i32)
+    (call $count (i32.const 0xfff0) (i32.const 0x100) (i32.const 0x110)))
+  (func (export "sum") (result i32)
+    (call $sum (i32.const 0x300) (i32.const 0x118)))
+  (func (export "sum_out_of_bounds") (result i32)
+    (call $sum (i32.const 0xfffc) (i32.const 0x118)))
+  (func (export "reset") (result i32)
+    (call $reset))
+  (func (export "explode") (result i32)
+    (call $explode (i32.const 7))))
@@ -0,0 +1,15 @@
+;; Calls `ml__with_callback`, a test import that calls back into `callback`
+;; while it holds the ML module, which then polls a future through another
+;; ML import. Passing a non-zero `trap` makes the callback trap instead.
+(module
+  (import "env" "ml__with_callback" (func $with_callback (param i32) (result i32)))
+  (import "env" "ml__poll_future" (func $poll_future (param i64 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "run") (param $trap i32) (result i32)
+    (call $with_callback (local.get $trap)))
+  (func (export "callback") (param $trap i32) (result i32)
+    (if (local.get $trap)
+      (then unreachable))
+    (call $poll_future (i64.const 1) (i32.const 64)))
+  (func (export "poll") (result i32)
+    (call $poll_future (i64.const 1) (i32.const 64))))
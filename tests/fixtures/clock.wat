@@ -0,0 +1,5 @@
+;; Reads the wall clock through `time__unix_ms`.
+(module
+  (import "env" "time__unix_ms" (func $unix_ms (result i64)))
+  (func (export "now") (result i64)
+    (call $unix_ms)))
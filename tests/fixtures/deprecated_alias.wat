@@ -0,0 +1,10 @@
+;; Polls future 1 through `ml__poll_future_v0`, a deprecated alias of
+;; `ml__poll_future`, or through the import itself.
+(module
+  (import "env" "ml__poll_future_v0" (func $poll_future_v0 (param i64 i32) (result i32)))
+  (import "env" "ml__poll_future" (func $poll_future (param i64 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "poll_old") (result i32)
+    (call $poll_future_v0 (i64.const 1) (i32.const 64)))
+  (func (export "poll_new") (result i32)
+    (call $poll_future (i64.const 1) (i32.const 64))))
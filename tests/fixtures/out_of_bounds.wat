@@ -0,0 +1,21 @@
+;; Starts a training with a model name running past the end of the single
+;; page of guest memory.
+(module
+  (import "env" "ml__start_training"
+    (func $start_training
+      (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+      (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 80) "data/mnist")
+  (func (export "start") (result i32)
+    (call $start_training
+      (i32.const 65534) (i32.const 5)
+      (i32.const 3)
+      (i32.const 80) (i32.const 10)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i64.const 42)
+      (i32.const 0)
+      (i32.const 32))))
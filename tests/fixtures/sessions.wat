@@ -0,0 +1,79 @@
+;; Creates and destroys training sessions and starts and polls trainings of
+;; model "mnist" in them, session `0` being the default session, and reaches
+;; trainings through the plain imports that don't take a session. Outputs
+;; are written to the address passed to each export.
+(module
+  (import "env" "ml__create_session"
+    (func $create_session (param $protocol i32) (param $output i32) (result i32)))
+  (import "env" "ml__destroy_session"
+    (func $destroy_session (param $session i64) (result i32)))
+  (import "env" "ml__start_training_in_session"
+    (func $start_training_in_session
+      (param $session i64)
+      (param $model_name_ptr i32) (param $model_name_len i32)
+      (param $epochs i32)
+      (param $dataset_uri_ptr i32) (param $dataset_uri_len i32)
+      (param $checkpoint_path_ptr i32) (param $checkpoint_path_len i32)
+      (param $eval_interval i32)
+      (param $optimizer_ptr i32) (param $optimizer_len i32)
+      (param $run_name_ptr i32) (param $run_name_len i32)
+      (param $seed i64)
+      (param $protocol i32)
+      (param $output i32)
+      (result i32)))
+  (import "env" "ml__poll_future_in_session"
+    (func $poll_future_in_session
+      (param $session i64) (param $handle i64) (param $status_out i32) (result i32)))
+  (import "env" "ml__poll_future"
+    (func $poll_future (param $handle i64) (param $status_out i32) (result i32)))
+  (import "env" "ml__cancel_training"
+    (func $cancel_training (param $handle i64) (result i32)))
+  (import "env" "ml__free_future"
+    (func $free_future (param $handle i64) (result i32)))
+  (import "env" "ml__get_future_result"
+    (func $get_future_result
+      (param $handle i64) (param $buf_ptr i32) (param $buf_len i32) (result i32)))
+  (import "env" "ml__get_training_metrics"
+    (func $get_training_metrics (param $handle i64) (param $metrics_out i32) (result i32)))
+  (import "env" "ml__list_active_futures"
+    (func $list_active_futures
+      (param $buf_ptr i32) (param $buf_len i32) (param $count_out i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 64) "mnist")
+  (data (i32.const 80) "data/mnist")
+  (func (export "create") (param $output i32) (result i32)
+    (call $create_session (i32.const 0) (local.get $output)))
+  (func (export "destroy") (param $session i64) (result i32)
+    (call $destroy_session (local.get $session)))
+  (func (export "start") (param $session i64) (param $output i32) (result i32)
+    (call $start_training_in_session
+      (local.get $session)
+      (i32.const 64) (i32.const 5)
+      (i32.const 3)
+      (i32.const 80) (i32.const 10)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i64.const 42)
+      (i32.const 0)
+      (local.get $output)))
+  (func (export "poll") (param $session i64) (param $handle i64) (param $status_out i32)
+    (result i32)
+    (call $poll_future_in_session
+      (local.get $session) (local.get $handle) (local.get $status_out)))
+  (func (export "poll_plain") (param $handle i64) (param $status_out i32) (result i32)
+    (call $poll_future (local.get $handle) (local.get $status_out)))
+  (func (export "cancel") (param $handle i64) (result i32)
+    (call $cancel_training (local.get $handle)))
+  (func (export "free") (param $handle i64) (result i32)
+    (call $free_future (local.get $handle)))
+  (func (export "result") (param $handle i64) (param $buf_ptr i32) (param $buf_len i32)
+    (result i32)
+    (call $get_future_result (local.get $handle) (local.get $buf_ptr) (local.get $buf_len)))
+  (func (export "metrics") (param $handle i64) (param $metrics_out i32) (result i32)
+    (call $get_training_metrics (local.get $handle) (local.get $metrics_out)))
+  (func (export "list") (param $buf_ptr i32) (param $buf_len i32) (param $count_out i32)
+    (result i32)
+    (call $list_active_futures
+      (local.get $buf_ptr) (local.get $buf_len) (local.get $count_out))))
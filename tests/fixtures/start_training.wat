@@ -0,0 +1,31 @@
+;; Starts a training of model "mnist" on "data/mnist" for 3 epochs, writing
+;; the future handle to 32.
+(module
+  (import "env" "ml__start_training"
+    (func $start_training
+      (param $model_name_ptr i32) (param $model_name_len i32)
+      (param $epochs i32)
+      (param $dataset_uri_ptr i32) (param $dataset_uri_len i32)
+      (param $checkpoint_path_ptr i32) (param $checkpoint_path_len i32)
+      (param $eval_interval i32)
+      (param $optimizer_ptr i32) (param $optimizer_len i32)
+      (param $run_name_ptr i32) (param $run_name_len i32)
+      (param $seed i64)
+      (param $protocol i32)
+      (param $output i32)
+      (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 64) "mnist")
+  (data (i32.const 80) "data/mnist")
+  (func (export "start") (result i32)
+    (call $start_training
+      (i32.const 64) (i32.const 5)
+      (i32.const 3)
+      (i32.const 80) (i32.const 10)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i32.const 0)
+      (i64.const 42)
+      (i32.const 0)
+      (i32.const 32))))
@@ -0,0 +1,47 @@
+;; Starts a training of model "mnist" on "data/mnist", polls it until it
+;; completes and copies its result to 128, then frees it. The handle is
+;; written to 32, the last status to 48 and the number of polls to 96.
+(module
+  (import "env" "ml__start_training"
+    (func $start_training
+      (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32 i32)
+      (result i32)))
+  (import "env" "ml__poll_future"
+    (func $poll_future (param $handle i64) (param $status_out i32) (result i32)))
+  (import "env" "ml__get_future_result"
+    (func $get_future_result
+      (param $handle i64) (param $buf_ptr i32) (param $buf_len i32)
+      (result i32)))
+  (import "env" "ml__free_future"
+    (func $free_future (param $handle i64) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 64) "mnist")
+  (data (i32.const 80) "data/mnist")
+  (func (export "run") (result i32)
+    (local $code i32)
+    (local.set $code
+      (call $start_training
+        (i32.const 64) (i32.const 5)
+        (i32.const 3)
+        (i32.const 80) (i32.const 10)
+        (i32.const 0) (i32.const 0)
+        (i32.const 0)
+        (i32.const 0) (i32.const 0)
+        (i32.const 0) (i32.const 0)
+        (i64.const 42)
+        (i32.const 0)
+        (i32.const 32)))
+    (if (local.get $code) (then (return (local.get $code))))
+    (loop $poll
+      (local.set $code
+        (call $poll_future (i64.load (i32.const 32)) (i32.const 48)))
+      (if (local.get $code) (then (return (local.get $code))))
+      (i32.store (i32.const 96)
+        (i32.add (i32.load (i32.const 96)) (i32.const 1)))
+      ;; `FutureStatus::PENDING`
+      (br_if $poll (i32.eqz (i32.load (i32.const 48)))))
+    (local.set $code
+      (call $get_future_result
+        (i64.load (i32.const 32)) (i32.const 128) (i32.const 64)))
+    (if (local.get $code) (then (return (local.get $code))))
+    (call $free_future (i64.load (i32.const 32)))))
@@ -0,0 +1,25 @@
+;; Exports trapping in each of the ways `ApiError::from` tells apart, and an
+;; allocator that traps when the host calls it to return the last error.
+(module
+  (import "env" "metrics__counter_add" (func $counter_add (param i32 i32 i64) (result i32)))
+  (import "env" "metrics__get_last_error_alloc" (func $last_error (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 64) "bad name")
+  (func (export "unreachable")
+    unreachable)
+  (func (export "out_of_bounds") (result i32)
+    (i32.load (i32.const 65536)))
+  (func $recurse (export "stack_overflow")
+    call $recurse)
+  (func (export "spin")
+    (loop $forever
+      br $forever))
+  (func (export "divide_by_zero") (result i32)
+    (i32.div_u (i32.const 1) (i32.const 0)))
+  (func (export "host_alloc") (param $len i32) (param $align i32) (result i32)
+    unreachable)
+  ;; Fails a call, then has the host copy the error into a buffer of the
+  ;; trapping allocator
+  (func (export "last_error") (result i32)
+    (drop (call $counter_add (i32.const 64) (i32.const 8) (i64.const 1)))
+    (call $last_error (i32.const 16) (i32.const 20))))
@@ -0,0 +1,8 @@
+;; Imports `ml__start_training` with a signature the host doesn't provide,
+;; as a guest built against another version of the host would.
+(module
+  (import "env" "ml__start_training"
+    (func $start_training (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "start") (result i32)
+    (call $start_training (i32.const 0) (i32.const 0))))
@@ -0,0 +1,55 @@
+//! Runs the targets in `fuzz/` on random inputs, so they keep compiling and
+//! catch the easy bugs without a nightly toolchain and libFuzzer. `cargo test`
+//! only runs a thousand inputs per target, the ignored `smoke_for_a_while`
+//! keeps going for `FUZZ_SMOKE_SECS`, 30 seconds by default. Set
+//! `FUZZ_SMOKE_SEED` to the seed a failed run printed to replay its inputs.
+
+#[path = "../fuzz/src/lib.rs"]
+mod targets;
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::rng::Rng;
+
+/// Random bytes, up to a KiB of them.
+fn fill(rng: &mut Rng, buf: &mut Vec<u8>) {
+    buf.clear();
+    let len = rng.below(1024);
+    buf.extend((0..len).map(|_| rng.next_u64() as u8));
+}
+
+/// Runs both targets on `rounds` inputs each, or until `deadline`.
+fn run(rounds: u64, deadline: Option<Instant>) {
+    let seed = common::rng::seed("FUZZ_SMOKE_SEED");
+    // Printed in case of a failure, to replay it with `FUZZ_SMOKE_SEED`
+    println!("seed {:#x}", seed);
+    let mut rng = Rng::new(seed);
+    let mut input = Vec::new();
+    for _ in 0..rounds {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        fill(&mut rng, &mut input);
+        targets::memory_access(&input);
+        fill(&mut rng, &mut input);
+        targets::protocol_config(&input);
+    }
+}
+
+#[test]
+fn smoke() {
+    run(1_000, None);
+}
+
+/// The long run, with `cargo test --test fuzz_smoke -- --ignored`.
+#[test]
+#[ignore]
+fn smoke_for_a_while() {
+    let budget = std::env::var("FUZZ_SMOKE_SECS")
+        .ok()
+        .map(|secs| secs.parse().expect("`FUZZ_SMOKE_SECS` must be a number"))
+        .unwrap_or(30);
+    run(u64::MAX, Some(Instant::now() + Duration::from_secs(budget)));
+}
@@ -0,0 +1,228 @@
+//! Builds a `no_std` wasm guest from the bindings generated for this crate's
+//! imports and runs it against the host.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use common::context;
+use rustc_nightly_reduction::{
+    ml_imports, register_host_modules, ErrorCode, HostLinker, ModuleContext, ModuleRegistry,
+    Overrides,
+};
+
+/// The guest around the bindings, defining the pointee types they refer to
+/// with the host's layout.
+const GUEST: &str = r#"
+#![no_std]
+#![deny(warnings)]
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    core::arch::wasm32::unreachable()
+}
+
+macro_rules! handles {
+    ($($name:ident),*) => {
+        $(
+            #[derive(Clone, Copy)]
+            #[repr(transparent)]
+            pub struct $name(pub u64);
+        )*
+    };
+}
+
+handles!(FutureHandle, SessionHandle, DatasetHandle, UploadHandle, DownloadHandle);
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FutureStatus {
+    pub state: u32,
+    pub result_len: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ProtocolConfig {
+    pub version: u32,
+    pub batch_size: u32,
+    pub learning_rate: f32,
+    pub flags: u32,
+    pub warmup_steps: u32,
+    pub weight_decay: f32,
+    pub timeout_ms: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct TrainingMetrics {
+    pub epoch: u32,
+    pub total_epochs: u32,
+    pub loss: f32,
+    pub samples_per_sec: f32,
+}
+
+include!("bindings.rs");
+
+static mut HANDLE: u64 = 0;
+
+/// Starts a training of "mnist" and polls it, returning the error code of
+/// the first import that failed.
+#[no_mangle]
+pub extern "C" fn start() -> u32 {
+    let handle = match ml::start_training("mnist", 3, "data/mnist", "", 0, "", "", 42, None) {
+        Ok(handle) => handle,
+        Err(code) => return code.code(),
+    };
+    unsafe { HANDLE = handle.0 };
+    match ml::poll_future(handle.0) {
+        Ok(_) => 0,
+        Err(code) => code.code(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn handle() -> u64 {
+    unsafe { HANDLE }
+}
+
+/// `1` if polling a handle that was never handed out fails with `NotFound`.
+#[no_mangle]
+pub extern "C" fn poll_unknown() -> u32 {
+    matches!(ml::poll_future(u64::MAX), Err(ErrorCode::NotFound)) as u32
+}
+"#;
+
+/// Writes the guest and the bindings for every import of this crate to a
+/// directory of their own, returning the path of the guest's source.
+fn guest_source(name: &str) -> PathBuf {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("bindings.rs"), linker.manifest().to_guest_rust()).unwrap();
+    let guest = dir.join("guest.rs");
+    std::fs::write(&guest, GUEST).unwrap();
+    guest
+}
+
+fn rustc_command() -> Command {
+    Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()))
+}
+
+/// Whether the standard library of `wasm32-unknown-unknown` is installed,
+/// printing that the test is skipped if it isn't. rustup installs it with
+/// `rustup target add wasm32-unknown-unknown`.
+fn has_wasm_target() -> bool {
+    let libdir = rustc_command()
+        .args([
+            "--print",
+            "target-libdir",
+            "--target",
+            "wasm32-unknown-unknown",
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    let has_core = libdir
+        .and_then(|libdir| std::fs::read_dir(libdir).ok())
+        .is_some_and(|mut files| {
+            files.any(|file| {
+                file.is_ok_and(|file| file.file_name().to_string_lossy().starts_with("libcore-"))
+            })
+        });
+    if !has_core {
+        eprintln!(
+            "skipped, `wasm32-unknown-unknown` isn't installed \
+             (`rustup target add wasm32-unknown-unknown`)"
+        );
+    }
+    has_core
+}
+
+/// Compiles `source` for `wasm32-unknown-unknown` with `args`.
+fn rustc(source: &Path, args: &[&str]) {
+    let output = rustc_command()
+        .args(["--edition", "2018", "--target", "wasm32-unknown-unknown"])
+        .args(args)
+        .arg("--out-dir")
+        .arg(source.parent().unwrap())
+        .arg(source)
+        .output()
+        .expect("failed to run rustc");
+    assert!(
+        output.status.success(),
+        "the guest bindings don't compile, is `wasm32-unknown-unknown` installed \
+         (`rustup target add wasm32-unknown-unknown`)?\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn bindings_compile_for_a_no_std_guest() {
+    if !has_wasm_target() {
+        return;
+    }
+    let source = guest_source("bindings_check");
+    rustc(&source, &["--crate-type", "lib", "--emit", "metadata"]);
+}
+
+#[test]
+fn guest_built_from_the_bindings_starts_a_training() {
+    if !has_wasm_target() {
+        return;
+    }
+    let source = guest_source("bindings_guest");
+    rustc(
+        &source,
+        &[
+            "--crate-type",
+            "cdylib",
+            "-C",
+            "opt-level=1",
+            "-C",
+            "panic=abort",
+        ],
+    );
+
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let module = wasmtime::Module::from_file(&engine, source.with_file_name("guest.wasm")).unwrap();
+    let mut store = context().build().unwrap().into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+
+    let code = ModuleContext::call_export::<(), u32>(&mut store, &instance, "start", ()).unwrap();
+    assert_eq!(
+        code,
+        ErrorCode::Success as u32,
+        "{:?}",
+        store.data().last_error()
+    );
+    let handle =
+        ModuleContext::call_export::<(), u64>(&mut store, &instance, "handle", ()).unwrap();
+    assert_ne!(handle, 0, "no future handle written");
+    let stats = store.data().call_stats();
+    let calls: Vec<_> = stats
+        .iter()
+        .map(|stat| (stat.function, stat.calls))
+        .collect();
+    assert_eq!(
+        calls,
+        [
+            (ml_imports::START_TRAINING, 1),
+            (ml_imports::POLL_FUTURE, 1)
+        ]
+    );
+
+    let found =
+        ModuleContext::call_export::<(), u32>(&mut store, &instance, "poll_unknown", ()).unwrap();
+    assert_eq!(
+        found, 1,
+        "unknown handles don't map to `ErrorCode::NotFound`"
+    );
+}
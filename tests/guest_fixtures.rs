@@ -0,0 +1,76 @@
+mod common;
+
+use common::{context, fixture, Guest};
+use rustc_nightly_reduction::{
+    ml_imports, register_host_modules, ErrorCode, HostLinker, ImportName, InstantiationError,
+    ModuleContext, ModuleRegistry, Overrides, GET_LAST_ERROR,
+};
+
+#[test]
+fn start_training() {
+    let mut guest = Guest::new("start_training");
+    assert_eq!(guest.call("start"), ErrorCode::Success as u32);
+    assert!(guest.last_error().is_none());
+    assert_ne!(guest.read_u64(32), 0, "no future handle written");
+    // the strings are left as they were
+    guest.assert_memory(64, b"mnist");
+    let stats = guest.store.data().call_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].function, ml_imports::START_TRAINING);
+    assert_eq!((stats[0].calls, stats[0].failures), (1, 0));
+}
+
+#[test]
+fn stats_and_registry_share_import_names() {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let module = wasmtime::Module::new(&engine, fixture("start_training")).unwrap();
+    let mut store = context().build().unwrap().into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    ModuleContext::call_export::<(), u32>(&mut store, &instance, "start", ()).unwrap();
+
+    let imports = linker.registered_imports();
+    let registered = imports.get("env", ml_imports::START_TRAINING).unwrap();
+    let stats = store.data().call_stats();
+    assert!(std::ptr::eq(stats[0].function, registered.name.as_str()));
+    // Names built at runtime are interned as well
+    let last_error = imports.get("env", "ml__get_last_error").unwrap();
+    assert!(std::ptr::eq(
+        ImportName::join("ml", GET_LAST_ERROR).as_str(),
+        last_error.name.as_str()
+    ));
+}
+
+#[test]
+fn out_of_bounds_pointer() {
+    let mut guest = Guest::new("out_of_bounds");
+    assert_eq!(guest.call("start"), ErrorCode::OutOfBounds as u32);
+    let err = guest.last_error().unwrap();
+    assert_eq!(err.code(), ErrorCode::OutOfBounds);
+    assert!(
+        err.display()
+            .to_string()
+            .contains("outside of guest memory"),
+        "{}",
+        err.display()
+    );
+    guest.assert_zeroed(32, 8);
+}
+
+#[test]
+fn wrong_signature() {
+    let err = Guest::try_new("wrong_signature").err().unwrap();
+    match err {
+        InstantiationError::SignatureMismatch {
+            name,
+            expected,
+            found,
+            ..
+        } => {
+            assert_eq!(name, format!("env::{}", ml_imports::START_TRAINING));
+            assert_ne!(expected, found);
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
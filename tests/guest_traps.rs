@@ -0,0 +1,132 @@
+//! Traps of calls the host makes into the guest, converted into `ApiError`s.
+
+mod common;
+
+use common::fixture;
+use rustc_nightly_reduction::{
+    register_host_modules, ApiError, ErrorCode, HostLinker, MetricsApiHost, ModuleContext,
+    ModuleError, ModuleRegistry, Overrides, Severity,
+};
+
+/// The `traps` fixture in an interruptible store.
+fn instance() -> (wasmtime::Store<ModuleContext>, wasmtime::Instance) {
+    let context = common::context()
+        .with_module(MetricsApiHost::new())
+        .with_interrupts();
+    let engine =
+        wasmtime::Engine::new(context.configure_engine(&mut wasmtime::Config::new())).unwrap();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let module = wasmtime::Module::new(&engine, fixture("traps")).unwrap();
+    let mut store = context.build().unwrap().into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    (store, instance)
+}
+
+fn trap_of(name: &str) -> ApiError {
+    let (mut store, instance) = instance();
+    let func = instance.get_func(&mut store, name).unwrap();
+    let mut results = vec![wasmtime::Val::I32(0); func.ty(&store).results().len()];
+    let err = func.call(&mut store, &[], &mut results).unwrap_err();
+    ApiError::from_guest_call(err)
+}
+
+#[test]
+fn known_traps_have_their_own_codes() {
+    for (export, code) in [
+        ("unreachable", ErrorCode::GuestUnreachable),
+        ("out_of_bounds", ErrorCode::OutOfBounds),
+        ("stack_overflow", ErrorCode::StackOverflow),
+        ("divide_by_zero", ErrorCode::GuestTrapped),
+    ] {
+        let err = trap_of(export);
+        assert_eq!(err.code(), code, "{}: {}", export, err.display());
+        assert_eq!(err.severity(), Severity::Recoverable);
+        assert!(!code.is_graceful(), "{:?}", code);
+    }
+}
+
+#[test]
+fn trap_reason_is_the_message() {
+    let err = trap_of("unreachable");
+    let message = err.display().to_string();
+    // The wasm backtrace of the trap isn't part of it, the backtrace of the
+    // `ApiError` itself follows if one was captured
+    assert!(!message.contains("wasm backtrace"), "{}", message);
+    assert_eq!(
+        message.lines().next(),
+        Some("GuestUnreachable(13): wasm trap: wasm `unreachable` instruction executed")
+    );
+}
+
+#[test]
+fn typed_calls_convert_the_same() {
+    let (mut store, instance) = instance();
+    let func = instance
+        .get_typed_func::<(), i32>(&mut store, "out_of_bounds")
+        .unwrap();
+    let err = ApiError::from_guest_call(func.call(&mut store, ()).unwrap_err());
+    assert_eq!(err.code(), ErrorCode::OutOfBounds);
+}
+
+#[test]
+fn interrupts_are_graceful() {
+    let (mut store, instance) = instance();
+    let spin = instance
+        .get_typed_func::<(), ()>(&mut store, "spin")
+        .unwrap();
+    // Taken before the call, the guest traps on entering it
+    store.data().interrupt_handle().unwrap().interrupt();
+    let err = ApiError::from_guest_call(spin.call(&mut store, ()).unwrap_err());
+    assert_eq!(err.code(), ErrorCode::Interrupted);
+    assert!(err.code().is_graceful());
+    assert!(
+        err.backtrace().is_none(),
+        "graceful errors have no backtrace"
+    );
+}
+
+#[test]
+fn host_traps_are_internal() {
+    let err = ApiError::from(anyhow::anyhow!("host function failed"));
+    assert_eq!(err.code(), ErrorCode::Internal);
+    let message = err.display().to_string();
+    assert_eq!(
+        message.lines().next(),
+        Some("Internal(7): host function failed")
+    );
+}
+
+#[test]
+fn call_errors_that_arent_traps_are_internal() {
+    let (mut store, instance) = instance();
+    let func = instance.get_func(&mut store, "unreachable").unwrap();
+    // `unreachable` takes no arguments
+    let err = func
+        .call(&mut store, &[wasmtime::Val::I32(1)], &mut [])
+        .unwrap_err();
+    assert_eq!(ApiError::from_guest_call(err).code(), ErrorCode::Internal);
+}
+
+#[test]
+fn trapping_allocator_keeps_the_trap_code() {
+    let (mut store, instance) = instance();
+    let result = ModuleContext::call_export::<(), u32>(&mut store, &instance, "last_error", ());
+    match result {
+        Err(ModuleError::HostError(err)) => {
+            assert_eq!(err.code(), ErrorCode::GuestUnreachable);
+            assert_eq!(err.severity(), Severity::Fatal);
+            assert!(
+                err.display()
+                    .to_string()
+                    .starts_with("GuestUnreachable(13): guest export `host_alloc` trapped: "),
+                "{}",
+                err.display()
+            );
+        }
+        result => panic!(
+            "unexpected result: {:?}",
+            result.map_err(|err| err.to_string())
+        ),
+    }
+}
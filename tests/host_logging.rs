@@ -0,0 +1,72 @@
+//! Guests see the same results with and without the `host-logging`
+//! feature, which only decides whether the host logs anything about their
+//! calls. Run with `--no-default-features` as well as with the defaults.
+
+mod common;
+
+use std::sync::Mutex;
+
+use common::Guest;
+use rustc_nightly_reduction::{ml_imports, ErrorCode, HostModule, MLApiHost};
+
+/// Collects the target and message of every record logged by the host.
+struct RecordLog(Mutex<Vec<(String, String)>>);
+
+impl log::Log for RecordLog {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let entry = (record.target().to_owned(), record.args().to_string());
+        self.0.lock().unwrap().push(entry);
+    }
+
+    fn flush(&self) {}
+}
+
+static RECORDS: RecordLog = RecordLog(Mutex::new(Vec::new()));
+
+/// Messages logged so far containing `needle`.
+fn logged(needle: &str) -> Vec<(String, String)> {
+    let records = RECORDS.0.lock().unwrap();
+    records
+        .iter()
+        .filter(|(_, message)| message.contains(needle))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn host_call_logging_only_changes_the_logs() {
+    log::set_logger(&RECORDS).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let mut guest = Guest::new("out_of_bounds");
+    assert_eq!(guest.call("start"), ErrorCode::OutOfBounds as u32);
+    let failed = logged("host call failed");
+    if cfg!(all(feature = "host-logging", not(feature = "tracing"))) {
+        assert_eq!(failed.len(), 1, "{:?}", failed);
+        assert_eq!(failed[0].0, MLApiHost::log_target());
+    } else {
+        assert!(failed.is_empty(), "{:?}", failed);
+    }
+
+    let mut guest = Guest::new("start_training");
+    assert_eq!(guest.call("start"), ErrorCode::Success as u32);
+    let leaked = guest.store.data_mut().finish_session();
+    assert_eq!(leaked.len(), 1);
+    let stats = guest.store.data().call_stats();
+    let start = stats
+        .iter()
+        .find(|stat| stat.function == ml_imports::START_TRAINING)
+        .unwrap();
+    assert_eq!((start.calls, start.failures, start.leaked), (1, 0, 1));
+    let warnings = logged("leaked future handle");
+    assert_eq!(
+        warnings.len(),
+        if cfg!(feature = "host-logging") { 1 } else { 0 },
+        "{:?}",
+        warnings
+    );
+}
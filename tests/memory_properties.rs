@@ -0,0 +1,270 @@
+//! Round trips of `PlainOldData` values and strings through guest memory,
+//! and the error codes of the accesses that are rejected.
+//!
+//! `PROPTEST_CASES` runs more or fewer than 256 cases per property. Failing
+//! cases are saved to `proptest-regressions/` and tried first on later runs.
+
+mod common;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use rustc_nightly_reduction::{ApiError, ErrorCode, GuestStr, PlainOldData, WasmMemoryHandle};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct Sample {
+    id: u32,
+    flags: u16,
+    kind: u8,
+    tag: u8,
+    weight: u64,
+    bytes: [u8; 8],
+}
+
+const _: () = {
+    assert!(std::mem::size_of::<Sample>() == 24);
+    assert!(std::mem::align_of::<Sample>() == 8);
+};
+
+// SAFETY: `repr(C)` of `PlainOldData` fields without padding
+unsafe impl PlainOldData for Sample {}
+
+const SAMPLE_SIZE: u64 = std::mem::size_of::<Sample>() as u64;
+
+prop_compose! {
+    fn sample()(
+        id in any::<u32>(),
+        flags in any::<u16>(),
+        kind in any::<u8>(),
+        tag in any::<u8>(),
+        weight in any::<u64>(),
+        bytes in any::<[u8; 8]>(),
+    ) -> Sample {
+        Sample { id, flags, kind, tag, weight, bytes }
+    }
+}
+
+/// A guest memory size and an address, mostly in or just past the memory,
+/// sometimes where adding a length overflows.
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    memory_len: u64,
+    ptr: u64,
+}
+
+impl Location {
+    fn in_bounds(self, len: u64) -> bool {
+        self.ptr
+            .checked_add(len)
+            .is_some_and(|end| end <= self.memory_len)
+    }
+}
+
+/// `0..near`, or now and then close to `u64::MAX` where adding to it
+/// overflows.
+fn offset(near: u64) -> impl Strategy<Value = u64> {
+    prop_oneof![15 => 0..near, 1 => u64::MAX - 63..=u64::MAX]
+}
+
+prop_compose! {
+    fn location()(memory_len in 0..512u64)(
+        memory_len in Just(memory_len),
+        ptr in offset(memory_len + 64),
+    ) -> Location {
+        Location { memory_len, ptr }
+    }
+}
+
+/// A piece of guest string memory, valid UTF-8 on its own or not.
+#[derive(Clone, Copy, Debug)]
+enum Piece {
+    Ascii(u8),
+    Char(char),
+    /// A byte of `0x80..=0xff`, invalid UTF-8 unless it happens to continue
+    /// a preceding piece.
+    Byte(u8),
+}
+
+impl Piece {
+    fn encode(self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Ascii(byte) | Self::Byte(byte) => bytes.push(byte),
+            Self::Char(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+}
+
+fn piece() -> impl Strategy<Value = Piece> {
+    prop_oneof![
+        2 => (b' '..=b'~').prop_map(Piece::Ascii),
+        1 => (0x80..0x11_0000u32)
+            .prop_map(|c| Piece::Char(std::char::from_u32(c).unwrap_or('\u{fffd}'))),
+        1 => (0x80..=0xffu8).prop_map(Piece::Byte),
+    ]
+}
+
+/// Guest memory made up of string pieces, and a `(ptr, len)` string in it.
+#[derive(Clone, Debug)]
+struct StrCase {
+    pieces: Vec<Piece>,
+    ptr: u64,
+    len: u64,
+}
+
+impl StrCase {
+    fn memory(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for piece in &self.pieces {
+            piece.encode(&mut bytes);
+        }
+        bytes
+    }
+}
+
+prop_compose! {
+    fn str_case()(pieces in vec(piece(), 0..=32))(
+        ptr in 0..pieces.len() as u64 * 2 + 4,
+        len in offset(pieces.len() as u64 * 2 + 4),
+        pieces in Just(pieces),
+    ) -> StrCase {
+        StrCase { pieces, ptr, len }
+    }
+}
+
+fn code(err: &ApiError) -> String {
+    format!("{:?}: {}", err.code(), err.display())
+}
+
+proptest! {
+    #[test]
+    fn pod_round_trip(location in location(), value in sample()) {
+        let mut bytes = vec![0; location.memory_len as usize];
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let aligned = location.ptr.is_multiple_of(8);
+        let valid = aligned && location.in_bounds(SAMPLE_SIZE);
+        match memory.write_pod(location.ptr, &value) {
+            Ok(()) => {
+                prop_assert!(valid, "wrote to an invalid location");
+                match memory.read_pod::<Sample>(location.ptr) {
+                    Ok(read) => prop_assert!(read == value, "read back {:?}", read),
+                    Err(err) => prop_assert!(false, "failed to read back: {}", code(&err)),
+                }
+            }
+            Err(err) => {
+                // Alignment is checked before bounds
+                let expected = if aligned {
+                    ErrorCode::OutOfBounds
+                } else {
+                    ErrorCode::InvalidArgument
+                };
+                prop_assert!(!valid, "rejected a valid location: {}", code(&err));
+                prop_assert!(
+                    err.code() == expected,
+                    "expected {:?}, got {}",
+                    expected,
+                    code(&err)
+                );
+                let read = memory.read_pod::<Sample>(location.ptr).map(|_| ());
+                prop_assert!(
+                    read.as_ref().map_err(ApiError::code) == Err(expected),
+                    "read_pod disagrees with write_pod: {:?}",
+                    read.map_err(|err| code(&err))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pod_slice_round_trip(location in location(), values in vec(sample(), 0..=8)) {
+        let mut bytes = vec![0; location.memory_len as usize];
+        let host_addr = bytes.as_ptr() as u64;
+        let mut memory = WasmMemoryHandle::new(&mut bytes);
+        let count = values.len() as u64;
+        let in_bounds = location.in_bounds(count * SAMPLE_SIZE);
+        match memory.write_pod_slice(location.ptr, &values) {
+            Ok(()) => prop_assert!(in_bounds, "wrote to an invalid location"),
+            Err(err) => {
+                prop_assert!(!in_bounds, "rejected a valid location: {}", code(&err));
+                prop_assert!(err.code() == ErrorCode::OutOfBounds, "{}", code(&err));
+                return Ok(());
+            }
+        }
+        match memory.read_pod_vec::<Sample>(location.ptr, count) {
+            Ok(read) => prop_assert!(read == values, "read back {:?}", read),
+            Err(err) => prop_assert!(false, "failed to read back: {}", code(&err)),
+        }
+        // Borrowing depends on where the memory is in the host, copying
+        // doesn't
+        let host_aligned = host_addr.wrapping_add(location.ptr).is_multiple_of(8);
+        match memory.read_pod_slice::<Sample>(location.ptr, count) {
+            Ok(read) => {
+                prop_assert!(host_aligned, "borrowed a misaligned slice");
+                prop_assert!(read == &values[..], "borrowed {:?}", read);
+            }
+            Err(err) => {
+                prop_assert!(!host_aligned, "rejected an aligned slice: {}", code(&err));
+                prop_assert!(err.code() == ErrorCode::InvalidArgument, "{}", code(&err));
+            }
+        }
+        // The byte length of the count overflows
+        let overflowing = memory.read_pod_vec::<Sample>(location.ptr, u64::MAX / 2);
+        prop_assert!(
+            overflowing.as_ref().map_err(ApiError::code).err() == Some(ErrorCode::OutOfBounds),
+            "read an overflowing count"
+        );
+    }
+
+    #[test]
+    fn read_str_agrees_with_from_utf8(case in str_case()) {
+        let mut bytes = case.memory();
+        let memory_len = bytes.len() as u64;
+        let memory = WasmMemoryHandle::new(&mut bytes);
+        let range = case
+            .ptr
+            .checked_add(case.len)
+            .filter(|&end| end <= memory_len)
+            .map(|end| case.ptr as usize..end as usize);
+        let result = memory.read_str(case.ptr, case.len);
+        match (&range, &result) {
+            (None, Ok(read)) => prop_assert!(false, "read {:?} out of bounds", read),
+            (None, Err(err)) => {
+                prop_assert!(err.code() == ErrorCode::OutOfBounds, "{}", code(err));
+            }
+            (Some(range), _) => {
+                let bytes = case.memory();
+                match (std::str::from_utf8(&bytes[range.clone()]), &result) {
+                    (Ok(expected), Ok(read)) => {
+                        prop_assert!(expected == *read, "read {:?}", read)
+                    }
+                    (Ok(expected), Err(err)) => {
+                        prop_assert!(false, "rejected {:?}: {}", expected, code(err))
+                    }
+                    (Err(utf8), Ok(read)) => {
+                        prop_assert!(false, "read {:?} despite {}", read, utf8)
+                    }
+                    (Err(utf8), Err(err)) => {
+                        prop_assert!(err.code() == ErrorCode::InvalidUtf8, "{}", code(err));
+                        let after = format!("after {} bytes", utf8.valid_up_to());
+                        prop_assert!(
+                            err.display().to_string().contains(&after),
+                            "{} doesn't say it is valid {}",
+                            code(err),
+                            after
+                        );
+                    }
+                }
+            }
+        }
+        // `GuestStr` additionally rejects null pointers of non-empty strings
+        let guest = GuestStr::new(case.ptr, case.len).read(&memory);
+        let expected = if case.ptr == 0 && case.len != 0 {
+            Err(ErrorCode::InvalidArgument)
+        } else {
+            result.map_err(|err| err.code())
+        };
+        prop_assert!(
+            guest.map_err(|err| err.code()) == expected,
+            "GuestStr::read disagrees with read_str"
+        );
+    }
+}
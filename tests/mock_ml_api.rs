@@ -0,0 +1,69 @@
+//! A guest exercised against [`MockMLApi`] instead of a training backend.
+
+mod common;
+
+use common::Guest;
+use rustc_nightly_reduction::{
+    ApiError, ErrorCode, FutureHandle, FutureStatus, MockMLApi, ModuleContext, ModuleRegistry,
+    TrainingRequest,
+};
+
+fn guest(mock: MockMLApi) -> Guest {
+    let registry = ModuleRegistry::empty().with_module::<MockMLApi>();
+    let context = ModuleContext::builder().with_module(mock);
+    Guest::try_with_registry("train_and_wait", &registry, context)
+        .unwrap_or_else(|err| panic!("failed to instantiate fixture: {}", err))
+}
+
+fn mock(guest: &Guest) -> &MockMLApi {
+    guest.store.data().module::<MockMLApi>().unwrap()
+}
+
+#[test]
+fn completes_after_polls() {
+    let mut guest = guest(
+        MockMLApi::new()
+            .with_completion_after(3)
+            .with_result(*b"weights"),
+    );
+    assert_eq!(guest.call("run"), ErrorCode::Success as u32);
+    assert_eq!(guest.read_u32(96), 3, "polls until completed");
+    assert_eq!(guest.read_u32(48), FutureStatus::COMPLETED);
+    guest.assert_memory(128, b"weights");
+
+    let handle = FutureHandle::from_raw(guest.read_u64(32));
+    let mock = mock(&guest);
+    assert!(mock.future(handle).is_none(), "the guest freed the future");
+    assert_eq!(mock.calls().len(), 1);
+    assert_eq!(
+        mock.calls()[0].request(),
+        TrainingRequest {
+            model_name: "mnist",
+            epochs: 3,
+            dataset_uri: "data/mnist",
+            checkpoint_path: "",
+            eval_interval: 0,
+            optimizer: "",
+            run_name: "",
+            seed: 42,
+        }
+    );
+}
+
+#[test]
+fn programmed_responses() {
+    let mut guest = guest(
+        MockMLApi::new()
+            .with_error(ApiError::new(ErrorCode::Busy, "no trainers available"))
+            .with_handle(FutureHandle::from_raw(7))
+            .with_completion_after(1),
+    );
+    assert_eq!(guest.call("run"), ErrorCode::Busy as u32);
+    assert_eq!(guest.last_error().unwrap().code(), ErrorCode::Busy);
+    guest.assert_zeroed(32, 8);
+
+    assert_eq!(guest.call("run"), ErrorCode::Success as u32);
+    assert_eq!(guest.read_u64(32), 7);
+    // Both calls are recorded, including the one that failed
+    assert_eq!(mock(&guest).calls().len(), 2);
+}
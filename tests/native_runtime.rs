@@ -0,0 +1,125 @@
+//! `MLApiHost` shims called through the `NativeLinker`, without any wasm.
+
+use std::convert::TryInto;
+
+use rustc_nightly_reduction::{
+    ml_imports, ErrorCode, FutureStatus, MLApiHost, ModuleContext, NativeInstance, NativeLinker,
+    NativeValue, PortableImports,
+};
+
+const MODEL: usize = 64;
+const DATASET: usize = 80;
+const HANDLE_OUT: u32 = 32;
+const STATUS_OUT: u32 = 48;
+
+fn instance() -> NativeInstance {
+    let mut linker = NativeLinker::new();
+    MLApiHost::portable_imports(&mut linker).unwrap();
+    let context = ModuleContext::builder()
+        .with_module(MLApiHost::default())
+        .with_job_threads(1)
+        .build()
+        .unwrap();
+    let mut instance = linker.instantiate(context, 4096);
+    let memory = instance.memory_mut();
+    memory[MODEL..MODEL + 5].copy_from_slice(b"mnist");
+    memory[DATASET..DATASET + 10].copy_from_slice(b"data/mnist");
+    instance
+}
+
+/// Calls `start_training` like the `start_training` fixture, for a model
+/// name of `model_len` bytes.
+fn start_training(instance: &mut NativeInstance, model_len: u32, output: u32) -> u32 {
+    let args: [NativeValue; 15] = [
+        (MODEL as u32).into(),
+        model_len.into(),
+        3u32.into(),
+        (DATASET as u32).into(),
+        10u32.into(),
+        0u32.into(),
+        0u32.into(),
+        0u32.into(),
+        0u32.into(),
+        0u32.into(),
+        0u32.into(),
+        0u32.into(),
+        42u64.into(),
+        0u32.into(),
+        output.into(),
+    ];
+    instance
+        .call("env", ml_imports::START_TRAINING, &args)
+        .unwrap()
+}
+
+fn read_u32(instance: &NativeInstance, addr: u32) -> u32 {
+    let addr = addr as usize;
+    u32::from_le_bytes(instance.memory()[addr..addr + 4].try_into().unwrap())
+}
+
+fn read_u64(instance: &NativeInstance, addr: u32) -> u64 {
+    let addr = addr as usize;
+    u64::from_le_bytes(instance.memory()[addr..addr + 8].try_into().unwrap())
+}
+
+#[test]
+fn trainings_start_and_poll_without_wasm() {
+    let mut instance = instance();
+    assert_eq!(start_training(&mut instance, 5, HANDLE_OUT), 0);
+    let handle = read_u64(&instance, HANDLE_OUT);
+    assert_ne!(handle, 0);
+    let code = instance
+        .call(
+            "env",
+            ml_imports::POLL_FUTURE,
+            &[handle.into(), STATUS_OUT.into()],
+        )
+        .unwrap();
+    assert_eq!(code, 0);
+    assert_eq!(read_u32(&instance, STATUS_OUT), FutureStatus::PENDING);
+    let ml = instance.context().module::<MLApiHost>().unwrap();
+    assert_eq!(ml.futures().len(), 1);
+}
+
+#[test]
+fn errors_are_returned_as_codes() {
+    let mut instance = instance();
+    assert_eq!(
+        start_training(&mut instance, 0, HANDLE_OUT),
+        ErrorCode::InvalidArgument as u32
+    );
+    let message = instance
+        .context()
+        .last_error()
+        .unwrap()
+        .display()
+        .to_string();
+    assert!(message.contains("model name"), "{}", message);
+    assert_eq!(
+        start_training(&mut instance, 5, 4096),
+        ErrorCode::OutOfBounds as u32
+    );
+}
+
+#[test]
+fn mismatched_calls_trap() {
+    let mut instance = instance();
+    let trap = instance
+        .call("env", ml_imports::POLL_FUTURE, &[1u32.into()])
+        .unwrap_err();
+    assert!(
+        trap.message().contains("don't match the signature"),
+        "{}",
+        trap
+    );
+    let trap = instance.call("env", "ml__evaluate", &[]).unwrap_err();
+    assert_eq!(trap.message(), "unknown import `env::ml__evaluate`");
+}
+
+#[test]
+fn aliases_are_registered() {
+    let mut linker = NativeLinker::new();
+    MLApiHost::portable_imports(&mut linker).unwrap();
+    assert!(linker.provides("env", ml_imports::START_TRAINING_V1));
+    assert!(MLApiHost::portable_imports(&mut linker).is_err());
+}
@@ -0,0 +1,77 @@
+//! Imports of host modules replaced by the embedder through `Overrides`.
+
+mod common;
+
+use rustc_nightly_reduction::{
+    register_host_modules, time_imports, HostLinker, InstantiationError, ModuleContext,
+    ModuleRegistry, Overrides,
+};
+
+/// Frozen clock of a replayed session.
+const REPLAY_MS: i64 = 1_600_000_000_000;
+
+fn linker(overrides: &Overrides) -> HostLinker {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), overrides).unwrap();
+    linker
+}
+
+fn override_clock(linker: &mut HostLinker) -> Result<(), InstantiationError> {
+    linker.func_wrap("replay", "env", time_imports::UNIX_MS, || REPLAY_MS)
+}
+
+#[test]
+fn declared_overrides_replace_the_import() {
+    let mut linker = linker(&Overrides::none().with("env", time_imports::UNIX_MS));
+    override_clock(&mut linker).unwrap();
+    let engine = linker.linker().engine().clone();
+    let module = wasmtime::Module::new(&engine, common::fixture("clock")).unwrap();
+    let mut store = common::context().build().unwrap().into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let now = ModuleContext::call_export::<(), i64>(&mut store, &instance, "now", ());
+    assert_eq!(now.unwrap(), REPLAY_MS);
+    // An import is only replaced once
+    match override_clock(&mut linker) {
+        Err(InstantiationError::DuplicateImport { module, .. }) => assert_eq!(module, "replay"),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn undeclared_collisions_are_duplicates() {
+    let mut linker = linker(&Overrides::none().with("env", time_imports::MONOTONIC_NS));
+    match override_clock(&mut linker) {
+        Err(InstantiationError::DuplicateImport { module, name }) => {
+            assert_eq!(module, "time_api");
+            assert_eq!(name, format!("env::{}", time_imports::UNIX_MS));
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let imports = linker.registered_imports();
+    let import = imports.get("env", time_imports::UNIX_MS).unwrap();
+    assert_eq!((import.module, import.overrides), ("time_api", None));
+}
+
+#[test]
+fn registered_imports_list_overrides() {
+    let mut linker = linker(&Overrides::none().with("env", time_imports::UNIX_MS));
+    override_clock(&mut linker).unwrap();
+    let imports = linker.registered_imports();
+    let import = imports.get("env", time_imports::UNIX_MS).unwrap();
+    assert_eq!(
+        (import.module, import.overrides),
+        ("replay", Some("time_api"))
+    );
+    assert_eq!(imports.of_module("replay").count(), 1);
+    let listing = imports.to_string();
+    assert!(
+        listing
+            .lines()
+            .any(|line| line == "env::time__unix_ms() -> i64 [replay, overrides time_api]"),
+        "{}",
+        listing
+    );
+    let monotonic = imports.get("env", time_imports::MONOTONIC_NS).unwrap();
+    assert_eq!(monotonic.overrides, None);
+}
@@ -0,0 +1,101 @@
+//! Guests re-entering a host module through a callback of one of its calls.
+
+mod common;
+
+use common::{context, fixture};
+use rustc_nightly_reduction::{
+    register_host_modules, CallSite, ErrorCode, HostLinker, HostModule, MLApiHost, ModuleContext,
+    ModuleError, ModuleRegistry, Overrides,
+};
+
+const WITH_CALLBACK: &str = "ml__with_callback";
+
+/// Leases the ML module like a host call would, then calls the guest's
+/// `callback` export with it held.
+fn with_callback(
+    mut caller: wasmtime::Caller<'_, ModuleContext>,
+    trap: i32,
+) -> anyhow::Result<u32> {
+    let site = CallSite::register(MLApiHost::name(), WITH_CALLBACK);
+    let _lease = caller
+        .data()
+        .lease_module(site)
+        .map_err(|err| anyhow::Error::msg(err.display().to_string()))?;
+    let callback = caller
+        .get_export("callback")
+        .and_then(wasmtime::Extern::into_func)
+        .expect("the guest exports `callback`")
+        .typed::<i32, u32>(&caller)
+        .map_err(|err| anyhow::Error::msg(err.to_string()))?;
+    callback.call(&mut caller, trap)
+}
+
+fn instance() -> (wasmtime::Store<ModuleContext>, wasmtime::Instance) {
+    let engine = wasmtime::Engine::default();
+    let mut linker = HostLinker::new(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    linker
+        .func_wrap(MLApiHost::name(), "env", WITH_CALLBACK, with_callback)
+        .unwrap();
+    let module = wasmtime::Module::new(&engine, fixture("callback_loop")).unwrap();
+    let mut store = context().build().unwrap().into_store(&engine);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    (store, instance)
+}
+
+#[test]
+fn nested_calls_are_busy() {
+    let (mut store, instance) = instance();
+    let code = ModuleContext::call_export::<i32, u32>(&mut store, &instance, "run", 0).unwrap();
+    assert_eq!(code, ErrorCode::Busy as u32);
+    let err = store.data().last_error().unwrap();
+    assert_eq!(err.code(), ErrorCode::Busy);
+    let message = err.display().to_string();
+    assert!(
+        message.contains(&format!(
+            "host module `ml_api` is in use by `{}`",
+            WITH_CALLBACK
+        )),
+        "{}",
+        message
+    );
+    // The lease ended with the outer call
+    let code = ModuleContext::call_export::<(), u32>(&mut store, &instance, "poll", ()).unwrap();
+    assert_eq!(code, ErrorCode::NotFound as u32);
+}
+
+#[test]
+fn trapping_callbacks_release_the_module() {
+    let (mut store, instance) = instance();
+    let err = ModuleContext::call_export::<i32, u32>(&mut store, &instance, "run", 1).unwrap_err();
+    assert!(matches!(err, ModuleError::Trap { .. }), "{}", err);
+    let code = ModuleContext::call_export::<(), u32>(&mut store, &instance, "poll", ()).unwrap();
+    assert_eq!(code, ErrorCode::NotFound as u32);
+}
+
+#[test]
+fn leases_are_released_on_panic() {
+    let context = ModuleContext::new();
+    let site = CallSite::register(MLApiHost::name(), WITH_CALLBACK);
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _lease = context.lease_module(site).unwrap();
+        panic!("host call panicked");
+    }));
+    assert!(panicked.is_err());
+    let lease = context.lease_module(site).unwrap();
+    let err = context.lease_module(site).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Busy);
+    drop(lease);
+    let _lease = context.lease_module(site).unwrap();
+}
+
+#[test]
+fn modules_are_leased_separately() {
+    let context = ModuleContext::new();
+    let _ml = context
+        .lease_module(CallSite::register(MLApiHost::name(), WITH_CALLBACK))
+        .unwrap();
+    let _storage = context
+        .lease_module(CallSite::register("storage", "storage__with_callback"))
+        .unwrap();
+}
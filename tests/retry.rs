@@ -0,0 +1,242 @@
+//! Host side retries of trainings the backend failed to start.
+
+mod common;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use common::{fixture, Guest};
+use rustc_nightly_reduction::{
+    register_host_modules, ApiError, ErrorCode, FutureHandle, FutureState, HostLinker, MLApiHost,
+    ModuleContext, ModuleContextBuilder, ModuleRegistry, Overrides, ProtocolConfig, RetryPolicy,
+    Shim, TrainingBackend, TrainingRequest, TrainingStart,
+};
+
+/// Fails the first `failures` starts with `code`, then starts every
+/// training as completed.
+struct FlakyBackend {
+    failures: u32,
+    code: ErrorCode,
+    attempts: Arc<AtomicU32>,
+}
+
+impl TrainingBackend for FlakyBackend {
+    fn start(
+        &mut self,
+        _handle: FutureHandle,
+        _req: TrainingRequest<'_>,
+        _protocol: &ProtocolConfig,
+    ) -> Result<TrainingStart, ApiError> {
+        let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        if attempt <= self.failures {
+            return Err(ApiError::new(self.code, "training queue is full"));
+        }
+        Ok(FutureState::Completed(Vec::new()).into())
+    }
+}
+
+/// A context with a [`FlakyBackend`] and `policy`, and the counter of its
+/// start attempts.
+fn flaky(
+    failures: u32,
+    code: ErrorCode,
+    policy: RetryPolicy,
+) -> (ModuleContextBuilder, Arc<AtomicU32>) {
+    let attempts = Arc::new(AtomicU32::new(0));
+    let backend = FlakyBackend {
+        failures,
+        code,
+        attempts: attempts.clone(),
+    };
+    let context = ModuleContext::builder()
+        .with_module(MLApiHost::default().with_backend(backend))
+        .with_retry_policy(policy)
+        .with_job_threads(1);
+    (context, attempts)
+}
+
+fn quick(_attempt: u32) -> Duration {
+    Duration::from_millis(20)
+}
+
+/// Waits for the only training of `guest` to settle, applying what its
+/// retries reported.
+fn settle(guest: &mut Guest) -> &FutureState {
+    let ml = guest.store.data_mut().module_mut::<MLApiHost>().unwrap();
+    let futures = ml.futures_mut();
+    let handle = futures.handles().next().expect("no training started");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while matches!(futures.get(handle), Ok(FutureState::Pending)) && futures.wait(deadline) {
+        futures.update(|_| {});
+    }
+    futures.get(handle).unwrap()
+}
+
+#[test]
+fn sync_retries_run_on_the_executor() {
+    let policy = RetryPolicy::new(3).with_backoff(quick);
+    let (context, attempts) = flaky(2, ErrorCode::Busy, policy);
+    let mut guest = Guest::with_context("start_training", context);
+    let started = Instant::now();
+    // The guest gets a pending future instead of the backend's error
+    assert_eq!(guest.call("start"), ErrorCode::Success as u32);
+    assert!(guest.last_error().is_none());
+    assert_ne!(guest.read_u64(32), 0, "no future handle written");
+
+    let state = settle(&mut guest);
+    assert!(matches!(state, FutureState::Completed(_)), "{:?}", state);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert!(started.elapsed() >= Duration::from_millis(40));
+}
+
+#[test]
+fn sync_retries_are_exhausted_too() {
+    let policy = RetryPolicy::new(2).with_backoff(quick);
+    let (context, attempts) = flaky(5, ErrorCode::TimedOut, policy);
+    let mut guest = Guest::with_context("start_training", context);
+    assert_eq!(guest.call("start"), ErrorCode::Success as u32);
+
+    let message = match settle(&mut guest) {
+        FutureState::Failed(err) => err.display().to_string(),
+        state => panic!("{:?}", state),
+    };
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        message.lines().next(),
+        Some("TimedOut(5): failed after 2 attempts: training queue is full")
+    );
+}
+
+#[test]
+fn cancelled_trainings_are_not_retried() {
+    let policy = RetryPolicy::new(3).with_backoff(|_| Duration::from_millis(100));
+    let (context, attempts) = flaky(1, ErrorCode::Busy, policy);
+    let mut guest = Guest::with_context("start_training", context);
+    assert_eq!(guest.call("start"), ErrorCode::Success as u32);
+    let written = guest.read_u64(32);
+    let ml = guest.store.data_mut().module_mut::<MLApiHost>().unwrap();
+    let handle = ml.futures().handles().next().unwrap();
+    assert_eq!(handle.raw(), written);
+    ml.cancel_training_shim(handle).unwrap();
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    assert!(matches!(settle(&mut guest), FutureState::Cancelled));
+}
+
+#[test]
+fn other_errors_are_not_retried() {
+    let (context, attempts) = flaky(1, ErrorCode::PermissionDenied, RetryPolicy::new(3));
+    let mut guest = Guest::with_context("start_training", context);
+    assert_eq!(guest.call("start"), ErrorCode::PermissionDenied as u32);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    let message = guest.last_error().unwrap().display().to_string();
+    assert_eq!(
+        message.lines().next(),
+        Some("PermissionDenied(4): training queue is full")
+    );
+}
+
+#[test]
+fn nothing_is_retried_by_default() {
+    let (context, attempts) = flaky(1, ErrorCode::Busy, RetryPolicy::default());
+    let mut guest = Guest::with_context("start_training", context);
+    assert_eq!(guest.call("start"), ErrorCode::Busy as u32);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    let message = guest.last_error().unwrap().display().to_string();
+    assert_eq!(
+        message.lines().next(),
+        Some("Busy(9): training queue is full")
+    );
+}
+
+#[test]
+fn exponential_backoff_is_capped() {
+    assert_eq!(
+        RetryPolicy::exponential_backoff(2),
+        Duration::from_millis(50)
+    );
+    assert_eq!(
+        RetryPolicy::exponential_backoff(3),
+        Duration::from_millis(100)
+    );
+    assert_eq!(
+        RetryPolicy::exponential_backoff(u32::MAX),
+        Duration::from_secs(5)
+    );
+}
+
+struct Unpark(std::thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` on this thread, returning its output and how often it was
+/// pending.
+fn block_on<F: Future>(future: F) -> (F::Output, u32) {
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    let mut pending = 0;
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return (output, pending),
+            Poll::Pending => {
+                pending += 1;
+                std::thread::park();
+            }
+        }
+    }
+}
+
+/// Calls the `start` export of the `start_training` fixture through an
+/// async linker, returning its code, how often the call was pending and the
+/// instance's last error.
+fn start_async(context: ModuleContextBuilder) -> (u32, u32, Option<ApiError>) {
+    let engine = wasmtime::Engine::new(wasmtime::Config::new().async_support(true)).unwrap();
+    let mut linker = HostLinker::new_async(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let module = wasmtime::Module::new(&engine, fixture("start_training")).unwrap();
+    let mut store = context.build().unwrap().into_store(&engine);
+    let instance = block_on(linker.linker().instantiate_async(&mut store, &module))
+        .0
+        .unwrap();
+    let start = instance
+        .get_typed_func::<(), u32>(&mut store, "start")
+        .unwrap();
+    let (code, pending) = block_on(start.call_async(&mut store, ()));
+    (code.unwrap(), pending, store.data().last_error().cloned())
+}
+
+#[test]
+fn async_retries_wait_without_blocking() {
+    let policy = RetryPolicy::new(3).with_backoff(quick);
+    let (context, attempts) = flaky(2, ErrorCode::Busy, policy);
+    let started = Instant::now();
+    let (code, pending, _) = start_async(context);
+    assert_eq!(code, ErrorCode::Success as u32);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert!(started.elapsed() >= Duration::from_millis(40));
+    // Each backoff returned control to the caller instead of sleeping
+    assert!(pending >= 2, "pending {} times", pending);
+}
+
+#[test]
+fn async_retries_are_exhausted_too() {
+    let policy = RetryPolicy::new(2).with_backoff(quick);
+    let (context, attempts) = flaky(5, ErrorCode::TimedOut, policy);
+    let (code, _, err) = start_async(context);
+    assert_eq!(code, ErrorCode::TimedOut as u32);
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    let message = err.unwrap().display().to_string();
+    assert_eq!(
+        message.lines().next(),
+        Some("TimedOut(5): failed after 2 attempts: training queue is full")
+    );
+}
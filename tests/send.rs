@@ -0,0 +1,91 @@
+//! Stores driven from the worker threads of a multi-threaded runtime.
+
+mod common;
+
+use std::time::Duration;
+
+use common::fixture;
+use rustc_nightly_reduction::{
+    register_host_modules, ApiError, BoxFuture, FutureCompleter, FutureHandle, FutureState,
+    HostLinker, JobQueue, MLApiHost, ModuleContext, ModuleContextBuilder, ModuleRegistry,
+    Overrides, ProtocolConfig, TrainingBackend, TrainingRequest, TrainingStart,
+};
+
+fn assert_send<T: Send>() {}
+
+#[test]
+fn contexts_are_send() {
+    assert_send::<ModuleContext>();
+    assert_send::<ModuleContextBuilder>();
+    assert_send::<wasmtime::Store<ModuleContext>>();
+    assert_send::<MLApiHost>();
+    assert_send::<FutureCompleter>();
+    assert_send::<JobQueue>();
+}
+
+/// Starts trainings after yielding to the runtime, so the store is moved
+/// across an await point inside the host call.
+struct YieldingBackend;
+
+impl TrainingBackend for YieldingBackend {
+    fn start(
+        &mut self,
+        _handle: FutureHandle,
+        _req: TrainingRequest<'_>,
+        _protocol: &ProtocolConfig,
+    ) -> Result<TrainingStart, ApiError> {
+        unreachable!("only called through an async linker")
+    }
+
+    fn start_async<'a>(
+        &'a mut self,
+        _handle: FutureHandle,
+        req: TrainingRequest<'a>,
+        _protocol: &'a ProtocolConfig,
+    ) -> BoxFuture<'a, Result<TrainingStart, ApiError>> {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Ok(FutureState::Completed(req.model_name.as_bytes().to_vec()).into())
+        })
+    }
+}
+
+/// The result of the training the `start_training` fixture started.
+async fn start_training(engine: wasmtime::Engine) -> Option<Vec<u8>> {
+    let mut linker = HostLinker::new_async(&engine);
+    register_host_modules(&mut linker, &ModuleRegistry::default(), &Overrides::none()).unwrap();
+    let module = wasmtime::Module::new(&engine, fixture("start_training")).unwrap();
+    let mut store = ModuleContext::builder()
+        .with_module(MLApiHost::default().with_backend(YieldingBackend))
+        .with_job_threads(1)
+        .build()
+        .unwrap()
+        .into_store(&engine);
+    let instance = linker
+        .linker()
+        .instantiate_async(&mut store, &module)
+        .await
+        .unwrap();
+    let start = instance
+        .get_typed_func::<(), u32>(&mut store, "start")
+        .unwrap();
+    assert_eq!(start.call_async(&mut store, ()).await.unwrap(), 0);
+    tokio::task::yield_now().await;
+    let ml = store.data().module::<MLApiHost>().unwrap();
+    let handle = ml.futures().handles().next().unwrap();
+    match ml.futures().get(handle).unwrap() {
+        FutureState::Completed(result) => Some(result.clone()),
+        _ => None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn stores_move_between_worker_threads() {
+    let engine = wasmtime::Engine::new(wasmtime::Config::new().async_support(true)).unwrap();
+    let tasks: Vec<_> = (0..8)
+        .map(|_| tokio::spawn(start_training(engine.clone())))
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap().as_deref(), Some(&b"mnist"[..]));
+    }
+}
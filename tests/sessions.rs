@@ -0,0 +1,244 @@
+//! Trainings in separate sessions of one `MLApiHost`.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::Guest;
+use rustc_nightly_reduction::{
+    ApiError, ErrorCode, FutureHandle, FutureState, FutureStatus, MLApiHost, ModuleContext,
+    ProtocolConfig, TrainingBackend, TrainingRequest, TrainingStart,
+};
+
+/// Keeps every training pending, recording the protocols trainings were
+/// started with and the trainings that were cancelled.
+#[derive(Clone, Default)]
+struct PendingBackend {
+    protocols: Arc<Mutex<Vec<ProtocolConfig>>>,
+    cancelled: Arc<Mutex<Vec<FutureHandle>>>,
+}
+
+impl TrainingBackend for PendingBackend {
+    fn start(
+        &mut self,
+        _handle: FutureHandle,
+        _req: TrainingRequest<'_>,
+        protocol: &ProtocolConfig,
+    ) -> Result<TrainingStart, ApiError> {
+        self.protocols.lock().unwrap().push(*protocol);
+        Ok(FutureState::Pending.into())
+    }
+
+    fn cancel(&mut self, handle: FutureHandle) {
+        self.cancelled.lock().unwrap().push(handle);
+    }
+}
+
+const OUT: i32 = 16;
+const STATUS: i32 = 48;
+
+struct Sessions {
+    guest: Guest,
+    backend: PendingBackend,
+}
+
+impl Sessions {
+    fn new(ml: MLApiHost) -> Self {
+        let backend = PendingBackend::default();
+        let context = ModuleContext::builder()
+            .with_module(ml.with_backend(backend.clone()))
+            .with_job_threads(1)
+            // For `list_active_futures`
+            .with_diagnostics(true);
+        Self {
+            guest: Guest::with_context("sessions", context),
+            backend,
+        }
+    }
+
+    fn call<P: wasmtime::WasmParams>(&mut self, name: &str, params: P) -> u32 {
+        let guest = &mut self.guest;
+        ModuleContext::call_export::<P, u32>(&mut guest.store, &guest.instance, name, params)
+            .unwrap_or_else(|err| panic!("calling `{}` failed: {}", name, err))
+    }
+
+    #[track_caller]
+    fn create(&mut self) -> u64 {
+        assert_eq!(self.call("create", OUT), ErrorCode::Success as u32);
+        self.guest.read_u64(OUT as usize)
+    }
+
+    #[track_caller]
+    fn start(&mut self, session: u64) -> u64 {
+        assert_eq!(
+            self.call("start", (session, OUT)),
+            ErrorCode::Success as u32
+        );
+        self.guest.read_u64(OUT as usize)
+    }
+
+    fn poll(&mut self, session: u64, handle: u64) -> Result<u32, ErrorCode> {
+        match self.call("poll", (session, handle, STATUS)) {
+            0 => Ok(self.guest.read_u32(STATUS as usize)),
+            code => Err(ErrorCode::from_raw(code).unwrap()),
+        }
+    }
+
+    fn ml(&self) -> &MLApiHost {
+        self.guest.store.data().module::<MLApiHost>().unwrap()
+    }
+}
+
+#[test]
+fn trainings_are_only_visible_to_their_session() {
+    let mut sessions = Sessions::new(MLApiHost::default());
+    let first = sessions.create();
+    let second = sessions.create();
+    assert_ne!(first, 0);
+    assert_ne!(first, second);
+    let in_first = sessions.start(first);
+    let in_second = sessions.start(second);
+    let in_default = sessions.start(0);
+
+    assert_eq!(sessions.poll(first, in_first), Ok(FutureStatus::PENDING));
+    assert_eq!(sessions.poll(second, in_second), Ok(FutureStatus::PENDING));
+    assert_eq!(sessions.poll(0, in_default), Ok(FutureStatus::PENDING));
+    assert_eq!(sessions.poll(second, in_first), Err(ErrorCode::NotFound));
+    assert_eq!(sessions.poll(0, in_first), Err(ErrorCode::NotFound));
+    assert_eq!(sessions.poll(first, in_default), Err(ErrorCode::NotFound));
+    let message = sessions.guest.last_error().unwrap().display().to_string();
+    assert_eq!(
+        message.lines().next(),
+        Some(
+            format!(
+                "NotFound(6): training {} isn't part of session {}",
+                in_default, first
+            )
+            .as_str()
+        )
+    );
+    let ml = sessions.ml();
+    assert_eq!(ml.open_sessions(), 2);
+    assert_eq!(ml.futures().len(), 3);
+}
+
+#[test]
+fn destroying_a_session_cancels_and_frees_its_trainings() {
+    let mut sessions = Sessions::new(MLApiHost::default());
+    let first = sessions.create();
+    let second = sessions.create();
+    let in_first = sessions.start(first);
+    let in_second = sessions.start(second);
+
+    assert_eq!(sessions.call("destroy", first), ErrorCode::Success as u32);
+    let cancelled = sessions.backend.cancelled.lock().unwrap().clone();
+    assert_eq!(
+        cancelled
+            .iter()
+            .map(|handle| handle.raw())
+            .collect::<Vec<_>>(),
+        [in_first]
+    );
+    assert_eq!(sessions.poll(second, in_second), Ok(FutureStatus::PENDING));
+    let ml = sessions.ml();
+    assert_eq!(ml.open_sessions(), 1);
+    assert_eq!(ml.futures().len(), 1);
+    // The session is gone along with its trainings
+    assert_eq!(
+        sessions.call("start", (first, OUT)),
+        ErrorCode::StaleHandle as u32
+    );
+    assert_eq!(sessions.poll(first, in_first), Err(ErrorCode::StaleHandle));
+    assert_eq!(
+        sessions.call("destroy", first),
+        ErrorCode::StaleHandle as u32
+    );
+}
+
+#[test]
+fn the_default_session_cant_be_destroyed() {
+    let mut sessions = Sessions::new(MLApiHost::default());
+    let handle = sessions.start(0);
+    assert_eq!(
+        sessions.call("destroy", 0u64),
+        ErrorCode::InvalidArgument as u32
+    );
+    assert_eq!(sessions.poll(0, handle), Ok(FutureStatus::PENDING));
+}
+
+#[test]
+fn sessions_beyond_the_limit_are_busy() {
+    let mut sessions = Sessions::new(MLApiHost::default().with_session_limit(1));
+    let session = sessions.create();
+    assert_eq!(sessions.call("create", OUT), ErrorCode::Busy as u32);
+    assert_eq!(sessions.call("destroy", session), ErrorCode::Success as u32);
+    sessions.create();
+}
+
+#[test]
+fn trainings_default_to_the_protocol_of_their_session() {
+    let mut sessions = Sessions::new(MLApiHost::default());
+    let protocol = ProtocolConfig {
+        batch_size: 128,
+        ..ProtocolConfig::default()
+    };
+    let configured = {
+        let ml = sessions.guest.store.data_mut().module_mut::<MLApiHost>();
+        ml.unwrap().create_session(Some(protocol)).unwrap()
+    };
+    let from_defaults = sessions.create();
+    sessions.start(configured.raw());
+    sessions.start(from_defaults);
+    sessions.start(0);
+    let batch_sizes: Vec<_> = sessions
+        .backend
+        .protocols
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|protocol| protocol.batch_size)
+        .collect();
+    let default = ProtocolConfig::default().batch_size;
+    assert_eq!(batch_sizes, [128, default, default]);
+}
+
+#[test]
+fn plain_imports_only_reach_the_default_session() {
+    let mut sessions = Sessions::new(MLApiHost::default());
+    let session = sessions.create();
+    let in_session = sessions.start(session);
+    let in_default = sessions.start(0);
+
+    let not_found = ErrorCode::NotFound as u32;
+    assert_eq!(sessions.call("poll_plain", (in_session, STATUS)), not_found);
+    assert_eq!(sessions.call("cancel", in_session), not_found);
+    assert_eq!(sessions.call("free", in_session), not_found);
+    assert_eq!(sessions.call("result", (in_session, OUT, 16)), not_found);
+    assert_eq!(sessions.call("metrics", (in_session, STATUS)), not_found);
+    assert_eq!(
+        sessions.call("list", (OUT, 4, STATUS)),
+        ErrorCode::Success as u32
+    );
+    assert_eq!(sessions.guest.read_u32(STATUS as usize), 1);
+    assert_eq!(sessions.guest.read_u64(OUT as usize), in_default);
+    assert!(sessions.backend.cancelled.lock().unwrap().is_empty());
+    assert_eq!(
+        sessions.poll(session, in_session),
+        Ok(FutureStatus::PENDING)
+    );
+
+    assert_eq!(
+        sessions.call("poll_plain", (in_default, STATUS)),
+        ErrorCode::Success as u32
+    );
+    assert_eq!(
+        sessions.call("metrics", (in_default, STATUS)),
+        ErrorCode::Success as u32
+    );
+    assert_eq!(
+        sessions.call("cancel", in_default),
+        ErrorCode::Success as u32
+    );
+    assert_eq!(sessions.call("free", in_default), ErrorCode::Success as u32);
+    assert_eq!(sessions.ml().futures().len(), 1);
+}
@@ -0,0 +1,689 @@
+//! `#[wasm_shim]` generates `Shim::imports` from the `*_shim` methods of a
+//! `Shim` impl block, registering each of them through `host_import!`.
+//! `#[derive(PlainOldData)]` implements `PlainOldData` for guest structs
+//! after checking that they can be copied to and from guest memory.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, AttributeArgs, Data, DeriveInput, FnArg, GenericArgument, Ident, ImplItem,
+    ImplItemMethod, ItemImpl, Lit, Meta, NestedMeta, Pat, PathArguments, ReturnType, Type,
+    TypePath,
+};
+
+/// Generates the import registration for the `*_shim` methods of an impl
+/// block.
+///
+/// Applied to a `Shim` impl the attribute generates `fn imports`. Applied to
+/// an inherent impl, for modules whose shim methods aren't part of the `Shim`
+/// trait, it generates `fn shim_imports(linker: &mut HostLinker)` instead for
+/// `Shim::imports` to call. Both also register `Shim::aliases`.
+///
+/// Every method whose name ends in `_shim` is registered as the import
+/// `{prefix}__{name without _shim}`. Parameters may be `&str`, `&T` for a
+/// `FromGuest` type, or a wasm scalar (`u32`, `u64`, `i32`, `i64`, `f32`,
+/// `f64`). A method returning `Result<T, _>` with a non-unit `T` gets an
+/// extra trailing output pointer parameter that `T` is written to.
+///
+/// With `#[wasm_shim(prefix = "...", dynamic)]` the methods are registered
+/// as `DynHostFunction`s through `HostLinker::define_dynamic` instead, which
+/// decode the same parameters from untyped wasm values. Their calls are
+/// slower, but none of the registration is generic over the signature, so
+/// rarely called modules compile to less code.
+///
+/// ```ignore
+/// #[wasm_shim(prefix = "eval")]
+/// impl Evaluator {
+///     fn evaluate_shim(&mut self, model_name: &str, steps: u32) -> Result<FutureHandle, ApiError> {
+///         // ...
+///     }
+/// }
+///
+/// impl<'t> Shim<'t> for Evaluator {
+///     // ...
+///     fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {
+///         Self::shim_imports(linker)
+///     }
+/// }
+/// ```
+///
+/// Misuse is a compile error pointing at the offending part of the impl,
+/// such as a shim method taking `&self` or a parameter of a type the guest
+/// can't pass. `tests/ui` has a case with the error of each.
+#[proc_macro_attribute]
+pub fn wasm_shim(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let mut item = parse_macro_input!(input as ItemImpl);
+    match expand(args, &mut item) {
+        Ok(imports) => {
+            item.items.push(ImplItem::Verbatim(imports));
+            quote!(#item).into()
+        }
+        Err(err) => {
+            let err = err.to_compile_error();
+            quote!(#item #err).into()
+        }
+    }
+}
+
+fn expand(args: AttributeArgs, item: &mut ItemImpl) -> syn::Result<TokenStream2> {
+    let (prefix, dynamic) = parse_args(&args)?;
+    if let Some(imports) = item.items.iter().find_map(|item| match item {
+        ImplItem::Method(method) if method.sig.ident == "imports" => Some(method),
+        _ => None,
+    }) {
+        return Err(syn::Error::new(
+            imports.sig.ident.span(),
+            "`imports` is generated by `#[wasm_shim]` and must not be implemented by hand",
+        ));
+    }
+
+    let mut registrations = Vec::new();
+    for impl_item in &item.items {
+        if let ImplItem::Method(method) = impl_item {
+            if let Some(function) = method.sig.ident.to_string().strip_suffix("_shim") {
+                let shim = ShimMethod::parse(&prefix, function, method)?;
+                registrations.push(if dynamic {
+                    shim.dynamic_registration(&item.self_ty)
+                } else {
+                    shim.registration(&item.self_ty)
+                });
+            }
+        }
+    }
+
+    let signature = if item.trait_.is_some() {
+        quote!(fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError>)
+    } else {
+        quote! {
+            pub fn shim_imports(
+                linker: &mut ::rustc_nightly_reduction::HostLinker,
+            ) -> Result<(), ::rustc_nightly_reduction::InstantiationError>
+        }
+    };
+    Ok(quote! {
+        #signature {
+            #(#registrations)*
+            let (namespace, _prefix) = <Self as ::rustc_nightly_reduction::Shim<'_>>::namespace();
+            for &alias in <Self as ::rustc_nightly_reduction::Shim<'_>>::aliases() {
+                linker.alias(
+                    <Self as ::rustc_nightly_reduction::HostModule>::name(),
+                    namespace,
+                    alias,
+                )?;
+            }
+            Ok(())
+        }
+    })
+}
+
+/// The arguments of `#[wasm_shim]`: the import name prefix and whether the
+/// imports are registered dynamically.
+fn parse_args(args: &[NestedMeta]) -> syn::Result<(String, bool)> {
+    let (prefix, flags) = match args {
+        [NestedMeta::Meta(Meta::NameValue(nv)), flags @ ..] if nv.path.is_ident("prefix") => {
+            match &nv.lit {
+                Lit::Str(prefix) => (prefix.value(), flags),
+                lit => return Err(syn::Error::new(lit.span(), "`prefix` must be a string")),
+            }
+        }
+        [] => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "missing import name prefix, use `#[wasm_shim(prefix = \"...\")]`",
+            ))
+        }
+        [arg, ..] => return Err(syn::Error::new(arg.span(), "expected `prefix = \"...\"`")),
+    };
+    match flags {
+        [] => Ok((prefix, false)),
+        [NestedMeta::Meta(Meta::Path(path))] if path.is_ident("dynamic") => Ok((prefix, true)),
+        [flag, ..] => Err(syn::Error::new(flag.span(), "expected `dynamic`")),
+    }
+}
+
+/// A `*_shim` method and the import it is registered as.
+struct ShimMethod<'m> {
+    import: String,
+    shim: &'m Ident,
+    params: Vec<(&'m Ident, ParamKind<'m>)>,
+    output: Option<&'m Type>,
+}
+
+/// How a shim parameter is passed by the guest.
+enum ParamKind<'m> {
+    /// A `&str`, as a pointer and a length.
+    Str,
+    /// A `&T` read from a pointer to a `FromGuest` value.
+    Ref(&'m TypePath),
+    /// A wasm scalar, passed as is.
+    Scalar(&'m TypePath),
+}
+
+impl<'m> ShimMethod<'m> {
+    fn parse(prefix: &str, function: &str, method: &'m ImplItemMethod) -> syn::Result<Self> {
+        let mut params = Vec::new();
+        for input in &method.sig.inputs {
+            let input = match input {
+                FnArg::Receiver(receiver) => {
+                    if receiver.reference.is_none() || receiver.mutability.is_none() {
+                        return Err(syn::Error::new(
+                            receiver.span(),
+                            "shim methods must take `&mut self`",
+                        ));
+                    }
+                    continue;
+                }
+                FnArg::Typed(input) => input,
+            };
+            let ident = match &*input.pat {
+                Pat::Ident(pat) => &pat.ident,
+                pat => {
+                    return Err(syn::Error::new(
+                        pat.span(),
+                        "shim parameters must be plain identifiers",
+                    ))
+                }
+            };
+            params.push((ident, param_kind(&input.ty)?));
+        }
+        Ok(Self {
+            import: format!("{}__{}", prefix, function),
+            shim: &method.sig.ident,
+            params,
+            output: output_type(&method.sig.output)?,
+        })
+    }
+
+    /// The parameters of the import in the syntax of `host_import!`.
+    fn import_params(&self) -> Vec<TokenStream2> {
+        let mut params: Vec<_> = self
+            .params
+            .iter()
+            .map(|(ident, kind)| match kind {
+                ParamKind::Str => quote!(#ident: str),
+                ParamKind::Ref(path) => quote!(#ident: &#path),
+                ParamKind::Scalar(path) => quote!(#ident: #path),
+            })
+            .collect();
+        if let Some(output) = self.output {
+            params.push(quote_spanned!(output.span()=> output: *mut #output));
+        }
+        params
+    }
+
+    fn registration(&self, self_ty: &Type) -> TokenStream2 {
+        let Self { import, shim, .. } = self;
+        let params = self.import_params();
+        let args = self.params.iter().map(|(ident, _)| ident);
+        quote! {
+            ::rustc_nightly_reduction::host_import!(linker, #self_ty, #import, (#(#params,)*)
+                => |host| host.#shim(#(#args),*))?;
+        }
+    }
+
+    /// Registers the method as a `DynHostFunction` decoding the same wasm
+    /// parameters `host_import!` takes.
+    fn dynamic_registration(&self, self_ty: &Type) -> TokenStream2 {
+        let Self { import, shim, .. } = self;
+        let mut val_types = Vec::new();
+        let mut decode = Vec::new();
+        for (ident, kind) in &self.params {
+            match kind {
+                ParamKind::Str => {
+                    val_types.extend([quote!(addr_type.clone()), quote!(addr_type.clone())]);
+                    decode.push(quote! {
+                        let #ident = ::rustc_nightly_reduction::GuestStr::new(
+                            wasm_args.addr()?,
+                            wasm_args.addr()?,
+                        )
+                        .read(guest_memory)?;
+                    });
+                }
+                ParamKind::Ref(path) => {
+                    val_types.push(quote!(addr_type.clone()));
+                    decode.push(quote! {
+                        let #ident = &<#path as ::rustc_nightly_reduction::FromGuest>::from_guest(
+                            guest_memory,
+                            ::rustc_nightly_reduction::GuestPtr::new(wasm_args.addr()?),
+                        )?;
+                    });
+                }
+                ParamKind::Scalar(path) => {
+                    val_types
+                        .push(quote!(<#path as ::rustc_nightly_reduction::ImportValue>::VAL_TYPE));
+                    decode.push(quote!(let #ident = wasm_args.value::<#path>()?;));
+                }
+            }
+        }
+        let (call, write) = match self.output {
+            Some(output) => {
+                val_types.push(quote!(addr_type.clone()));
+                decode.push(quote! {
+                    let output = ::rustc_nightly_reduction::GuestPtr::<#output>::new(
+                        wasm_args.addr()?,
+                    )
+                    .non_null()?;
+                });
+                (
+                    quote!(let value = ),
+                    quote!(output.write(guest_memory, &value)?;),
+                )
+            }
+            None => (quote!(), quote!()),
+        };
+        let params = self.import_params();
+        let args = self.params.iter().map(|(ident, _)| ident);
+        quote! {{
+            let addr_type = if linker.is_memory64() {
+                <u64 as ::rustc_nightly_reduction::ImportValue>::VAL_TYPE
+            } else {
+                <u32 as ::rustc_nightly_reduction::ImportValue>::VAL_TYPE
+            };
+            let func = ::rustc_nightly_reduction::DynHostFunction::new(
+                #import,
+                vec![#(#val_types),*],
+                Vec::new(),
+                |host_context, guest_memory, wasm_args, _results| {
+                    let mut wasm_args = ::rustc_nightly_reduction::DynArgs::new(wasm_args);
+                    #(#decode)*
+                    let host = <#self_ty as ::rustc_nightly_reduction::HostModule>::get(host_context)?;
+                    #call host.#shim(#(#args),*)?;
+                    #write
+                    Ok(())
+                },
+            )
+            .with_doc(&::rustc_nightly_reduction::host_import!(@doc [] #(#params,)*));
+            let (namespace, _prefix) = <#self_ty as ::rustc_nightly_reduction::Shim<'_>>::namespace();
+            linker.define_dynamic::<#self_ty>(namespace, func)?;
+        }}
+    }
+}
+
+/// The kind of a shim parameter.
+fn param_kind(ty: &Type) -> syn::Result<ParamKind<'_>> {
+    const SCALARS: &[&str] = &["u32", "u64", "i32", "i64", "f32", "f64"];
+    match ty {
+        Type::Reference(reference) if reference.mutability.is_none() => match &*reference.elem {
+            Type::Path(path) if path.path.is_ident("str") => Ok(ParamKind::Str),
+            Type::Path(path) => Ok(ParamKind::Ref(path)),
+            elem => Err(unsupported(elem)),
+        },
+        Type::Path(path) if SCALARS.iter().any(|scalar| path.path.is_ident(scalar)) => {
+            Ok(ParamKind::Scalar(path))
+        }
+        ty => Err(unsupported(ty)),
+    }
+}
+
+fn unsupported(ty: &Type) -> syn::Error {
+    syn::Error::new(
+        ty.span(),
+        "unsupported shim parameter type, expected `&str`, `&T` or a wasm scalar",
+    )
+}
+
+/// The `T` of a `Result<T, _>` return type, or `None` if it is `()`.
+fn output_type(output: &ReturnType) -> syn::Result<Option<&Type>> {
+    let ty = match output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => {
+            return Err(syn::Error::new(
+                output.span(),
+                "shim methods must return a `Result`",
+            ))
+        }
+    };
+    let ok = match &**ty {
+        Type::Path(path) => path.path.segments.last().and_then(|segment| {
+            match (&segment.arguments, segment.ident == "Result") {
+                (PathArguments::AngleBracketed(args), true) => args.args.first(),
+                _ => None,
+            }
+        }),
+        _ => None,
+    };
+    match ok {
+        Some(GenericArgument::Type(Type::Tuple(tuple))) if tuple.elems.is_empty() => Ok(None),
+        Some(GenericArgument::Type(ty)) => Ok(Some(ty)),
+        _ => Err(syn::Error::new(
+            ty.span(),
+            "shim methods must return a `Result`",
+        )),
+    }
+}
+
+/// Implements `PlainOldData` for a struct, failing to compile unless it is
+/// `#[repr(C)]` or `#[repr(transparent)]`, every field is `PlainOldData` and
+/// the fields leave no padding. `bool`s, enums, references and pointers
+/// aren't `PlainOldData`, as not every bit pattern of them is valid.
+///
+/// ```
+/// use rustc_nightly_reduction::PlainOldData;
+///
+/// #[derive(Clone, Copy, PlainOldData)]
+/// #[repr(C)]
+/// struct Sample {
+///     label: u32,
+///     weight: f32,
+/// }
+/// ```
+///
+/// Each misuse, such as a `bool` field or padding between fields, has a
+/// case in `tests/ui` with the error it fails with.
+#[proc_macro_derive(PlainOldData)]
+pub fn derive_plain_old_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match plain_old_data(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn plain_old_data(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "`PlainOldData` can only be derived for structs",
+            ))
+        }
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "`PlainOldData` can't be derived for generic structs",
+        ));
+    }
+    if !has_stable_repr(input) {
+        return Err(syn::Error::new(
+            input.ident.span(),
+            "`PlainOldData` structs must be `#[repr(C)]` or `#[repr(transparent)]`",
+        ));
+    }
+    let name = &input.ident;
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let field_checks = types
+        .iter()
+        .map(|ty| quote_spanned!(ty.span()=> is_plain_old_data::<#ty>();));
+    let padding = format!("`{}` has padding between or after its fields", name);
+    Ok(quote! {
+        const _: () = {
+            fn is_plain_old_data<T: ::rustc_nightly_reduction::PlainOldData>() {}
+            fn fields() {
+                #(#field_checks)*
+            }
+            assert!(
+                ::std::mem::size_of::<#name>() == 0 #(+ ::std::mem::size_of::<#types>())*,
+                #padding
+            );
+        };
+
+        // SAFETY: `repr(C)` or `repr(transparent)`, only `PlainOldData`
+        // fields and no padding, all checked above
+        unsafe impl ::rustc_nightly_reduction::PlainOldData for #name {}
+    })
+}
+
+fn has_stable_repr(input: &DeriveInput) -> bool {
+    input.attrs.iter().any(|attr| {
+        if !attr.path.is_ident("repr") {
+            return false;
+        }
+        match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested.iter().any(|nested| {
+                matches!(nested, NestedMeta::Meta(Meta::Path(path))
+                    if path.is_ident("C") || path.is_ident("transparent"))
+            }),
+            _ => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse::Parser;
+    use syn::parse_quote;
+    use syn::punctuated::Punctuated;
+
+    fn attribute_args(args: TokenStream2) -> AttributeArgs {
+        Punctuated::<NestedMeta, syn::Token![,]>::parse_terminated
+            .parse2(args)
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    fn shim_error(args: TokenStream2, mut item: ItemImpl) -> String {
+        expand(attribute_args(args), &mut item)
+            .expect_err("expansion should have failed")
+            .to_string()
+    }
+
+    fn shim_method(method: ImplItemMethod) -> ItemImpl {
+        parse_quote!(impl Evaluator { #method })
+    }
+
+    #[test]
+    fn shim_imports_are_generated_for_both_modes() {
+        for args in [quote!(prefix = "eval"), quote!(prefix = "eval", dynamic)] {
+            let mut item = shim_method(parse_quote! {
+                fn evaluate_shim(&mut self, model_name: &str, config: &Config, steps: u32) -> Result<FutureHandle, ApiError> {}
+            });
+            let tokens = expand(attribute_args(args), &mut item).unwrap().to_string();
+            assert!(tokens.contains("\"eval__evaluate\""), "{}", tokens);
+            assert!(tokens.contains("fn shim_imports"), "{}", tokens);
+        }
+    }
+
+    #[test]
+    fn shim_methods_take_mut_self() {
+        for method in [
+            parse_quote!(
+                fn a_shim(&self) -> Result<(), ApiError> {}
+            ),
+            parse_quote!(
+                fn a_shim(self) -> Result<(), ApiError> {}
+            ),
+            parse_quote!(
+                fn a_shim(mut self) -> Result<(), ApiError> {}
+            ),
+        ] {
+            assert_eq!(
+                shim_error(quote!(prefix = "eval"), shim_method(method)),
+                "shim methods must take `&mut self`"
+            );
+        }
+    }
+
+    #[test]
+    fn shim_parameters_are_guest_types() {
+        for method in [
+            parse_quote!(
+                fn a_shim(&mut self, steps: u8) -> Result<(), ApiError> {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self, name: String) -> Result<(), ApiError> {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self, buf: &mut [u8]) -> Result<(), ApiError> {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self, buf: &[u8]) -> Result<(), ApiError> {}
+            ),
+        ] {
+            assert_eq!(
+                shim_error(quote!(prefix = "eval"), shim_method(method)),
+                "unsupported shim parameter type, expected `&str`, `&T` or a wasm scalar"
+            );
+        }
+        assert_eq!(
+            shim_error(
+                quote!(prefix = "eval"),
+                shim_method(parse_quote! {
+                    fn a_shim(&mut self, (a, b): (u32, u32)) -> Result<(), ApiError> {}
+                })
+            ),
+            "shim parameters must be plain identifiers"
+        );
+    }
+
+    #[test]
+    fn shim_methods_return_results() {
+        for method in [
+            parse_quote!(
+                fn a_shim(&mut self) {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self) -> Option<u32> {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self) -> Result {}
+            ),
+            parse_quote!(
+                fn a_shim(&mut self) -> (u32, u32) {}
+            ),
+        ] {
+            assert_eq!(
+                shim_error(quote!(prefix = "eval"), shim_method(method)),
+                "shim methods must return a `Result`"
+            );
+        }
+    }
+
+    #[test]
+    fn shim_arguments_are_checked() {
+        let method = || {
+            shim_method(parse_quote!(
+                fn a_shim(&mut self) -> Result<(), ApiError> {}
+            ))
+        };
+        assert_eq!(
+            shim_error(quote!(), method()),
+            "missing import name prefix, use `#[wasm_shim(prefix = \"...\")]`"
+        );
+        assert_eq!(
+            shim_error(quote!(prefix = 1), method()),
+            "`prefix` must be a string"
+        );
+        assert_eq!(
+            shim_error(quote!(name = "eval"), method()),
+            "expected `prefix = \"...\"`"
+        );
+        for flags in [
+            quote!(prefix = "eval", dynamic = true),
+            quote!(prefix = "eval", fast),
+            quote!(prefix = "eval", dynamic, dynamic),
+        ] {
+            assert_eq!(shim_error(flags, method()), "expected `dynamic`");
+        }
+    }
+
+    #[test]
+    fn shim_imports_are_not_written_by_hand() {
+        let item = parse_quote! {
+            impl<'t> Shim<'t> for Evaluator {
+                fn imports(linker: Self::ImportTable) -> Result<(), Self::ImportError> {}
+            }
+        };
+        assert_eq!(
+            shim_error(quote!(prefix = "eval"), item),
+            "`imports` is generated by `#[wasm_shim]` and must not be implemented by hand"
+        );
+    }
+
+    fn plain_old_data_error(input: DeriveInput) -> String {
+        plain_old_data(&input)
+            .expect_err("derive should have failed")
+            .to_string()
+    }
+
+    #[test]
+    fn plain_old_data_is_implemented_for_pinned_structs() {
+        for input in [
+            parse_quote!(
+                #[repr(C)]
+                struct Sample {
+                    label: u32,
+                    weight: f32,
+                }
+            ),
+            parse_quote!(
+                #[repr(transparent)]
+                struct Handle(u64);
+            ),
+            parse_quote!(
+                #[derive(Clone, Copy)]
+                #[repr(C, align(8))]
+                struct Aligned(u64);
+            ),
+        ] {
+            let tokens = plain_old_data(&input).unwrap().to_string();
+            assert!(tokens.contains("unsafe impl"), "{}", tokens);
+        }
+    }
+
+    #[test]
+    fn plain_old_data_is_only_derived_for_structs() {
+        assert_eq!(
+            plain_old_data_error(parse_quote!(
+                #[repr(u32)]
+                enum Mode {
+                    Train,
+                    Eval,
+                }
+            )),
+            "`PlainOldData` can only be derived for structs"
+        );
+        assert_eq!(
+            plain_old_data_error(parse_quote!(#[repr(C)] union Bits { int: u32, float: f32 })),
+            "`PlainOldData` can only be derived for structs"
+        );
+    }
+
+    #[test]
+    fn plain_old_data_is_not_derived_for_generic_structs() {
+        assert_eq!(
+            plain_old_data_error(parse_quote!(
+                #[repr(C)]
+                struct Wrapper<T> {
+                    value: T,
+                }
+            )),
+            "`PlainOldData` can't be derived for generic structs"
+        );
+    }
+
+    #[test]
+    fn plain_old_data_structs_need_a_stable_repr() {
+        for input in [
+            parse_quote!(
+                struct Unpinned {
+                    value: u32,
+                }
+            ),
+            parse_quote!(
+                #[repr(packed)]
+                struct Packed {
+                    small: u8,
+                    large: u64,
+                }
+            ),
+            parse_quote!(
+                #[repr = "C"]
+                struct Malformed {
+                    value: u32,
+                }
+            ),
+        ] {
+            assert_eq!(
+                plain_old_data_error(input),
+                "`PlainOldData` structs must be `#[repr(C)]` or `#[repr(transparent)]`"
+            );
+        }
+    }
+}
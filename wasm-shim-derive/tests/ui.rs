@@ -0,0 +1,9 @@
+//! Compile errors of misused derives and attributes, with the message and
+//! span pinned by the `.stderr` next to each case. Regenerate them with
+//! `TRYBUILD=overwrite cargo test -p wasm-shim-derive --test ui`.
+
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,10 @@
+use rustc_nightly_reduction::PlainOldData;
+
+#[derive(Clone, Copy, PlainOldData)]
+#[repr(C)]
+struct Flagged {
+    value: u8,
+    enabled: bool,
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use rustc_nightly_reduction::PlainOldData;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Train,
+    Eval,
+}
+
+#[derive(Clone, Copy, PlainOldData)]
+#[repr(C)]
+struct Step {
+    mode: Mode,
+}
+
+fn main() {}
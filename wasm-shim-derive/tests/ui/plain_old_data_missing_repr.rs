@@ -0,0 +1,8 @@
+use rustc_nightly_reduction::PlainOldData;
+
+#[derive(Clone, Copy, PlainOldData)]
+struct Unpinned {
+    value: u32,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use rustc_nightly_reduction::PlainOldData;
+
+#[derive(Clone, Copy, PlainOldData)]
+#[repr(C)]
+struct Padded {
+    small: u8,
+    large: u64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use rustc_nightly_reduction::wasm_shim;
+
+struct Evaluator;
+
+#[wasm_shim(prefix = "eval")]
+impl Evaluator {
+    fn imports() {}
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use rustc_nightly_reduction::{wasm_shim, ApiError};
+
+struct Evaluator;
+
+#[wasm_shim]
+impl Evaluator {
+    fn evaluate_shim(&mut self, steps: u32) -> Result<(), ApiError> {
+        let _ = steps;
+        Ok(())
+    }
+}
+
+fn main() {}
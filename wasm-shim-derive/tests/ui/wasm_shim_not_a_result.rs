@@ -0,0 +1,12 @@
+use rustc_nightly_reduction::wasm_shim;
+
+struct Evaluator;
+
+#[wasm_shim(prefix = "eval")]
+impl Evaluator {
+    fn evaluate_shim(&mut self, steps: u32) -> Option<u32> {
+        Some(steps)
+    }
+}
+
+fn main() {}